@@ -12,10 +12,11 @@
 //!
 //! You should have received a copy of the GNU General Public License
 //! along with this program.  If not, see <https://www.gnu.org/licenses/>.
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    io::Write,
+    collections::{hash_map::Entry, BTreeSet, HashMap},
+    io::{BufReader, Read, Write},
     os::unix::{
         fs::MetadataExt,
         prelude::{FileTypeExt, OsStrExt},
@@ -24,27 +25,261 @@ use std::{
 };
 use walkdir::WalkDir;
 
+use crate::config::LayerConfig;
+
 // https://mgorny.pl/articles/portability-of-tar-features.html#id25
 const PAX_SCHILY_XATTR: &[u8; 13] = b"SCHILY.xattr.";
 
+/// The format of the tar headers written to image layers
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TarFormat {
+    /// ustar headers, with pax extended headers for long paths, long link targets and xattrs
+    #[default]
+    Pax,
+    /// GNU headers, using the GNU extensions for long paths and link targets.
+    /// xattrs are still stored in pax extended headers.
+    Gnu,
+}
+
+impl TarFormat {
+    fn new_header(self) -> tar::Header {
+        match self {
+            TarFormat::Pax => tar::Header::new_ustar(),
+            TarFormat::Gnu => tar::Header::new_gnu(),
+        }
+    }
+}
+
+/// How the mtimes of the entries in image layers are set
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MtimePolicy {
+    /// mtimes later than the image creation time are clamped to it
+    #[default]
+    Clamp,
+    /// Paths owned by a package have the package's build time, and other paths have the image creation time
+    BuildTime,
+}
+
+/// The mtimes given to the entries of a layer
+#[derive(Debug)]
+pub(crate) struct Mtimes {
+    /// The image creation time
+    creation_time: i64,
+    /// The build time of the package owning each path, relative to the root filesystem,
+    /// if paths take the build time of their package
+    build_times: Option<HashMap<PathBuf, i64>>,
+}
+
+impl Mtimes {
+    /// Clamp mtimes to the image creation time, as with tar's `--clamp-mtime` option
+    pub(crate) fn clamp(creation_time: i64) -> Self {
+        Self {
+            creation_time,
+            build_times: None,
+        }
+    }
+
+    /// Give paths the build time of the package that owns them, and other paths the image creation time.
+    /// Paths may be absolute or relative to the root filesystem.
+    pub(crate) fn build_times(
+        creation_time: i64,
+        build_times: impl IntoIterator<Item = (PathBuf, i64)>,
+    ) -> Self {
+        Self {
+            creation_time,
+            build_times: Some(
+                build_times
+                    .into_iter()
+                    .map(|(path, time)| match path.strip_prefix("/") {
+                        Ok(path) => (path.to_path_buf(), time),
+                        Err(_) => (path, time),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The mtime to give the entry at `path`, whose mtime in the root filesystem is `mtime`,
+    /// or `None` if it keeps its mtime
+    fn mtime(&self, path: &Path, mtime: i64) -> Option<i64> {
+        match &self.build_times {
+            Some(build_times) => Some(build_times.get(path).copied().unwrap_or(self.creation_time)),
+            None => (mtime > self.creation_time).then_some(self.creation_time),
+        }
+    }
+}
+
+/// A mapping of a range of ids, in the style of `/proc/<pid>/uid_map`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct IdMapping {
+    /// The first id of the range in the root filesystem
+    pub(crate) from: u32,
+    /// The id that the first id of the range is mapped to in the layer
+    pub(crate) to: u32,
+    /// The length of the range. Defaults to 1.
+    #[serde(default = "id_mapping_count_default")]
+    pub(crate) count: u32,
+}
+
+fn id_mapping_count_default() -> u32 {
+    1
+}
+
+impl IdMapping {
+    fn map(&self, id: u32) -> Option<u32> {
+        id.checked_sub(self.from)
+            .filter(|offset| *offset < self.count)
+            .map(|offset| self.to + offset)
+    }
+}
+
+/// Check that the ranges of an id map are non-empty, within bounds and don't overlap
+pub(crate) fn validate_id_map(map: &[IdMapping]) -> Result<()> {
+    for (i, mapping) in map.iter().enumerate() {
+        if mapping.count == 0 {
+            bail!("Invalid id mapping {:?}: count must be non-zero", mapping);
+        }
+        if mapping.from.checked_add(mapping.count - 1).is_none()
+            || mapping.to.checked_add(mapping.count - 1).is_none()
+        {
+            bail!(
+                "Invalid id mapping {:?}: range exceeds the maximum id",
+                mapping
+            );
+        }
+        let end = |mapping: &IdMapping| u64::from(mapping.from) + u64::from(mapping.count);
+        if let Some(other) = map[..i].iter().find(|other| {
+            u64::from(mapping.from) < end(other) && u64::from(other.from) < end(mapping)
+        }) {
+            bail!(
+                "Invalid id mapping {:?}: range overlaps with {:?}",
+                mapping,
+                other
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Map an id using the first mapping whose range contains it. Unmapped ids are unchanged.
+fn map_id(map: &[IdMapping], id: u32) -> u32 {
+    map.iter().find_map(|mapping| mapping.map(id)).unwrap_or(id)
+}
+
+/// How the root filesystem is split between a lower layer and a top layer
+#[derive(Debug, Default)]
+pub(crate) struct LayerSplit {
+    /// Files, symlinks and hardlinks in the top layer
+    files: BTreeSet<PathBuf>,
+    /// Paths whose entire subtree is in the top layer
+    subtrees: Vec<PathBuf>,
+    /// Directories containing the top layer's contents, which are in both layers
+    ancestors: BTreeSet<PathBuf>,
+}
+
+impl LayerSplit {
+    /// Split the root filesystem so that the given files and subtrees are in the top layer.
+    /// Paths may be absolute or relative to the root filesystem.
+    pub(crate) fn new(
+        files: impl IntoIterator<Item = PathBuf>,
+        subtrees: impl IntoIterator<Item = PathBuf>,
+    ) -> Self {
+        let relative = |path: PathBuf| match path.strip_prefix("/") {
+            Ok(path) => path.to_path_buf(),
+            Err(_) => path,
+        };
+        let files = files.into_iter().map(relative).collect::<BTreeSet<_>>();
+        let subtrees = subtrees.into_iter().map(relative).collect::<Vec<_>>();
+        let ancestors = files
+            .iter()
+            .chain(&subtrees)
+            .flat_map(|path| path.ancestors().skip(1))
+            .filter(|path| *path != Path::new(""))
+            .map(Path::to_path_buf)
+            .collect();
+        Self {
+            files,
+            subtrees,
+            ancestors,
+        }
+    }
+
+    /// Whether the entry at `path` belongs in the top layer
+    fn in_top(&self, path: &Path, is_dir: bool) -> bool {
+        self.subtrees
+            .iter()
+            .any(|subtree| path.starts_with(subtree))
+            || (!is_dir && self.files.contains(path))
+    }
+}
+
+/// The part of the root filesystem that is archived into a layer
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum LayerPart<'a> {
+    /// The entire root filesystem
+    All,
+    /// Everything outside the top layer of the split
+    Lower(&'a LayerSplit),
+    /// The top layer of the split, and the directories containing it
+    Top(&'a LayerSplit),
+}
+
+impl LayerPart<'_> {
+    fn contains(&self, path: &Path, is_dir: bool) -> bool {
+        match self {
+            LayerPart::All => true,
+            LayerPart::Lower(split) => !split.in_top(path, is_dir),
+            LayerPart::Top(split) => {
+                split.in_top(path, is_dir) || (is_dir && split.ancestors.contains(path))
+            }
+        }
+    }
+}
+
+/// A pax extended header record
+type PaxRecord = (Vec<u8>, Vec<u8>);
+
 /// custom implementation of tar-rs's append_dir_all that:
 /// - works around https://github.com/alexcrichton/tar-rs/issues/102 so that security capabilities are preserved
-/// - emulates tar's `--clamp-mtime` option so that any file/dir/symlink mtimes are no later than a specific value
+/// - emulates tar's `--clamp-mtime` option so that any file/dir/symlink mtimes are no later than a specific value,
+///   or sets mtimes to the build time of the owning package
 /// - supports hardlinks
+/// - writes headers in the configured format
+/// - maps file ownership using the configured uid and gid maps
+/// - skips the contents of excluded paths, optionally keeping excluded directories
+/// - copies file contents using a buffer of the configured size
+/// - only archives the given part of the directory
 pub(super) fn append_dir_all_with_xattrs(
     builder: &mut tar::Builder<impl Write>,
     src_path: impl AsRef<Path>,
-    clamp_mtime: i64,
+    mtimes: &Mtimes,
+    cfg: &LayerConfig,
+    part: LayerPart,
 ) -> Result<()> {
     let src_path = src_path.as_ref();
+    let format = cfg.tar_format;
+    validate_id_map(&cfg.uid_map).context("Invalid layer.uid_map")?;
+    validate_id_map(&cfg.gid_map).context("Invalid layer.gid_map")?;
+    if cfg.copy_buffer_size == 0 {
+        bail!("Invalid layer.copy_buffer_size: must be greater than zero");
+    }
     // Map (dev, inode) -> path for hardlinks
     let mut hardlinks: HashMap<(u64, u64), PathBuf> = HashMap::new();
 
-    for entry in WalkDir::new(src_path)
+    let exclude_paths = cfg
+        .exclude_paths
+        .iter()
+        .map(|path| path.strip_prefix("/").unwrap_or(path))
+        .collect::<Vec<_>>();
+
+    let mut walker = WalkDir::new(src_path)
         .follow_links(false)
         .sort_by_file_name()
-        .into_iter()
-    {
+        .into_iter();
+    while let Some(entry) = walker.next() {
         let entry = entry?;
         let meta = entry.metadata()?;
         // skip sockets as tar-rs errors when trying to archive them.
@@ -59,72 +294,163 @@ pub(super) fn append_dir_all_with_xattrs(
             continue;
         }
 
-        if entry.file_type().is_symlink() {
-            if meta.mtime() > clamp_mtime {
-                // Setting the mtime on a symlink is fiddly with tar-rs, so we use filetime to change
-                // the mtime before adding the symlink to the tar archive
-                let mtime = filetime::FileTime::from_unix_time(clamp_mtime, 0);
-                filetime::set_symlink_file_times(entry.path(), mtime, mtime)?;
+        let file_type = entry.file_type();
+        if exclude_paths.contains(&rel_path.as_path()) {
+            if !file_type.is_dir() {
+                continue;
             }
-            add_pax_extension_header(entry.path(), builder)?;
-            builder.append_path_with_name(entry.path(), rel_path)?;
-        } else if entry.file_type().is_file() || entry.file_type().is_dir() {
-            add_pax_extension_header(entry.path(), builder)?;
-
-            // If this is a hardlink, add a link header instead of the file
-            // if this isn't the first time we've seen this inode
-            if meta.nlink() > 1 {
-                match hardlinks.entry((meta.dev(), meta.ino())) {
-                    Entry::Occupied(e) => {
-                        // Add link header and continue to next entry
-                        let mut header = tar::Header::new_gnu();
-                        header.set_metadata(&meta);
-                        if meta.mtime() > clamp_mtime {
-                            header.set_mtime(clamp_mtime as u64);
-                        }
-                        header.set_entry_type(tar::EntryType::Link);
-                        header.set_cksum();
-                        builder.append_link(&mut header, &rel_path, e.get())?;
-                        continue;
-                    }
-                    Entry::Vacant(e) => {
-                        // This is the first time we've seen this inode
-                        e.insert(rel_path.clone());
-                    }
-                }
+            walker.skip_current_dir();
+            if !cfg.keep_excluded_dirs {
+                continue;
+            }
+        }
+        if !(file_type.is_symlink() || file_type.is_file() || file_type.is_dir()) {
+            continue;
+        }
+        if !part.contains(&rel_path, file_type.is_dir()) {
+            // Nothing beneath a directory outside the part is in it either
+            if file_type.is_dir() {
+                walker.skip_current_dir();
             }
+            continue;
+        }
+
+        let mut header = format.new_header();
+        header.set_metadata(&meta);
+        if let Some(mtime) = mtimes.mtime(&rel_path, meta.mtime()) {
+            header.set_mtime(mtime as u64);
+        }
+        header.set_uid(map_id(&cfg.uid_map, meta.uid()).into());
+        header.set_gid(map_id(&cfg.gid_map, meta.gid()).into());
+        let xattrs = xattr_records(entry.path())?;
 
-            let mut header = tar::Header::new_gnu();
-            header.set_size(meta.len());
-            header.set_metadata(&meta);
-            if meta.mtime() > clamp_mtime {
-                header.set_mtime(clamp_mtime as u64);
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(entry.path())?;
+            append_entry(
+                builder,
+                format,
+                header,
+                &rel_path,
+                Some(&target),
+                xattrs,
+                std::io::empty(),
+            )?;
+            continue;
+        }
+
+        // If this is a hardlink, add a link header instead of the file
+        // if this isn't the first time we've seen this inode
+        if meta.nlink() > 1 && file_type.is_file() {
+            match hardlinks.entry((meta.dev(), meta.ino())) {
+                Entry::Occupied(e) => {
+                    // Add link header and continue to next entry
+                    header.set_entry_type(tar::EntryType::Link);
+                    header.set_size(0);
+                    append_entry(
+                        builder,
+                        format,
+                        header,
+                        &rel_path,
+                        Some(e.get()),
+                        xattrs,
+                        std::io::empty(),
+                    )?;
+                    continue;
+                }
+                Entry::Vacant(e) => {
+                    // This is the first time we've seen this inode
+                    e.insert(rel_path.clone());
+                }
             }
-            if entry.file_type().is_file() {
-                builder.append_data(
-                    &mut header,
-                    rel_path,
-                    &mut std::fs::File::open(entry.path())?,
-                )?;
-            } else {
-                builder.append_data(&mut header, rel_path, &mut std::io::empty())?;
-            };
+        }
+
+        if file_type.is_file() {
+            append_entry(
+                builder,
+                format,
+                header,
+                &rel_path,
+                None,
+                xattrs,
+                BufReader::with_capacity(cfg.copy_buffer_size, std::fs::File::open(entry.path())?),
+            )?;
+        } else {
+            append_entry(
+                builder,
+                format,
+                header,
+                &rel_path,
+                None,
+                xattrs,
+                std::io::empty(),
+            )?;
         }
     }
 
     Ok(())
 }
 
-// Convert any extended attributes on the specified path to a tar PAX extension header, and add it to the tar archive
-fn add_pax_extension_header(
-    path: impl AsRef<Path>,
+/// Append an entry to the archive, preceded by a pax extended header if required.
+///
+/// For the GNU format, tar-rs handles long paths and link names via GNU extensions.
+/// For the pax format, long paths and link names are added to the pax extended header, and truncated
+/// values are stored in the ustar header for readers that don't support pax.
+fn append_entry(
     builder: &mut tar::Builder<impl Write>,
-) -> Result<(), anyhow::Error> {
+    format: TarFormat,
+    mut header: tar::Header,
+    path: &Path,
+    link_name: Option<&Path>,
+    mut records: Vec<PaxRecord>,
+    data: impl Read,
+) -> Result<()> {
+    match format {
+        TarFormat::Gnu => {
+            append_pax_extension_header(builder, format, &records)?;
+            match link_name {
+                Some(link_name) => builder.append_link(&mut header, path, link_name)?,
+                None => builder.append_data(&mut header, path, data)?,
+            }
+        }
+        TarFormat::Pax => {
+            let mut long_records = Vec::new();
+            if header.set_path(path).is_err() {
+                long_records.push((b"path".to_vec(), path.as_os_str().as_bytes().to_vec()));
+                header.set_path(truncate(path, header.as_old().name.len()))?;
+            }
+            if let Some(link_name) = link_name {
+                if header.set_link_name(link_name).is_err() {
+                    long_records.push((
+                        b"linkpath".to_vec(),
+                        link_name.as_os_str().as_bytes().to_vec(),
+                    ));
+                    header.set_link_name(truncate(link_name, header.as_old().linkname.len()))?;
+                }
+            }
+            long_records.append(&mut records);
+            append_pax_extension_header(builder, format, &long_records)?;
+            header.set_cksum();
+            builder.append(&header, data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Truncate a path to at most `len` bytes, on a UTF-8 character boundary
+fn truncate(path: &Path, len: usize) -> &str {
+    let bytes = &path.as_os_str().as_bytes()[..len.min(path.as_os_str().len())];
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap(),
+    }
+}
+
+// Read any extended attributes on the specified path as pax extension header records
+fn xattr_records(path: impl AsRef<Path>) -> Result<Vec<PaxRecord>, anyhow::Error> {
     let path = path.as_ref();
     let xattrs = xattr::list(path)
         .with_context(|| format!("Failed to list xattrs from `{}`", path.display()))?;
-    let mut pax_header = tar::Header::new_gnu();
-    let mut pax_data = Vec::new();
+    let mut records = Vec::new();
     for key in xattrs {
         let value = xattr::get(path, &key)
             .with_context(|| {
@@ -135,22 +461,36 @@ fn add_pax_extension_header(
                 )
             })?
             .unwrap_or_default();
+        let mut pax_key = PAX_SCHILY_XATTR.to_vec();
+        pax_key.extend_from_slice(key.as_bytes());
+        records.push((pax_key, value));
+    }
+    Ok(records)
+}
 
+// Add a pax extension header containing the specified records to the tar archive
+fn append_pax_extension_header(
+    builder: &mut tar::Builder<impl Write>,
+    format: TarFormat,
+    records: &[PaxRecord],
+) -> Result<(), anyhow::Error> {
+    let mut pax_data = Vec::new();
+    for (key, value) in records {
         // each entry is "<len> <key>=<value>\n": https://www.ibm.com/docs/en/zos/2.3.0?topic=SSLTBW_2.3.0/com.ibm.zos.v2r3.bpxa500/paxex.html
-        let data_len = PAX_SCHILY_XATTR.len() + key.as_bytes().len() + value.len() + 3;
+        let data_len = key.len() + value.len() + 3;
         // Calculate the total length, including the length of the length field
         let mut len_len = 1;
         while data_len + len_len >= 10usize.pow(len_len.try_into().unwrap()) {
             len_len += 1;
         }
         write!(pax_data, "{} ", data_len + len_len)?;
-        pax_data.write_all(PAX_SCHILY_XATTR)?;
-        pax_data.write_all(key.as_bytes())?;
+        pax_data.write_all(key)?;
         pax_data.write_all("=".as_bytes())?;
-        pax_data.write_all(&value)?;
+        pax_data.write_all(value)?;
         pax_data.write_all("\n".as_bytes())?;
     }
     if !pax_data.is_empty() {
+        let mut pax_header = format.new_header();
         pax_header.set_size(pax_data.len() as u64);
         pax_header.set_entry_type(tar::EntryType::XHeader);
         pax_header.set_cksum();
@@ -158,3 +498,396 @@ fn add_pax_extension_header(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        path::{Path, PathBuf},
+    };
+
+    use super::{
+        append_dir_all_with_xattrs, validate_id_map, IdMapping, LayerPart, LayerSplit, Mtimes,
+        TarFormat,
+    };
+    use crate::config::LayerConfig;
+
+    fn archive_with_config(src: &Path, cfg: &LayerConfig) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.follow_symlinks(false);
+        append_dir_all_with_xattrs(&mut builder, src, &Mtimes::clamp(0), cfg, LayerPart::All)
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn archive(src: &Path, format: TarFormat) -> Vec<u8> {
+        archive_with_config(
+            src,
+            &LayerConfig {
+                tar_format: format,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn copy_buffer_sizes() {
+        // A file whose size isn't a multiple of any of the buffer sizes
+        let src = tempfile::tempdir().unwrap();
+        let contents = (0..64 * 1024 + 7)
+            .map(|i: u32| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(src.path().join("large"), &contents).unwrap();
+
+        let archives = [1, 1000, 8 * 1024, 1024 * 1024]
+            .into_iter()
+            .map(|copy_buffer_size| {
+                archive_with_config(
+                    src.path(),
+                    &LayerConfig {
+                        copy_buffer_size,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+        // The buffer size doesn't affect the layer
+        assert!(archives.iter().all(|archive| *archive == archives[0]));
+        let mut archive = tar::Archive::new(archives[0].as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let mut read = Vec::new();
+        entry.read_to_end(&mut read).unwrap();
+        assert!(read == contents);
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let cfg = LayerConfig {
+            copy_buffer_size: 0,
+            ..Default::default()
+        };
+        assert!(append_dir_all_with_xattrs(
+            &mut builder,
+            src.path(),
+            &Mtimes::clamp(0),
+            &cfg,
+            LayerPart::All
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn long_paths_are_readable_in_each_format() {
+        let src = tempfile::tempdir().unwrap();
+        let dir = src.path().join("a".repeat(60)).join("b".repeat(60));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file_name = "c".repeat(120);
+        std::fs::write(dir.join(&file_name), "hello").unwrap();
+        let long_path = Path::new(&"a".repeat(60))
+            .join("b".repeat(60))
+            .join(&file_name);
+        std::os::unix::fs::symlink(&long_path, src.path().join("link")).unwrap();
+
+        for format in [TarFormat::Pax, TarFormat::Gnu] {
+            let data = archive(src.path(), format);
+            // Archiving is deterministic
+            assert_eq!(data, archive(src.path(), format));
+
+            let mut archive = tar::Archive::new(data.as_slice());
+            let mut found_file = false;
+            let mut found_link = false;
+            for entry in archive.entries().unwrap() {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                match format {
+                    TarFormat::Pax => assert!(header.as_ustar().is_some()),
+                    TarFormat::Gnu => assert!(header.as_gnu().is_some()),
+                }
+                let path = entry.path().unwrap().into_owned();
+                if path == long_path {
+                    assert_eq!(header.size().unwrap(), 5);
+                    found_file = true;
+                } else if path == Path::new("link") {
+                    assert_eq!(entry.link_name().unwrap().unwrap(), long_path);
+                    found_link = true;
+                }
+            }
+            assert!(found_file, "{:?} archive missing long path", format);
+            assert!(found_link, "{:?} archive missing long link", format);
+        }
+    }
+
+    #[test]
+    fn mtimes_are_set_to_package_build_times() {
+        let src = tempfile::tempdir().unwrap();
+        std::fs::create_dir(src.path().join("usr")).unwrap();
+        std::fs::write(src.path().join("usr/packaged"), "hello").unwrap();
+        std::fs::write(src.path().join("unowned"), "hello").unwrap();
+        let mtimes = |mtimes: &Mtimes| {
+            let mut builder = tar::Builder::new(Vec::new());
+            append_dir_all_with_xattrs(
+                &mut builder,
+                src.path(),
+                mtimes,
+                &LayerConfig::default(),
+                LayerPart::All,
+            )
+            .unwrap();
+            let data = builder.into_inner().unwrap();
+            let mut archive = tar::Archive::new(data.as_slice());
+            archive
+                .entries()
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    (
+                        entry.path().unwrap().display().to_string(),
+                        entry.header().mtime().unwrap(),
+                    )
+                })
+                .collect::<std::collections::BTreeMap<_, _>>()
+        };
+
+        let build_times = mtimes(&Mtimes::build_times(
+            2000,
+            [("/usr/packaged".into(), 1000), ("/usr".into(), 500)],
+        ));
+        assert_eq!(build_times["usr/packaged"], 1000);
+        assert_eq!(build_times["usr"], 500);
+        assert_eq!(build_times["unowned"], 2000);
+
+        // By default, mtimes are only clamped
+        let clamped = mtimes(&Mtimes::clamp(2000));
+        assert!(clamped.values().all(|mtime| *mtime == 2000));
+        let unclamped = mtimes(&Mtimes::clamp(i64::MAX));
+        assert!(unclamped.values().all(|mtime| *mtime > 2000));
+    }
+
+    #[test]
+    fn ownership_is_mapped() {
+        let src = tempfile::tempdir().unwrap();
+        std::fs::create_dir(src.path().join("dir")).unwrap();
+        std::fs::write(src.path().join("dir/file"), "hello").unwrap();
+        std::os::unix::fs::symlink("dir/file", src.path().join("link")).unwrap();
+        let meta = std::fs::metadata(src.path()).unwrap();
+
+        let cfg = LayerConfig {
+            uid_map: vec![IdMapping {
+                from: meta.uid(),
+                to: 1000,
+                count: 1,
+            }],
+            gid_map: vec![IdMapping {
+                from: meta.gid(),
+                to: 1000,
+                count: 1,
+            }],
+            ..Default::default()
+        };
+        let data = archive_with_config(src.path(), &cfg);
+        let mut archive = tar::Archive::new(data.as_slice());
+        let mut entries = 0;
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            assert_eq!(entry.header().uid().unwrap(), 1000);
+            assert_eq!(entry.header().gid().unwrap(), 1000);
+            entries += 1;
+        }
+        assert_eq!(entries, 3);
+    }
+
+    #[test]
+    fn invalid_id_maps_are_rejected() {
+        let mapping = |from, to, count| IdMapping { from, to, count };
+        validate_id_map(&[mapping(0, 1000, 1), mapping(1, 100000, 65536)]).unwrap();
+        validate_id_map(&[mapping(u32::MAX, 0, 1), mapping(0, 1, 1)]).unwrap();
+        assert!(validate_id_map(&[mapping(0, 1000, 0)]).is_err());
+        assert!(validate_id_map(&[mapping(0, u32::MAX, 2)]).is_err());
+        assert!(validate_id_map(&[mapping(0, 1000, 10), mapping(5, 2000, 1)]).is_err());
+    }
+
+    #[test]
+    fn empty_directories_are_kept() {
+        let src = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(src.path().join("var/log/app")).unwrap();
+        std::fs::create_dir_all(src.path().join("opt/app/cache")).unwrap();
+        std::fs::write(src.path().join("opt/app/bin"), "").unwrap();
+        std::fs::set_permissions(
+            src.path().join("var/log/app"),
+            std::fs::Permissions::from_mode(0o750),
+        )
+        .unwrap();
+
+        let entries = |part: LayerPart| {
+            let mut builder = tar::Builder::new(Vec::new());
+            append_dir_all_with_xattrs(
+                &mut builder,
+                src.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                part,
+            )
+            .unwrap();
+            let data = builder.into_inner().unwrap();
+            let mut archive = tar::Archive::new(data.as_slice());
+            archive
+                .entries()
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    (
+                        entry.path().unwrap().display().to_string(),
+                        entry.header().entry_type(),
+                        entry.header().mode().unwrap() & 0o7777,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let all = entries(LayerPart::All);
+        assert!(all.contains(&("var/log/app".to_string(), tar::EntryType::Directory, 0o750)));
+        assert!(all
+            .iter()
+            .any(|(path, entry_type, _)| path == "opt/app/cache"
+                && *entry_type == tar::EntryType::Directory));
+
+        // Empty directories stay in the lower layer when the root filesystem is split,
+        // unless they're under a top path
+        let split = LayerSplit::new(Vec::new(), vec![PathBuf::from("/opt/app")]);
+        let lower = entries(LayerPart::Lower(&split));
+        let top = entries(LayerPart::Top(&split));
+        assert!(lower.iter().any(|(path, _, _)| path == "var/log/app"));
+        assert!(!lower.iter().any(|(path, _, _)| path == "opt/app/cache"));
+        assert!(top.iter().any(|(path, _, _)| path == "opt/app/cache"));
+        assert!(!top.iter().any(|(path, _, _)| path == "var/log/app"));
+    }
+
+    #[test]
+    fn excluded_paths_are_skipped() {
+        let src = tempfile::tempdir().unwrap();
+        for dir in ["proc/1", "sys/kernel", "usr/bin"] {
+            std::fs::create_dir_all(src.path().join(dir)).unwrap();
+        }
+        std::fs::write(src.path().join("proc/1/status"), "State: R").unwrap();
+        std::fs::write(src.path().join("usr/bin/sh"), "").unwrap();
+
+        let paths = |cfg: &LayerConfig| {
+            let data = archive_with_config(src.path(), cfg);
+            let mut archive = tar::Archive::new(data.as_slice());
+            archive
+                .entries()
+                .unwrap()
+                .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            paths(&LayerConfig::default()),
+            vec!["proc", "sys", "usr", "usr/bin", "usr/bin/sh"]
+        );
+        assert_eq!(
+            paths(&LayerConfig {
+                keep_excluded_dirs: false,
+                ..Default::default()
+            }),
+            vec!["usr", "usr/bin", "usr/bin/sh"]
+        );
+    }
+
+    #[test]
+    fn split_layers_partition_the_root_filesystem() {
+        let src = tempfile::tempdir().unwrap();
+        for dir in ["usr/bin", "usr/lib/app", "opt/app/data", "var/lib/rpm"] {
+            std::fs::create_dir_all(src.path().join(dir)).unwrap();
+        }
+        // Files of the runtime and application packages, and files injected under /opt/app
+        for file in [
+            "usr/bin/sh",
+            "usr/bin/app",
+            "usr/lib/app/lib.so",
+            "opt/app/data/config",
+            "var/lib/rpm/rpmdb.sqlite",
+        ] {
+            std::fs::write(src.path().join(file), file).unwrap();
+        }
+        std::fs::hard_link(
+            src.path().join("usr/bin/app"),
+            src.path().join("usr/bin/app2"),
+        )
+        .unwrap();
+
+        let split = LayerSplit::new(
+            [
+                "/usr/bin/app",
+                "/usr/bin/app2",
+                "/usr/lib/app",
+                "/usr/lib/app/lib.so",
+            ]
+            .map(Into::into),
+            ["/opt/app", "/var/lib/rpm"].map(Into::into),
+        );
+        let paths = |part: LayerPart| {
+            let mut builder = tar::Builder::new(Vec::new());
+            append_dir_all_with_xattrs(
+                &mut builder,
+                src.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                part,
+            )
+            .unwrap();
+            let data = builder.into_inner().unwrap();
+            let mut archive = tar::Archive::new(data.as_slice());
+            archive
+                .entries()
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    let link = entry.header().entry_type() == tar::EntryType::Link;
+                    let path = entry.path().unwrap().display().to_string();
+                    if link {
+                        format!("{} (link)", path)
+                    } else {
+                        path
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Directories owned by packages in the top layer may be shared with other packages,
+        // so are kept in the lower layer
+        assert_eq!(
+            paths(LayerPart::Lower(&split)),
+            vec![
+                "opt",
+                "usr",
+                "usr/bin",
+                "usr/bin/sh",
+                "usr/lib",
+                "usr/lib/app",
+                "var",
+                "var/lib"
+            ]
+        );
+        // The top layer includes the directories containing its files, and hardlinks
+        // only refer to files in the same layer
+        assert_eq!(
+            paths(LayerPart::Top(&split)),
+            vec![
+                "opt",
+                "opt/app",
+                "opt/app/data",
+                "opt/app/data/config",
+                "usr",
+                "usr/bin",
+                "usr/bin/app",
+                "usr/bin/app2 (link)",
+                "usr/lib",
+                "usr/lib/app",
+                "usr/lib/app/lib.so",
+                "var",
+                "var/lib",
+                "var/lib/rpm",
+                "var/lib/rpm/rpmdb.sqlite"
+            ]
+        );
+        assert_eq!(paths(LayerPart::All).len(), 16);
+    }
+}
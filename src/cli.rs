@@ -19,6 +19,10 @@ use std::path::PathBuf;
 use clap::{Parser, Subcommand};
 use clap_verbosity_flag::Verbosity;
 
+use crate::config::{is_valid_build_arg_name, Platform};
+use crate::lockfile::{FeedFormat, LockfileFormat};
+use crate::{DigestAlgorithm, ExistingLayout, LayerCompression};
+
 /// Main CLI struct
 #[derive(Debug, Parser)]
 #[clap(
@@ -30,9 +34,22 @@ pub struct Cli {
     #[clap(subcommand)]
     /// The available subcommand
     pub command: Command,
-    /// Verbosity
+    /// Verbosity. `--quiet` also silences progress messages other than errors
     #[clap(flatten)]
     pub verbose: Verbosity,
+    /// The format of progress messages, which are written to stderr
+    #[clap(long = "progress", value_enum, default_value_t, global = true)]
+    pub progress: ProgressFormat,
+}
+
+/// The format of progress messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressFormat {
+    /// Human readable messages, with the output of dnf
+    #[default]
+    Human,
+    /// A JSON object per line, without the output of dnf
+    Json,
 }
 
 fn label_parser(s: &str) -> Result<(String, String), String> {
@@ -42,6 +59,23 @@ fn label_parser(s: &str) -> Result<(String, String), String> {
     }
 }
 
+fn build_arg_parser(s: &str) -> Result<(String, String), String> {
+    let (key, value) = label_parser(s)?;
+    if !is_valid_build_arg_name(&key) {
+        return Err(format!(
+            "`{}` is not a valid build argument name. Names may only contain ASCII letters, digits and underscores, and may not start with a digit.",
+            key
+        ));
+    }
+    Ok((key, value))
+}
+
+fn platform_parser(s: &str) -> Result<Platform, String> {
+    let platform = s.parse::<Platform>().map_err(|err| err.to_string())?;
+    platform.dnf_arch().map_err(|err| err.to_string())?;
+    Ok(platform)
+}
+
 /// Subcommands
 #[derive(Debug, Subcommand)]
 pub enum Command {
@@ -56,6 +90,14 @@ pub enum Command {
         /// local RPMs being present, which may be useful in dependency updating scenarios.
         #[clap(long = "from-lockfile")]
         from_lockfile: bool,
+        /// The format to write the lock file in.
+        /// By default, the format of the existing lock file is kept, or TOML is used if there isn't one.
+        #[clap(long = "output-format", value_enum)]
+        output_format: Option<LockfileFormat>,
+        /// The platform to build for, as `os/arch[/variant]`, e.g `linux/arm64/v8`.
+        /// Overrides the platform, architecture, os and variant in the manifest
+        #[clap(long = "platform", value_parser = platform_parser)]
+        platform: Option<Platform>,
     },
     /// Build an OCI image
     Build {
@@ -63,6 +105,10 @@ pub enum Command {
         /// an error if the lock file is missing or needs to be updated
         #[clap(long = "locked")]
         locked: bool,
+        /// Install exactly the packages in the lock file, downloading them from the locations it records
+        /// without loading repository metadata. Implies --locked.
+        #[clap(long = "frozen", conflicts_with = "vendor_dir")]
+        frozen: bool,
         #[clap(long = "image")]
         /// Path to OCI image layout
         image: String,
@@ -70,6 +116,10 @@ pub enum Command {
         /// Specify additional labels to apply to the image
         /// Labels are specified as KEY=VALUE
         label: Vec<(String, String)>,
+        #[clap(long = "build-arg", value_parser = build_arg_parser)]
+        /// Specify build arguments, which can be referenced in label values as `${BUILD_ARG_KEY}`.
+        /// Build arguments are specified as KEY=VALUE
+        build_arg: Vec<(String, String)>,
         #[clap(long = "tag")]
         /// The tag to give the image in the specified OCI image layout
         tag: String,
@@ -78,20 +128,227 @@ pub enum Command {
         /// `rpmoci build --image foo --tag bar --vendor-dir vendor`
         #[clap(long = "vendor-dir")]
         vendor_dir: Option<PathBuf>,
+        /// Don't import the GPG keys in the lock file when verifying package signatures.
+        /// Instead, packages are verified using keys already imported into the host's rpm keyring.
+        #[clap(long = "no-gpgkey-import")]
+        no_gpgkey_import: bool,
+        /// Import the GPG keys in the lock file into the rpm keyring under this directory, which is kept after the build,
+        /// instead of a temporary one. The host's rpm keyring is never modified. Ignored with --no-gpgkey-import
+        #[clap(long = "keyring-dir", env = "RPMOCI_KEYRING_DIR")]
+        keyring_dir: Option<PathBuf>,
+        /// Directory in which to create the temporary installroot and downloaded RPMs.
+        /// Defaults to the system temporary directory
+        #[clap(long = "tmp-dir")]
+        tmp_dir: Option<PathBuf>,
+        /// The algorithm used to compute the digests of the image's blobs
+        #[clap(long = "digest-algorithm", value_enum, default_value_t)]
+        digest_algorithm: DigestAlgorithm,
+        /// Sign the image with the PEM encoded ECDSA private key at this path, storing the signature
+        /// in the image layout as a sigstore `.sig` manifest.
+        /// Encrypted keys are decrypted with the password in the COSIGN_PASSWORD environment variable
+        #[clap(long = "sign-by")]
+        sign_by: Option<PathBuf>,
+        /// Write an in-toto statement with a SLSA provenance predicate describing the build to this path.
+        /// It records the digests of the rpmoci manifest and lock file, the repositories and the image's manifest digest
+        #[clap(long = "provenance")]
+        provenance: Option<PathBuf>,
+        /// The platform to build for, as `os/arch[/variant]`, e.g `linux/arm64/v8`.
+        /// Overrides the platform, architecture, os and variant in the manifest
+        #[clap(long = "platform", value_parser = platform_parser)]
+        platform: Option<Platform>,
+        /// Build the image a second time into a scratch image layout, and fail unless the two
+        /// builds produce the same manifest digest, reporting the first blob that differs.
+        /// SOURCE_DATE_EPOCH is set to the current time for both builds if it isn't already set
+        #[clap(long = "check-reproducible")]
+        check_reproducible: bool,
+        /// Add a package specification for this build only. The manifest and lock file aren't modified,
+        /// with the packages resolved for the build keeping the locked versions where possible
+        #[clap(long = "add-package", conflicts_with_all = ["locked", "frozen", "provenance"])]
+        add_package: Vec<String>,
+        /// Remove a package specification, which must be in the manifest, for this build only.
+        /// The manifest and lock file aren't modified
+        #[clap(long = "remove-package", conflicts_with_all = ["locked", "frozen", "provenance"])]
+        remove_package: Vec<String>,
+        /// Resolve, download and install the packages without writing an image, reporting the size of
+        /// the root filesystem that would be archived into the image
+        #[clap(long = "dry-run", conflicts_with_all = ["sign_by", "provenance", "check_reproducible"])]
+        dry_run: bool,
+        /// What to do if the image directory already contains an image layout: add the image to it,
+        /// fail, or replace the layout
+        #[clap(long = "existing-layout", value_enum, default_value_t)]
+        existing_layout: ExistingLayout,
         /// Path to rpmoci manifest file.
         /// By default, rpmoci searches for rpmoci.toml in the current directory
         #[clap(short = 'f', long = "file", default_value = "rpmoci.toml")]
         manifest_path: PathBuf,
     },
+    /// Build an OCI image from each of a group of rpmoci manifest files.
+    /// Each image is tagged with the file stem of its manifest, and has its own lock file.
+    /// A build failure doesn't stop the remaining images being built, unless --fail-fast is passed
+    BuildAll {
+        /// A directory containing rpmoci manifest files with a `.toml` extension, or a glob pattern
+        /// matching rpmoci manifest files
+        configs: String,
+        /// Require that the lock files are up-to-date
+        #[clap(long = "locked")]
+        locked: bool,
+        /// Stop after the first image that fails to build
+        #[clap(long = "fail-fast")]
+        fail_fast: bool,
+        #[clap(long = "image")]
+        /// Path to OCI image layout
+        image: String,
+        #[clap(long = "build-arg", value_parser = build_arg_parser)]
+        /// Specify build arguments, which can be referenced in label values as `${BUILD_ARG_KEY}`.
+        /// Build arguments are specified as KEY=VALUE
+        build_arg: Vec<(String, String)>,
+        /// Don't import the GPG keys in the lock files when verifying package signatures.
+        #[clap(long = "no-gpgkey-import")]
+        no_gpgkey_import: bool,
+        /// Import the GPG keys in the lock file into the rpm keyring under this directory, which is kept after the build,
+        /// instead of a temporary one. The host's rpm keyring is never modified. Ignored with --no-gpgkey-import
+        #[clap(long = "keyring-dir", env = "RPMOCI_KEYRING_DIR")]
+        keyring_dir: Option<PathBuf>,
+        /// Directory in which to create the temporary installroots and downloaded RPMs.
+        /// Defaults to the system temporary directory
+        #[clap(long = "tmp-dir")]
+        tmp_dir: Option<PathBuf>,
+        /// The algorithm used to compute the digests of the images' blobs
+        #[clap(long = "digest-algorithm", value_enum, default_value_t)]
+        digest_algorithm: DigestAlgorithm,
+        /// The platform to build for, as `os/arch[/variant]`, e.g `linux/arm64/v8`.
+        /// Overrides the platform, architecture, os and variant in the manifests
+        #[clap(long = "platform", value_parser = platform_parser)]
+        platform: Option<Platform>,
+    },
+    /// Explain why a package is in the lock file
+    Explain {
+        /// The name of the package
+        package: String,
+        /// Path to rpmoci manifest file.
+        /// By default, rpmoci searches for rpmoci.toml in the current directory.
+        #[clap(short = 'f', long = "file", default_value = "rpmoci.toml")]
+        manifest_path: PathBuf,
+    },
     /// Vendor RPM dependencies locally
     Vendor {
         /// The directory in which to store downloaded RPMs.
         /// This can subsequently be passed to `rpmoci build`
         #[clap(long = "out-dir")]
         out_dir: PathBuf,
+        /// Don't import the GPG keys in the lock file when verifying package signatures.
+        /// Instead, packages are verified using keys already imported into the host's rpm keyring.
+        #[clap(long = "no-gpgkey-import")]
+        no_gpgkey_import: bool,
+        /// Import the GPG keys in the lock file into the rpm keyring under this directory, which is kept after the build,
+        /// instead of a temporary one. The host's rpm keyring is never modified. Ignored with --no-gpgkey-import
+        #[clap(long = "keyring-dir", env = "RPMOCI_KEYRING_DIR")]
+        keyring_dir: Option<PathBuf>,
+        /// Path to rpmoci manifest file.
+        /// By default, rpmoci searches for rpmoci.toml in the current directory.
+        #[clap(short = 'f', long = "file", default_value = "rpmoci.toml")]
+        manifest_path: PathBuf,
+    },
+    /// Populate the dnf cache with repository metadata, so later commands can avoid downloading it
+    Prefetch {
+        /// Also download the locked packages to the cache. Requires an up-to-date lock file
+        #[clap(long)]
+        packages: bool,
         /// Path to rpmoci manifest file.
         /// By default, rpmoci searches for rpmoci.toml in the current directory.
         #[clap(short = 'f', long = "file", default_value = "rpmoci.toml")]
         manifest_path: PathBuf,
     },
+    /// Print the configured repositories as a dnf .repo file, to run dnf against the same repositories.
+    /// Credentials from the environment aren't included
+    RepoFile {
+        /// Path to rpmoci manifest file.
+        /// By default, rpmoci searches for rpmoci.toml in the current directory.
+        #[clap(short = 'f', long = "file", default_value = "rpmoci.toml")]
+        manifest_path: PathBuf,
+    },
+    /// Check the packages in the lock file against a vulnerability feed, reporting those with known vulnerabilities.
+    /// Exits with an error if any are found
+    Audit {
+        /// Path or https URL of the vulnerability feed
+        #[clap(long = "feed")]
+        feed: String,
+        /// The format of the vulnerability feed
+        #[clap(long = "feed-format", value_enum, default_value_t)]
+        feed_format: FeedFormat,
+        /// Path to rpmoci manifest file.
+        /// By default, rpmoci searches for rpmoci.toml in the current directory.
+        #[clap(short = 'f', long = "file", default_value = "rpmoci.toml")]
+        manifest_path: PathBuf,
+    },
+    /// Manage the manifests in an OCI image layout's index
+    Index {
+        /// Path to OCI image layout
+        #[clap(long = "image")]
+        image: String,
+        /// The index subcommand
+        #[clap(subcommand)]
+        command: IndexCommand,
+    },
+}
+
+/// Index subcommands
+#[derive(Debug, Subcommand)]
+pub enum IndexCommand {
+    /// List the manifests in the index, with their tags and digests
+    List,
+    /// Remove manifests from the index. Their blobs are left in the image layout
+    Remove {
+        /// The tag or digest of the manifests to remove
+        reference: String,
+    },
+    /// Check that the image layout conforms to the OCI image spec
+    Validate,
+    /// Attach an artifact, e.g. an SBOM, to a manifest as an OCI referrer.
+    /// The artifact is added to the index as an untagged manifest whose subject is the manifest
+    Attach {
+        /// The tag or digest of the manifest to attach the artifact to
+        subject: String,
+        /// Path to the artifact
+        path: PathBuf,
+        /// The artifact type, e.g. `application/spdx+json`
+        #[clap(long = "artifact-type")]
+        artifact_type: String,
+        /// The media type of the artifact's blob. Defaults to the artifact type
+        #[clap(long = "media-type")]
+        media_type: Option<String>,
+        /// The algorithm used to compute the digests of the artifact's blobs
+        #[clap(long = "digest-algorithm", value_enum, default_value_t)]
+        digest_algorithm: DigestAlgorithm,
+    },
+    /// Extract the root filesystem of a manifest into a directory, applying its layers and their whiteouts in order.
+    /// File ownership isn't restored
+    Unpack {
+        /// The tag or digest of the manifest
+        reference: String,
+        /// The directory to extract the root filesystem into, which is created if it doesn't exist
+        dest: PathBuf,
+    },
+    /// Recompress the layers of a manifest, e.g. to match the compression of layers that will be stacked on it.
+    /// The manifest is replaced in the index. Diff_ids, and so the image configuration, are unchanged
+    Recompress {
+        /// The tag or digest of the manifest
+        reference: String,
+        /// The compression of the recompressed layers
+        #[clap(long = "compression", value_enum)]
+        compression: LayerCompression,
+    },
+    /// Print the image configuration of a manifest as JSON, after verifying the manifest and config digests
+    Config {
+        /// The tag or digest of the manifest
+        reference: String,
+    },
+    /// Print the referrers of a manifest as an OCI image index, as the referrers API returns them
+    Referrers {
+        /// The tag or digest of the manifest
+        subject: String,
+        /// Only list referrers with this artifact type
+        #[clap(long = "artifact-type")]
+        artifact_type: Option<String>,
+    },
 }
@@ -0,0 +1,387 @@
+//! Copyright (C) Microsoft Corporation.
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Computing the composefs digest of an image layer.
+//!
+//! The digest is computed from the layer's tar stream rather than the root filesystem it was created from,
+//! so that it reflects what's in the layer after paths are excluded, ownership is mapped and mtimes are set.
+//! The tar stream is converted to a composefs dump file (see composefs-dump(5)), from which `mkcomposefs` computes the digest.
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use tar::EntryType;
+
+/// The block size of the fs-verity Merkle tree
+const FSVERITY_BLOCK_SIZE: usize = 4096;
+/// Prefix of pax records holding extended attributes
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Compute the composefs digest of an uncompressed layer tar stream using `mkcomposefs`
+pub(crate) fn composefs_digest(layer: impl Read) -> Result<String> {
+    let mut dump = tempfile::NamedTempFile::new()?;
+    dump.write_all(&dump_file(layer).context("Failed to read layer")?)?;
+    dump.flush()?;
+    let output = Command::new("mkcomposefs")
+        .arg("--from-file")
+        .arg("--print-digest-only")
+        .arg(dump.path())
+        .output()
+        .context("Failed to run `mkcomposefs`. Is composefs installed?")?;
+    if !output.status.success() {
+        bail!(
+            "mkcomposefs failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8(output.stdout)
+        .context("mkcomposefs printed an invalid digest")?
+        .trim()
+        .to_string())
+}
+
+/// An entry of a composefs dump file
+struct DumpEntry {
+    path: PathBuf,
+    size: u64,
+    /// The file type and permission bits
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    rdev: u64,
+    mtime: u64,
+    /// Whether this is a hardlink to `link_target`
+    hardlink: bool,
+    /// The symlink target, or hardlink target if this is a hardlink
+    link_target: Option<PathBuf>,
+    /// The fs-verity digest of a regular file's contents
+    digest: Option<String>,
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Convert a layer tar stream to a composefs dump file.
+///
+/// Regular files are backed by content addressed files named by their fs-verity digests,
+/// as with `mkcomposefs --digest-store`.
+pub(crate) fn dump_file(layer: impl Read) -> Result<Vec<u8>> {
+    // Layers don't have an entry for the root directory
+    let mut entries = vec![DumpEntry {
+        path: PathBuf::from("/"),
+        size: 0,
+        mode: libc_mode::S_IFDIR | 0o755,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        mtime: 0,
+        hardlink: false,
+        link_target: None,
+        digest: None,
+        xattrs: Vec::new(),
+    }];
+    let mut archive = tar::Archive::new(layer);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = Path::new("/").join(entry.path()?.components().collect::<PathBuf>());
+        if path == Path::new("/") {
+            continue;
+        }
+        let header = entry.header();
+        let file_type = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => libc_mode::S_IFREG,
+            EntryType::Directory => libc_mode::S_IFDIR,
+            EntryType::Symlink => libc_mode::S_IFLNK,
+            EntryType::Link => libc_mode::S_IFLNK,
+            EntryType::Char => libc_mode::S_IFCHR,
+            EntryType::Block => libc_mode::S_IFBLK,
+            EntryType::Fifo => libc_mode::S_IFIFO,
+            entry_type => bail!(
+                "Unsupported tar entry type {:?} for `{}`",
+                entry_type,
+                path.display()
+            ),
+        };
+        let hardlink = header.entry_type() == EntryType::Link;
+        let rdev = match file_type {
+            libc_mode::S_IFCHR | libc_mode::S_IFBLK => makedev(
+                header.device_major()?.unwrap_or_default().into(),
+                header.device_minor()?.unwrap_or_default().into(),
+            ),
+            _ => 0,
+        };
+        let mut dump_entry = DumpEntry {
+            size: 0,
+            mode: file_type | (header.mode()? & 0o7777),
+            uid: header.uid()?,
+            gid: header.gid()?,
+            rdev,
+            mtime: header.mtime()?,
+            hardlink,
+            link_target: entry.link_name()?.map(|target| match hardlink {
+                true => Path::new("/").join(target.components().collect::<PathBuf>()),
+                false => target.into_owned(),
+            }),
+            digest: None,
+            xattrs: Vec::new(),
+            path,
+        };
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                if let Some(name) = extension
+                    .key_bytes()
+                    .strip_prefix(PAX_XATTR_PREFIX.as_bytes())
+                {
+                    dump_entry
+                        .xattrs
+                        .push((name.to_vec(), extension.value_bytes().to_vec()));
+                }
+            }
+        }
+        if file_type == libc_mode::S_IFREG {
+            let (digest, size) = fsverity_digest(&mut entry)?;
+            dump_entry.digest = Some(digest);
+            dump_entry.size = size;
+        }
+        entries.push(dump_entry);
+    }
+
+    // Hardlinks add to the link count of their target, and subdirectories to that of their parent
+    let mut nlinks = HashMap::<PathBuf, u64>::new();
+    for entry in &entries {
+        if entry.hardlink {
+            if let Some(target) = &entry.link_target {
+                *nlinks.entry(target.clone()).or_insert(1) += 1;
+            }
+        } else if entry.mode & libc_mode::S_IFMT == libc_mode::S_IFDIR {
+            nlinks.entry(entry.path.clone()).or_insert(2);
+            if let Some(parent) = entry.path.parent() {
+                *nlinks.entry(parent.to_path_buf()).or_insert(2) += 1;
+            }
+        }
+    }
+
+    let mut dump = Vec::new();
+    for entry in &entries {
+        write_field(&mut dump, entry.path.as_os_str().as_encoded_bytes());
+        if entry.hardlink {
+            dump.extend_from_slice(b" 0 @120000 - - - - 0.0 ");
+            write_optional_field(
+                &mut dump,
+                entry
+                    .link_target
+                    .as_deref()
+                    .map(|p| p.as_os_str().as_encoded_bytes()),
+            );
+            dump.extend_from_slice(b" - -\n");
+            continue;
+        }
+        let nlink = nlinks.get(&entry.path).copied().unwrap_or(1);
+        dump.extend_from_slice(
+            format!(
+                " {} {:o} {} {} {} {} {}.0 ",
+                entry.size, entry.mode, nlink, entry.uid, entry.gid, entry.rdev, entry.mtime
+            )
+            .as_bytes(),
+        );
+        let payload = match (&entry.link_target, &entry.digest) {
+            (Some(target), _) => Some(target.as_os_str().as_encoded_bytes().to_vec()),
+            (None, Some(digest)) => Some(format!("{}/{}", &digest[..2], &digest[2..]).into_bytes()),
+            (None, None) => None,
+        };
+        write_optional_field(&mut dump, payload.as_deref());
+        dump.extend_from_slice(b" - ");
+        write_optional_field(&mut dump, entry.digest.as_ref().map(|d| d.as_bytes()));
+        for (name, value) in &entry.xattrs {
+            dump.push(b' ');
+            write_field(&mut dump, name);
+            dump.push(b'=');
+            write_field(&mut dump, value);
+        }
+        dump.push(b'\n');
+    }
+    Ok(dump)
+}
+
+/// File type bits of `st_mode`
+mod libc_mode {
+    pub(super) const S_IFMT: u32 = 0o170000;
+    pub(super) const S_IFIFO: u32 = 0o010000;
+    pub(super) const S_IFCHR: u32 = 0o020000;
+    pub(super) const S_IFDIR: u32 = 0o040000;
+    pub(super) const S_IFBLK: u32 = 0o060000;
+    pub(super) const S_IFREG: u32 = 0o100000;
+    pub(super) const S_IFLNK: u32 = 0o120000;
+}
+
+/// Encode a device number as glibc's `makedev` does
+fn makedev(major: u64, minor: u64) -> u64 {
+    ((major & 0xffff_f000) << 32)
+        | ((major & 0x0000_0fff) << 8)
+        | ((minor & 0xffff_ff00) << 12)
+        | (minor & 0x0000_00ff)
+}
+
+/// Write a dump file field, escaping bytes that aren't printable ASCII, and backslashes and `=`
+fn write_field(dump: &mut Vec<u8>, value: &[u8]) {
+    // A lone `-` denotes an empty optional field
+    if value == b"-" {
+        dump.extend_from_slice(b"\\x2d");
+        return;
+    }
+    for &byte in value {
+        if byte.is_ascii_graphic() && byte != b'\\' && byte != b'=' {
+            dump.push(byte);
+        } else {
+            dump.extend_from_slice(format!("\\x{:02x}", byte).as_bytes());
+        }
+    }
+}
+
+/// Write an optional dump file field, using `-` if it's absent
+fn write_optional_field(dump: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        Some(value) => write_field(dump, value),
+        None => dump.push(b'-'),
+    }
+}
+
+/// Compute the fs-verity digest of a file's contents, using SHA-256 and 4096 byte blocks, and the size of the contents.
+///
+/// This is the SHA-256 digest of the fs-verity descriptor, which includes the root hash of the Merkle tree of the contents.
+pub(crate) fn fsverity_digest(mut contents: impl Read) -> Result<(String, u64)> {
+    // The hashes of the data blocks, the last of which is zero padded
+    let mut hashes = Vec::new();
+    let mut size = 0;
+    let mut block = vec![0; FSVERITY_BLOCK_SIZE];
+    loop {
+        let mut len = 0;
+        while len < FSVERITY_BLOCK_SIZE {
+            match contents.read(&mut block[len..])? {
+                0 => break,
+                read => len += read,
+            }
+        }
+        if len == 0 {
+            break;
+        }
+        size += len as u64;
+        block[len..].fill(0);
+        hashes.extend_from_slice(&Sha256::digest(&block));
+        if len < FSVERITY_BLOCK_SIZE {
+            break;
+        }
+    }
+
+    let root_hash = if size == 0 {
+        vec![0; 32]
+    } else {
+        // Hash each level of the tree in zero padded blocks of hashes, until a level is a single hash
+        while hashes.len() > 32 {
+            hashes = hashes
+                .chunks(FSVERITY_BLOCK_SIZE)
+                .flat_map(|chunk| {
+                    let mut block = chunk.to_vec();
+                    block.resize(FSVERITY_BLOCK_SIZE, 0);
+                    Sha256::digest(&block)
+                })
+                .collect();
+        }
+        hashes
+    };
+
+    let mut descriptor = Vec::with_capacity(256);
+    descriptor.push(1); // version
+    descriptor.push(1); // hash algorithm: SHA-256
+    descriptor.push(FSVERITY_BLOCK_SIZE.trailing_zeros() as u8);
+    descriptor.push(0); // salt size
+    descriptor.extend_from_slice(&[0; 4]);
+    descriptor.extend_from_slice(&size.to_le_bytes());
+    descriptor.extend_from_slice(&root_hash);
+    descriptor.resize(256, 0);
+    Ok((format!("{:x}", Sha256::digest(&descriptor)), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump_file, fsverity_digest, FSVERITY_BLOCK_SIZE};
+
+    #[test]
+    fn fsverity_digests() {
+        // The digest of an empty file, as printed by `fsverity digest`
+        assert_eq!(
+            fsverity_digest(&b""[..]).unwrap(),
+            (
+                "3d248ca542a24fc62d1c43b916eae5016878e2533c88238480b26128a1f1af95".to_string(),
+                0
+            )
+        );
+        // Contents that differ only in trailing zeros have different digests, as the size is included
+        let zeros = vec![0; FSVERITY_BLOCK_SIZE];
+        assert_ne!(
+            fsverity_digest(&zeros[..1]).unwrap(),
+            fsverity_digest(&zeros[..]).unwrap()
+        );
+        // Multiple levels of the Merkle tree
+        let large = vec![1; FSVERITY_BLOCK_SIZE * 200];
+        let (_, size) = fsverity_digest(&large[..]).unwrap();
+        assert_eq!(size, large.len() as u64);
+    }
+
+    #[test]
+    fn dump_files_describe_the_layer() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_uid(1000);
+        header.set_gid(1000);
+        header.set_mtime(100);
+        header.set_size(0);
+        builder.append_data(&mut header, "etc/", &b""[..]).unwrap();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(5);
+        builder
+            .append_data(&mut header, "etc/my file", &b"hello"[..])
+            .unwrap();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "etc/link", "etc/my file")
+            .unwrap();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, "etc/-", "-").unwrap();
+        let dump =
+            String::from_utf8(dump_file(&builder.into_inner().unwrap()[..]).unwrap()).unwrap();
+
+        let (digest, _) = fsverity_digest(&b"hello"[..]).unwrap();
+        assert_eq!(
+            dump,
+            format!(
+                "/ 0 40755 3 0 0 0 0.0 - - -\n\
+                 /etc 0 40755 2 1000 1000 0 100.0 - - -\n\
+                 /etc/my\\x20file 5 100644 2 1000 1000 0 100.0 {}/{} - {}\n\
+                 /etc/link 0 @120000 - - - - 0.0 /etc/my\\x20file - -\n\
+                 /etc/- 0 120777 1 1000 1000 0 100.0 \\x2d - -\n",
+                &digest[..2],
+                &digest[2..],
+                digest
+            )
+        );
+    }
+}
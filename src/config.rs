@@ -14,15 +14,18 @@
 //!
 //! You should have received a copy of the GNU General Public License
 //! along with this program.  If not, see <https://www.gnu.org/licenses/>.
-use anyhow::Result;
-use ocidir::oci_spec::{
-    image::{Arch, ConfigBuilder, ImageConfiguration, ImageConfigurationBuilder, Os},
-    OciSpecError,
+use anyhow::{bail, Context, Result};
+use ocidir::oci_spec::image::{
+    Arch, ConfigBuilder, ImageConfiguration, ImageConfigurationBuilder, Os,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use url::Url;
 
+use crate::archive::{IdMapping, MtimePolicy, TarFormat};
+use crate::oci::MediaTypes;
+
 #[derive(Debug, Serialize, Default, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 /// Image configuration options
@@ -44,13 +47,226 @@ pub(crate) struct ImageConfig {
     pub(crate) labels: HashMap<String, String>,
     #[serde(default)]
     pub(crate) workingdir: Option<String>,
-    #[serde(default)]
+    #[serde(default, alias = "stop_signal")]
     pub(crate) stopsignal: Option<String>,
     #[serde(default)]
     pub(crate) author: Option<String>,
+    /// Whether extensions to the image configuration that Docker supports, but the OCI image spec doesn't define, can be used
+    #[serde(default)]
+    pub(crate) docker_compat: bool,
+    /// A Docker healthcheck. Requires `docker_compat`.
+    #[serde(default)]
+    pub(crate) healthcheck: Option<Healthcheck>,
+    /// The platform the image is built for, as `os/arch[/variant]`, e.g `linux/arm64/v8`.
+    /// Packages are resolved and installed for the matching rpm architecture. Defaults to `linux/amd64`.
+    #[serde(default)]
+    pub(crate) platform: Option<String>,
+    /// The image's CPU architecture, overriding the architecture of `platform`
+    #[serde(default)]
+    pub(crate) architecture: Option<String>,
+    /// The image's operating system, overriding the operating system of `platform`
+    #[serde(default)]
+    pub(crate) os: Option<String>,
+    /// The image's CPU architecture variant, overriding the variant of `platform`
+    #[serde(default)]
+    pub(crate) variant: Option<String>,
+    /// Whether the image's manifest, config and layers have OCI or Docker media types
+    #[serde(default)]
+    pub(crate) media_types: MediaTypes,
+    /// Annotations added to the image layout's index, which is shared by all the images in the layout
+    #[serde(default)]
+    pub(crate) index_annotations: HashMap<String, String>,
+    /// Annotations added to the image manifest
+    #[serde(default)]
+    pub(crate) annotations: HashMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Default, Deserialize, Clone)]
+/// A Docker healthcheck, which isn't part of the OCI image spec
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Healthcheck {
+    /// The test to run: `["NONE"]`, `["CMD", args...]` or `["CMD-SHELL", command]`
+    pub(crate) test: Vec<String>,
+    /// The time between checks, e.g `30s` or `1m30s`
+    #[serde(default)]
+    pub(crate) interval: Option<String>,
+    /// The time after which a check is considered to have failed
+    #[serde(default)]
+    pub(crate) timeout: Option<String>,
+    /// The time after the container starts during which failed checks aren't counted
+    #[serde(default)]
+    pub(crate) start_period: Option<String>,
+    /// The number of consecutive failed checks after which the container is unhealthy
+    #[serde(default)]
+    pub(crate) retries: Option<u32>,
+}
+
+impl Healthcheck {
+    /// The healthcheck in the form of Docker's image configuration, with durations in nanoseconds
+    fn to_docker_json(&self) -> Result<serde_json::Value> {
+        match self.test.first().map(String::as_str) {
+            Some("NONE") if self.test.len() == 1 => {}
+            Some("CMD") if self.test.len() > 1 => {}
+            Some("CMD-SHELL") if self.test.len() == 2 => {}
+            _ => bail!("image.healthcheck.test must be [\"NONE\"], [\"CMD\", args...] or [\"CMD-SHELL\", command]"),
+        }
+        let mut healthcheck = serde_json::Map::new();
+        healthcheck.insert("Test".to_string(), self.test.clone().into());
+        for (key, name, duration) in [
+            ("Interval", "interval", &self.interval),
+            ("Timeout", "timeout", &self.timeout),
+            ("StartPeriod", "start_period", &self.start_period),
+        ] {
+            if let Some(duration) = duration {
+                let nanos = parse_duration(duration)
+                    .with_context(|| format!("Invalid image.healthcheck.{}", name))?;
+                healthcheck.insert(key.to_string(), nanos.into());
+            }
+        }
+        if let Some(retries) = self.retries {
+            healthcheck.insert("Retries".to_string(), retries.into());
+        }
+        Ok(healthcheck.into())
+    }
+}
+
+/// The platform an image is built for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    /// The operating system, which must be `linux`
+    pub(crate) os: String,
+    /// The CPU architecture, using Go's `GOARCH` names as the OCI image spec does, e.g `arm64`
+    pub(crate) architecture: String,
+    /// The CPU architecture variant, e.g `v7`
+    pub(crate) variant: Option<String>,
+}
+
+impl Default for Platform {
+    fn default() -> Self {
+        Platform {
+            os: "linux".to_string(),
+            architecture: "amd64".to_string(),
+            variant: None,
+        }
+    }
+}
+
+impl std::str::FromStr for Platform {
+    type Err = anyhow::Error;
+
+    /// Parse a platform of the form `os/arch[/variant]`, e.g `linux/arm/v7`
+    fn from_str(platform: &str) -> Result<Self> {
+        let parts = platform.split('/').collect::<Vec<_>>();
+        let (os, architecture, variant) = match parts.as_slice() {
+            [os, architecture] => (os, architecture, None),
+            [os, architecture, variant] => (os, architecture, Some(variant.to_string())),
+            _ => bail!(
+                "Invalid platform `{}`: expected `os/arch` or `os/arch/variant`",
+                platform
+            ),
+        };
+        if parts.iter().any(|part| part.is_empty()) {
+            bail!("Invalid platform `{}`: empty component", platform);
+        }
+        Ok(Platform {
+            os: os.to_string(),
+            architecture: normalize_architecture(architecture),
+            variant,
+        })
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+impl Platform {
+    /// The rpm architecture of packages built for this platform, which dnf resolves and installs packages for
+    pub(crate) fn dnf_arch(&self) -> Result<&'static str> {
+        if self.os != "linux" {
+            bail!(
+                "Unsupported platform `{}`: only linux images can be built",
+                self
+            );
+        }
+        Ok(
+            match (self.architecture.as_str(), self.variant.as_deref()) {
+                ("amd64", _) => "x86_64",
+                ("arm64", _) => "aarch64",
+                ("arm", None | Some("v7")) => "armv7hl",
+                ("arm", Some("v6")) => "armv6hl",
+                ("arm", Some("v5")) => "armv5tel",
+                ("386", _) => "i686",
+                ("ppc64le", _) => "ppc64le",
+                ("s390x", _) => "s390x",
+                ("riscv64", _) => "riscv64",
+                ("mips64le", _) => "mips64el",
+                ("loong64", _) => "loongarch64",
+                _ => bail!(
+                    "Unsupported platform `{}`: no matching rpm architecture",
+                    self
+                ),
+            },
+        )
+    }
+}
+
+/// Map rpm and other common architecture names to the names the OCI image spec uses
+fn normalize_architecture(architecture: &str) -> String {
+    match architecture {
+        "x86_64" | "x86-64" => "amd64",
+        "aarch64" => "arm64",
+        "i386" | "i686" => "386",
+        architecture => architecture,
+    }
+    .to_string()
+}
+
+/// Parse a duration such as `1m30s` into nanoseconds.
+/// Durations are a sequence of numbers with units of `ms`, `s`, `m` or `h`.
+fn parse_duration(duration: &str) -> Result<u64> {
+    let mut nanos: u64 = 0;
+    let mut rest = duration;
+    if rest.is_empty() {
+        bail!("empty duration");
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let units = rest[digits..]
+            .find(|c: char| c.is_ascii_digit())
+            .map_or(rest.len(), |i| digits + i);
+        let number = rest[..digits]
+            .parse::<u64>()
+            .with_context(|| format!("invalid duration `{}`", duration))?;
+        let scale: u64 = match &rest[digits..units] {
+            "ms" => 1_000_000,
+            "s" => 1_000_000_000,
+            "m" => 60 * 1_000_000_000,
+            "h" => 60 * 60 * 1_000_000_000,
+            unit => bail!(
+                "invalid unit `{}` in duration `{}`, expected ms, s, m or h",
+                unit,
+                duration
+            ),
+        };
+        nanos = number
+            .checked_mul(scale)
+            .and_then(|n| nanos.checked_add(n))
+            .with_context(|| format!("duration `{}` is too long", duration))?;
+        rest = &rest[units..];
+    }
+    Ok(nanos)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 /// Configuration on packages to install
 pub(crate) struct PackageConfig {
@@ -68,6 +284,287 @@ pub(crate) struct PackageConfig {
     /// needing to add the <distro>-release package.
     #[serde(default = "os_release_default")]
     pub(crate) os_release: bool,
+    /// Per-package allowlists of paths to keep in the image.
+    /// Files owned by a listed package that don't match any of its globs are removed after installation.
+    #[serde(default)]
+    pub(crate) keep_paths: HashMap<String, Vec<String>>,
+    /// Whether repositories that can't be loaded are skipped during resolution rather than failing it.
+    /// Can be overridden per repository. Defaults to false.
+    #[serde(default)]
+    pub(crate) skip_if_unavailable: bool,
+    /// Allowlists of fingerprints for the keys served by gpgkey URLs.
+    /// Resolution fails if a listed URL serves a key whose fingerprint isn't in its allowlist.
+    #[serde(default)]
+    pub(crate) gpgkey_fingerprints: HashMap<Url, Vec<String>>,
+    /// Files to add to the image after packages are installed
+    #[serde(default)]
+    pub(crate) files: Vec<InjectedFile>,
+    /// The mode of added files that don't specify one. Defaults to 0o644.
+    #[serde(default = "default_file_mode_default")]
+    pub(crate) default_file_mode: u32,
+    /// The mode of directories created for added files. Defaults to 0o755.
+    #[serde(default = "default_dir_mode_default")]
+    pub(crate) default_dir_mode: u32,
+    /// A lockfile whose package versions are preferred during resolution, so that
+    /// images sharing a base set of packages install the same versions of them.
+    #[serde(default)]
+    pub(crate) base_lockfile: Option<PathBuf>,
+    /// Whether dnf keeps downloaded RPMs in its cache. Defaults to false.
+    #[serde(default)]
+    pub(crate) keepcache: bool,
+    /// Commands run in the root filesystem, in order, after packages are installed and files are added
+    #[serde(default)]
+    pub(crate) hooks: Vec<Vec<String>>,
+    /// How failures to install packages are handled. Defaults to stopping on the first error.
+    #[serde(default)]
+    pub(crate) install_policy: InstallPolicy,
+    /// How symlinks whose targets escape the root filesystem are handled. Defaults to allowing them.
+    #[serde(default)]
+    pub(crate) symlink_escapes: SymlinkEscapePolicy,
+    /// dnf main configuration options, overriding rpmoci's defaults
+    #[serde(default)]
+    pub(crate) dnf_options: HashMap<String, String>,
+    /// Packages that can't be installed, specified by name glob or by a capability they provide
+    #[serde(default)]
+    pub(crate) exclude: Vec<String>,
+    /// The package expected to provide /etc/os-release. Resolution fails if another package provides it.
+    #[serde(default)]
+    pub(crate) os_release_provider: Option<OsReleaseProvider>,
+    /// How files claimed by several packages with different contents are handled. Defaults to failing the build.
+    #[serde(default)]
+    pub(crate) file_conflicts: FileConflictPolicy,
+    /// Whether repository and GPG key URLs may use plain `http`. Defaults to false.
+    #[serde(default)]
+    pub(crate) allow_insecure_http: bool,
+    /// The module platform id used to resolve modular repositories, e.g `platform:el9`.
+    /// By default it's taken from the lockfile, or detected from the host's os-release.
+    #[serde(default)]
+    pub(crate) module_platform_id: Option<String>,
+    /// Whether the signatures of local RPMs are verified, using the keys of repositories with gpgcheck enabled.
+    /// Defaults to false.
+    #[serde(default)]
+    pub(crate) localpkg_gpgcheck: bool,
+    /// The classes of weak dependencies that are installed. By default none are.
+    #[serde(default)]
+    pub(crate) weak_deps: WeakDependencies,
+    /// Transformations applied to files in the root filesystem after packages are installed
+    #[serde(default)]
+    pub(crate) transformations: Transformations,
+    /// How configuration files that rpm saved alongside each other, as `.rpmnew`, `.rpmsave` or `.rpmorig` files,
+    /// are handled. Defaults to keeping the packaged files, as on a clean install.
+    #[serde(default)]
+    pub(crate) conffiles: ConffilePolicy,
+    /// Whether dnf downloads zchunk compressed repository metadata where repositories provide it,
+    /// rather than the gzip or xz compressed metadata every repository provides. Defaults to true, as in dnf.
+    #[serde(default = "zchunk_default")]
+    pub(crate) zchunk: bool,
+    /// Whether dependencies that are only required by removed package specifications are removed with them,
+    /// when a lockfile is re-resolved keeping the locked versions, e.g. by `rpmoci build --remove-package`.
+    /// Defaults to true, as in dnf. If false, those dependencies are kept in the image at their locked versions.
+    #[serde(default = "clean_requirements_on_remove_default")]
+    pub(crate) clean_requirements_on_remove: bool,
+    /// rpm macros defined for the install transaction, e.g `_install_langs`, keyed by name with or without a leading `%`
+    #[serde(default)]
+    pub(crate) rpm_macros: HashMap<String, String>,
+    /// Whether to record the rpmoci version, the manifest's path and digest, and the time in generated lockfiles
+    #[serde(default)]
+    pub(crate) lockfile_metadata: bool,
+    /// The names of packages that are already installed in the image this image's layer is stacked on.
+    /// They're resolved so that they satisfy dependencies, but their files, and those of the packages they
+    /// depend on, aren't included in the layer
+    #[serde(default)]
+    pub(crate) assume_installed: Vec<String>,
+    /// The lockfile of the image this image's layer is stacked on. All of its packages are assumed
+    /// installed at their locked versions, as are their dependencies
+    #[serde(default)]
+    pub(crate) assume_installed_lockfile: Option<PathBuf>,
+    /// The rpm architecture that packages are resolved and installed for, derived from the image's platform.
+    /// Defaults to the host's architecture
+    #[serde(skip)]
+    pub(crate) arch: Option<String>,
+    /// Whether packages are verified with the keys already imported into the host's rpm keyring,
+    /// as with `--no-gpgkey-import`, so repositories don't need keys configured
+    #[serde(skip)]
+    pub(crate) host_keyring: bool,
+}
+
+/// The classes of weak dependencies that are installed
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WeakDependencies {
+    /// Whether to install packages recommended by installed packages
+    #[serde(default)]
+    pub(crate) recommends: bool,
+    /// Whether to install packages that supplement installed packages
+    #[serde(default)]
+    pub(crate) supplements: bool,
+    /// Whether to install packages suggested by installed packages
+    #[serde(default)]
+    pub(crate) suggests: bool,
+    /// Whether to install packages that enhance installed packages
+    #[serde(default)]
+    pub(crate) enhances: bool,
+}
+
+impl WeakDependencies {
+    /// The names of the enabled classes, as expected by resolve.py
+    pub(crate) fn classes(&self) -> Vec<&'static str> {
+        [
+            ("recommends", self.recommends),
+            ("supplements", self.supplements),
+            ("suggests", self.suggests),
+            ("enhances", self.enhances),
+        ]
+        .into_iter()
+        .filter_map(|(class, enabled)| enabled.then_some(class))
+        .collect()
+    }
+}
+
+/// Transformations applied to files in the root filesystem after packages are installed
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Transformations {
+    /// Transformations applied to all files
+    #[serde(default)]
+    pub(crate) all: Vec<Transformation>,
+    /// Transformations applied to the files owned by each package
+    #[serde(default)]
+    pub(crate) packages: HashMap<String, Vec<Transformation>>,
+}
+
+/// A transformation of files in the root filesystem
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Transformation {
+    /// Remove debugging symbols from ELF files with `strip --strip-debug`
+    StripDebug,
+    /// Compress man pages with gzip
+    CompressManPages,
+}
+
+/// How failures to install packages are handled
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum InstallPolicy {
+    /// Fail the build if any package fails to install
+    #[default]
+    StopOnError,
+    /// Install the packages that can be installed and report those that can't.
+    /// The resulting image is annotated as potentially incomplete.
+    BestEffort,
+}
+
+/// How configuration files that rpm didn't install over an existing file are handled
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ConffilePolicy {
+    /// Keep the packaged file, removing the existing one, as if the package had been installed on a clean root filesystem
+    #[default]
+    KeepNew,
+    /// Keep the existing file, removing the packaged one
+    KeepOld,
+    /// Keep both files, with rpm's `.rpmnew`, `.rpmsave` and `.rpmorig` extensions
+    Rpmnew,
+}
+
+/// How symlinks whose targets escape the root filesystem are handled
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SymlinkEscapePolicy {
+    /// Don't scan for escaping symlinks
+    #[default]
+    Allow,
+    /// Warn about each escaping symlink
+    Warn,
+    /// Fail the build if there are any escaping symlinks
+    Deny,
+}
+
+/// How files claimed by several packages with different contents are handled
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FileConflictPolicy {
+    /// Fail the build, as rpm does
+    #[default]
+    Fail,
+    /// Keep the file from the first of the packages, ordered by name
+    FirstWins,
+    /// Keep the file from the last of the packages, ordered by name
+    LastWins,
+}
+
+/// A file to add to the image
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct InjectedFile {
+    /// The path of the file on the build host
+    pub(crate) source: PathBuf,
+    /// The absolute path of the file in the image
+    pub(crate) destination: PathBuf,
+    /// The mode of the file in the image
+    #[serde(default)]
+    pub(crate) mode: Option<u32>,
+}
+
+/// The package expected to provide /etc/os-release
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OsReleaseProvider {
+    /// The name of the package
+    pub(crate) name: String,
+    /// The version of the package, if it's pinned
+    #[serde(default)]
+    pub(crate) version: Option<String>,
+}
+
+impl Default for PackageConfig {
+    fn default() -> Self {
+        Self {
+            repositories: Vec::new(),
+            packages: Vec::new(),
+            gpgkeys: Vec::new(),
+            docs: docs_default(),
+            os_release: os_release_default(),
+            keep_paths: HashMap::new(),
+            skip_if_unavailable: false,
+            gpgkey_fingerprints: HashMap::new(),
+            files: Vec::new(),
+            default_file_mode: default_file_mode_default(),
+            default_dir_mode: default_dir_mode_default(),
+            base_lockfile: None,
+            keepcache: false,
+            hooks: Vec::new(),
+            install_policy: InstallPolicy::default(),
+            symlink_escapes: SymlinkEscapePolicy::default(),
+            dnf_options: HashMap::new(),
+            exclude: Vec::new(),
+            os_release_provider: None,
+            file_conflicts: FileConflictPolicy::default(),
+            allow_insecure_http: false,
+            module_platform_id: None,
+            localpkg_gpgcheck: false,
+            weak_deps: WeakDependencies::default(),
+            transformations: Transformations::default(),
+            conffiles: ConffilePolicy::default(),
+            zchunk: zchunk_default(),
+            clean_requirements_on_remove: clean_requirements_on_remove_default(),
+            rpm_macros: HashMap::new(),
+            lockfile_metadata: false,
+            assume_installed: Vec::new(),
+            assume_installed_lockfile: None,
+            arch: None,
+            host_keyring: false,
+        }
+    }
+}
+
+fn default_file_mode_default() -> u32 {
+    0o644
+}
+
+fn default_dir_mode_default() -> u32 {
+    0o755
 }
 
 fn docs_default() -> bool {
@@ -78,6 +575,87 @@ fn os_release_default() -> bool {
     true
 }
 
+fn zchunk_default() -> bool {
+    true
+}
+
+fn clean_requirements_on_remove_default() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+/// Configuration of how the image layer is created
+pub(crate) struct LayerConfig {
+    /// The format of tar headers in the layer. Defaults to pax.
+    #[serde(default)]
+    pub(crate) tar_format: TarFormat,
+    /// Mappings applied to the owning uid of files in the layer
+    #[serde(default)]
+    pub(crate) uid_map: Vec<IdMapping>,
+    /// Mappings applied to the owning gid of files in the layer
+    #[serde(default)]
+    pub(crate) gid_map: Vec<IdMapping>,
+    /// Paths in the root filesystem whose contents are excluded from the layer,
+    /// such as mount points for virtual filesystems.
+    #[serde(default = "exclude_paths_default")]
+    pub(crate) exclude_paths: Vec<PathBuf>,
+    /// Whether excluded directories are kept in the layer as empty directories. Defaults to true.
+    #[serde(default = "keep_excluded_dirs_default")]
+    pub(crate) keep_excluded_dirs: bool,
+    /// Whether to annotate the layer with the composefs digest of the root filesystem. Defaults to false.
+    #[serde(default)]
+    pub(crate) composefs_digest: bool,
+    /// The size in bytes of the buffer used to copy file contents into the layer. Defaults to 64KiB.
+    #[serde(default = "copy_buffer_size_default")]
+    pub(crate) copy_buffer_size: usize,
+    /// Packages whose files are split into a separate top layer, above the rest of the root filesystem
+    #[serde(default)]
+    pub(crate) top_packages: Vec<String>,
+    /// Paths whose contents are split into a separate top layer, above the rest of the root filesystem
+    #[serde(default)]
+    pub(crate) top_paths: Vec<PathBuf>,
+    /// How the mtimes of entries in the layer are set. Defaults to clamping them to the image creation time.
+    #[serde(default)]
+    pub(crate) mtime: MtimePolicy,
+    /// The maximum uncompressed size in bytes of a root filesystem layer. The build fails if it's exceeded.
+    #[serde(default)]
+    pub(crate) max_size: Option<u64>,
+}
+
+impl Default for LayerConfig {
+    fn default() -> Self {
+        Self {
+            tar_format: TarFormat::default(),
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+            exclude_paths: exclude_paths_default(),
+            keep_excluded_dirs: keep_excluded_dirs_default(),
+            composefs_digest: false,
+            copy_buffer_size: copy_buffer_size_default(),
+            top_packages: Vec::new(),
+            top_paths: Vec::new(),
+            mtime: MtimePolicy::default(),
+            max_size: None,
+        }
+    }
+}
+
+fn copy_buffer_size_default() -> usize {
+    64 * 1024
+}
+
+fn exclude_paths_default() -> Vec<PathBuf> {
+    ["/proc", "/sys", "/dev", "/run"]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn keep_excluded_dirs_default() -> bool {
+    true
+}
+
 /// Configuration file for rpmoci
 #[derive(Debug, Serialize, Default, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
@@ -85,11 +663,56 @@ pub struct Config {
     pub(crate) contents: PackageConfig,
     #[serde(default)]
     pub(crate) image: ImageConfig,
+    #[serde(default)]
+    pub(crate) layer: LayerConfig,
+}
+
+impl Config {
+    /// Derive the architecture that packages are resolved and installed for from the image's platform.
+    ///
+    /// If `platform` is given, e.g. by `--platform`, it replaces the platform configured by the image.
+    pub(crate) fn apply_platform(&mut self, platform: Option<&Platform>) -> Result<()> {
+        if let Some(platform) = platform {
+            self.image.platform = Some(platform.to_string());
+            self.image.architecture = None;
+            self.image.os = None;
+            self.image.variant = None;
+        }
+        self.contents.arch = self
+            .image
+            .platform()?
+            .map(|platform| platform.dnf_arch().map(str::to_string))
+            .transpose()?;
+        Ok(())
+    }
+
+    /// Add and remove package specifications, e.g. for a single build, without modifying the manifest file.
+    ///
+    /// Removed specifications must be in the configuration. Added specifications already in it are ignored.
+    pub(crate) fn override_packages(&mut self, add: &[String], remove: &[String]) -> Result<()> {
+        for spec in remove {
+            let Some(position) = self.contents.packages.iter().position(|p| p == spec) else {
+                bail!(
+                    "Can't remove package `{}` as it isn't in contents.packages",
+                    spec
+                );
+            };
+            self.contents.packages.remove(position);
+        }
+        for spec in add {
+            if !self.contents.packages.contains(spec) {
+                self.contents.packages.push(spec.clone());
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Configuration of a yum/dnf repository
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(deny_unknown_fields)]
+///
+/// Environment variables referenced as `${VAR}` or `${VAR:-default}` in repository URLs, ids
+/// and option values are expanded when the configuration is loaded.
+#[derive(Debug, Serialize, Clone)]
 #[serde(untagged)]
 pub(crate) enum Repository {
     Url(Url),
@@ -98,15 +721,217 @@ pub(crate) enum Repository {
 }
 
 /// A repository with a URL + config options
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(deny_unknown_fields)]
+#[derive(Debug, Serialize, Clone)]
 pub(crate) struct RepositoryDefinition {
     pub(crate) id: Option<String>,
     // The base url of the repository
     pub(crate) url: Url,
     /// Additional repository options.
-    #[serde(default)]
     pub(crate) options: HashMap<String, String>,
+    /// Whether to skip the repository if it can't be loaded, overriding the global default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) skip_if_unavailable: Option<bool>,
+    /// Whether to verify the signature of the repository metadata, overriding dnf's default (off)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) repo_gpgcheck: Option<bool>,
+    /// A command that prints the base URL to use for the repository, e.g to mint presigned URLs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) url_command: Option<Vec<String>>,
+    /// Seconds to wait for a connection before giving up, overriding the global default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) timeout: Option<u32>,
+    /// Minimum download rate in bytes per second, below which downloads are aborted, overriding the global default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) minrate: Option<u32>,
+    /// Number of times to retry downloads, overriding the global default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) retries: Option<u32>,
+    /// The order in which the repository's mirrors are tried, overriding the global default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) failovermethod: Option<FailoverMethod>,
+}
+
+/// The order in which dnf tries a repository's mirrors.
+/// Whichever is used, dnf moves on to the next mirror when a download from one fails, until every mirror has been tried.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FailoverMethod {
+    /// Try the mirrors in the order they are listed
+    Priority,
+    /// Try the mirrors in order of their connection latency, measured before downloading
+    Fastest,
+}
+
+impl FailoverMethod {
+    /// The value of dnf's `fastestmirror` option
+    pub(crate) fn fastestmirror(self) -> bool {
+        self == FailoverMethod::Fastest
+    }
+}
+
+/// Repository configuration as written in the config file, prior to environment variable expansion
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawRepository {
+    UrlOrId(String),
+    Definition(RawRepositoryDefinition),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRepositoryDefinition {
+    id: Option<String>,
+    url: String,
+    #[serde(default)]
+    options: HashMap<String, String>,
+    #[serde(default)]
+    skip_if_unavailable: Option<bool>,
+    #[serde(default)]
+    repo_gpgcheck: Option<bool>,
+    #[serde(default)]
+    url_command: Option<Vec<String>>,
+    #[serde(default)]
+    timeout: Option<u32>,
+    #[serde(default)]
+    minrate: Option<u32>,
+    #[serde(default)]
+    retries: Option<u32>,
+    #[serde(default)]
+    failovermethod: Option<FailoverMethod>,
+}
+
+impl<'de> Deserialize<'de> for Repository {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repository = match RawRepository::deserialize(deserializer)? {
+            RawRepository::UrlOrId(value) => {
+                let value = expand_env_vars(&value, &process_env).map_err(D::Error::custom)?;
+                // Anything that isn't a URL is a repo id
+                match Url::parse(&value) {
+                    Ok(url) => Repository::Url(url),
+                    Err(_) => Repository::Id(value),
+                }
+            }
+            RawRepository::Definition(definition) => {
+                let url =
+                    expand_env_vars(&definition.url, &process_env).map_err(D::Error::custom)?;
+                let options = definition
+                    .options
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, expand_env_vars(&value, &process_env)?)))
+                    .collect::<Result<HashMap<_, _>>>()
+                    .map_err(D::Error::custom)?;
+                Repository::Definition(RepositoryDefinition {
+                    id: definition
+                        .id
+                        .map(|id| expand_env_vars(&id, &process_env))
+                        .transpose()
+                        .map_err(D::Error::custom)?,
+                    url: Url::parse(&url)
+                        .with_context(|| format!("Invalid repository url `{}`", url))
+                        .map_err(D::Error::custom)?,
+                    options,
+                    skip_if_unavailable: definition.skip_if_unavailable,
+                    repo_gpgcheck: definition.repo_gpgcheck,
+                    url_command: definition
+                        .url_command
+                        .map(|command| {
+                            command
+                                .iter()
+                                .map(|arg| expand_env_vars(arg, &process_env))
+                                .collect::<Result<Vec<_>>>()
+                        })
+                        .transpose()
+                        .map_err(D::Error::custom)?,
+                    timeout: definition.timeout,
+                    minrate: definition.minrate,
+                    retries: definition.retries,
+                    failovermethod: definition.failovermethod,
+                })
+            }
+        };
+        Ok(repository)
+    }
+}
+
+/// Looks up an environment variable.
+/// Functions that read the environment take one of these, so tests don't need to modify the process environment.
+pub(crate) type Env<'a> = &'a dyn Fn(&str) -> Result<String, std::env::VarError>;
+
+/// Looks up a variable in the process environment
+pub(crate) fn process_env(name: &str) -> Result<String, std::env::VarError> {
+    std::env::var(name)
+}
+
+/// Expand references to environment variables of the form `${VAR}` or `${VAR:-default}`.
+/// It is an error to reference an unset variable without a default.
+fn expand_env_vars(value: &str, env: Env) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            bail!("Unterminated environment variable reference in `{}`", value);
+        };
+        let reference = &rest[start + 2..start + 2 + len];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        match (env(name), default) {
+            (Ok(var), _) => expanded.push_str(&var),
+            (Err(std::env::VarError::NotPresent), Some(default)) => expanded.push_str(default),
+            (Err(e), _) => bail!(
+                "Failed to expand environment variable `{}` in `{}`: {}",
+                name,
+                value,
+                e
+            ),
+        }
+        rest = &rest[start + 2 + len + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Prefix of references to build arguments in label values
+const BUILD_ARG_PREFIX: &str = "${BUILD_ARG_";
+
+/// Whether `name` can be used as a build argument name: ASCII alphanumerics and underscores,
+/// not starting with a digit
+pub(crate) fn is_valid_build_arg_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Interpolate references to build arguments of the form `${BUILD_ARG_NAME}`.
+/// It is an error to reference an undefined build argument.
+pub(crate) fn interpolate_build_args(
+    value: &str,
+    build_args: &HashMap<String, String>,
+) -> Result<String> {
+    let mut interpolated = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find(BUILD_ARG_PREFIX) {
+        interpolated.push_str(&rest[..start]);
+        let name_start = start + BUILD_ARG_PREFIX.len();
+        let Some(len) = rest[name_start..].find('}') else {
+            bail!("Unterminated build argument reference in `{}`", value);
+        };
+        let name = &rest[name_start..name_start + len];
+        let Some(arg) = build_args.get(name) else {
+            bail!(
+                "Undefined build argument `{}` referenced in `{}`. Pass it with `--build-arg {}=VALUE`",
+                name,
+                value,
+                name
+            );
+        };
+        interpolated.push_str(arg);
+        rest = &rest[name_start + len + 1..];
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
 }
 
 impl Repository {
@@ -141,11 +966,69 @@ impl Repository {
 }
 
 impl ImageConfig {
+    /// The platform the image is built for, if one is configured.
+    /// The `architecture`, `os` and `variant` fields override the corresponding parts of `platform`.
+    pub(crate) fn platform(&self) -> Result<Option<Platform>> {
+        let mut platform = self
+            .platform
+            .as_deref()
+            .map(str::parse::<Platform>)
+            .transpose()
+            .context("Invalid image.platform")?;
+        if self.architecture.is_none() && self.os.is_none() && self.variant.is_none() {
+            return Ok(platform);
+        }
+        let platform = platform.get_or_insert_with(Platform::default);
+        if let Some(architecture) = &self.architecture {
+            platform.architecture = normalize_architecture(architecture);
+            // A variant is specific to the architecture it was given with
+            platform.variant = None;
+        }
+        if let Some(os) = &self.os {
+            platform.os.clone_from(os);
+        }
+        if let Some(variant) = &self.variant {
+            platform.variant = Some(variant.clone());
+        }
+        Ok(Some(platform.clone()))
+    }
+
+    /// Interpolate build arguments into label and annotation values
+    pub(crate) fn interpolate_build_args(
+        &mut self,
+        build_args: &HashMap<String, String>,
+    ) -> Result<()> {
+        for value in self
+            .labels
+            .values_mut()
+            .chain(self.annotations.values_mut())
+            .chain(self.index_annotations.values_mut())
+        {
+            *value = interpolate_build_args(value, build_args)?;
+        }
+        Ok(())
+    }
+
+    /// Fields to add to the `config` object of the image configuration, that Docker supports but
+    /// the OCI image spec doesn't define
+    pub(crate) fn docker_config_extensions(
+        &self,
+    ) -> Result<serde_json::Map<String, serde_json::Value>> {
+        let mut extensions = serde_json::Map::new();
+        if let Some(healthcheck) = &self.healthcheck {
+            if !self.docker_compat {
+                bail!("image.healthcheck isn't part of the OCI image spec, so requires image.docker_compat = true");
+            }
+            extensions.insert("Healthcheck".to_string(), healthcheck.to_docker_json()?);
+        }
+        Ok(extensions)
+    }
+
     pub(crate) fn to_oci_image_configuration(
         &self,
         cli_labels: HashMap<String, String>,
         creation_time: chrono::DateTime<chrono::Utc>,
-    ) -> Result<ImageConfiguration, OciSpecError> {
+    ) -> Result<ImageConfiguration> {
         let ImageConfig {
             user,
             exposed_ports,
@@ -159,6 +1042,12 @@ impl ImageConfig {
             author,
             ..
         } = &self;
+        for port in exposed_ports {
+            validate_exposed_port(port)?;
+        }
+        if let Some(stopsignal) = stopsignal {
+            validate_stop_signal(stopsignal)?;
+        }
         let mut builder = ConfigBuilder::default();
         let mut merged_labels = labels.clone();
         merged_labels.extend(cli_labels);
@@ -190,23 +1079,63 @@ impl ImageConfig {
         }
         let config = builder.build()?;
 
+        let platform = self.platform()?.unwrap_or_default();
         let mut builder = ImageConfigurationBuilder::default()
             .config(config)
-            .architecture(Arch::Amd64)
-            .os(Os::Linux)
+            .architecture(Arch::from(platform.architecture.as_str()))
+            .os(Os::from(platform.os.as_str()))
             .created(creation_time.to_rfc3339());
+        if let Some(variant) = platform.variant {
+            builder = builder.variant(variant);
+        }
         if let Some(author) = author {
             builder = builder.author(author);
         }
-        builder.build()
+        Ok(builder.build()?)
+    }
+}
+
+/// Check an exposed port has the form `<port>`, `<port>/tcp`, `<port>/udp` or `<port>/sctp`
+fn validate_exposed_port(port: &str) -> Result<()> {
+    let (number, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+    if !matches!(protocol, "tcp" | "udp" | "sctp") {
+        bail!(
+            "Invalid exposed port `{}`: the protocol must be tcp, udp or sctp",
+            port
+        );
+    }
+    match number.parse::<u16>() {
+        Ok(number) if number != 0 => Ok(()),
+        _ => bail!(
+            "Invalid exposed port `{}`: the port must be a number between 1 and 65535",
+            port
+        ),
+    }
+}
+
+/// Check a stop signal is a signal number or a signal name of the form `SIGNAME`, e.g `SIGTERM` or `SIGRTMIN+3`
+fn validate_stop_signal(signal: &str) -> Result<()> {
+    let valid = signal.parse::<u32>().is_ok()
+        || signal.strip_prefix("SIG").is_some_and(|name| {
+            name.starts_with(|c: char| c.is_ascii_uppercase())
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '+' || c == '-')
+        });
+    if !valid {
+        bail!(
+            "Invalid stop signal `{}`: expected a signal name such as SIGTERM, or a signal number",
+            signal
+        );
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Config;
-    use crate::config::ImageConfig;
-    use ocidir::oci_spec::image::ImageConfiguration;
+    use super::{expand_env_vars, is_valid_build_arg_name, Config, Platform};
+    use crate::config::{ImageConfig, Repository};
+    use ocidir::oci_spec::image::{Arch, ImageConfiguration, Os};
     use std::collections::HashMap;
 
     #[test]
@@ -281,6 +1210,56 @@ mod tests {
         toml::from_str::<Config>(config).unwrap();
     }
 
+    #[test]
+    fn repository_env_var_expansion() {
+        let env = |name: &str| match name {
+            "RPMOCI_TEST_MIRROR" => Ok("mirror.example.com".to_string()),
+            _ => Err(std::env::VarError::NotPresent),
+        };
+        assert_eq!(
+            expand_env_vars("https://${RPMOCI_TEST_MIRROR}/base", &env).unwrap(),
+            "https://mirror.example.com/base"
+        );
+        assert_eq!(
+            expand_env_vars("${RPMOCI_TEST_UNSET:-foo}-base", &env).unwrap(),
+            "foo-base"
+        );
+        assert_eq!(
+            expand_env_vars("${RPMOCI_TEST_MIRROR:-fallback.example.com}", &env).unwrap(),
+            "mirror.example.com"
+        );
+        let err = expand_env_vars("https://${RPMOCI_TEST_UNSET}/base", &env).unwrap_err();
+        assert!(err.to_string().contains("RPMOCI_TEST_UNSET"));
+        assert!(expand_env_vars("${RPMOCI_TEST_MIRROR", &env).is_err());
+
+        // Repositories are expanded using the process environment, in which `RPMOCI_TEST_UNSET` is never set
+        let config = r#"[contents]
+        repositories = [
+          "${RPMOCI_TEST_UNSET:-foo}-base",
+          { url = "https://${RPMOCI_TEST_UNSET:-fallback.example.com}/extra", options = { includepkgs = "${RPMOCI_TEST_UNSET:-bar}" } },
+        ]
+        packages = ["tini"]
+        "#;
+        let config = toml::from_str::<Config>(config).unwrap();
+        let repositories = &config.contents.repositories;
+        assert!(matches!(&repositories[0], Repository::Id(id) if id == "foo-base"));
+        let Repository::Definition(definition) = &repositories[1] else {
+            panic!("expected repository definition");
+        };
+        assert_eq!(
+            definition.url.as_str(),
+            "https://fallback.example.com/extra"
+        );
+        assert_eq!(definition.options["includepkgs"], "bar");
+
+        let config = r#"[contents]
+        repositories = ["https://${RPMOCI_TEST_UNSET}/base"]
+        packages = ["tini"]
+        "#;
+        let err = toml::from_str::<Config>(config).unwrap_err();
+        assert!(err.to_string().contains("RPMOCI_TEST_UNSET"));
+    }
+
     #[test]
     fn path_env_defaulting() {
         let config_with_path = r#"
@@ -337,4 +1316,270 @@ mod tests {
         assert_eq!(labels.get("foo.baz").unwrap(), "quux");
         assert_eq!(labels.len(), 2);
     }
+
+    #[test]
+    fn stop_signal_and_exposed_ports() {
+        let config_str = r#"
+        stop_signal = "SIGRTMIN+3"
+        exposed_ports = ["8080/tcp", "53/udp", "9000"]
+        "#;
+        let config = toml::from_str::<ImageConfig>(config_str)
+            .unwrap()
+            .to_oci_image_configuration(HashMap::new(), chrono::Utc::now())
+            .unwrap();
+        // Read the values back from the serialized config blob
+        let blob = serde_json::to_vec(&config).unwrap();
+        let config = ImageConfiguration::from_reader(blob.as_slice()).unwrap();
+        let config = config.config().as_ref().unwrap();
+        assert_eq!(config.stop_signal().as_deref(), Some("SIGRTMIN+3"));
+        let mut ports = config.exposed_ports().clone().unwrap();
+        ports.sort();
+        assert_eq!(ports, ["53/udp", "8080/tcp", "9000"]);
+
+        for config_str in [
+            r#"exposed_ports = ["http"]"#,
+            r#"exposed_ports = ["8080/http"]"#,
+            r#"exposed_ports = ["0/tcp"]"#,
+            r#"exposed_ports = ["65536"]"#,
+            r#"exposed_ports = ["8080-8090/tcp"]"#,
+            r#"stopsignal = "TERM""#,
+            r#"stopsignal = "SIGterm""#,
+            r#"stopsignal = "SIG""#,
+        ] {
+            assert!(
+                toml::from_str::<ImageConfig>(config_str)
+                    .unwrap()
+                    .to_oci_image_configuration(HashMap::new(), chrono::Utc::now())
+                    .is_err(),
+                "{}",
+                config_str
+            );
+        }
+    }
+
+    #[test]
+    fn build_arg_interpolation() {
+        let config_str = r#"
+        labels = { "org.opencontainers.image.revision" = "${BUILD_ARG_GIT_SHA}", "static" = "${FOO}" }
+        "#;
+        let build_args = [("GIT_SHA".to_string(), "abc123".to_string())]
+            .into_iter()
+            .collect();
+        let mut config = toml::from_str::<ImageConfig>(config_str).unwrap();
+        config.interpolate_build_args(&build_args).unwrap();
+        let config = config
+            .to_oci_image_configuration(HashMap::new(), chrono::Utc::now())
+            .unwrap();
+        let labels = config.config().as_ref().unwrap().labels().as_ref().unwrap();
+        assert_eq!(
+            labels.get("org.opencontainers.image.revision").unwrap(),
+            "abc123"
+        );
+        // Only build argument references are interpolated
+        assert_eq!(labels.get("static").unwrap(), "${FOO}");
+
+        let mut config = toml::from_str::<ImageConfig>(
+            r#"
+        annotations = { "org.opencontainers.image.revision" = "${BUILD_ARG_GIT_SHA}" }
+        index_annotations = { "org.opencontainers.image.version" = "v${BUILD_ARG_GIT_SHA}" }
+        "#,
+        )
+        .unwrap();
+        config.interpolate_build_args(&build_args).unwrap();
+        assert_eq!(
+            config.annotations["org.opencontainers.image.revision"],
+            "abc123"
+        );
+        assert_eq!(
+            config.index_annotations["org.opencontainers.image.version"],
+            "vabc123"
+        );
+
+        let err = toml::from_str::<ImageConfig>(config_str)
+            .unwrap()
+            .interpolate_build_args(&HashMap::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("GIT_SHA"));
+
+        assert!(is_valid_build_arg_name("GIT_SHA"));
+        assert!(!is_valid_build_arg_name("1SHA"));
+        assert!(!is_valid_build_arg_name("GIT-SHA"));
+        assert!(!is_valid_build_arg_name(""));
+    }
+
+    #[test]
+    fn packages_are_overridden() {
+        let mut config: Config = toml::from_str(
+            r#"
+[contents]
+repositories = []
+packages = ["bash", "curl"]
+"#,
+        )
+        .unwrap();
+        config
+            .override_packages(
+                &["zlib".to_string(), "bash".to_string()],
+                &["curl".to_string()],
+            )
+            .unwrap();
+        assert_eq!(config.contents.packages, vec!["bash", "zlib"]);
+        let err = config
+            .override_packages(&[], &["curl".to_string()])
+            .unwrap_err();
+        assert!(err.to_string().contains("isn't in contents.packages"));
+    }
+
+    #[test]
+    fn weak_dependency_classes() {
+        let config: Config = toml::from_str(
+            r#"
+[contents]
+repositories = ["https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64"]
+packages = ["pcre2-tools"]
+"#,
+        )
+        .unwrap();
+        assert!(config.contents.weak_deps.classes().is_empty());
+
+        let config: Config = toml::from_str(
+            r#"
+[contents]
+repositories = ["https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64"]
+packages = ["pcre2-tools"]
+weak_deps = { supplements = true, enhances = true }
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.contents.weak_deps.classes(),
+            vec!["supplements", "enhances"]
+        );
+
+        assert!(toml::from_str::<Config>(
+            r#"
+[contents]
+repositories = []
+packages = []
+weak_deps = { recommend = true }
+"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn healthchecks_require_docker_compat() {
+        let image = |healthcheck: &str, docker_compat: bool| {
+            toml::from_str::<ImageConfig>(&format!(
+                "docker_compat = {}\nhealthcheck = {}",
+                docker_compat, healthcheck
+            ))
+            .unwrap()
+            .docker_config_extensions()
+        };
+        let extensions = image(
+            r#"{ test = ["CMD-SHELL", "curl -f http://localhost"], interval = "30s", timeout = "1500ms", start_period = "1h2m" }"#,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            extensions["Healthcheck"],
+            serde_json::json!({
+                "Test": ["CMD-SHELL", "curl -f http://localhost"],
+                "Interval": 30_000_000_000u64,
+                "Timeout": 1_500_000_000u64,
+                "StartPeriod": 3_720_000_000_000u64,
+            })
+        );
+        assert!(toml::from_str::<ImageConfig>("")
+            .unwrap()
+            .docker_config_extensions()
+            .unwrap()
+            .is_empty());
+
+        let err = image(r#"{ test = ["NONE"] }"#, false).unwrap_err();
+        assert!(err.to_string().contains("docker_compat"));
+        assert!(image(r#"{ test = ["curl"] }"#, true).is_err());
+        assert!(image(r#"{ test = ["CMD"] }"#, true).is_err());
+        for interval in ["", "30", "s", "30x", "1.5s", "99999999999h"] {
+            assert!(
+                image(
+                    &format!(r#"{{ test = ["NONE"], interval = "{}" }}"#, interval),
+                    true
+                )
+                .is_err(),
+                "{}",
+                interval
+            );
+        }
+    }
+
+    #[test]
+    fn platforms_set_the_dnf_arch_and_image_platform() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [contents]
+            repositories = []
+            packages = []
+            [image]
+            platform = "linux/arm/v7"
+            "#,
+        )
+        .unwrap();
+        config.apply_platform(None).unwrap();
+        assert_eq!(config.contents.arch.as_deref(), Some("armv7hl"));
+        let image = config
+            .image
+            .to_oci_image_configuration(HashMap::new(), chrono::Utc::now())
+            .unwrap();
+        assert_eq!(image.os(), &Os::Linux);
+        assert_eq!(image.architecture(), &Arch::ARM);
+        assert_eq!(image.variant().as_deref(), Some("v7"));
+
+        // The individual fields override the platform's
+        config.image.variant = Some("v6".to_string());
+        config.apply_platform(None).unwrap();
+        assert_eq!(config.contents.arch.as_deref(), Some("armv6hl"));
+        config.image.architecture = Some("aarch64".to_string());
+        config.apply_platform(None).unwrap();
+        assert_eq!(config.contents.arch.as_deref(), Some("aarch64"));
+        assert_eq!(
+            config.image.platform().unwrap().unwrap().to_string(),
+            "linux/arm64/v6"
+        );
+
+        // A platform given on the command line replaces the configured one
+        config
+            .apply_platform(Some(&"linux/amd64".parse().unwrap()))
+            .unwrap();
+        assert_eq!(config.contents.arch.as_deref(), Some("x86_64"));
+        let image = config
+            .image
+            .to_oci_image_configuration(HashMap::new(), chrono::Utc::now())
+            .unwrap();
+        assert_eq!(image.architecture(), &Arch::Amd64);
+        assert_eq!(image.variant(), &None);
+
+        // Without a platform, the host's architecture is used and the image is linux/amd64
+        let mut config: Config =
+            toml::from_str("[contents]\nrepositories = []\npackages = []").unwrap();
+        config.apply_platform(None).unwrap();
+        assert_eq!(config.contents.arch, None);
+        let image = config
+            .image
+            .to_oci_image_configuration(HashMap::new(), chrono::Utc::now())
+            .unwrap();
+        assert_eq!(image.architecture(), &Arch::Amd64);
+
+        for platform in ["arm64", "linux/arm64/v8/extra", "linux//v7"] {
+            assert!(platform.parse::<Platform>().is_err(), "{}", platform);
+        }
+        for platform in ["windows/amd64", "linux/sparc"] {
+            assert!(
+                platform.parse::<Platform>().unwrap().dnf_arch().is_err(),
+                "{}",
+                platform
+            );
+        }
+    }
 }
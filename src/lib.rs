@@ -16,6 +16,7 @@
 //! You should have received a copy of the GNU General Public License
 //! along with this program.  If not, see <https://www.gnu.org/licenses/>.
 use std::{
+    collections::HashMap,
     fs,
     path::{Path, PathBuf},
     time::Instant,
@@ -24,13 +25,22 @@ use std::{
 use anyhow::{bail, Context};
 mod archive;
 pub mod cli;
+mod composefs;
 pub mod config;
 pub mod lockfile;
+pub mod nevra;
+mod oci;
+pub use oci::{BlobStore, DigestAlgorithm, ExistingLayout, LayerCompression};
+mod provenance;
+mod rootfs;
+mod sign;
 pub mod write;
 use anyhow::Result;
-use cli::Command;
-use config::Config;
-use lockfile::Lockfile;
+use cli::{Command, IndexCommand};
+use config::{interpolate_build_args, Config};
+use lockfile::{BuildOptions, Keyring, Lockfile, LockfileFormat, RpmSource};
+use ocidir::cap_std::fs::Dir;
+use ocidir::OciDir;
 
 pub(crate) const NAME: &str = "rpmoci";
 
@@ -40,7 +50,8 @@ fn load_config_and_lock_file(
     let config_file = config_file.as_ref();
     let contents = std::fs::read_to_string(config_file)
         .context(format!("Failed to read `{}`", config_file.display()))?;
-    let cfg: Config = toml::from_str(&contents)?;
+    let mut cfg: Config = toml::from_str(&contents)?;
+    cfg.apply_platform(None)?;
     let mut lockfile_path = PathBuf::from(config_file);
     lockfile_path.set_extension("lock");
     Ok((cfg, lockfile_path.clone(), read_lockfile(&lockfile_path)))
@@ -48,20 +59,193 @@ fn load_config_and_lock_file(
 
 fn read_lockfile(lockfile: impl AsRef<Path>) -> Result<Option<Lockfile>> {
     match std::fs::read_to_string(lockfile) {
-        Ok(d) => Ok(Some(toml::from_str(&d).context("Invalid lockfile")?)),
+        Ok(d) => Ok(Some(Lockfile::parse(&d).context("Invalid lockfile")?)),
         Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
         Err(e) => Err(e.into()),
     }
 }
 
+/// The format of an existing lockfile, so that rewriting it doesn't change its format.
+/// Defaults to TOML.
+fn existing_lockfile_format(lockfile: impl AsRef<Path>) -> LockfileFormat {
+    std::fs::read_to_string(lockfile)
+        .map(|contents| LockfileFormat::detect(&contents))
+        .unwrap_or_default()
+}
+
+/// The lock file to build a configuration from.
+/// Unless `locked` is set, a missing or out of date lock file is generated, and written to `lockfile_path` if `persist` is set.
+fn lockfile_for_build(
+    cfg: &Config,
+    manifest_path: &Path,
+    lockfile_path: &Path,
+    existing_lockfile: Result<Option<Lockfile>>,
+    locked: bool,
+    persist: bool,
+) -> Result<Lockfile> {
+    let mut changed = false;
+    let mut lockfile = match (existing_lockfile, locked) {
+        (Ok(Some(lockfile)), true) => {
+            // TODO: consider whether this can move to including local RPMs. (Subtlety here is that may
+            // break scenarios where the user is using local RPMs that have a subset of the locked local RPM dependencies.)
+            let staleness = lockfile.staleness(cfg);
+            if !staleness.is_empty() {
+                bail!(format!(
+                    "the lock file {} needs to be updated but --locked was passed to prevent this: {}",
+                    lockfile_path.display(),
+                    staleness
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+            lockfile
+        }
+        (Ok(Some(lockfile)), false) => {
+            if lockfile.is_compatible_including_local_rpms(cfg)? {
+                // Compatible lockfile, use it
+                lockfile
+            } else {
+                // Incompatible lockfile, update it
+                changed = true;
+                write::ok(
+                    "Generating",
+                    format!(
+                        "new lock file. The existing lock file {} is not up-to-date.",
+                        lockfile_path.display()
+                    ),
+                )?;
+                lockfile.resolve_from_config_keeping_versions(cfg)?
+            }
+        }
+        (Err(err), false) => {
+            write::warning(
+                "Warning",
+                format!(
+                    "failed to parse existing lock file. Generating a new one. Error: {}",
+                    err
+                ),
+            )?;
+            err.chain()
+                .skip(1)
+                .for_each(|cause| eprintln!("caused by: {}", cause));
+            changed = true;
+            Lockfile::resolve_from_config(cfg)?
+        }
+        (Err(err), true) => {
+            return Err(err.context(format!(
+                "failed to parse existing lock file {}",
+                lockfile_path.display()
+            )))
+        }
+        (Ok(None), true) => {
+            bail!(format!(
+            "the lock file {} is missing and needs to be generated but --locked was passed to prevent this",
+            lockfile_path.display()
+        ))
+        }
+        (Ok(None), false) => {
+            changed = true;
+            Lockfile::resolve_from_config(cfg)?
+        }
+    };
+
+    if changed && persist {
+        if cfg.contents.lockfile_metadata {
+            lockfile.record_metadata(manifest_path)?;
+        }
+        let format = existing_lockfile_format(lockfile_path);
+        lockfile.write_to_file_as(lockfile_path, format)?;
+    } else if changed {
+        write::ok(
+            "Resolved",
+            format!(
+                "packages for this build only, leaving {} unchanged",
+                lockfile_path.display()
+            ),
+        )?;
+    }
+    Ok(lockfile)
+}
+
+/// The rpmoci manifest files matching `configs`, which is either a directory containing manifests
+/// with a `.toml` extension or a glob pattern
+fn manifest_paths(configs: &str) -> Result<Vec<PathBuf>> {
+    let pattern = if Path::new(configs).is_dir() {
+        Path::new(configs).join("*.toml").display().to_string()
+    } else {
+        configs.to_string()
+    };
+    let mut paths = glob::glob(&pattern)
+        .with_context(|| format!("Invalid glob pattern `{}`", pattern))?
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| path.is_file());
+    paths.sort();
+    if paths.is_empty() {
+        bail!("No rpmoci manifest files match `{}`", configs);
+    }
+    Ok(paths)
+}
+
+/// The tag of the image built from an rpmoci manifest: the manifest's file stem
+fn manifest_tag(manifest_path: &Path) -> Result<String> {
+    manifest_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(ToString::to_string)
+        .with_context(|| format!("Invalid manifest path `{}`", manifest_path.display()))
+}
+
+/// Build the image again into a scratch image layout, failing unless it has the same manifest
+/// digest as the image already built into `image`
+fn check_reproducible_build(
+    lockfile: &Lockfile,
+    cfg: &Config,
+    image: &str,
+    tag: &str,
+    labels: HashMap<String, String>,
+    options: BuildOptions,
+) -> Result<()> {
+    write::ok("Rebuilding", "image to check it's reproducible")?;
+    let scratch = match options.tmp_dir {
+        Some(tmp_dir) => tempfile::tempdir_in(tmp_dir),
+        None => tempfile::tempdir(),
+    }
+    .context("Failed to create scratch image layout")?;
+    let scratch_image = scratch.path().join("image");
+    let scratch_image = scratch_image
+        .to_str()
+        .context("Scratch image layout path isn't valid UTF-8")?;
+    lockfile.build(cfg, scratch_image, tag, labels, options)?;
+
+    let open = |path: &str| -> Result<OciDir> {
+        let dir = Dir::open_ambient_dir(path, ocidir::cap_std::ambient_authority())
+            .with_context(|| format!("Failed to open image directory `{}`", path))?;
+        OciDir::open(&dir)
+    };
+    if let Some(difference) = oci::image_difference(&open(image)?, &open(scratch_image)?, tag)? {
+        bail!("The image isn't reproducible: {}", difference);
+    }
+    write::ok(
+        "Verified",
+        format!("image '{}:{}' is reproducible", image, tag),
+    )?;
+    Ok(())
+}
+
 /// Run rpmoci
 pub fn main(command: Command) -> anyhow::Result<()> {
     match command {
         Command::Update {
             manifest_path,
             from_lockfile,
+            output_format,
+            platform,
         } => {
-            let (cfg, lockfile_path, existing_lockfile) = load_config_and_lock_file(manifest_path)?;
+            let (mut cfg, lockfile_path, existing_lockfile) =
+                load_config_and_lock_file(&manifest_path)?;
+            cfg.apply_platform(platform.as_ref())?;
 
             let lockfile = if let Ok(Some(lockfile)) = &existing_lockfile {
                 if lockfile.is_compatible_excluding_local_rpms(&cfg) && from_lockfile {
@@ -79,115 +263,252 @@ pub fn main(command: Command) -> anyhow::Result<()> {
                 Lockfile::resolve_from_config(&cfg)?
             };
 
+            let mut lockfile = lockfile;
+            if cfg.contents.lockfile_metadata {
+                lockfile.record_metadata(&manifest_path)?;
+            }
             lockfile.print_updates(existing_lockfile.unwrap_or_default().as_ref())?;
-            lockfile.write_to_file(lockfile_path)?;
+            let format = output_format.unwrap_or_else(|| existing_lockfile_format(&lockfile_path));
+            lockfile.write_to_file_as(lockfile_path, format)?;
         }
         Command::Build {
             locked,
+            frozen,
             image,
             tag,
             vendor_dir,
             manifest_path,
             label,
+            build_arg,
+            no_gpgkey_import,
+            keyring_dir,
+            tmp_dir,
+            digest_algorithm,
+            sign_by,
+            provenance,
+            platform,
+            check_reproducible,
+            add_package,
+            remove_package,
+            dry_run,
+            existing_layout,
         } => {
             let now = Instant::now();
-            let mut changed = false;
-            let (cfg, lockfile_path, existing_lockfile) = load_config_and_lock_file(manifest_path)?;
-            let lockfile = match (existing_lockfile, locked) {
-                (Ok(Some(lockfile)), true) => {
-                    // TODO: consider whether this can move to including local RPMs. (Subtlety here is that may
-                    // break scenarios where the user is using local RPMs that have a subset of the locked local RPM dependencies.)
-                    if !lockfile.is_compatible_excluding_local_rpms(&cfg) {
-                        bail!(format!(
-                            "the lock file {} needs to be updated but --locked was passed to prevent this",
-                            lockfile_path.display()
-                        ));
-                    }
-                    lockfile
-                }
-                (Ok(Some(lockfile)), false) => {
-                    if lockfile.is_compatible_including_local_rpms(&cfg)? {
-                        // Compatible lockfile, use it
-                        lockfile
-                    } else {
-                        // Incompatible lockfile, update it
-                        changed = true;
-                        write::ok(
-                            "Generating",
-                            format!(
-                                "new lock file. The existing lock file {} is not up-to-date.",
-                                lockfile_path.display()
-                            ),
-                        )?;
-                        Lockfile::resolve_from_config(&cfg)?
-                    }
-                }
-                (Err(err), false) => {
+            let (mut cfg, lockfile_path, existing_lockfile) =
+                load_config_and_lock_file(&manifest_path)?;
+            cfg.apply_platform(platform.as_ref())?;
+            cfg.contents.host_keyring = no_gpgkey_import;
+            // Overridden package specifications don't match the manifest, so the lock file isn't updated for them
+            let persist_lockfile = add_package.is_empty() && remove_package.is_empty();
+            cfg.override_packages(&add_package, &remove_package)?;
+            let build_args = build_arg.into_iter().collect::<HashMap<_, _>>();
+            cfg.image.interpolate_build_args(&build_args)?;
+            let labels = label
+                .into_iter()
+                .map(|(key, value)| Ok((key, interpolate_build_args(&value, &build_args)?)))
+                .collect::<Result<HashMap<_, _>>>()?;
+            // Load the signing key up front, so that a bad key or password fails before the build
+            let signing_key = sign_by
+                .map(|path| {
+                    sign::load_signing_key(
+                        &path,
+                        std::env::var(sign::SIGNING_PASSWORD_ENV).ok().as_deref(),
+                    )
+                })
+                .transpose()?;
+            let locked = locked || frozen;
+            let lockfile = lockfile_for_build(
+                &cfg,
+                &manifest_path,
+                &lockfile_path,
+                existing_lockfile,
+                locked,
+                persist_lockfile,
+            )?;
+
+            if check_reproducible && std::env::var_os("SOURCE_DATE_EPOCH").is_none() {
+                // Both builds need the same creation time
+                std::env::set_var(
+                    "SOURCE_DATE_EPOCH",
+                    chrono::Utc::now().timestamp().to_string(),
+                );
+            }
+            let options = BuildOptions {
+                source: match (&vendor_dir, frozen) {
+                    (Some(vendor_dir), _) => RpmSource::Vendor(vendor_dir),
+                    (None, true) => RpmSource::Frozen,
+                    (None, false) => RpmSource::Download,
+                },
+                keyring: keyring(no_gpgkey_import, keyring_dir.as_deref()),
+                tmp_dir: tmp_dir.as_deref(),
+                digest_algorithm,
+                dry_run,
+                existing_layout,
+            };
+            lockfile.build(&cfg, &image, &tag, labels.clone(), options)?;
+            if check_reproducible {
+                check_reproducible_build(&lockfile, &cfg, &image, &tag, labels, options)?;
+            }
+            if let Some(key) = signing_key {
+                let dir = Dir::open_ambient_dir(&image, ocidir::cap_std::ambient_authority())
+                    .with_context(|| format!("Failed to open image directory `{}`", image))?;
+                let signature_tag =
+                    sign::sign_image(&OciDir::open(&dir)?, &key, &image, &tag, digest_algorithm)?;
+                write::ok("Signed", format!("image as '{}:{}'", image, signature_tag))?;
+            }
+            if let Some(path) = provenance {
+                let dir = Dir::open_ambient_dir(&image, ocidir::cap_std::ambient_authority())
+                    .with_context(|| format!("Failed to open image directory `{}`", image))?;
+                provenance::write_provenance(
+                    &path,
+                    &OciDir::open(&dir)?,
+                    &image,
+                    &tag,
+                    &manifest_path,
+                    &lockfile_path,
+                    &cfg.contents.repositories,
+                )?;
+                write::ok("Wrote", format!("provenance to {}", path.display()))?;
+            }
+            let elapsed_time = now.elapsed();
+            if dry_run {
+                write::ok(
+                    "Success",
+                    format!(
+                        "dry run of '{}:{}' completed in {:2}s",
+                        image,
+                        tag,
+                        elapsed_time.as_secs_f32()
+                    ),
+                )?;
+            } else {
+                write::ok(
+                    "Success",
+                    format!(
+                        "image '{}:{}' created in {:2}s",
+                        image,
+                        tag,
+                        elapsed_time.as_secs_f32()
+                    ),
+                )?;
+            }
+        }
+        Command::BuildAll {
+            configs,
+            locked,
+            fail_fast,
+            image,
+            build_arg,
+            no_gpgkey_import,
+            keyring_dir,
+            tmp_dir,
+            digest_algorithm,
+            platform,
+        } => {
+            let now = Instant::now();
+            let build_args = build_arg.into_iter().collect::<HashMap<_, _>>();
+            let options = BuildOptions {
+                source: RpmSource::Download,
+                keyring: keyring(no_gpgkey_import, keyring_dir.as_deref()),
+                tmp_dir: tmp_dir.as_deref(),
+                digest_algorithm,
+                dry_run: false,
+                existing_layout: ExistingLayout::Reuse,
+            };
+            let manifest_paths = manifest_paths(&configs)?;
+            let mut failed = Vec::new();
+            let mut attempted = 0;
+            for manifest_path in &manifest_paths {
+                attempted += 1;
+                let tag = manifest_tag(manifest_path)?;
+                write::ok(
+                    "Building",
+                    format!("'{}:{}' from {}", image, tag, manifest_path.display()),
+                )?;
+                let result = (|| {
+                    let (mut cfg, lockfile_path, existing_lockfile) =
+                        load_config_and_lock_file(manifest_path)?;
+                    cfg.apply_platform(platform.as_ref())?;
+                    cfg.contents.host_keyring = no_gpgkey_import;
+                    cfg.image.interpolate_build_args(&build_args)?;
+                    let lockfile = lockfile_for_build(
+                        &cfg,
+                        manifest_path,
+                        &lockfile_path,
+                        existing_lockfile,
+                        locked,
+                        true,
+                    )?;
+                    lockfile.build(&cfg, &image, &tag, HashMap::new(), options)
+                })();
+                if let Err(err) = result {
                     write::error(
-                        "Warning",
-                        format!(
-                            "failed to parse existing lock file. Generating a new one. Error: {}",
-                            err
-                        ),
+                        "Failed",
+                        format!("to build {}: {:#}", manifest_path.display(), err),
                     )?;
-                    err.chain()
-                        .skip(1)
-                        .for_each(|cause| eprintln!("caused by: {}", cause));
-                    changed = true;
-                    Lockfile::resolve_from_config(&cfg)?
-                }
-                (Err(err), true) => {
-                    return Err(err.context(format!(
-                        "failed to parse existing lock file {}",
-                        lockfile_path.display()
-                    )))
-                }
-                (Ok(None), true) => {
-                    bail!(format!(
-                    "the lock file {} is missing and needs to be generated but --locked was passed to prevent this",
-                    lockfile_path.display()
-                ))
-                }
-                (Ok(None), false) => {
-                    changed = true;
-                    Lockfile::resolve_from_config(&cfg)?
+                    failed.push(manifest_path.display().to_string());
+                    if fail_fast {
+                        break;
+                    }
                 }
-            };
-
-            if changed {
-                lockfile.write_to_file(lockfile_path)?;
             }
-
-            lockfile.build(
-                &cfg,
-                &image,
-                &tag,
-                vendor_dir.as_deref(),
-                label.into_iter().collect(),
-            )?;
             let elapsed_time = now.elapsed();
+            if !failed.is_empty() {
+                let skipped = manifest_paths.len() - attempted;
+                bail!(
+                    "{} of {} images failed to build{}: {}",
+                    failed.len(),
+                    manifest_paths.len(),
+                    if skipped > 0 {
+                        format!(" ({} skipped by --fail-fast)", skipped)
+                    } else {
+                        String::new()
+                    },
+                    failed.join(", ")
+                );
+            }
             write::ok(
                 "Success",
                 format!(
-                    "image '{}:{}' created in {:2}s",
-                    image,
-                    tag,
+                    "{} images created in {:2}s",
+                    manifest_paths.len(),
                     elapsed_time.as_secs_f32()
                 ),
             )?;
         }
+        Command::Explain {
+            package,
+            manifest_path,
+        } => {
+            let (_cfg, lockfile_path, existing_lockfile) =
+                load_config_and_lock_file(manifest_path)?;
+            let Some(lockfile) = existing_lockfile? else {
+                bail!("the lock file {} is missing", lockfile_path.display());
+            };
+            match lockfile.dependency_chain(&package)? {
+                Some(chain) => println!("{}", chain.join(" -> ")),
+                None => bail!("package `{}` is not in the lock file", package),
+            }
+        }
         Command::Vendor {
             out_dir,
             manifest_path,
+            no_gpgkey_import,
+            keyring_dir,
         } => {
             fs::create_dir_all(&out_dir).context("Failed to create vendor directory")?;
-            let (cfg, _lockfile_path, existing_lockfile) =
+            let (mut cfg, _lockfile_path, existing_lockfile) =
                 load_config_and_lock_file(manifest_path)?;
+            cfg.contents.host_keyring = no_gpgkey_import;
 
             if let Ok(Some(lockfile)) = existing_lockfile {
                 if lockfile.is_compatible_excluding_local_rpms(&cfg) {
                     lockfile.download_rpms(&cfg, &out_dir)?;
-                    lockfile.check_gpg_keys(&out_dir)?;
+                    lockfile.verify_checksums(&out_dir)?;
+                    lockfile.check_gpg_keys(
+                        &out_dir,
+                        keyring(no_gpgkey_import, keyring_dir.as_deref()),
+                    )?;
                 } else {
                     bail!(
                         "Lockfile out of date. `vendor` can only be run with a compatible lockfile"
@@ -199,6 +520,152 @@ pub fn main(command: Command) -> anyhow::Result<()> {
                 )
             }
         }
+        Command::Prefetch {
+            packages,
+            manifest_path,
+        } => {
+            let (cfg, _lockfile_path, existing_lockfile) =
+                load_config_and_lock_file(manifest_path)?;
+            if packages {
+                match existing_lockfile {
+                    Ok(Some(lockfile)) if lockfile.is_compatible_excluding_local_rpms(&cfg) => {
+                        lockfile.cache_rpms(&cfg)?
+                    }
+                    _ => bail!(
+                        "No up-to-date lockfile found. `prefetch --packages` can only be run with a compatible lockfile"
+                    ),
+                }
+            } else {
+                Lockfile::cache_metadata(&cfg)?;
+            }
+        }
+        Command::RepoFile { manifest_path } => {
+            let (cfg, _lockfile_path, _existing_lockfile) =
+                load_config_and_lock_file(manifest_path)?;
+            print!(
+                "{}",
+                lockfile::repo_file(&cfg.contents, &config::process_env)
+            );
+        }
+        Command::Audit {
+            feed,
+            feed_format,
+            manifest_path,
+        } => {
+            let (_cfg, lockfile_path, existing_lockfile) =
+                load_config_and_lock_file(manifest_path)?;
+            let Some(lockfile) = existing_lockfile? else {
+                bail!("the lock file {} is missing", lockfile_path.display());
+            };
+            let findings = lockfile.audit(&feed, feed_format)?;
+            for finding in &findings {
+                println!("{}", finding);
+            }
+            if !findings.is_empty() {
+                bail!(
+                    "{} known vulnerabilities affect packages in the lock file",
+                    findings.len()
+                );
+            }
+            write::ok("Audited", "no known vulnerabilities found")?;
+        }
+        Command::Index { image, command } => {
+            let dir = Dir::open_ambient_dir(&image, ocidir::cap_std::ambient_authority())
+                .with_context(|| format!("Failed to open image directory `{}`", image))?;
+            let oci_dir = OciDir::open(&dir)?;
+            match command {
+                IndexCommand::List => {
+                    for manifest in oci::list_manifests(&oci_dir)? {
+                        println!(
+                            "{}\t{}",
+                            manifest.tag.as_deref().unwrap_or("<none>"),
+                            manifest.digest
+                        );
+                    }
+                }
+                IndexCommand::Remove { reference } => {
+                    let removed = oci::remove_manifests(&oci_dir, &reference)?;
+                    write::ok(
+                        "Removed",
+                        format!("{} manifest(s) matching `{}`", removed, reference),
+                    )?;
+                }
+                IndexCommand::Validate => {
+                    let violations = oci::validate_layout(&oci_dir)?;
+                    if !violations.is_empty() {
+                        for violation in &violations {
+                            write::error("Invalid", violation)?;
+                        }
+                        bail!("image layout `{}` is invalid", image);
+                    }
+                    write::ok("Validated", format!("image layout `{}`", image))?;
+                }
+                IndexCommand::Attach {
+                    subject,
+                    path,
+                    artifact_type,
+                    media_type,
+                    digest_algorithm,
+                } => {
+                    let artifact = fs::File::open(&path)
+                        .with_context(|| format!("Failed to open `{}`", path.display()))?;
+                    let descriptor = oci::attach_artifact(
+                        &oci_dir,
+                        &subject,
+                        &artifact_type,
+                        artifact,
+                        media_type.as_deref().unwrap_or(&artifact_type),
+                        digest_algorithm,
+                    )?;
+                    write::ok(
+                        "Attached",
+                        format!(
+                            "{} to `{}` as {}",
+                            path.display(),
+                            subject,
+                            descriptor.digest()
+                        ),
+                    )?;
+                }
+                IndexCommand::Unpack { reference, dest } => {
+                    oci::unpack(&oci_dir, &reference, &dest)?;
+                    write::ok(
+                        "Unpacked",
+                        format!("`{}` to `{}`", reference, dest.display()),
+                    )?;
+                }
+                IndexCommand::Recompress {
+                    reference,
+                    compression,
+                } => {
+                    let recompressed = oci::recompress_image(&oci_dir, &reference, compression)?;
+                    write::ok(
+                        "Recompressed",
+                        format!("{} layer(s) of `{}`", recompressed, reference),
+                    )?;
+                }
+                IndexCommand::Config { reference } => {
+                    let config = oci::image_config(&oci_dir, &reference)?;
+                    println!("{}", serde_json::to_string_pretty(&config)?);
+                }
+                IndexCommand::Referrers {
+                    subject,
+                    artifact_type,
+                } => {
+                    let referrers = oci::referrers(&oci_dir, &subject, artifact_type.as_deref())?;
+                    println!("{}", serde_json::to_string_pretty(&referrers)?);
+                }
+            }
+        }
     }
     Ok(())
 }
+
+/// The rpm keyring used to verify packages
+fn keyring(no_gpgkey_import: bool, keyring_dir: Option<&Path>) -> Keyring<'_> {
+    match (no_gpgkey_import, keyring_dir) {
+        (true, _) => Keyring::Existing(Path::new("/")),
+        (false, Some(dir)) => Keyring::Scoped(dir),
+        (false, None) => Keyring::Temporary,
+    }
+}
@@ -0,0 +1,481 @@
+//! Copyright (C) Microsoft Corporation.
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+use pyo3::prelude::*;
+use serde_json::Value;
+
+use super::resolve::check_url_scheme;
+use super::Lockfile;
+
+/// The format of a vulnerability feed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FeedFormat {
+    /// A CSAF 2.0 document, such as a VEX document, identifying RPMs by purl
+    #[default]
+    Csaf,
+}
+
+/// A range of versions of a package affected by a vulnerability
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Advisory {
+    /// The vulnerability's id, e.g a CVE id
+    pub(crate) id: String,
+    /// The name of the affected package
+    pub(crate) package: String,
+    /// The affected architecture. If unset, all architectures are affected
+    pub(crate) arch: Option<String>,
+    /// Which versions of the package are affected
+    pub(crate) affected: Affected,
+}
+
+/// Which versions of a package are affected by a vulnerability
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Affected {
+    /// All versions, as no fix is available
+    All,
+    /// Only the given epoch-version-release
+    Exactly(String),
+    /// Versions earlier than the given epoch-version-release, which fixes the vulnerability
+    Before(String),
+}
+
+/// A locked package affected by a vulnerability
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Finding {
+    /// The package name
+    pub name: String,
+    /// The locked epoch-version-release
+    pub evr: String,
+    /// The locked architecture
+    pub arch: String,
+    /// The vulnerability's id
+    pub id: String,
+    /// The epoch-version-release that fixes the vulnerability, if known
+    pub fixed_in: Option<String>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}.{}: {}", self.name, self.evr, self.arch, self.id)?;
+        match &self.fixed_in {
+            Some(evr) => write!(f, " (fixed in {})", evr),
+            None => write!(f, " (no fix available)"),
+        }
+    }
+}
+
+impl Lockfile {
+    /// Check the locked packages against a vulnerability feed, which is a path or an https URL
+    pub fn audit(&self, feed: &str, format: FeedFormat) -> Result<BTreeSet<Finding>> {
+        let contents = read_feed(feed)?;
+        let advisories = match format {
+            FeedFormat::Csaf => parse_csaf(&contents),
+        }
+        .with_context(|| format!("Failed to parse vulnerability feed `{}`", feed))?;
+        Ok(self.findings(&advisories))
+    }
+
+    /// The locked packages affected by the given advisories.
+    ///
+    /// Feeds may list a package as affected in some products and fixed in others. As the lockfile
+    /// doesn't say which product its packages are from, a package is only considered affected at all
+    /// versions if no version fixes the vulnerability.
+    fn findings(&self, advisories: &[Advisory]) -> BTreeSet<Finding> {
+        let fixed = advisories
+            .iter()
+            .filter(|advisory| matches!(advisory.affected, Affected::Before(_)))
+            .map(|advisory| (advisory.package.as_str(), advisory.id.as_str()))
+            .collect::<BTreeSet<_>>();
+        let mut findings = BTreeSet::new();
+        for pkg in &self.packages {
+            let evr = rpm::Evr::parse(&pkg.evr);
+            for advisory in advisories {
+                if advisory.package != pkg.name
+                    || advisory.arch.as_ref().is_some_and(|arch| *arch != pkg.arch)
+                {
+                    continue;
+                }
+                let (affected, fixed_in) = match &advisory.affected {
+                    Affected::All => (
+                        !fixed.contains(&(advisory.package.as_str(), advisory.id.as_str())),
+                        None,
+                    ),
+                    Affected::Exactly(affected) => (evr == rpm::Evr::parse(affected), None),
+                    Affected::Before(fixed) => (evr < rpm::Evr::parse(fixed), Some(fixed.clone())),
+                };
+                if affected {
+                    findings.insert(Finding {
+                        name: pkg.name.clone(),
+                        evr: pkg.evr.clone(),
+                        arch: pkg.arch.clone(),
+                        id: advisory.id.clone(),
+                        fixed_in,
+                    });
+                }
+            }
+        }
+        findings
+    }
+}
+
+/// Read a vulnerability feed from a path, or download it from an https URL
+fn read_feed(feed: &str) -> Result<String> {
+    match url::Url::parse(feed) {
+        Ok(url) if url.scheme() != "file" => {
+            check_url_scheme(&url, false).context("Invalid vulnerability feed URL")?;
+            Python::with_gil(|py| {
+                let request = PyModule::import_bound(py, "urllib.request")?;
+                let response = request.call_method1("urlopen", (url.as_str(),))?;
+                let body: Vec<u8> = response.call_method0("read")?.extract()?;
+                Ok::<_, anyhow::Error>(String::from_utf8(body)?)
+            })
+            .with_context(|| format!("Failed to download vulnerability feed `{}`", feed))
+        }
+        Ok(url) => {
+            let path = url
+                .to_file_path()
+                .map_err(|_| anyhow::anyhow!("Invalid vulnerability feed URL `{}`", feed))?;
+            std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read vulnerability feed `{}`", path.display()))
+        }
+        Err(_) => std::fs::read_to_string(feed)
+            .with_context(|| format!("Failed to read vulnerability feed `{}`", feed)),
+    }
+}
+
+/// Parse the advisories for RPMs in a CSAF document.
+///
+/// Products are identified by the `pkg:rpm` purls of their product identification helpers.
+/// Products with a version in `fixed` fix the vulnerability for earlier versions, whereas products in
+/// `known_affected` are affected at their version, or at all versions if they have none.
+pub(crate) fn parse_csaf(contents: &str) -> Result<Vec<Advisory>> {
+    let document: Value = serde_json::from_str(contents)?;
+    if document["document"]["csaf_version"].as_str().is_none() {
+        bail!("Not a CSAF document: `document.csaf_version` is missing");
+    }
+
+    // Map product ids to RPMs. Relationships refer to the products they combine
+    let mut products = HashMap::new();
+    collect_products(&document["product_tree"], &mut products)?;
+    for relationship in document["product_tree"]["relationships"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        let (Some(id), Some(reference)) = (
+            relationship["full_product_name"]["product_id"].as_str(),
+            relationship["product_reference"].as_str(),
+        ) else {
+            continue;
+        };
+        if let Some(rpm) = products.get(reference).cloned() {
+            products.insert(id.to_string(), rpm);
+        }
+    }
+
+    let mut advisories = Vec::new();
+    for vulnerability in document["vulnerabilities"].as_array().into_iter().flatten() {
+        let Some(id) = vulnerability["cve"]
+            .as_str()
+            .or_else(|| vulnerability["ids"][0]["text"].as_str())
+        else {
+            continue;
+        };
+        for (status, fixed) in [("fixed", true), ("known_affected", false)] {
+            for product_id in vulnerability["product_status"][status]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+            {
+                let Some(rpm) = products.get(product_id) else {
+                    continue;
+                };
+                let affected = match (&rpm.evr, fixed) {
+                    (Some(evr), true) => Affected::Before(evr.clone()),
+                    // A fix without a version doesn't say which versions are affected
+                    (None, true) => continue,
+                    (Some(evr), false) => Affected::Exactly(evr.clone()),
+                    (None, false) => Affected::All,
+                };
+                advisories.push(Advisory {
+                    id: id.to_string(),
+                    package: rpm.name.clone(),
+                    arch: rpm.arch.clone(),
+                    affected,
+                });
+            }
+        }
+    }
+    Ok(advisories)
+}
+
+/// An RPM identified by a purl
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PurlRpm {
+    name: String,
+    evr: Option<String>,
+    arch: Option<String>,
+}
+
+/// Collect the RPM products in a CSAF product tree's branches and full product names
+fn collect_products(node: &Value, products: &mut HashMap<String, PurlRpm>) -> Result<()> {
+    for product in node["full_product_names"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .chain(node.get("product"))
+    {
+        let (Some(id), Some(purl)) = (
+            product["product_id"].as_str(),
+            product["product_identification_helper"]["purl"].as_str(),
+        ) else {
+            continue;
+        };
+        if let Some(rpm) = parse_rpm_purl(purl)? {
+            products.insert(id.to_string(), rpm);
+        }
+    }
+    for branch in node["branches"].as_array().into_iter().flatten() {
+        collect_products(branch, products)?;
+    }
+    Ok(())
+}
+
+/// Parse a `pkg:rpm` purl, e.g `pkg:rpm/redhat/openssl@3.0.7-16.el9?arch=x86_64&epoch=1`.
+/// Returns `None` for purls of other package types.
+fn parse_rpm_purl(purl: &str) -> Result<Option<PurlRpm>> {
+    let Some(rest) = purl.strip_prefix("pkg:rpm/") else {
+        return Ok(None);
+    };
+    let rest = rest.split_once('#').map_or(rest, |(rest, _)| rest);
+    let (rest, qualifiers) = rest.split_once('?').unwrap_or((rest, ""));
+    let (path, version) = match rest.split_once('@') {
+        Some((path, version)) => (path, Some(percent_decode(version)?)),
+        None => (rest, None),
+    };
+    let Some(name) = path.rsplit('/').next().filter(|name| !name.is_empty()) else {
+        bail!("Invalid purl `{}`: missing package name", purl);
+    };
+    let qualifiers = qualifiers
+        .split('&')
+        .filter_map(|qualifier| qualifier.split_once('='))
+        .map(|(key, value)| Ok((key, percent_decode(value)?)))
+        .collect::<Result<HashMap<_, _>>>()?;
+    let evr = version.map(|version| match qualifiers.get("epoch") {
+        Some(epoch) if !version.contains(':') => format!("{}:{}", epoch, version),
+        _ => version,
+    });
+    Ok(Some(PurlRpm {
+        name: percent_decode(name)?,
+        evr,
+        arch: qualifiers.get("arch").cloned(),
+    }))
+}
+
+/// Decode the percent-encoded characters of a purl component
+fn percent_decode(s: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [
+                iter.next().unwrap_or_default(),
+                iter.next().unwrap_or_default(),
+            ];
+            let hex = std::str::from_utf8(&hex).unwrap_or_default();
+            bytes.push(
+                u8::from_str_radix(hex, 16)
+                    .with_context(|| format!("Invalid percent-encoding in `{}`", s))?,
+            );
+        } else {
+            bytes.push(b);
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_csaf, parse_rpm_purl, Affected, FeedFormat, Finding, PurlRpm};
+    use crate::lockfile::Lockfile;
+
+    const CSAF: &str = r#"{
+  "document": { "csaf_version": "2.0", "category": "csaf_vex", "title": "test" },
+  "product_tree": {
+    "branches": [{
+      "category": "vendor",
+      "name": "Example",
+      "branches": [
+        {
+          "category": "product_version",
+          "name": "openssl-1:3.0.7-17.el9.x86_64",
+          "product": {
+            "name": "openssl-1:3.0.7-17.el9.x86_64",
+            "product_id": "openssl-1:3.0.7-17.el9.x86_64",
+            "product_identification_helper": { "purl": "pkg:rpm/example/openssl@3.0.7-17.el9?arch=x86_64&epoch=1" }
+          }
+        },
+        {
+          "category": "product_version",
+          "name": "zlib",
+          "product": {
+            "name": "zlib",
+            "product_id": "zlib",
+            "product_identification_helper": { "purl": "pkg:rpm/example/zlib" }
+          }
+        },
+        {
+          "category": "product_name",
+          "name": "Example Linux 9",
+          "product": { "name": "Example Linux 9", "product_id": "el9" }
+        }
+      ]
+    }],
+    "relationships": [{
+      "category": "default_component_of",
+      "full_product_name": { "name": "openssl on Example Linux 9", "product_id": "el9:openssl-1:3.0.7-17.el9.x86_64" },
+      "product_reference": "openssl-1:3.0.7-17.el9.x86_64",
+      "relates_to_product_reference": "el9"
+    }]
+  },
+  "vulnerabilities": [
+    { "cve": "CVE-2023-0001", "product_status": { "fixed": ["el9:openssl-1:3.0.7-17.el9.x86_64"] } },
+    { "cve": "CVE-2023-0002", "product_status": { "known_affected": ["zlib"] } },
+    { "cve": "CVE-2023-0003", "product_status": { "known_affected": ["el9"] } }
+  ]
+}"#;
+
+    fn lockfile(packages: &[(&str, &str)]) -> Lockfile {
+        let mut toml = "pkg_specs = []\n".to_string();
+        for (name, evr) in packages {
+            toml.push_str(&format!(
+                "[[packages]]\nname = \"{}\"\nevr = \"{}\"\narch = \"x86_64\"\nrepoid = \"repo\"\n[packages.checksum]\nalgorithm = \"sha256\"\nchecksum = \"0\"\n",
+                name, evr
+            ));
+        }
+        toml::from_str(&toml).unwrap()
+    }
+
+    #[test]
+    fn rpm_purls_are_parsed() {
+        assert_eq!(
+            parse_rpm_purl("pkg:rpm/fedora/curl@7.50.3-1.fc25?arch=i386&distro=fedora-25").unwrap(),
+            Some(PurlRpm {
+                name: "curl".to_string(),
+                evr: Some("7.50.3-1.fc25".to_string()),
+                arch: Some("i386".to_string())
+            })
+        );
+        assert_eq!(
+            parse_rpm_purl("pkg:rpm/redhat/libstdc%2B%2B@1.0-1?epoch=2")
+                .unwrap()
+                .unwrap()
+                .evr,
+            Some("2:1.0-1".to_string())
+        );
+        assert_eq!(
+            parse_rpm_purl("pkg:rpm/redhat/libstdc%2B%2B@1.0-1?epoch=2")
+                .unwrap()
+                .unwrap()
+                .name,
+            "libstdc++"
+        );
+        assert_eq!(parse_rpm_purl("pkg:deb/debian/curl@7.50.3").unwrap(), None);
+        assert!(parse_rpm_purl("pkg:rpm/redhat/bad%zz@1.0").is_err());
+    }
+
+    #[test]
+    fn csaf_feeds_are_matched_against_the_lockfile() {
+        let advisories = parse_csaf(CSAF).unwrap();
+        assert_eq!(advisories.len(), 2);
+        assert_eq!(
+            advisories[0].affected,
+            Affected::Before("1:3.0.7-17.el9".to_string())
+        );
+        assert_eq!(advisories[1].affected, Affected::All);
+
+        let findings = lockfile(&[("openssl", "1:3.0.7-16.el9"), ("zlib", "1.2.13-1.el9")])
+            .findings(&advisories);
+        assert_eq!(
+            findings.into_iter().collect::<Vec<_>>(),
+            vec![
+                Finding {
+                    name: "openssl".to_string(),
+                    evr: "1:3.0.7-16.el9".to_string(),
+                    arch: "x86_64".to_string(),
+                    id: "CVE-2023-0001".to_string(),
+                    fixed_in: Some("1:3.0.7-17.el9".to_string()),
+                },
+                Finding {
+                    name: "zlib".to_string(),
+                    evr: "1.2.13-1.el9".to_string(),
+                    arch: "x86_64".to_string(),
+                    id: "CVE-2023-0002".to_string(),
+                    fixed_in: None,
+                },
+            ]
+        );
+
+        // The fixed version, and later ones, aren't affected
+        for evr in ["1:3.0.7-17.el9", "1:3.0.8-1.el9"] {
+            assert!(lockfile(&[("openssl", evr)])
+                .findings(&advisories)
+                .is_empty());
+        }
+        // Packages listed as affected without a version aren't affected at versions with a fix
+        let mut advisories = advisories;
+        advisories.push(super::Advisory {
+            id: "CVE-2023-0001".to_string(),
+            package: "openssl".to_string(),
+            arch: None,
+            affected: Affected::All,
+        });
+        assert!(lockfile(&[("openssl", "1:3.0.7-17.el9")])
+            .findings(&advisories)
+            .is_empty());
+        // Nor is an earlier version on another architecture
+        advisories[0].arch = Some("aarch64".to_string());
+        assert!(lockfile(&[("openssl", "1:3.0.7-16.el9")])
+            .findings(&advisories)
+            .is_empty());
+    }
+
+    #[test]
+    fn feeds_are_read_from_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let feed = dir.path().join("feed.json");
+        std::fs::write(&feed, CSAF).unwrap();
+        let findings = lockfile(&[("zlib", "1.2.13-1.el9")])
+            .audit(feed.to_str().unwrap(), FeedFormat::Csaf)
+            .unwrap();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(
+            findings.first().unwrap().to_string(),
+            "zlib-1.2.13-1.el9.x86_64: CVE-2023-0002 (no fix available)"
+        );
+
+        std::fs::write(&feed, "{}").unwrap();
+        assert!(lockfile(&[("zlib", "1.2.13-1.el9")])
+            .audit(feed.to_str().unwrap(), FeedFormat::Csaf)
+            .is_err());
+        assert!(lockfile(&[("zlib", "1.2.13-1.el9")])
+            .audit("http://example.com/feed.json", FeedFormat::Csaf)
+            .is_err());
+    }
+}
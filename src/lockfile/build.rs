@@ -12,27 +12,71 @@
 //!
 //! You should have received a copy of the GNU General Public License
 //! along with this program.  If not, see <https://www.gnu.org/licenses/>.
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi::OsStr;
-use std::path::Path;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::{fs, process::Command};
 
 use anyhow::{bail, Context, Result};
 use chrono::DateTime;
-use flate2::Compression;
-use glob::glob;
+use glob::{glob, Pattern};
+use log::debug;
 use ocidir::oci_spec::image::MediaType;
 use ocidir::{new_empty_manifest, OciDir};
 use rusqlite::Connection;
 use tempfile::TempDir;
 
-use super::Lockfile;
-use crate::archive::append_dir_all_with_xattrs;
-use crate::config::Config;
+use super::transform::apply_transformations;
+use super::{Keyring, Lockfile};
+use crate::archive::{LayerPart, LayerSplit, MtimePolicy, Mtimes};
+use crate::config::{
+    ConffilePolicy, Config, FileConflictPolicy, InjectedFile, InstallPolicy, SymlinkEscapePolicy,
+    Transformations,
+};
+use crate::oci::{
+    check_layer_size, composefs_digest, create_image_layer, insert_manifest_and_extended_config,
+    layer_annotations, push_layer, remove_stale_temp_files, set_index_annotations, DigestAlgorithm,
+    ExistingLayout, FAILED_PACKAGES_ANNOTATION, POTENTIALLY_INCOMPLETE_ANNOTATION,
+};
+use crate::rootfs::resolve_in_root;
 use crate::write;
 use ocidir::cap_std::fs::Dir;
 
 const CREATED_BY: &str = "Created by rpmoci";
+/// Locations of the rpm database, which is put in the top layer when the root filesystem is split,
+/// as it changes whenever any package does
+const RPMDB_PATHS: [&str; 2] = ["/var/lib/rpm", "/usr/lib/sysimage/rpm"];
+/// How many compressed bytes of a layer are written between progress messages
+const LAYER_PROGRESS_INTERVAL: u64 = 64 << 20;
+
+/// Where the RPMs installed in an image are obtained from
+#[derive(Debug, Clone, Copy)]
+pub enum RpmSource<'a> {
+    /// Download RPMs from their repositories, using repository metadata to find them
+    Download,
+    /// Download RPMs from the locations recorded in the lockfile, without loading repository metadata
+    Frozen,
+    /// Use RPMs from a vendor directory
+    Vendor(&'a Path),
+}
+
+/// Options controlling how an image is built
+#[derive(Debug, Clone, Copy)]
+pub struct BuildOptions<'a> {
+    /// Where to obtain RPMs from
+    pub source: RpmSource<'a>,
+    /// The rpm keyring to verify package signatures against
+    pub keyring: Keyring<'a>,
+    /// Directory in which to create temporary files, instead of the system temporary directory
+    pub tmp_dir: Option<&'a Path>,
+    /// The algorithm used to compute the digests of the image's blobs
+    pub digest_algorithm: DigestAlgorithm,
+    /// Stop after creating the root filesystem, reporting its size instead of writing an image
+    pub dry_run: bool,
+    /// What to do if the image directory already contains files
+    pub existing_layout: ExistingLayout,
+}
 
 impl Lockfile {
     /// Build a container image from a lockfile
@@ -41,41 +85,104 @@ impl Lockfile {
         cfg: &Config,
         image: &str,
         tag: &str,
-        vendor_dir: Option<&Path>,
         labels: HashMap<String, String>,
+        options: BuildOptions,
     ) -> Result<()> {
-        // Ensure OCI directory exists
-        fs::create_dir_all(image)
-            .context(format!("Failed to create OCI image directory `{}`", &image))?;
-        let dir = Dir::open_ambient_dir(image, ocidir::cap_std::ambient_authority())
-            .context("Failed to open image directory")?;
-        let oci_dir = OciDir::ensure(&dir)?;
+        // A dry run leaves the OCI directory untouched
+        let oci_dir = if options.dry_run {
+            None
+        } else {
+            Some(open_image_layout(image, options.existing_layout)?)
+        };
 
+        let split_layers = !cfg.layer.top_packages.is_empty() || !cfg.layer.top_paths.is_empty();
+        if split_layers && cfg.layer.composefs_digest {
+            bail!("layer.composefs_digest can't be used with layer.top_packages or layer.top_paths, as the root filesystem is split across layers");
+        }
+
+        let config_extensions = cfg.image.docker_config_extensions()?;
         let creation_time = creation_time()?;
-        let installroot = TempDir::new()?; // This needs to outlive the layer builder below.
-        if let Some(vendor_dir) = vendor_dir {
-            // Use vendored RPMs rather than downloading
-            self.create_installroot(installroot.path(), vendor_dir, false, cfg, &creation_time)
+        // This needs to outlive the layer builder below.
+        let installroot = temp_dir(options.tmp_dir)?;
+        // RPMs are downloaded to a temporary directory unless they are vendored
+        let tmp_rpm_dir = temp_dir(options.tmp_dir)?;
+        let rpm_dir = match options.source {
+            RpmSource::Vendor(vendor_dir) => vendor_dir,
+            RpmSource::Download | RpmSource::Frozen => tmp_rpm_dir.path(),
+        };
+        let (failed_packages, top_files) = self
+            .create_installroot(installroot.path(), rpm_dir, options, cfg, &creation_time)
+            .context("Failed to create installroot")?;
+
+        // Create the root filesystem layer, or the lower and top layers if it's split
+        let split = LayerSplit::new(
+            top_files,
+            cfg.layer
+                .top_paths
+                .iter()
+                .cloned()
+                .chain(RPMDB_PATHS.iter().map(PathBuf::from)),
+        );
+        let mtimes = match cfg.layer.mtime {
+            MtimePolicy::Clamp => Mtimes::clamp(creation_time.timestamp()),
+            MtimePolicy::BuildTime => Mtimes::build_times(
+                creation_time.timestamp(),
+                package_build_times(installroot.path())?,
+            ),
+        };
+        let parts = if split_layers {
+            vec![LayerPart::Lower(&split), LayerPart::Top(&split)]
         } else {
-            // No vendoring - download RPMs
-            let tmp_rpm_dir = TempDir::new()?;
-            self.create_installroot(
-                installroot.path(),
-                tmp_rpm_dir.path(),
-                true,
-                cfg,
-                &creation_time,
-            )
-        }
-        .context("Failed to create installroot")?;
+            vec![LayerPart::All]
+        };
 
-        // Create the root filesystem layer
-        write::ok("Creating", "root filesystem layer")?;
-        let mut builder = oci_dir.create_layer(Compression::fast().into())?;
-        builder.follow_symlinks(false);
-        append_dir_all_with_xattrs(&mut builder, installroot.path(), creation_time.timestamp())
-            .context("failed to archive root filesystem")?;
-        let layer = builder.into_inner()?.complete()?;
+        let Some(oci_dir) = oci_dir else {
+            // The layers aren't written, but their sizes are still checked against the budget
+            if cfg.layer.max_size.is_some() {
+                for part in parts {
+                    check_layer_size(installroot.path(), &mtimes, &cfg.layer, part)?;
+                }
+            }
+            let (files, bytes) = rootfs_summary(installroot.path())?;
+            write::ok(
+                "Checked",
+                format!(
+                    "root filesystem of {} files and {} bytes uncompressed, without writing an image (dry run)",
+                    files, bytes
+                ),
+            )?;
+            return Ok(());
+        };
+        if split_layers {
+            write::ok("Creating", "lower and top root filesystem layers")?;
+        } else {
+            write::ok("Creating", "root filesystem layer")?;
+        }
+        let layers = parts
+            .into_iter()
+            .enumerate()
+            .map(|(i, part)| {
+                let mut reported = 0;
+                let mut progress = |written: u64| {
+                    if written >= reported + LAYER_PROGRESS_INTERVAL {
+                        reported = written;
+                        let _ = write::ok(
+                            "Writing",
+                            format!("layer {}: {} MiB compressed", i + 1, written >> 20),
+                        );
+                    }
+                };
+                create_image_layer(
+                    &oci_dir,
+                    options.digest_algorithm,
+                    installroot.path(),
+                    &mtimes,
+                    &cfg.layer,
+                    part,
+                    Some(&mut progress),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
 
         // Create the image configuration blob
         write::ok("Writing", "image configuration blob")?;
@@ -86,22 +193,43 @@ impl Lockfile {
         let mut manifest = new_empty_manifest()
             .media_type(MediaType::ImageManifest)
             .build()?;
-        oci_dir.push_layer_full(
-            &mut manifest,
-            &mut image_config,
-            layer,
-            Option::<HashMap<String, String>>::None,
-            CREATED_BY,
-            creation_time,
-        );
+        let mut annotations = cfg.image.annotations.clone();
+        if cfg.contents.install_policy == InstallPolicy::BestEffort {
+            annotations.extend(best_effort_annotations(&failed_packages));
+        }
+        if !annotations.is_empty() {
+            manifest.set_annotations(Some(annotations));
+        }
+        for layer in layers {
+            let composefs_digest = if cfg.layer.composefs_digest {
+                write::ok("Computing", "composefs digest")?;
+                Some(composefs_digest(&oci_dir, &layer)?)
+            } else {
+                None
+            };
+            push_layer(
+                &mut manifest,
+                &mut image_config,
+                layer,
+                layer_annotations(composefs_digest),
+                CREATED_BY,
+                creation_time,
+            )?;
+        }
 
         write::ok("Writing", "image manifest and config")?;
-        oci_dir.insert_manifest_and_config(
+        insert_manifest_and_extended_config(
+            &oci_dir,
             manifest,
             image_config,
-            Some(tag),
-            ocidir::oci_spec::image::Platform::default(),
+            config_extensions,
+            tag,
+            options.digest_algorithm,
+            cfg.image.media_types,
         )?;
+        if !cfg.image.index_annotations.is_empty() {
+            set_index_annotations(&oci_dir, &cfg.image.index_annotations)?;
+        }
         Ok(())
     }
 
@@ -109,37 +237,26 @@ impl Lockfile {
         &self,
         installroot: &Path,
         rpm_dir: &Path,
-        download_rpms: bool,
+        options: BuildOptions,
         cfg: &Config,
         creation_time: &DateTime<chrono::Utc>,
-    ) -> Result<(), anyhow::Error> {
-        if download_rpms {
-            self.download_rpms(cfg, rpm_dir)?;
+    ) -> Result<(Vec<String>, BTreeSet<PathBuf>), anyhow::Error> {
+        match options.source {
+            RpmSource::Download => self.download_rpms(cfg, rpm_dir)?,
+            RpmSource::Frozen => self.download_locked_rpms(cfg, rpm_dir)?,
+            RpmSource::Vendor(_) => {}
+        }
+        self.verify_checksums(rpm_dir)?;
+        self.check_gpg_keys(rpm_dir, options.keyring)?;
+        // Remote RPMs from the download or vendor dir
+        let mut rpm_paths = Vec::new();
+        let mut local_rpm_paths = Vec::new();
+        for file in fs::read_dir(rpm_dir)? {
+            let path = file?.path();
+            if path.extension() == Some(OsStr::new("rpm")) {
+                rpm_paths.push(path);
+            }
         }
-        self.check_gpg_keys(rpm_dir)?;
-        let mut dnf_install = Command::new("dnf");
-        dnf_install
-            .env("SOURCE_DATE_EPOCH", creation_time.timestamp().to_string())
-            .arg("--disablerepo=*")
-            .arg("--installroot")
-            .arg(installroot)
-            .arg("install")
-            .arg("--assumeyes")
-            .arg(format!(
-                "--setopt=tsflags={}",
-                if cfg.contents.docs { "" } else { "nodocs" }
-            ))
-            // Add remote RPMs from the download or vendor dir
-            .args({
-                let mut rpm_paths = Vec::new();
-                for file in fs::read_dir(rpm_dir)? {
-                    let path = file?.path();
-                    if path.extension() == Some(OsStr::new("rpm")) {
-                        rpm_paths.push(path);
-                    }
-                }
-                rpm_paths
-            });
         for glob_spec in cfg
             .contents
             .packages
@@ -148,20 +265,124 @@ impl Lockfile {
         {
             let mut found = false;
             for entry in glob(glob_spec)? {
-                dnf_install.arg(entry?);
+                local_rpm_paths.push(entry?);
                 found = true;
             }
             if !found {
                 bail!("No package found for spec '{}'", glob_spec);
             }
         }
+        if cfg.contents.localpkg_gpgcheck {
+            self.check_local_gpg_keys(&local_rpm_paths, options.keyring)?;
+        }
+        rpm_paths.append(&mut local_rpm_paths);
+
+        // Paths claimed by several packages are skipped by rpm during the install,
+        // and restored from the package that wins them afterwards
+        let conflicts = if cfg.contents.file_conflicts == FileConflictPolicy::Fail {
+            Vec::new()
+        } else {
+            file_conflicts(&rpm_paths)?
+        };
+        let rpm_macros = rpm_macro_definitions(&cfg.contents.rpm_macros)?;
+        let macros_home = TempDir::new()?;
+        let mut rpmmacros = rpm_macros
+            .iter()
+            .map(|(name, value)| format!("%{} {}\n", name, value))
+            .collect::<String>();
+
+        let mut dnf_install = Command::new("dnf");
+        dnf_install
+            .env("SOURCE_DATE_EPOCH", creation_time.timestamp().to_string())
+            .arg("--disablerepo=*")
+            .arg("--installroot")
+            .arg(installroot)
+            .arg("install")
+            .arg("--assumeyes")
+            // Only the locked packages are available, but be explicit that nothing else should be installed
+            .arg("--setopt=install_weak_deps=False")
+            .arg(format!(
+                "--setopt=tsflags={}",
+                if cfg.contents.docs { "" } else { "nodocs" }
+            ));
+        if !write::show_subprocess_output() {
+            dnf_install.arg("--quiet");
+        }
+        if let Some(arch) = &cfg.contents.arch {
+            dnf_install.arg(format!("--forcearch={}", arch));
+        }
+        if cfg.contents.install_policy == InstallPolicy::BestEffort {
+            // Install packages even if others they depend on can't be installed
+            dnf_install.arg("--setopt=strict=False");
+        }
+        if !conflicts.is_empty() {
+            for conflict in &conflicts {
+                write::warning(
+                    "Warning",
+                    format!(
+                        "`{}` is claimed by packages with different contents ({}), keeping the file from {}",
+                        conflict.path.display(),
+                        conflict.package_names().join(", "),
+                        conflict.winner(cfg.contents.file_conflicts).name
+                    ),
+                )?;
+            }
+            rpmmacros.push_str(&format!(
+                "%_netsharedpath {}\n",
+                netshared_path(conflicts.iter().map(|conflict| conflict.path.as_path()))
+            ));
+        }
+        if !rpmmacros.is_empty() {
+            // rpm reads macros from ~/.rpmmacros
+            fs::write(macros_home.path().join(".rpmmacros"), rpmmacros)?;
+            dnf_install.env("HOME", macros_home.path());
+        }
+        dnf_install.args(&rpm_paths);
         write::ok("Installing", "packages")?;
         log::debug!("Running `{:?}`", dnf_install);
         let status = dnf_install.status().context("Failed to run dnf")?;
-        if !status.success() {
-            bail!("failed to dnf install");
-        }
+        let failed_packages = match cfg.contents.install_policy {
+            InstallPolicy::StopOnError => {
+                if !status.success() {
+                    bail!("failed to dnf install");
+                }
+                Vec::new()
+            }
+            InstallPolicy::BestEffort => {
+                let failed_packages = uninstalled_packages(installroot, &rpm_paths)?;
+                if !status.success() || !failed_packages.is_empty() {
+                    write::warning(
+                        "Warning",
+                        format!(
+                            "some packages failed to install, so the image may be incomplete: {}",
+                            failed_packages.join(", ")
+                        ),
+                    )?;
+                }
+                failed_packages
+            }
+        };
+        restore_conflicting_files(
+            installroot,
+            &conflicts,
+            cfg.contents.file_conflicts,
+            cfg.contents.docs,
+            &rpm_macros,
+            creation_time,
+        )?;
         write::ok("Installed", "packages successfully")?;
+        apply_conffile_policy(installroot, cfg.contents.conffiles)
+            .context("Failed to resolve configuration file conflicts")?;
+
+        apply_keep_paths(installroot, &cfg.contents.keep_paths)
+            .context("Failed to remove files excluded by keep_paths")?;
+        apply_package_transformations(installroot, &cfg.contents.transformations, options.tmp_dir)
+            .context("Failed to transform files")?;
+
+        let mut top_files = BTreeSet::new();
+        for package in &cfg.layer.top_packages {
+            top_files.extend(package_files(installroot, package)?);
+        }
 
         // Remove unnecessary installation artifacts from the rootfs if present
         let _ = fs::remove_dir_all(installroot.join("var/log"));
@@ -169,6 +390,26 @@ impl Lockfile {
         let _ = fs::remove_dir_all(installroot.join("var/tmp"));
         let _ = fs::remove_dir_all(installroot.join("var/lib/dnf/"));
 
+        inject_files(
+            installroot,
+            &cfg.contents.files,
+            cfg.contents.default_file_mode,
+            cfg.contents.default_dir_mode,
+        )
+        .context("Failed to add files to the root filesystem")?;
+
+        run_hooks(installroot, &cfg.contents.hooks)?;
+
+        let assumed_installed = self
+            .iter_packages()
+            .filter(|pkg| pkg.assumed_installed)
+            .map(|pkg| pkg.name.as_str())
+            .collect::<BTreeSet<_>>();
+        remove_assumed_installed_files(installroot, &assumed_installed)
+            .context("Failed to remove the files of packages assumed to be installed")?;
+
+        check_symlink_escapes(installroot, cfg.contents.symlink_escapes)?;
+
         // rpm configures sqlite to persist the WAL and SHM files: https://github.com/rpm-software-management/rpm/blob/1cd9f9077a2829c363a198e5af56c8a56c6bc346/lib/backend/sqlite.c#L174C35-L174C59
         // this is a source of non-determinism, so we disable it here (should rpm need to be run against this db, it will re-create the journaling files)
         let legacy_rpmdb = installroot.join("var/lib/rpm/rpmdb.sqlite");
@@ -181,8 +422,242 @@ impl Lockfile {
             Ok(())
         }
         .context("Failed to disable sqlite journaling of RPM db")?;
-        Ok(())
+        Ok((failed_packages, top_files))
+    }
+}
+
+/// A path claimed by several packages with different contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileConflict {
+    path: PathBuf,
+    /// The packages claiming the path, ordered by name
+    packages: Vec<ConflictingPackage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ConflictingPackage {
+    name: String,
+    rpm_path: PathBuf,
+}
+
+impl FileConflict {
+    fn package_names(&self) -> Vec<&str> {
+        self.packages.iter().map(|pkg| pkg.name.as_str()).collect()
+    }
+
+    /// The package whose file is kept under the given policy
+    fn winner(&self, policy: FileConflictPolicy) -> &ConflictingPackage {
+        match policy {
+            FileConflictPolicy::LastWins => &self.packages[self.packages.len() - 1],
+            FileConflictPolicy::Fail | FileConflictPolicy::FirstWins => &self.packages[0],
+        }
+    }
+}
+
+/// The contents of a packaged file, for detecting conflicts
+#[derive(Debug, PartialEq, Eq)]
+enum PackagedFile {
+    Regular(Option<rpm::FileDigest>),
+    Symlink(String),
+}
+
+/// Find the regular files and symlinks that are claimed by several of the given RPMs with different contents,
+/// as rpm would refuse to install them together
+fn file_conflicts(rpm_paths: &[PathBuf]) -> Result<Vec<FileConflict>> {
+    let mut packages = Vec::new();
+    for path in rpm_paths {
+        let metadata = rpm::PackageMetadata::open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open RPM package {}: {}", path.display(), e))?;
+        let name = metadata
+            .get_name()
+            .map_err(|e| anyhow::anyhow!("Failed to get RPM name {}: {}", path.display(), e))?;
+        let entries = metadata.get_file_entries().map_err(|e| {
+            anyhow::anyhow!("Failed to get RPM file entries {}: {}", path.display(), e)
+        })?;
+        packages.push((
+            ConflictingPackage {
+                name: name.to_string(),
+                rpm_path: path.clone(),
+            },
+            entries,
+        ));
+    }
+    packages.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut claims: BTreeMap<PathBuf, Vec<(usize, PackagedFile)>> = BTreeMap::new();
+    for (i, (_, entries)) in packages.iter().enumerate() {
+        for entry in entries {
+            if entry.flags.contains(rpm::FileFlags::GHOST) {
+                continue;
+            }
+            let file = match entry.mode {
+                rpm::FileMode::Regular { .. } => PackagedFile::Regular(entry.digest.clone()),
+                rpm::FileMode::SymbolicLink { .. } => PackagedFile::Symlink(entry.linkto.clone()),
+                _ => continue,
+            };
+            claims
+                .entry(entry.path.clone())
+                .or_default()
+                .push((i, file));
+        }
     }
+    Ok(claims
+        .into_iter()
+        .filter(|(_, files)| files.iter().any(|(_, file)| *file != files[0].1))
+        .map(|(path, files)| FileConflict {
+            path,
+            packages: files
+                .into_iter()
+                .map(|(i, _)| packages[i].0.clone())
+                .collect(),
+        })
+        .collect())
+}
+
+/// The configured rpm macros as `(name, value)` pairs sorted by name, with any leading `%` removed from names
+fn rpm_macro_definitions(macros: &HashMap<String, String>) -> Result<Vec<(&str, &str)>> {
+    let mut definitions = Vec::new();
+    for (name, value) in macros {
+        let name = name.strip_prefix('%').unwrap_or(name);
+        let valid_name = name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            bail!("Invalid rpm macro name `{}`", name);
+        }
+        if value.contains('\n') {
+            bail!("The value of rpm macro `{}` can't contain newlines", name);
+        }
+        definitions.push((name, value.as_str()));
+    }
+    definitions.sort();
+    if let Some(duplicate) = definitions.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        bail!("rpm macro `{}` is defined more than once", duplicate[0].0);
+    }
+    Ok(definitions)
+}
+
+/// The value of rpm's `%_netsharedpath` macro for the given paths, whose files rpm won't install
+fn netshared_path<'a>(paths: impl IntoIterator<Item = &'a Path>) -> String {
+    let paths = paths
+        .into_iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        "%{nil}".to_string()
+    } else {
+        paths.join(":")
+    }
+}
+
+/// Reinstall the packages that win file conflicts, so that the conflicting paths skipped
+/// during the install are written with the winners' contents
+fn restore_conflicting_files(
+    installroot: &Path,
+    conflicts: &[FileConflict],
+    policy: FileConflictPolicy,
+    docs: bool,
+    rpm_macros: &[(&str, &str)],
+    creation_time: &DateTime<chrono::Utc>,
+) -> Result<()> {
+    let winners = conflicts
+        .iter()
+        .map(|conflict| conflict.winner(policy))
+        .collect::<BTreeSet<_>>();
+    for winner in winners {
+        // Paths the winner claims but doesn't win are still skipped
+        let skipped = conflicts.iter().filter(|conflict| {
+            conflict.winner(policy) != winner && conflict.packages.contains(winner)
+        });
+        let mut rpm = Command::new("rpm");
+        rpm.env("SOURCE_DATE_EPOCH", creation_time.timestamp().to_string())
+            .arg("--root")
+            .arg(installroot)
+            .arg("--reinstall")
+            .arg("--replacefiles")
+            .arg("--nodeps")
+            .arg("--noscripts")
+            .arg("--notriggers")
+            // The packages may be for another architecture, installed with dnf's --forcearch
+            .arg("--ignorearch")
+            // Signatures were verified before installing
+            .arg("--nosignature")
+            .arg("--define")
+            .arg(format!(
+                "_netsharedpath {}",
+                netshared_path(skipped.map(|conflict| conflict.path.as_path()))
+            ));
+        if !docs {
+            rpm.arg("--excludedocs");
+        }
+        for (name, value) in rpm_macros {
+            rpm.arg("--define").arg(format!("{} {}", name, value));
+        }
+        rpm.arg(&winner.rpm_path);
+        log::debug!("Running `{:?}`", rpm);
+        let output = rpm.output().context("Failed to run `rpm`")?;
+        if !output.status.success() {
+            bail!(
+                "Failed to restore conflicting files from `{}`: {}{}",
+                winner.name,
+                String::from_utf8_lossy(&output.stderr),
+                String::from_utf8_lossy(&output.stdout)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The manifest annotations of an image built with the best effort install policy
+fn best_effort_annotations(failed_packages: &[String]) -> HashMap<String, String> {
+    let mut annotations = HashMap::from([(
+        POTENTIALLY_INCOMPLETE_ANNOTATION.to_string(),
+        "true".to_string(),
+    )]);
+    if !failed_packages.is_empty() {
+        annotations.insert(
+            FAILED_PACKAGES_ANNOTATION.to_string(),
+            failed_packages.join(","),
+        );
+    }
+    annotations
+}
+
+/// The names of the given RPMs that aren't installed in the installroot, sorted
+fn uninstalled_packages(installroot: &Path, rpm_paths: &[PathBuf]) -> Result<Vec<String>> {
+    let output = Command::new("rpm")
+        .arg("--root")
+        .arg(installroot)
+        .arg("--query")
+        .arg("--all")
+        .arg("--queryformat")
+        .arg("%{NAME}\\n")
+        .output()
+        .context("Failed to run `rpm`")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to query installed packages: {}{}",
+            String::from_utf8_lossy(&output.stderr),
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let installed = stdout.lines().collect::<HashSet<_>>();
+    let mut uninstalled = Vec::new();
+    for path in rpm_paths {
+        let metadata = rpm::PackageMetadata::open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open RPM package {}: {}", path.display(), e))?;
+        let name = metadata
+            .get_name()
+            .map_err(|e| anyhow::anyhow!("Failed to get RPM name {}: {}", path.display(), e))?;
+        if !installed.contains(name) {
+            uninstalled.push(name.to_string());
+        }
+    }
+    uninstalled.sort();
+    Ok(uninstalled)
 }
 
 fn creation_time() -> Result<DateTime<chrono::Utc>, anyhow::Error> {
@@ -198,6 +673,542 @@ fn creation_time() -> Result<DateTime<chrono::Utc>, anyhow::Error> {
     Ok(creation_time)
 }
 
+/// Ensure the OCI image layout exists, removing temporary files left by an interrupted build.
+/// `existing` determines whether an image directory that already contains files is reused, replaced or refused.
+fn open_image_layout(image: &str, existing: ExistingLayout) -> Result<OciDir> {
+    let is_empty = match fs::read_dir(image) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+        Err(e) => {
+            return Err(e).context(format!("Failed to read OCI image directory `{}`", &image))
+        }
+    };
+    match existing {
+        _ if is_empty => {}
+        ExistingLayout::Reuse => {}
+        ExistingLayout::Fail => bail!(
+            "The OCI image directory `{}` isn't empty. Use `--existing-layout replace` to replace it, or `--existing-layout reuse` to add the image to it",
+            image
+        ),
+        ExistingLayout::Replace => {
+            // Only remove directories that rpmoci could have created
+            if !Path::new(image).join("oci-layout").is_file() {
+                bail!(
+                    "The directory `{}` isn't empty and isn't an OCI image layout, so it isn't replaced",
+                    image
+                );
+            }
+            fs::remove_dir_all(image)
+                .context(format!("Failed to remove OCI image directory `{}`", &image))?;
+            write::ok("Removed", format!("existing image layout `{}`", image))?;
+        }
+    }
+    fs::create_dir_all(image)
+        .context(format!("Failed to create OCI image directory `{}`", &image))?;
+    let dir = Dir::open_ambient_dir(image, ocidir::cap_std::ambient_authority())
+        .context("Failed to open image directory")?;
+    let oci_dir = OciDir::ensure(&dir)?;
+    let removed = remove_stale_temp_files(&oci_dir)?;
+    if removed > 0 {
+        write::ok(
+            "Removed",
+            format!("{} temporary file(s) left by an interrupted build", removed),
+        )?;
+    }
+    Ok(oci_dir)
+}
+
+/// The number of non-directory entries in the root filesystem, and the total size of its regular files.
+/// Hard linked files are counted once.
+fn rootfs_summary(installroot: &Path) -> Result<(u64, u64)> {
+    let mut seen = HashSet::new();
+    let (mut files, mut bytes) = (0, 0);
+    for entry in walkdir::WalkDir::new(installroot).follow_links(false) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if !seen.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+        files += 1;
+        if metadata.is_file() {
+            bytes += metadata.len();
+        }
+    }
+    Ok((files, bytes))
+}
+
+/// Remove files owned by packages with a `keep_paths` allowlist that don't match any of the allowlisted globs
+fn apply_keep_paths(installroot: &Path, keep_paths: &HashMap<String, Vec<String>>) -> Result<()> {
+    for (package, globs) in keep_paths {
+        let patterns = globs
+            .iter()
+            .map(|g| {
+                Pattern::new(g).with_context(|| {
+                    format!("Invalid keep_paths glob `{}` for package `{}`", g, package)
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let files = package_files(installroot, package)?;
+        remove_unkept_files(installroot, &files, &patterns)?;
+    }
+    Ok(())
+}
+
+/// Apply the configured transformations, first to the files of each package and then to all files
+fn apply_package_transformations(
+    installroot: &Path,
+    transformations: &Transformations,
+    tmp_dir: Option<&Path>,
+) -> Result<()> {
+    let tmp_dir = temp_dir(tmp_dir)?;
+    for (package, package_transformations) in &transformations.packages {
+        let files = package_files(installroot, package)?;
+        apply_transformations(
+            installroot,
+            package_transformations,
+            Some(&files),
+            tmp_dir.path(),
+        )?;
+    }
+    apply_transformations(installroot, &transformations.all, None, tmp_dir.path())
+}
+
+/// Query the files owned by a package installed in the installroot
+fn package_files(installroot: &Path, package: &str) -> Result<Vec<PathBuf>> {
+    let output = Command::new("rpm")
+        .arg("--root")
+        .arg(installroot)
+        .arg("--query")
+        .arg("--list")
+        .arg(package)
+        .output()
+        .context("Failed to run `rpm`")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to query files of package `{}`: {}{}",
+            package,
+            String::from_utf8_lossy(&output.stderr),
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+    // Packages without files output "(contains no files)"
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.starts_with('/'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// The build time of the package owning each path in the installroot.
+/// Paths owned by several packages take the latest of their build times.
+fn package_build_times(installroot: &Path) -> Result<HashMap<PathBuf, i64>> {
+    let output = Command::new("rpm")
+        .arg("--root")
+        .arg(installroot)
+        .arg("--query")
+        .arg("--all")
+        .arg("--queryformat")
+        .arg("[%{BUILDTIME} %{FILENAMES}\\n]")
+        .output()
+        .context("Failed to run `rpm`")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to query package build times: {}{}",
+            String::from_utf8_lossy(&output.stderr),
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+    parse_build_times(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse lines of `<build time> <path>`, as output by `package_build_times`'s query
+fn parse_build_times(output: &str) -> Result<HashMap<PathBuf, i64>> {
+    let mut build_times = HashMap::new();
+    for line in output.lines() {
+        let Some((time, path)) = line.split_once(' ') else {
+            bail!("Unexpected output from `rpm`: {}", line);
+        };
+        let time = time
+            .parse::<i64>()
+            .with_context(|| format!("Invalid build time in `rpm` output: {}", line))?;
+        build_times
+            .entry(PathBuf::from(path))
+            .and_modify(|t: &mut i64| *t = (*t).max(time))
+            .or_insert(time);
+    }
+    Ok(build_times)
+}
+
+/// Remove the files of packages that are assumed to be installed in the image being built on,
+/// so that they aren't included in the layer. They're installed so that the other packages' scriptlets and hooks can use them.
+/// Files that are also owned by other packages, or whose contents changed after they were installed,
+/// e.g. `/etc/passwd` after a scriptlet adds a user, are kept. The rpm database is kept as is, so lists every package.
+fn remove_assumed_installed_files(installroot: &Path, assumed: &BTreeSet<&str>) -> Result<()> {
+    if assumed.is_empty() {
+        return Ok(());
+    }
+    let output = Command::new("rpm")
+        .arg("--root")
+        .arg(installroot)
+        .arg("--query")
+        .arg("--all")
+        .arg("--queryformat")
+        .arg("[%{NAME} %{FILENAMES}\\n]")
+        .output()
+        .context("Failed to run `rpm`")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to query package files: {}{}",
+            String::from_utf8_lossy(&output.stderr),
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+    let mut owned_by_others = HashSet::new();
+    let mut files = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Some((name, path)) = line.split_once(' ') else {
+            bail!("Unexpected output from `rpm`: {}", line);
+        };
+        if assumed.contains(name) {
+            files.push(PathBuf::from(path));
+        } else {
+            owned_by_others.insert(PathBuf::from(path));
+        }
+    }
+
+    // rpm exits unsuccessfully if any files differ, so only the output is checked
+    let output = Command::new("rpm")
+        .arg("--root")
+        .arg(installroot)
+        .arg("--verify")
+        .args([
+            "--nomtime",
+            "--nouser",
+            "--nogroup",
+            "--nomode",
+            "--nordev",
+            "--nocaps",
+        ])
+        .args(assumed)
+        .output()
+        .context("Failed to run `rpm`")?;
+    let modified = parse_modified_files(&String::from_utf8_lossy(&output.stdout));
+
+    files.retain(|file| !owned_by_others.contains(file) && !modified.contains(file));
+    remove_unkept_files(installroot, &files, &[])
+}
+
+/// The paths whose size or digest differ from their package, from the output of `rpm --verify`.
+/// Lines are of the form `S.5......  c /etc/passwd`, or `missing     /path` for files that aren't installed.
+fn parse_modified_files(output: &str) -> HashSet<PathBuf> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (flags, _) = line.split_once(' ')?;
+            let path = &line[line.find('/')?..];
+            (flags != "missing" && (flags.contains('S') || flags.contains('5')))
+                .then(|| PathBuf::from(path))
+        })
+        .collect()
+}
+
+/// Remove any of the given files that don't match a keep pattern.
+/// Directories are left in place as they may be shared with other packages.
+fn remove_unkept_files(installroot: &Path, files: &[PathBuf], keep: &[Pattern]) -> Result<()> {
+    for file in files {
+        if keep.iter().any(|pattern| pattern.matches_path(file)) {
+            continue;
+        }
+        let Some(path) = resolve_in_root(installroot, file, false)? else {
+            continue;
+        };
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if !meta.is_dir() => fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove `{}`", path.display()))?,
+            Ok(_) => {}
+            // Files may not have been installed, e.g documentation when docs are disabled
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Scan the root filesystem for symlinks that escape it, handling them according to `policy`
+fn check_symlink_escapes(installroot: &Path, policy: SymlinkEscapePolicy) -> Result<()> {
+    if policy == SymlinkEscapePolicy::Allow {
+        return Ok(());
+    }
+    write::ok("Scanning", "root filesystem for escaping symlinks")?;
+    let escapes = symlink_escapes(installroot)?;
+    for (link, target) in &escapes {
+        write::warning(
+            "Warning",
+            format!(
+                "symlink `/{}` -> `{}` escapes the root filesystem",
+                link.display(),
+                target.display()
+            ),
+        )?;
+    }
+    if policy == SymlinkEscapePolicy::Deny && !escapes.is_empty() {
+        bail!("{} symlink(s) escape the root filesystem", escapes.len());
+    }
+    Ok(())
+}
+
+/// Resolve the configuration files that rpm saved alongside each other according to the policy.
+///
+/// When rpm doesn't install a `%config(noreplace)` file over an existing one that differs, it writes the packaged file
+/// with a `.rpmnew` extension. When it does install a `%config` file over an existing one, it keeps the existing file with
+/// a `.rpmsave` or `.rpmorig` extension.
+/// Only the saved copies of the installed packages' configuration files are considered, so files that merely
+/// have these extensions are left alone.
+fn apply_conffile_policy(installroot: &Path, policy: ConffilePolicy) -> Result<()> {
+    if policy == ConffilePolicy::Rpmnew {
+        return Ok(());
+    }
+    resolve_conffiles(installroot, &config_files(installroot)?, policy)
+}
+
+/// Resolve the saved copies of the given configuration files, which are absolute paths in the image
+fn resolve_conffiles(
+    installroot: &Path,
+    config_files: &BTreeSet<PathBuf>,
+    policy: ConffilePolicy,
+) -> Result<()> {
+    for config_file in config_files {
+        let Some(installed) = resolve_in_root(installroot, config_file, false)? else {
+            continue;
+        };
+        if fs::symlink_metadata(&installed).is_err() {
+            // Not a conflict, e.g. a file removed after rpm saved it
+            continue;
+        }
+        for extension in ["rpmnew", "rpmsave", "rpmorig"] {
+            let mut saved = installed.as_os_str().to_owned();
+            saved.push(".");
+            saved.push(extension);
+            let saved = PathBuf::from(saved);
+            if !fs::symlink_metadata(&saved).is_ok_and(|metadata| !metadata.is_dir()) {
+                continue;
+            }
+            // Whether the saved file is the packaged one, rather than the existing one
+            let packaged = extension == "rpmnew";
+            let keep_saved = packaged == (policy == ConffilePolicy::KeepNew);
+            debug!(
+                "keeping {} configuration file for `{}`",
+                if policy == ConffilePolicy::KeepNew {
+                    "new"
+                } else {
+                    "old"
+                },
+                config_file.display()
+            );
+            if keep_saved {
+                fs::rename(&saved, &installed)
+                    .with_context(|| format!("Failed to replace `{}`", installed.display()))?;
+            } else {
+                fs::remove_file(&saved)
+                    .with_context(|| format!("Failed to remove `{}`", saved.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Query the configuration files of the packages installed in the installroot
+fn config_files(installroot: &Path) -> Result<BTreeSet<PathBuf>> {
+    let output = Command::new("rpm")
+        .arg("--root")
+        .arg(installroot)
+        .args(["--query", "--all", "--configfiles"])
+        .output()
+        .context("Failed to run `rpm`")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to query configuration files: {}{}",
+            String::from_utf8_lossy(&output.stderr),
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+    // Packages without configuration files output "(contains no files)"
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.starts_with('/'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Find the symlinks in the root filesystem whose targets escape it, returning their paths relative to
+/// the root and their targets.
+///
+/// Targets are resolved lexically, with absolute targets resolved relative to the root, as they would be in a container.
+/// A target escapes if it has more `..` components than can be resolved within the root. These are harmless
+/// inside a container, but are a sign of a tampered package and can trick tools that inspect the root filesystem on the host.
+fn symlink_escapes(installroot: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut escapes = Vec::new();
+    for entry in walkdir::WalkDir::new(installroot)
+        .follow_links(false)
+        .sort_by_file_name()
+    {
+        let entry = entry?;
+        if !entry.file_type().is_symlink() {
+            continue;
+        }
+        let link = entry
+            .path()
+            .strip_prefix(installroot)
+            .expect("walkdir returns paths inside the root")
+            .to_path_buf();
+        let target = fs::read_link(entry.path())?;
+        // The depth below the root of the directory that the target is resolved against
+        let mut depth = if target.is_absolute() {
+            0
+        } else {
+            link.components().count() - 1
+        };
+        let mut escaped = false;
+        for component in target.components() {
+            match component {
+                Component::ParentDir if depth == 0 => {
+                    escaped = true;
+                    break;
+                }
+                Component::ParentDir => depth -= 1,
+                Component::Normal(_) => depth += 1,
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+        if escaped {
+            escapes.push((link, target));
+        }
+    }
+    Ok(escapes)
+}
+
+/// Run hook commands with the root filesystem as their working directory and in `RPMOCI_ROOTFS`.
+/// Their output is logged, and the build fails if any of them fail.
+fn run_hooks(installroot: &Path, hooks: &[Vec<String>]) -> Result<()> {
+    for hook in hooks {
+        let Some((program, args)) = hook.split_first() else {
+            bail!("Hook commands can't be empty");
+        };
+        write::ok("Running", format!("hook `{}`", hook.join(" ")))?;
+        let output = Command::new(program)
+            .args(args)
+            .current_dir(installroot)
+            .env("RPMOCI_ROOTFS", installroot)
+            .output()
+            .with_context(|| format!("Failed to run hook `{}`", hook.join(" ")))?;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            log::info!("{}: {}", program, line);
+        }
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            log::warn!("{}: {}", program, line);
+        }
+        if !output.status.success() {
+            bail!(
+                "Hook `{}` failed with {}: {}",
+                hook.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Create a temporary directory, in `tmp_dir` if specified or the system temporary directory otherwise
+fn temp_dir(tmp_dir: Option<&Path>) -> Result<TempDir> {
+    match tmp_dir {
+        Some(tmp_dir) => TempDir::new_in(tmp_dir).with_context(|| {
+            format!(
+                "Failed to create temporary directory in `{}`",
+                tmp_dir.display()
+            )
+        }),
+        None => TempDir::new().context("Failed to create temporary directory"),
+    }
+}
+
+/// Copy files into the installroot, creating any missing parent directories.
+/// Modes are set explicitly, so don't depend on the source files' modes or the umask.
+///
+/// Destinations are opened beneath the installroot, so destinations with `..` components,
+/// and symlinks in the installroot that lead outside of it, are refused rather than writing to the build host.
+fn inject_files(
+    installroot: &Path,
+    files: &[InjectedFile],
+    default_file_mode: u32,
+    default_dir_mode: u32,
+) -> Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    let root = Dir::open_ambient_dir(installroot, ocidir::cap_std::ambient_authority())
+        .with_context(|| format!("Failed to open `{}`", installroot.display()))?;
+    for file in files {
+        let Ok(relative) = file.destination.strip_prefix("/") else {
+            bail!(
+                "File destination `{}` is not an absolute path",
+                file.destination.display()
+            );
+        };
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            bail!(
+                "File destination `{}` must not contain `..` components",
+                file.destination.display()
+            );
+        }
+        if let Some(parent) = relative.parent() {
+            create_dirs(&root, parent, default_dir_mode)?;
+        }
+        let context = || {
+            format!(
+                "Failed to copy `{}` to `{}`",
+                file.source.display(),
+                file.destination.display()
+            )
+        };
+        let mut source = fs::File::open(&file.source).with_context(context)?;
+        let mut destination = root.create(relative).with_context(context)?;
+        std::io::copy(&mut source, &mut destination).with_context(context)?;
+        root.set_permissions(
+            relative,
+            ocidir::cap_std::fs::Permissions::from_std(fs::Permissions::from_mode(
+                file.mode.unwrap_or(default_file_mode),
+            )),
+        )
+        .with_context(context)?;
+    }
+    Ok(())
+}
+
+/// Create a directory and any missing parents beneath `root`, with the given mode
+fn create_dirs(root: &Dir, dir: &Path, mode: u32) -> Result<()> {
+    let context = || format!("Failed to create `{}`", Path::new("/").join(dir).display());
+    if dir.as_os_str().is_empty() || root.try_exists(dir).with_context(context)? {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        create_dirs(root, parent, mode)?;
+    }
+    root.create_dir(dir).with_context(context)?;
+    root.set_permissions(
+        dir,
+        ocidir::cap_std::fs::Permissions::from_std(fs::Permissions::from_mode(mode)),
+    )
+    .with_context(context)?;
+    Ok(())
+}
+
 fn disable_sqlite_journaling(path: &Path) -> Result<()> {
     let shm_path = path.with_extension("sqlite-shm");
     if shm_path.exists() {
@@ -207,3 +1218,483 @@ fn disable_sqlite_journaling(path: &Path) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap, HashSet};
+    use std::fs;
+    use std::path::PathBuf;
+
+    use glob::Pattern;
+
+    use super::{
+        apply_conffile_policy, check_symlink_escapes, file_conflicts, inject_files, netshared_path,
+        open_image_layout, parse_build_times, parse_modified_files, remove_unkept_files,
+        resolve_conffiles, rootfs_summary, rpm_macro_definitions, run_hooks, symlink_escapes,
+        temp_dir,
+    };
+    use crate::config::InjectedFile;
+    use crate::config::{ConffilePolicy, FileConflictPolicy, SymlinkEscapePolicy};
+    use crate::oci::ExistingLayout;
+
+    #[test]
+    fn build_times_are_parsed() {
+        let build_times = parse_build_times(
+            "100 /usr/bin/foo\n100 /usr/share/my file\n100 /usr\n300 /usr\n200 /usr\n",
+        )
+        .unwrap();
+        assert_eq!(build_times.len(), 3);
+        assert_eq!(build_times[&PathBuf::from("/usr/bin/foo")], 100);
+        assert_eq!(build_times[&PathBuf::from("/usr/share/my file")], 100);
+        // Directories shared by packages take the latest build time
+        assert_eq!(build_times[&PathBuf::from("/usr")], 300);
+        assert!(parse_build_times("(none)\n").is_err());
+    }
+
+    #[test]
+    fn rpm_macros_are_validated() {
+        let macros = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        let defined = macros(&[("%_install_langs", "en_US:de"), ("_excludedocs", "1")]);
+        assert_eq!(
+            rpm_macro_definitions(&defined).unwrap(),
+            vec![("_excludedocs", "1"), ("_install_langs", "en_US:de")]
+        );
+        for invalid in [
+            macros(&[("%", "1")]),
+            macros(&[("1foo", "1")]),
+            macros(&[("foo bar", "1")]),
+            macros(&[("foo", "1\n%bar 2")]),
+            macros(&[("foo", "1"), ("%foo", "2")]),
+        ] {
+            assert!(rpm_macro_definitions(&invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn file_conflicts_are_detected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = |contents: &str| {
+            let path = tmp.path().join(contents);
+            fs::write(&path, contents).unwrap();
+            path
+        };
+        let (a, b) = (source("a"), source("b"));
+        let package = |name: &str, files: &[(&PathBuf, &str)], links: &[(&str, &str)]| {
+            let mut builder = rpm::PackageBuilder::new(name, "1.0", "MIT", "noarch", name);
+            for (source, dest) in files {
+                builder = builder
+                    .with_file(source, rpm::FileOptions::new(*dest))
+                    .unwrap();
+            }
+            for (dest, target) in links {
+                builder = builder
+                    .with_file(
+                        &a,
+                        rpm::FileOptions::new(*dest)
+                            .symlink(*target)
+                            .mode(0o120777u16),
+                    )
+                    .unwrap();
+            }
+            let path = tmp.path().join(format!("{}.rpm", name));
+            builder.build().unwrap().write_file(&path).unwrap();
+            path
+        };
+        let rpms = vec![
+            package(
+                "zeta",
+                &[(&b, "/etc/conflict"), (&a, "/etc/identical")],
+                &[("/etc/link", "b")],
+            ),
+            package(
+                "alpha",
+                &[(&a, "/etc/conflict"), (&a, "/etc/identical")],
+                &[("/etc/link", "a")],
+            ),
+            package("beta", &[(&a, "/etc/other")], &[]),
+        ];
+
+        let conflicts = file_conflicts(&rpms).unwrap();
+        assert_eq!(
+            conflicts
+                .iter()
+                .map(|conflict| (conflict.path.to_str().unwrap(), conflict.package_names()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("/etc/conflict", vec!["alpha", "zeta"]),
+                ("/etc/link", vec!["alpha", "zeta"]),
+            ]
+        );
+        assert_eq!(
+            conflicts[0].winner(FileConflictPolicy::FirstWins).name,
+            "alpha"
+        );
+        assert_eq!(
+            conflicts[0].winner(FileConflictPolicy::LastWins).name,
+            "zeta"
+        );
+        assert_eq!(
+            conflicts[0].winner(FileConflictPolicy::LastWins).rpm_path,
+            rpms[0]
+        );
+
+        assert_eq!(
+            netshared_path(conflicts.iter().map(|conflict| conflict.path.as_path())),
+            "/etc/conflict:/etc/link"
+        );
+        assert_eq!(netshared_path([]), "%{nil}");
+    }
+
+    #[test]
+    fn keep_paths_removes_unmatched_files() {
+        let root = tempfile::tempdir().unwrap();
+        for file in ["usr/bin/foo", "usr/share/doc/foo/README", "etc/foo.conf"] {
+            let path = root.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "foo").unwrap();
+        }
+        let files = [
+            "/usr/bin/foo",
+            "/usr/share/doc/foo",
+            "/usr/share/doc/foo/README",
+            "/etc/foo.conf",
+            "/usr/share/man/man1/foo.1.gz",
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+
+        remove_unkept_files(root.path(), &files, &[Pattern::new("/usr/bin/*").unwrap()]).unwrap();
+        assert!(root.path().join("usr/bin/foo").exists());
+        assert!(root.path().join("usr/share/doc/foo").is_dir());
+        assert!(!root.path().join("usr/share/doc/foo/README").exists());
+        assert!(!root.path().join("etc/foo.conf").exists());
+    }
+
+    #[test]
+    fn unkept_files_are_resolved_inside_the_root() {
+        let host = tempfile::tempdir().unwrap();
+        let root = host.path().join("root");
+        let outside = host.path().join("outside");
+        fs::create_dir_all(root.join("usr")).unwrap();
+        fs::create_dir_all(root.join("lib/foo")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("foo.conf"), "host").unwrap();
+        fs::write(root.join("lib/foo/foo.conf"), "image").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("etc")).unwrap();
+        std::os::unix::fs::symlink("/lib", root.join("usr/lib")).unwrap();
+
+        let files = ["/etc/foo.conf", "/usr/lib/foo/foo.conf"]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        remove_unkept_files(&root, &files, &[]).unwrap();
+        assert!(outside.join("foo.conf").exists());
+        assert!(!root.join("lib/foo/foo.conf").exists());
+    }
+
+    #[test]
+    fn injected_files_cannot_escape_the_root() {
+        let host = tempfile::tempdir().unwrap();
+        let root = host.path().join("root");
+        let outside = host.path().join("outside");
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        let source = host.path().join("source");
+        fs::write(&source, "injected").unwrap();
+        let inject = |destination: &str| {
+            inject_files(
+                &root,
+                &[InjectedFile {
+                    source: source.clone(),
+                    destination: PathBuf::from(destination),
+                    mode: None,
+                }],
+                0o644,
+                0o755,
+            )
+        };
+
+        let err = inject("/../outside/dotdot").unwrap_err();
+        assert!(format!("{:#}", err).contains("`..`"));
+        assert!(inject("/usr/../../outside/dotdot").is_err());
+        assert!(!outside.join("dotdot").exists());
+
+        // Symlinks that lead outside of the root, as a package could ship
+        std::os::unix::fs::symlink(&outside, root.join("etc")).unwrap();
+        std::os::unix::fs::symlink("../../outside", root.join("usr/share")).unwrap();
+        assert!(inject("/etc/passwd").is_err());
+        assert!(inject("/etc/app/conf").is_err());
+        assert!(inject("/usr/share/app/conf").is_err());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+
+        // Symlinks within the root are followed
+        std::os::unix::fs::symlink("usr/lib", root.join("lib")).unwrap();
+        inject("/lib/app/conf").unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("usr/lib/app/conf")).unwrap(),
+            "injected"
+        );
+    }
+
+    #[test]
+    fn injected_files_have_default_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let src = tempfile::tempdir().unwrap();
+        let mut files = Vec::new();
+        // The modes in the image are independent of the source files' modes
+        for (name, mode) in [("private", 0o600), ("open", 0o777)] {
+            let path = src.path().join(name);
+            fs::write(&path, name).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            files.push(InjectedFile {
+                source: path,
+                destination: PathBuf::from("/etc/app/conf.d").join(name),
+                mode: None,
+            });
+        }
+        files.push(InjectedFile {
+            source: src.path().join("open"),
+            destination: PathBuf::from("/usr/local/bin/app"),
+            mode: Some(0o755),
+        });
+
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("etc")).unwrap();
+        fs::set_permissions(root.path().join("etc"), fs::Permissions::from_mode(0o700)).unwrap();
+        inject_files(root.path(), &files, 0o644, 0o755).unwrap();
+
+        let mode = |path: &str| {
+            fs::metadata(root.path().join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("etc/app/conf.d/private"), 0o644);
+        assert_eq!(mode("etc/app/conf.d/open"), 0o644);
+        assert_eq!(mode("etc/app"), 0o755);
+        assert_eq!(mode("etc/app/conf.d"), 0o755);
+        assert_eq!(mode("usr/local/bin/app"), 0o755);
+        // Existing directories are unchanged
+        assert_eq!(mode("etc"), 0o700);
+    }
+
+    #[test]
+    fn temp_dirs_are_created_in_tmp_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = temp_dir(Some(tmp.path())).unwrap();
+        assert_eq!(dir.path().parent().unwrap(), tmp.path());
+        assert!(temp_dir(Some(&tmp.path().join("missing"))).is_err());
+    }
+
+    #[test]
+    fn existing_layouts_are_reused_refused_or_replaced() {
+        let tmp = tempfile::tempdir().unwrap();
+        let image = tmp.path().join("image");
+        let image = image.to_str().unwrap();
+        let populate = || {
+            open_image_layout(image, ExistingLayout::Reuse).unwrap();
+            fs::write(tmp.path().join("image/blobs/sha256/existing"), "blob").unwrap();
+        };
+        let existing = tmp.path().join("image/blobs/sha256/existing");
+
+        populate();
+        open_image_layout(image, ExistingLayout::Reuse).unwrap();
+        assert!(existing.exists());
+
+        let err = open_image_layout(image, ExistingLayout::Fail).unwrap_err();
+        assert!(err.to_string().contains("isn't empty"));
+        assert!(existing.exists());
+
+        open_image_layout(image, ExistingLayout::Replace).unwrap();
+        assert!(!existing.exists());
+        assert!(tmp.path().join("image/oci-layout").exists());
+
+        // Empty and missing directories are fine whatever the mode
+        fs::remove_dir_all(image).unwrap();
+        open_image_layout(image, ExistingLayout::Fail).unwrap();
+
+        // Directories that aren't image layouts aren't removed
+        let other = tmp.path().join("other");
+        fs::create_dir(&other).unwrap();
+        fs::write(other.join("file"), "keep").unwrap();
+        let err = open_image_layout(other.to_str().unwrap(), ExistingLayout::Replace).unwrap_err();
+        assert!(err.to_string().contains("isn't an OCI image layout"));
+        assert!(other.join("file").exists());
+    }
+
+    #[test]
+    fn modified_files_are_parsed() {
+        let output = "S.5......  c /etc/passwd\n.....UG..    /usr/bin/foo\nmissing     /usr/share/doc/foo/README\n..5......    /usr/lib/lib foo.so\n";
+        assert_eq!(
+            parse_modified_files(output),
+            HashSet::from([
+                PathBuf::from("/etc/passwd"),
+                PathBuf::from("/usr/lib/lib foo.so")
+            ])
+        );
+    }
+
+    #[test]
+    fn rootfs_summary_counts_files_once() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("usr/bin")).unwrap();
+        fs::write(root.path().join("usr/bin/foo"), "12345").unwrap();
+        fs::hard_link(
+            root.path().join("usr/bin/foo"),
+            root.path().join("usr/bin/bar"),
+        )
+        .unwrap();
+        fs::write(root.path().join("etc"), "123").unwrap();
+        std::os::unix::fs::symlink("usr/bin", root.path().join("bin")).unwrap();
+        assert_eq!(rootfs_summary(root.path()).unwrap(), (3, 8));
+    }
+
+    #[test]
+    fn hooks_run_in_the_rootfs() {
+        let root = tempfile::tempdir().unwrap();
+        let hook = |script: &str| vec!["sh".to_string(), "-c".to_string(), script.to_string()];
+        run_hooks(
+            root.path(),
+            &[hook("touch marker"), hook("echo $RPMOCI_ROOTFS > rootfs")],
+        )
+        .unwrap();
+        assert!(root.path().join("marker").exists());
+        assert_eq!(
+            fs::read_to_string(root.path().join("rootfs"))
+                .unwrap()
+                .trim(),
+            root.path().to_str().unwrap()
+        );
+
+        let err = run_hooks(root.path(), &[hook("echo oops >&2; exit 3")]).unwrap_err();
+        assert!(err.to_string().contains("oops"));
+        assert!(run_hooks(root.path(), &[vec![]]).is_err());
+    }
+
+    #[test]
+    fn escaping_symlinks_are_detected() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("usr/bin")).unwrap();
+        fs::create_dir_all(root.path().join("etc/alternatives")).unwrap();
+        for (link, target) in [
+            // Absolute and relative targets within the root
+            ("usr/bin/java", "/etc/alternatives/java"),
+            ("usr/bin/python", "../../etc/alternatives/python"),
+            ("usr/bin/self", "./self"),
+            // Targets that escape the root
+            ("usr/bin/escape", "../../../etc/shadow"),
+            ("usr/bin/absolute", "/../etc/shadow"),
+            ("usr/bin/sneaky", "../lib/../../../../etc/shadow"),
+        ] {
+            std::os::unix::fs::symlink(target, root.path().join(link)).unwrap();
+        }
+
+        let escapes = symlink_escapes(root.path())
+            .unwrap()
+            .into_iter()
+            .map(|(link, _)| link)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            escapes,
+            ["usr/bin/absolute", "usr/bin/escape", "usr/bin/sneaky"].map(std::path::PathBuf::from)
+        );
+
+        check_symlink_escapes(root.path(), SymlinkEscapePolicy::Allow).unwrap();
+        check_symlink_escapes(root.path(), SymlinkEscapePolicy::Warn).unwrap();
+        assert!(check_symlink_escapes(root.path(), SymlinkEscapePolicy::Deny).is_err());
+    }
+
+    #[test]
+    fn conffile_conflicts_are_resolved() {
+        // The files rpm leaves when a package's configuration files are installed over existing ones
+        let stacked_root = || {
+            let root = tempfile::tempdir().unwrap();
+            let etc = root.path().join("etc");
+            fs::create_dir_all(&etc).unwrap();
+            // %config(noreplace): the existing file is kept, the packaged one is written alongside
+            fs::write(etc.join("noreplace.conf"), "existing").unwrap();
+            fs::write(etc.join("noreplace.conf.rpmnew"), "packaged").unwrap();
+            // %config: the packaged file is installed, the existing one is saved
+            fs::write(etc.join("replace.conf"), "packaged").unwrap();
+            fs::write(etc.join("replace.conf.rpmsave"), "existing").unwrap();
+            fs::write(etc.join("unowned.conf"), "packaged").unwrap();
+            fs::write(etc.join("unowned.conf.rpmorig"), "existing").unwrap();
+            // A saved file without a conflicting file isn't touched
+            fs::write(etc.join("removed.conf.rpmsave"), "existing").unwrap();
+            // Nor are files that aren't configuration files of installed packages
+            fs::write(etc.join("other.conf"), "other").unwrap();
+            fs::write(etc.join("other.conf.rpmsave"), "not saved by rpm").unwrap();
+            root
+        };
+        let config_files = [
+            "noreplace.conf",
+            "replace.conf",
+            "unowned.conf",
+            "removed.conf",
+        ]
+        .into_iter()
+        .map(|name| PathBuf::from("/etc").join(name))
+        .collect::<BTreeSet<_>>();
+        let contents = |root: &tempfile::TempDir| {
+            let mut files = fs::read_dir(root.path().join("etc"))
+                .unwrap()
+                .map(|entry| {
+                    let path = entry.unwrap().path();
+                    (
+                        path.file_name().unwrap().to_string_lossy().to_string(),
+                        fs::read_to_string(&path).unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        let expected = |files: &[(&str, &str)]| {
+            files
+                .iter()
+                .map(|(name, contents)| (name.to_string(), contents.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let root = stacked_root();
+        resolve_conffiles(root.path(), &config_files, ConffilePolicy::KeepNew).unwrap();
+        assert_eq!(
+            contents(&root),
+            expected(&[
+                ("noreplace.conf", "packaged"),
+                ("other.conf", "other"),
+                ("other.conf.rpmsave", "not saved by rpm"),
+                ("removed.conf.rpmsave", "existing"),
+                ("replace.conf", "packaged"),
+                ("unowned.conf", "packaged"),
+            ])
+        );
+
+        let root = stacked_root();
+        resolve_conffiles(root.path(), &config_files, ConffilePolicy::KeepOld).unwrap();
+        assert_eq!(
+            contents(&root),
+            expected(&[
+                ("noreplace.conf", "existing"),
+                ("other.conf", "other"),
+                ("other.conf.rpmsave", "not saved by rpm"),
+                ("removed.conf.rpmsave", "existing"),
+                ("replace.conf", "existing"),
+                ("unowned.conf", "existing"),
+            ])
+        );
+
+        let root = stacked_root();
+        let before = contents(&root);
+        apply_conffile_policy(root.path(), ConffilePolicy::Rpmnew).unwrap();
+        assert_eq!(contents(&root), before);
+    }
+}
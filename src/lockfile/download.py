@@ -20,29 +20,48 @@ import dnf
 from dnf.cli.progress import MultiFileProgressMeter
 
 
-def download(base, packages, directory):
+def download(base, packages, directory, progress=True):
     """Downloads packages.
     Parameters:
     - base needs to be a dnf.Base() object that has had repos configured and fill_sack called.
     packages is an array of requested package specifications
     - packages is a list of {name, evr, checksum} dicts.
     - directory, where to copy the RPMs to
+    - progress, whether to print download progress
     """
-    pkgs = [get_package(base, p[0], p[1], p[2]) for p in packages]
-    base.download_packages(pkgs, MultiFileProgressMeter(fo=sys.stdout))
-    for pkg in pkgs:
+    for pkg in cache(base, packages, progress):
         shutil.copy(pkg.localPkg(), directory)
 
 
+def cache(base, packages, progress=True):
+    """Downloads packages to dnf's cache, returning them.
+    Packages that are already cached aren't downloaded again.
+    Parameters:
+    - base needs to be a dnf.Base() object that has had repos configured and fill_sack called.
+    - packages is a list of {name, evr, checksum} dicts.
+    - progress, whether to print download progress
+    """
+    pkgs = [get_package(base, p[0], p[1], p[2]) for p in packages]
+    base.download_packages(pkgs, MultiFileProgressMeter(fo=sys.stdout) if progress else None)
+    return pkgs
+
+
 def get_package(base, name, evr, checksum):
-    """Find packages matching given spec."""
+    """Find the package with the given name, evr and checksum."""
     pkgs = base.sack.query().filter(name=name, evr=evr).run()
 
     if not pkgs:
         msg = (
             "Package could no longer be found in repositories. Name: '%s', evr: '%s'"
-            % name,
-            evr,
+            % (name, evr)
         )
         raise dnf.exceptions.DepsolveError(msg)
-    return pkgs[0]
+    for pkg in pkgs:
+        if pkg.chksum and pkg.chksum[1].hex() == checksum:
+            return pkg
+    msg = (
+        "Package found in repositories doesn't match the checksum in the lock file, it may have been rebuilt. "
+        "Name: '%s', evr: '%s', locked checksum: '%s', found: '%s'"
+        % (name, evr, checksum, ", ".join(p.chksum[1].hex() for p in pkgs if p.chksum))
+    )
+    raise dnf.exceptions.DepsolveError(msg)
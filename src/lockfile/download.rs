@@ -12,70 +12,225 @@
 //!
 //! You should have received a copy of the GNU General Public License
 //! along with this program.  If not, see <https://www.gnu.org/licenses/>.
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use std::{io::Write, process::Command};
 
 use anyhow::{bail, Context, Result};
+use openssl::hash::{Hasher, MessageDigest};
 use pyo3::prelude::*;
 use pyo3::types::PyTuple;
 use tempfile::{tempdir, TempDir};
 
-use super::resolve::setup_base;
+use super::resolve::{
+    base_url, check_unique_repo_ids, check_url_scheme, check_url_schemes, configure_base,
+    join_location, setup_base, Clock,
+};
 use super::Lockfile;
 use crate::config::Config;
 use crate::write;
 
+/// The rpm keyring that package signatures are verified against
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Keyring<'a> {
+    /// Import the lock file's keys into a temporary rpm database, removed after verification
+    #[default]
+    Temporary,
+    /// Import the lock file's keys into the rpm database under this directory, which is kept.
+    /// Keys that aren't in the lock file, e.g. imported by earlier builds, are removed, and builds sharing the
+    /// directory hold a lock on it while they use it, so only the lock file's keys are trusted
+    Scoped(&'a Path),
+    /// Don't import any keys, and verify against the keys already imported into the rpm database under this root
+    Existing(&'a Path),
+}
+
 impl Lockfile {
     /// Download RPMs to a given directory
     pub fn download_rpms(&self, cfg: &Config, dir: &Path) -> Result<()> {
-        let repositories = &cfg.contents.repositories;
-
         Python::with_gil(|py| {
-            let base = setup_base(py, repositories, &cfg.contents.gpgkeys)?;
+            let mut base = setup_base(py, &cfg.contents, &Clock::from_env()?)?;
             let download =
                 PyModule::from_code_bound(py, include_str!("download.py"), "resolve", "resolve")?;
 
-            let packages = self
-                .packages
-                .iter()
-                .map(|p| (p.name.clone(), p.evr.clone(), p.checksum.checksum.clone()))
-                .collect::<Vec<_>>();
-
             let args = PyTuple::new_bound(
                 py,
                 &[
                     base.to_object(py),
-                    packages.to_object(py),
+                    self.download_specs().to_object(py),
                     dir.to_object(py),
+                    write::show_subprocess_output().to_object(py),
                 ],
             );
             // Run the download function
+            let start = Instant::now();
             download.getattr("download")?.call1(args)?;
+            base.timings.download = start.elapsed();
             Ok::<_, anyhow::Error>(())
         })
         .context("Failed to download dependencies with dnf")
     }
 
-    /// Check GPG keys of downloaded packages against the GPG keys stored in the lockfile
-    pub fn check_gpg_keys(&self, dir: &Path) -> Result<()> {
-        // Overview:
-        // 1. create temporary directory
-        // 2. use rpm to import all keys from the lockfile into that directory
-        // 3. use rpmkeys to verify each download package
-        let tmp_dir = tempdir()?;
+    /// Populate dnf's cache with the metadata of the configured repositories, so that
+    /// subsequent resolutions and builds don't need to download it.
+    pub fn cache_metadata(cfg: &Config) -> Result<()> {
+        write::ok("Fetching", "repository metadata")?;
+        Python::with_gil(|py| {
+            setup_base(py, &cfg.contents, &Clock::from_env()?)?;
+            Ok::<_, anyhow::Error>(())
+        })
+        .context("Failed to fetch repository metadata with dnf")
+    }
 
-        write::ok("Verifying", "RPM signatures")?;
-        // Load GPG keys into a new rpm db
-        for (repoid, repo_key_info) in &self.repo_gpg_config {
-            if repo_key_info.gpgcheck {
-                for (i, key) in repo_key_info.keys.iter().enumerate() {
-                    load_key(&tmp_dir, &format!("{}-{}", repoid, i), key)?;
-                }
+    /// Populate dnf's cache with the metadata of the configured repositories and the locked RPMs,
+    /// so that subsequent builds don't need to download them.
+    pub fn cache_rpms(&self, cfg: &Config) -> Result<()> {
+        write::ok("Fetching", "repository metadata and locked packages")?;
+        Python::with_gil(|py| {
+            let mut base = setup_base(py, &cfg.contents, &Clock::from_env()?)?;
+            let download =
+                PyModule::from_code_bound(py, include_str!("download.py"), "resolve", "resolve")?;
+            let start = Instant::now();
+            download.getattr("cache")?.call1((
+                base.to_object(py),
+                self.download_specs(),
+                write::show_subprocess_output(),
+            ))?;
+            base.timings.download = start.elapsed();
+            Ok::<_, anyhow::Error>(())
+        })
+        .context("Failed to cache dependencies with dnf")
+    }
+
+    /// The (name, evr, checksum) of each locked package, as expected by download.py
+    fn download_specs(&self) -> Vec<(String, String, String)> {
+        self.packages
+            .iter()
+            .map(|p| (p.name.clone(), p.evr.clone(), p.checksum.checksum.clone()))
+            .collect()
+    }
+
+    /// Download RPMs to a given directory from the locations recorded in the lockfile,
+    /// verifying their checksums. Repository metadata isn't loaded and no dependency resolution is performed.
+    pub fn download_locked_rpms(&self, cfg: &Config, dir: &Path) -> Result<()> {
+        check_unique_repo_ids(&cfg.contents)?;
+        check_url_schemes(&cfg.contents)?;
+        let mut base_urls = HashMap::new();
+        for repo in &cfg.contents.repositories {
+            if let Some(url) = base_url(repo)? {
+                check_url_scheme(&url, cfg.contents.allow_insecure_http)
+                    .with_context(|| format!("Invalid URL for repository `{}`", repo.repo_id()))?;
+                base_urls.insert(repo.repo_id(), url);
+            }
+        }
+
+        let mut packages = Vec::new();
+        for pkg in &self.packages {
+            let Some(location) = &pkg.location else {
+                bail!(
+                    "The lock file doesn't record the location of package `{}`. Run `rpmoci update` to record it",
+                    pkg.name
+                );
+            };
+            let Some(base_url) = base_urls.get(&pkg.repoid) else {
+                bail!(
+                    "Package `{}` is from repository `{}`, which isn't defined by URL",
+                    pkg.name,
+                    pkg.repoid
+                );
+            };
+            let url = join_location(base_url, location)
+                .with_context(|| format!("Invalid location of package `{}`", pkg.name))?;
+            let file_name = Path::new(location)
+                .file_name()
+                .with_context(|| format!("Invalid location of package `{}`", pkg.name))?;
+            packages.push((
+                pkg.repoid.clone(),
+                url.to_string(),
+                dir.join(file_name),
+                pkg.checksum.algorithm.name(),
+                pkg.checksum.checksum.clone(),
+            ));
+        }
+
+        write::ok("Downloading", "locked packages")?;
+        Python::with_gil(|py| {
+            let base = configure_base(py, &cfg.contents)?;
+            let fetch = PyModule::from_code_bound(py, include_str!("fetch.py"), "fetch", "fetch")?;
+            fetch
+                .getattr("fetch")?
+                .call1((base.to_object(py), packages))?;
+            Ok::<_, anyhow::Error>(())
+        })
+        .context("Failed to download locked packages")
+    }
+
+    /// Check that each RPM in the directory is a locked package, and that its checksum matches the locked value.
+    ///
+    /// Repositories sometimes rebuild a package without bumping its release, so a package with
+    /// the locked NEVRA isn't necessarily the package that was locked.
+    pub fn verify_checksums(&self, dir: &Path) -> Result<()> {
+        write::ok("Verifying", "package checksums")?;
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            if path.extension() != Some(OsStr::new("rpm")) {
+                continue;
+            }
+            let metadata = rpm::PackageMetadata::open(&path).map_err(|e| {
+                anyhow::anyhow!("Failed to open RPM package {}: {}", path.display(), e)
+            })?;
+            let name = metadata
+                .get_name()
+                .map_err(|e| anyhow::anyhow!("Failed to get RPM name {}: {}", path.display(), e))?;
+            let arch = metadata
+                .get_arch()
+                .map_err(|e| anyhow::anyhow!("Failed to get RPM arch {}: {}", path.display(), e))?;
+            let evr = package_evr(&metadata).map_err(|e| {
+                anyhow::anyhow!("Failed to get RPM version {}: {}", path.display(), e)
+            })?;
+            // Several versions of a package may be locked, e.g. installonly packages like kernels
+            let Some(pkg) = self.packages.iter().find(|p| {
+                p.name == name
+                    && p.evr.strip_prefix("0:").unwrap_or(&p.evr) == evr
+                    && (p.arch.is_empty() || p.arch == arch)
+            }) else {
+                bail!(
+                    "Package `{}-{}.{}` ({}) isn't in the lock file",
+                    name,
+                    evr,
+                    arch,
+                    path.display()
+                );
+            };
+            let digest = file_digest(&path, pkg.checksum.algorithm.name())?;
+            if digest != pkg.checksum.checksum {
+                bail!(
+                    "Checksum mismatch for package `{}-{}.{}`: the lock file has {} {}, but {} has {}. The package may have been rebuilt by its repository",
+                    pkg.name,
+                    pkg.evr,
+                    arch,
+                    pkg.checksum.algorithm.name(),
+                    pkg.checksum.checksum,
+                    path.display(),
+                    digest
+                );
             }
         }
+        Ok(())
+    }
+
+    /// Check GPG keys of downloaded packages against the GPG keys stored in the lockfile,
+    /// using the given keyring.
+    pub fn check_gpg_keys(&self, dir: &Path, keyring: Keyring) -> Result<()> {
+        // Overview:
+        // 1. use rpm to import all keys from the lockfile into the keyring's root,
+        //    a temporary directory by default
+        // 2. use rpmkeys to verify each download package
+        write::ok("Verifying", "RPM signatures")?;
+        let keyring = self.keyring_root(keyring)?;
+        let root = keyring.path.as_path();
 
         // Get list of RPM names whose signatures need to be verified
         let gpgcheck_repoids = self
@@ -119,17 +274,156 @@ impl Lockfile {
                         e.to_string()
                     )
                 })?) {
-                    check_pkg_signature(&path, tmp_dir.path())?;
+                    check_pkg_signature(&path, root)?;
                 }
             }
         }
 
         Ok(())
     }
+
+    /// Check the signatures of local RPMs against the GPG keys of the repositories that have gpgcheck enabled,
+    /// using the given keyring.
+    pub(crate) fn check_local_gpg_keys(
+        &self,
+        rpm_paths: &[PathBuf],
+        keyring: Keyring,
+    ) -> Result<()> {
+        if rpm_paths.is_empty() {
+            return Ok(());
+        }
+        write::ok("Verifying", "local RPM signatures")?;
+        let keyring = self.keyring_root(keyring)?;
+        let root = keyring.path.as_path();
+        for path in rpm_paths {
+            check_pkg_signature(path, root)
+                .with_context(|| format!("Failed to verify local RPM `{}`", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// The root whose rpm database packages are verified against, importing the lock file's keys into it
+    /// unless the keyring is [`Keyring::Existing`].
+    ///
+    /// A scoped keyring is locked until the returned root is dropped, and keys that aren't in the lock file
+    /// are removed from it.
+    fn keyring_root(&self, keyring: Keyring) -> Result<KeyringRoot> {
+        match keyring {
+            Keyring::Temporary => {
+                let tmp_dir = tempdir()?;
+                self.import_gpg_keys(tmp_dir.path())?;
+                Ok(KeyringRoot {
+                    path: tmp_dir.path().to_path_buf(),
+                    _tmp_dir: Some(tmp_dir),
+                    _lock: None,
+                })
+            }
+            Keyring::Scoped(dir) => {
+                fs::create_dir_all(dir).with_context(|| {
+                    format!("Failed to create keyring directory `{}`", dir.display())
+                })?;
+                // rpm requires an absolute root
+                let dir = dir.canonicalize()?;
+                let lock_path = dir.join(KEYRING_LOCK_FILE);
+                let lock = File::create(&lock_path)
+                    .with_context(|| format!("Failed to create `{}`", lock_path.display()))?;
+                lock.lock()
+                    .with_context(|| format!("Failed to lock `{}`", lock_path.display()))?;
+                // The keys the lock file has, found by importing them into an empty database
+                let expected = {
+                    let tmp_dir = tempdir()?;
+                    self.import_gpg_keys(tmp_dir.path())?;
+                    gpg_pubkeys(tmp_dir.path())?
+                };
+                for key in gpg_pubkeys(&dir)?.difference(&expected) {
+                    remove_gpg_pubkey(&dir, key)?;
+                }
+                self.import_gpg_keys(&dir)?;
+                Ok(KeyringRoot {
+                    path: dir,
+                    _tmp_dir: None,
+                    _lock: Some(lock),
+                })
+            }
+            Keyring::Existing(root) => Ok(KeyringRoot {
+                path: root.to_path_buf(),
+                _tmp_dir: None,
+                _lock: None,
+            }),
+        }
+    }
+
+    /// Import the GPG keys of the repositories that have gpgcheck enabled into the rpm database under `root`
+    fn import_gpg_keys(&self, root: &Path) -> Result<()> {
+        // The key files are written outside of the root, so they don't accumulate in a scoped keyring
+        let key_dir = tempdir()?;
+        for (repoid, repo_key_info) in &self.repo_gpg_config {
+            if repo_key_info.gpgcheck {
+                for (i, key) in repo_key_info.keys.iter().enumerate() {
+                    load_key(root, &key_dir, &format!("{}-{}", repoid, i), key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The name of the file in a scoped keyring directory that builds lock while they use the keyring
+const KEYRING_LOCK_FILE: &str = ".rpmoci-keyring.lock";
+
+/// An rpm root whose database has the keys that packages are verified against
+struct KeyringRoot {
+    path: PathBuf,
+    /// A temporary root, removed when dropped
+    _tmp_dir: Option<TempDir>,
+    /// The lock held on a scoped keyring
+    _lock: Option<File>,
+}
+
+/// The `gpg-pubkey-<version>-<release>` packages in the rpm database under `root`
+fn gpg_pubkeys(root: &Path) -> Result<BTreeSet<String>> {
+    let output = Command::new("rpm")
+        .arg("--root")
+        .arg(root)
+        .args([
+            "-q",
+            "gpg-pubkey",
+            "--qf",
+            "%{NAME}-%{VERSION}-%{RELEASE}\\n",
+        ])
+        .output()
+        .context("Failed to run `rpm`")?;
+    // rpm fails if no keys are imported
+    if !output.status.success() {
+        return Ok(BTreeSet::new());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
 }
 
-fn load_key(tmp_dir: &TempDir, name: &str, key: &str) -> Result<(), anyhow::Error> {
-    let gpg_path = tmp_dir.path().join(name);
+/// Remove a `gpg-pubkey` package from the rpm database under `root`
+fn remove_gpg_pubkey(root: &Path, key: &str) -> Result<()> {
+    let output = Command::new("rpm")
+        .arg("--root")
+        .arg(root)
+        .args(["-e", "--allmatches", key])
+        .output()
+        .context("Failed to run `rpm`")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to remove `{}` from the keyring in `{}`: {}",
+            key,
+            root.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn load_key(root: &Path, key_dir: &TempDir, name: &str, key: &str) -> Result<(), anyhow::Error> {
+    let gpg_path = key_dir.path().join(name);
     let mut gpg_key =
         File::create(&gpg_path).context(format!("Failed to create {}", gpg_path.display()))?;
     gpg_key
@@ -138,7 +432,7 @@ fn load_key(tmp_dir: &TempDir, name: &str, key: &str) -> Result<(), anyhow::Erro
     gpg_key.flush()?;
     Command::new("rpm")
         .arg("--root")
-        .arg(tmp_dir.path())
+        .arg(root)
         .arg("--import")
         .arg(gpg_path)
         .status()
@@ -146,6 +440,22 @@ fn load_key(tmp_dir: &TempDir, name: &str, key: &str) -> Result<(), anyhow::Erro
     Ok(())
 }
 
+/// The hex digest of a file, using the named algorithm
+fn file_digest(path: &Path, algorithm: &str) -> Result<String> {
+    let digest = MessageDigest::from_name(algorithm)
+        .with_context(|| format!("Unsupported checksum algorithm `{}`", algorithm))?;
+    let mut hasher = Hasher::new(digest)?;
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(hasher
+        .finish()?
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
 /// Verify a package signature using rpmkeys
 fn check_pkg_signature(rpm_path: &Path, root: &Path) -> Result<()> {
     let output = Command::new("rpmkeys")
@@ -170,3 +480,339 @@ fn check_pkg_signature(rpm_path: &Path, root: &Path) -> Result<()> {
 
     Ok(())
 }
+
+/// A package's `[epoch:]version-release`, as dnf records it in lock files, omitting a zero epoch
+fn package_evr(metadata: &rpm::PackageMetadata) -> Result<String, rpm::Error> {
+    let version = metadata.get_version()?;
+    let release = metadata.get_release()?;
+    Ok(match metadata.get_epoch() {
+        Ok(epoch) if epoch != 0 => format!("{}:{}-{}", epoch, version, release),
+        _ => format!("{}-{}", version, release),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    use url::Url;
+
+    use crate::config::Config;
+    use crate::lockfile::resolve::cache_dir;
+    use crate::lockfile::{Keyring, Lockfile};
+
+    use super::KEYRING_LOCK_FILE;
+
+    #[test]
+    fn cache_metadata_populates_the_cache() {
+        let cfg: Config = toml::from_str(
+            r#"
+[contents]
+repositories = ["https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64"]
+gpgkeys = ["https://raw.githubusercontent.com/microsoft/CBL-Mariner/2.0/SPECS/mariner-repos/MICROSOFT-RPM-GPG-KEY"]
+packages = []
+"#,
+        )
+        .unwrap();
+        Lockfile::cache_metadata(&cfg).unwrap();
+
+        // dnf caches each repository's metadata in a directory prefixed by its id
+        let repo_id = cfg.contents.repositories[0].repo_id();
+        assert!(fs::read_dir(cache_dir().unwrap()).unwrap().any(|entry| {
+            let entry = entry.unwrap();
+            entry.file_name().to_string_lossy().starts_with(&repo_id)
+                && entry.path().join("repodata").is_dir()
+        }));
+    }
+
+    /// sha256 of "foo"
+    const FOO_SHA256: &str = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+
+    /// A lock file containing package foo-1.0-1.x86_64 from the given repository
+    fn locked_foo(repoid: &str, checksum: &str) -> Lockfile {
+        toml::from_str(&format!(
+            r#"
+pkg_specs = ["foo"]
+
+[[packages]]
+name = "foo"
+evr = "1.0-1"
+arch = "x86_64"
+repoid = "{}"
+location = "Packages/foo-1.0-1.x86_64.rpm"
+
+[packages.checksum]
+algorithm = "sha256"
+checksum = "{}"
+"#,
+            repoid, checksum
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn download_locked_rpms_verifies_checksums() {
+        let repo = tempfile::tempdir().unwrap();
+        fs::create_dir(repo.path().join("Packages")).unwrap();
+        fs::write(repo.path().join("Packages/foo-1.0-1.x86_64.rpm"), "foo").unwrap();
+        let repo_url = Url::from_directory_path(repo.path()).unwrap();
+        let cfg: Config = toml::from_str(&format!(
+            "[contents]\nrepositories = [\"{}\"]\npackages = [\"foo\"]",
+            // the location should be resolved relative to the repository, even without a trailing slash
+            repo_url.as_str().trim_end_matches('/')
+        ))
+        .unwrap();
+        let repoid = cfg.contents.repositories[0].repo_id();
+        let lockfile = |checksum: &str| locked_foo(&repoid, checksum);
+
+        let out = tempfile::tempdir().unwrap();
+        lockfile(FOO_SHA256)
+            .download_locked_rpms(&cfg, out.path())
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(out.path().join("foo-1.0-1.x86_64.rpm")).unwrap(),
+            "foo"
+        );
+
+        let out = tempfile::tempdir().unwrap();
+        let err = lockfile("0000000000000000000000000000000000000000000000000000000000000000")
+            .download_locked_rpms(&cfg, out.path())
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Checksum mismatch"));
+        assert!(!out.path().join("foo-1.0-1.x86_64.rpm").exists());
+    }
+
+    #[test]
+    fn download_locked_rpms_uses_repository_options() {
+        // A proxy that serves "foo" for a single request, returning the request line
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = proxy.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = proxy.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap() > 2 {
+                header.clear();
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nfoo")
+                .unwrap();
+            request_line
+        });
+
+        // The repository's host doesn't resolve, so the package can only be downloaded through its proxy
+        let cfg: Config = toml::from_str(&format!(
+            r#"
+[contents]
+allow_insecure_http = true
+repositories = [{{ url = "http://rpmoci.invalid/repo/", options = {{ proxy = "http://127.0.0.1:{}" }} }}]
+packages = ["foo"]
+"#,
+            port
+        ))
+        .unwrap();
+        let repoid = cfg.contents.repositories[0].repo_id();
+        let out = tempfile::tempdir().unwrap();
+        locked_foo(&repoid, FOO_SHA256)
+            .download_locked_rpms(&cfg, out.path())
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(out.path().join("foo-1.0-1.x86_64.rpm")).unwrap(),
+            "foo"
+        );
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with("GET http://rpmoci.invalid/repo/Packages/foo-1.0-1.x86_64.rpm "));
+    }
+
+    #[test]
+    fn verify_checksums_rejects_rebuilt_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let rpm = dir.path().join("foo-1.0-1.noarch.rpm");
+        let build = |contents: &str| {
+            fs::write(&source, contents).unwrap();
+            rpm::PackageBuilder::new("foo", "1.0", "MIT", "noarch", "foo")
+                .release("1")
+                .with_file(&source, rpm::FileOptions::new("/etc/foo"))
+                .unwrap()
+                .build()
+                .unwrap()
+                .write_file(&rpm)
+                .unwrap();
+            super::file_digest(&rpm, "sha256").unwrap()
+        };
+        let lockfile: Lockfile = toml::from_str(&format!(
+            r#"
+pkg_specs = ["foo"]
+
+[[packages]]
+name = "foo"
+evr = "1.0-1"
+arch = "noarch"
+repoid = "repo"
+
+[packages.checksum]
+algorithm = "sha256"
+checksum = "{}"
+"#,
+            build("original")
+        ))
+        .unwrap();
+        lockfile.verify_checksums(dir.path()).unwrap();
+
+        // a rebuild with the same NEVRA but different contents
+        build("rebuilt");
+        let err = lockfile.verify_checksums(dir.path()).unwrap_err();
+        assert!(format!("{:#}", err).contains("Checksum mismatch for package `foo-1.0-1.noarch`"));
+
+        fs::remove_file(&rpm).unwrap();
+        rpm::PackageBuilder::new("bar", "1.0", "MIT", "noarch", "bar")
+            .build()
+            .unwrap()
+            .write_file(dir.path().join("bar.rpm"))
+            .unwrap();
+        let err = lockfile.verify_checksums(dir.path()).unwrap_err();
+        assert!(format!("{:#}", err).contains("isn't in the lock file"));
+    }
+
+    #[test]
+    fn verify_checksums_matches_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let build = |version: &str, epoch: u32| {
+            let rpm = dir.path().join(format!("foo-{}.rpm", version));
+            rpm::PackageBuilder::new("foo", version, "MIT", "noarch", "foo")
+                .release("1")
+                .epoch(epoch)
+                .build()
+                .unwrap()
+                .write_file(&rpm)
+                .unwrap();
+            super::file_digest(&rpm, "sha256").unwrap()
+        };
+        let old = build("1.0", 0);
+        let new = build("2.0", 1);
+        let lockfile = |old_evr: &str, old: &str, new: &str| -> Lockfile {
+            toml::from_str(&format!(
+                r#"
+pkg_specs = ["foo"]
+
+[[packages]]
+name = "foo"
+evr = "{}"
+arch = "noarch"
+repoid = "repo"
+
+[packages.checksum]
+algorithm = "sha256"
+checksum = "{}"
+
+[[packages]]
+name = "foo"
+evr = "1:2.0-1"
+arch = "noarch"
+repoid = "repo"
+
+[packages.checksum]
+algorithm = "sha256"
+checksum = "{}"
+"#,
+                old_evr, old, new
+            ))
+            .unwrap()
+        };
+        // Each version is checked against its own checksum
+        lockfile("1.0-1", &old, &new)
+            .verify_checksums(dir.path())
+            .unwrap();
+        lockfile("0:1.0-1", &old, &new)
+            .verify_checksums(dir.path())
+            .unwrap();
+        let err = lockfile("1.0-1", &new, &old)
+            .verify_checksums(dir.path())
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Checksum mismatch for package `foo-"));
+
+        // A version that isn't locked is rejected
+        let err = lockfile("1.1-1", &old, &new)
+            .verify_checksums(dir.path())
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("`foo-1.0-1.noarch`")
+                && format!("{:#}", err).contains("isn't in the lock file"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn keys_are_imported_into_a_scoped_keyring() {
+        let lockfile: Lockfile = toml::from_str(
+            &fs::read_to_string(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/updatable_lockfile/rpmoci.lock"
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+        let pubkeys = |root: &std::path::Path| {
+            let output = std::process::Command::new("rpm")
+                .arg("--root")
+                .arg(root)
+                .args(["-q", "gpg-pubkey", "--qf", "%{VERSION}-%{RELEASE}\\n"])
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout)
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        let host_keys = pubkeys(std::path::Path::new("/"));
+
+        let keyring = tempfile::tempdir().unwrap();
+        let root = lockfile
+            .keyring_root(Keyring::Scoped(keyring.path()))
+            .unwrap();
+        assert!(root._tmp_dir.is_none());
+        assert_eq!(root.path, keyring.path().canonicalize().unwrap());
+        let scoped_keys = pubkeys(keyring.path());
+        assert!(!scoped_keys.is_empty());
+        // Only the key files' contents are imported, not the files themselves
+        assert!(fs::read_dir(keyring.path()).unwrap().all(|entry| {
+            let entry = entry.unwrap();
+            entry.file_type().unwrap().is_dir() || entry.file_name() == KEYRING_LOCK_FILE
+        }));
+        // The host's keyring is untouched
+        assert_eq!(pubkeys(std::path::Path::new("/")), host_keys);
+        // The keyring is locked while it's in use
+        let lock = fs::File::open(keyring.path().join(KEYRING_LOCK_FILE)).unwrap();
+        assert!(lock.try_lock().is_err());
+        drop(root);
+        lock.try_lock().unwrap();
+        drop(lock);
+
+        // Importing into the same keyring again is fine
+        lockfile
+            .keyring_root(Keyring::Scoped(keyring.path()))
+            .unwrap();
+        assert_eq!(pubkeys(keyring.path()), scoped_keys);
+
+        // Keys that aren't in the lock file don't stay trusted
+        let mut without_keys = lockfile.clone();
+        without_keys.repo_gpg_config.clear();
+        without_keys
+            .keyring_root(Keyring::Scoped(keyring.path()))
+            .unwrap();
+        assert!(pubkeys(keyring.path()).is_empty());
+        lockfile
+            .keyring_root(Keyring::Scoped(keyring.path()))
+            .unwrap();
+        assert_eq!(pubkeys(keyring.path()), scoped_keys);
+    }
+}
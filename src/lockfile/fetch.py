@@ -0,0 +1,42 @@
+"""Fetches locked packages for rpmoci without loading repository metadata"""
+
+# Copyright (C) Microsoft Corporation.
+#
+# This program is free software: you can redistribute it and/or modify
+# it under the terms of the GNU General Public License as published by
+# the Free Software Foundation, either version 3 of the License, or
+# (at your option) any later version.
+#
+# This program is distributed in the hope that it will be useful,
+# but WITHOUT ANY WARRANTY; without even the implied warranty of
+# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+# GNU General Public License for more details.
+#
+# You should have received a copy of the GNU General Public License
+# along with this program.  If not, see <https://www.gnu.org/licenses/>.
+import hashlib
+import os
+
+
+def fetch(base, packages):
+    """Downloads packages, verifying their checksums.
+    Each package is downloaded with its repository's configuration, so its TLS, client certificate,
+    proxy and credential settings apply, without loading the repository's metadata.
+    Parameters:
+    - base is a dnf.Base() object with the packages' repositories configured
+    - packages is a list of (repoid, url, path, algorithm, checksum) tuples
+    """
+    for repoid, url, path, algorithm, checksum in packages:
+        repo = base.repos[repoid]
+        with open(path, "wb") as f:
+            repo._repo.downloadUrl(url, f.fileno())
+        digest = hashlib.new(algorithm)
+        with open(path, "rb") as f:
+            for chunk in iter(lambda: f.read(1024 * 1024), b""):
+                digest.update(chunk)
+        if digest.hexdigest() != checksum:
+            os.remove(path)
+            raise ValueError(
+                "Checksum mismatch for %s: expected %s %s, got %s"
+                % (url, algorithm, checksum, digest.hexdigest())
+            )
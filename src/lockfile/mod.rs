@@ -14,31 +14,169 @@
 //!
 //! You should have received a copy of the GNU General Public License
 //! along with this program.  If not, see <https://www.gnu.org/licenses/>.
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::io::Write;
 use std::path::Path;
 
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize, Serializer};
 
 use crate::write;
-use crate::{config::Config, NAME};
+use crate::{
+    config::{Config, PackageConfig},
+    nevra::Nevra,
+    NAME,
+};
 
+mod audit;
 mod build;
 mod download;
 mod resolve;
+mod transform;
+pub use audit::{FeedFormat, Finding};
+pub use build::{BuildOptions, RpmSource};
+pub use download::Keyring;
+pub(crate) use resolve::repo_file;
+pub use resolve::MissingPythonModule;
 
 /// Represents an rpmoci lockfile
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Lockfile {
+    #[serde(serialize_with = "serialize_sorted")]
     pkg_specs: Vec<String>,
     packages: BTreeSet<Package>,
     #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
     local_packages: BTreeSet<LocalPackage>,
-    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
-    repo_gpg_config: HashMap<String, RepoKeyInfo>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    repo_gpg_config: BTreeMap<String, RepoKeyInfo>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     global_key_specs: Vec<url::Url>,
+    /// The names of the packages selected by each package spec
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    spec_packages: BTreeMap<String, BTreeSet<String>>,
+    /// Repositories that were skipped during resolution as they were unavailable
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    skipped_repos: BTreeSet<String>,
+    /// The ids of the repositories that the lockfile was resolved against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    repositories: Option<BTreeSet<String>>,
+    /// Whether /etc/os-release was included as a dependency during resolution
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    os_release: Option<bool>,
+    /// The module platform id used to resolve modular repositories
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    module_platform_id: Option<String>,
+    /// The rpm architecture packages were resolved for, if the image's platform set one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arch: Option<String>,
+    /// The names of the packages configured as assumed installed, whose dependencies are also marked
+    /// as assumed installed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    assume_installed: Option<Vec<String>>,
+    /// The digest of the packages in `contents.assume_installed_lockfile`, if one was configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    assume_installed_lockfile: Option<String>,
+    /// How the lockfile was generated, if `contents.lockfile_metadata` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<LockfileMetadata>,
+}
+
+/// Information about how a lockfile was generated.
+/// This is informational only, so isn't considered when checking whether a lockfile is up to date
+/// or when reporting changes between lockfiles.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct LockfileMetadata {
+    /// The version of rpmoci that generated the lockfile
+    pub rpmoci_version: String,
+    /// The path of the rpmoci manifest the lockfile was generated from, as given to rpmoci
+    pub config_path: String,
+    /// The digest of the rpmoci manifest's contents
+    pub config_digest: String,
+    /// When the lockfile was generated, in RFC 3339 format
+    pub generated_at: String,
+}
+
+/// The format of a lockfile on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LockfileFormat {
+    /// TOML, for human review
+    #[default]
+    Toml,
+    /// JSON, for machine consumption
+    Json,
+}
+
+impl LockfileFormat {
+    /// Detect the format of a lockfile's contents.
+    /// JSON lockfiles are objects, which a TOML document can't start with.
+    pub fn detect(contents: &str) -> Self {
+        if contents.trim_start().starts_with('{') {
+            LockfileFormat::Json
+        } else {
+            LockfileFormat::Toml
+        }
+    }
+}
+
+/// The total and per-package sizes of the packages in a lockfile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeSummary {
+    /// The total size of the packages' RPMs in bytes
+    pub download_size: u64,
+    /// The total size of the packages' installed files in bytes
+    pub install_size: u64,
+    /// The size of each package, largest installed size first
+    pub packages: Vec<PackageSize>,
+    /// The names of packages whose sizes weren't recorded, which are excluded from the totals.
+    /// Run `rpmoci update` to record them.
+    pub unknown: Vec<String>,
+}
+
+/// The sizes of a package in a lockfile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageSize {
+    /// The package name
+    pub name: String,
+    /// The size of the package's RPM in bytes
+    pub download_size: u64,
+    /// The size of the package's installed files in bytes
+    pub install_size: u64,
+}
+
+/// A way in which a lockfile is out of date with a configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Staleness {
+    /// The package specifications have changed
+    Packages,
+    /// The repositories have changed
+    Repositories,
+    /// The global GPG keys have changed
+    GpgKeys,
+    /// Whether /etc/os-release is included has changed
+    OsRelease,
+    /// The architecture derived from the image's platform has changed
+    Arch,
+    /// The packages assumed to be installed have changed
+    AssumedInstalled,
+    /// The lockfile of the image being built on, or its packages, have changed
+    AssumeInstalledLockfile,
+}
+
+impl std::fmt::Display for Staleness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Staleness::Packages => write!(f, "package specifications have changed"),
+            Staleness::Repositories => write!(f, "repositories have changed"),
+            Staleness::GpgKeys => write!(f, "gpgkeys have changed"),
+            Staleness::OsRelease => write!(f, "os_release has changed"),
+            Staleness::Arch => write!(f, "the image platform's architecture has changed"),
+            Staleness::AssumedInstalled => write!(f, "assume_installed has changed"),
+            Staleness::AssumeInstalledLockfile => {
+                write!(f, "the packages in assume_installed_lockfile have changed")
+            }
+        }
+    }
 }
 
 /// A package that the user has specified locally
@@ -54,6 +192,9 @@ pub struct LocalPackage {
     name: String,
     /// The RPM requires
     requires: Vec<String>,
+    /// The names of the resolved packages that satisfy this package's requires
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    dependencies: BTreeSet<String>,
 }
 
 /// Format of dnf resolve script output
@@ -64,7 +205,12 @@ struct DnfOutput {
     /// Local packages
     local_packages: Vec<LocalPackage>,
     /// Repository GPG configuration
-    repo_gpg_config: HashMap<String, RepoKeyInfo>,
+    repo_gpg_config: BTreeMap<String, RepoKeyInfo>,
+    /// The names of the packages selected by each package spec
+    spec_packages: BTreeMap<String, BTreeSet<String>>,
+    /// The fingerprints of the keys served by each gpgkey URL
+    #[serde(default)]
+    key_fingerprints: BTreeMap<String, Vec<String>>,
 }
 
 /// GPG key configuration for a specified repository
@@ -77,16 +223,172 @@ struct RepoKeyInfo {
 }
 
 /// A resolved package
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, PartialOrd, Eq, Ord)]
+///
+/// Packages are ordered by name, epoch-version-release and then architecture.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Package {
     /// The package name
     pub name: String,
     /// The package epoch-version-release
     pub evr: String,
+    /// The package architecture
+    #[serde(default)]
+    pub arch: String,
     /// The package checksum
     pub checksum: Checksum,
     /// The id of the package's repository
     pub repoid: String,
+    /// The location of the package, relative to its repository's base URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// The URL the package is downloaded from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+    /// The ids of other repositories that provide an identical package
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub alternate_repoids: BTreeSet<String>,
+    /// The size of the package's RPM in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_size: Option<u64>,
+    /// The size of the package's installed files in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_size: Option<u64>,
+    /// The names of the resolved packages that satisfy this package's requires
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub dependencies: BTreeSet<String>,
+    /// The package's obsoletes, e.g `foo < 2.0`
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub obsoletes: BTreeSet<String>,
+    /// Whether the package is already installed in the image being built on, so isn't included in the layer
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub assumed_installed: bool,
+}
+
+impl Package {
+    /// The package's NEVRA
+    pub fn nevra(&self) -> Result<Nevra> {
+        Nevra::parse(&format!("{}-{}.{}", self.name, self.evr, self.arch))
+    }
+
+    /// Whether any of the package's obsoletes apply to packages with the given name
+    fn obsoletes_name(&self, name: &str) -> bool {
+        self.obsoletes
+            .iter()
+            .any(|obsoletes| obsoletes.split_whitespace().next() == Some(name))
+    }
+}
+
+/// A change to a package between two lockfiles
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PackageChange {
+    /// A package was added
+    Added {
+        /// The package name
+        name: String,
+        /// The package epoch-version-release
+        evr: String,
+    },
+    /// A package was removed
+    Removed {
+        /// The package name
+        name: String,
+        /// The package epoch-version-release
+        evr: String,
+    },
+    /// A package's version changed
+    Updated {
+        /// The package name
+        name: String,
+        /// The previous epoch-version-release
+        from: String,
+        /// The new epoch-version-release
+        to: String,
+    },
+    /// A package was replaced by a package that obsoletes it
+    Obsoleted {
+        /// The name of the obsoleted package
+        name: String,
+        /// The epoch-version-release of the obsoleted package
+        evr: String,
+        /// The name of the obsoleting package
+        by: String,
+        /// The epoch-version-release of the obsoleting package
+        by_evr: String,
+    },
+}
+
+impl Ord for Package {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (
+            &self.name,
+            &self.evr,
+            &self.arch,
+            &self.checksum,
+            &self.repoid,
+            &self.location,
+            &self.download_url,
+            &self.alternate_repoids,
+            &self.download_size,
+            &self.install_size,
+            &self.dependencies,
+            &self.obsoletes,
+        )
+            .cmp(&(
+                &other.name,
+                &other.evr,
+                &other.arch,
+                &other.checksum,
+                &other.repoid,
+                &other.location,
+                &other.download_url,
+                &other.alternate_repoids,
+                &other.download_size,
+                &other.install_size,
+                &other.dependencies,
+                &other.obsoletes,
+            ))
+            .then(self.assumed_installed.cmp(&other.assumed_installed))
+    }
+}
+
+impl PartialOrd for Package {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Collapse packages with the same NEVRA and checksum that were resolved from multiple repositories
+/// into a single package, recording the other repositories in its `alternate_repoids`.
+/// The first repository a package was resolved from is kept as its `repoid`.
+fn merge_duplicate_packages(packages: impl IntoIterator<Item = Package>) -> BTreeSet<Package> {
+    let mut merged: Vec<Package> = Vec::new();
+    for pkg in packages {
+        match merged.iter_mut().find(|existing| {
+            (
+                &existing.name,
+                &existing.evr,
+                &existing.arch,
+                &existing.checksum,
+            ) == (&pkg.name, &pkg.evr, &pkg.arch, &pkg.checksum)
+        }) {
+            Some(existing) => {
+                if pkg.repoid != existing.repoid {
+                    existing.alternate_repoids.insert(pkg.repoid);
+                }
+                existing.alternate_repoids.extend(pkg.alternate_repoids);
+                existing.dependencies.extend(pkg.dependencies);
+            }
+            None => merged.push(pkg),
+        }
+    }
+    merged.into_iter().collect()
+}
+
+/// Serialize a list of strings in sorted order, so the lockfile is independent of the order of the input
+fn serialize_sorted<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    let mut values = values.iter().collect::<Vec<_>>();
+    values.sort();
+    values.serialize(serializer)
 }
 
 /// Checksum of RPM package
@@ -114,6 +416,28 @@ pub enum Algorithm {
     SHA512,
 }
 
+/// The ids of the repositories in a configuration
+fn repository_ids(contents: &PackageConfig) -> BTreeSet<String> {
+    contents
+        .repositories
+        .iter()
+        .map(|repo| repo.repo_id())
+        .collect()
+}
+
+impl Algorithm {
+    /// The name of the algorithm, as used by Python's hashlib
+    fn name(&self) -> &'static str {
+        match self {
+            Algorithm::MD5 => "md5",   //Devskim: ignore DS126858
+            Algorithm::SHA1 => "sha1", //Devskim: ignore DS126858
+            Algorithm::SHA256 => "sha256",
+            Algorithm::SHA384 => "sha384",
+            Algorithm::SHA512 => "sha512",
+        }
+    }
+}
+
 impl Lockfile {
     /// Returns true if the lockfile is compatible with the
     /// given configuration, false otherwise
@@ -122,7 +446,57 @@ impl Lockfile {
     /// without them being present
     #[must_use]
     pub fn is_compatible_excluding_local_rpms(&self, cfg: &Config) -> bool {
-        self.pkg_specs == cfg.contents.packages && self.global_key_specs == cfg.contents.gpgkeys
+        self.staleness(cfg).is_empty()
+    }
+
+    /// Returns the ways in which the lockfile is out of date with the given configuration,
+    /// which is empty if the lockfile is up to date.
+    ///
+    /// This doesn't perform dependency resolution, or consider local RPMs.
+    /// Repositories and os_release are only compared if the lockfile records them,
+    /// which lockfiles created by older versions of rpmoci don't.
+    #[must_use]
+    pub fn staleness(&self, cfg: &Config) -> Vec<Staleness> {
+        let mut staleness = Vec::new();
+        let mut pkg_specs = self.pkg_specs.iter().collect::<Vec<_>>();
+        pkg_specs.sort();
+        let mut cfg_pkg_specs = cfg.contents.packages.iter().collect::<Vec<_>>();
+        cfg_pkg_specs.sort();
+        if pkg_specs != cfg_pkg_specs {
+            staleness.push(Staleness::Packages);
+        }
+        if let Some(repositories) = &self.repositories {
+            if *repositories != repository_ids(&cfg.contents) {
+                staleness.push(Staleness::Repositories);
+            }
+        }
+        if self.global_key_specs != cfg.contents.gpgkeys {
+            staleness.push(Staleness::GpgKeys);
+        }
+        if self
+            .os_release
+            .is_some_and(|os_release| os_release != cfg.contents.os_release)
+        {
+            staleness.push(Staleness::OsRelease);
+        }
+        if self.arch != cfg.contents.arch {
+            staleness.push(Staleness::Arch);
+        }
+        // Older lockfiles only mark the configured packages as assumed installed
+        let assume_installed = match &self.assume_installed {
+            Some(assume_installed) => assume_installed.iter().collect(),
+            None => self.assumed_installed(),
+        };
+        if assume_installed != cfg.contents.assume_installed.iter().collect() {
+            staleness.push(Staleness::AssumedInstalled);
+        }
+        // A lockfile that can't be read is treated as changed, so that resolution reports why
+        if resolve::assumed_packages_digest(&cfg.contents).ok()
+            != Some(self.assume_installed_lockfile.clone())
+        {
+            staleness.push(Staleness::AssumeInstalledLockfile);
+        }
+        staleness
     }
 
     /// Returns true if the lockfile is compatible with the
@@ -142,22 +516,73 @@ impl Lockfile {
             && Self::read_local_rpm_deps(cfg)? == local_package_deps)
     }
 
+    /// Parse a lockfile in either format
+    pub fn parse(contents: &str) -> Result<Self> {
+        Ok(match LockfileFormat::detect(contents) {
+            LockfileFormat::Toml => toml::from_str(contents)?,
+            LockfileFormat::Json => serde_json::from_str(contents)?,
+        })
+    }
+
+    /// Serialize the lockfile in the given format
+    pub fn serialize_as(&self, format: LockfileFormat) -> Result<String> {
+        Ok(match format {
+            LockfileFormat::Toml => format!(
+                "# This file is @generated by {}\n# It is not intended for manual editing.\n{}",
+                NAME.to_ascii_uppercase(),
+                toml::to_string_pretty(&self)?
+            ),
+            LockfileFormat::Json => serde_json::to_string_pretty(&self)? + "\n",
+        })
+    }
+
+    /// The names of the packages that are assumed to be installed in the image being built on
+    fn assumed_installed(&self) -> BTreeSet<&String> {
+        self.packages
+            .iter()
+            .filter(|pkg| pkg.assumed_installed)
+            .map(|pkg| &pkg.name)
+            .collect()
+    }
+
+    /// Record how the lockfile was generated from the rpmoci manifest at `config_path`
+    pub fn record_metadata(&mut self, config_path: &Path) -> Result<()> {
+        let contents = std::fs::read(config_path)
+            .with_context(|| format!("Failed to read `{}`", config_path.display()))?;
+        self.metadata = Some(LockfileMetadata {
+            rpmoci_version: env!("CARGO_PKG_VERSION").to_string(),
+            config_path: config_path.display().to_string(),
+            config_digest: format!(
+                "sha256:{:x}",
+                <sha2::Sha256 as sha2::Digest>::digest(&contents)
+            ),
+            generated_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        });
+        Ok(())
+    }
+
+    /// How the lockfile was generated, if it was recorded
+    pub fn metadata(&self) -> Option<&LockfileMetadata> {
+        self.metadata.as_ref()
+    }
+
     /// Write the lockfile to a file on disk
     pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.write_to_file_as(path, LockfileFormat::Toml)
+    }
+
+    /// Write the lockfile to a file on disk in the given format
+    pub fn write_to_file_as(&self, path: impl AsRef<Path>, format: LockfileFormat) -> Result<()> {
         let mut lock = std::fs::File::create(path.as_ref())?;
-        lock.write_all(
-            format!(
-                "# This file is @generated by {}\n# It is not intended for manual editing.\n",
-                NAME.to_ascii_uppercase(),
-            )
-            .as_bytes(),
-        )?;
-        lock.write_all(toml::to_string_pretty(&self)?.as_bytes())?;
+        lock.write_all(self.serialize_as(format)?.as_bytes())?;
         Ok(())
     }
 
-    /// Print messages to stderr showing changes from a previous lockfile.
-    pub fn print_updates(&self, previous: Option<&Lockfile>) -> Result<()> {
+    /// The changes to packages from a previous lockfile.
+    ///
+    /// Removed packages that are obsoleted by a package in this lockfile are reported as obsoleted,
+    /// and the obsoleting package isn't also reported as added.
+    pub fn package_changes(&self, previous: Option<&Lockfile>) -> Vec<PackageChange> {
         let mut new = self
             .packages
             .iter()
@@ -173,19 +598,67 @@ impl Lockfile {
             })
             .unwrap_or_default();
 
-        for (name, evr) in old {
+        let mut changes = Vec::new();
+        let mut obsoleting = BTreeSet::new();
+        for (name, evr) in &old {
             if let Some(new_evr) = new.remove(name) {
-                if new_evr != evr {
-                    write::ok("Updating", format!("{} {} -> {}", name, evr, new_evr))?;
+                if new_evr != *evr {
+                    changes.push(PackageChange::Updated {
+                        name: name.to_string(),
+                        from: evr.to_string(),
+                        to: new_evr.to_string(),
+                    });
                 }
+            } else if let Some(by) = self.packages.iter().find(|pkg| pkg.obsoletes_name(name)) {
+                obsoleting.insert(&by.name);
+                changes.push(PackageChange::Obsoleted {
+                    name: name.to_string(),
+                    evr: evr.to_string(),
+                    by: by.name.clone(),
+                    by_evr: by.evr.clone(),
+                });
             } else {
-                write::ok("Removing", format!("{} {}", name, evr))?;
+                changes.push(PackageChange::Removed {
+                    name: name.to_string(),
+                    evr: evr.to_string(),
+                });
             }
         }
         for (name, evr) in new {
-            write::ok("Adding", format!("{} {}", name, evr))?;
+            if !obsoleting.contains(name) {
+                changes.push(PackageChange::Added {
+                    name: name.to_string(),
+                    evr: evr.to_string(),
+                });
+            }
         }
+        changes
+    }
 
+    /// Print messages to stderr showing changes from a previous lockfile.
+    pub fn print_updates(&self, previous: Option<&Lockfile>) -> Result<()> {
+        for change in self.package_changes(previous) {
+            match change {
+                PackageChange::Added { name, evr } => {
+                    write::ok("Adding", format!("{} {}", name, evr))?
+                }
+                PackageChange::Removed { name, evr } => {
+                    write::ok("Removing", format!("{} {}", name, evr))?
+                }
+                PackageChange::Updated { name, from, to } => {
+                    write::ok("Updating", format!("{} {} -> {}", name, from, to))?
+                }
+                PackageChange::Obsoleted {
+                    name,
+                    evr,
+                    by,
+                    by_evr,
+                } => write::ok(
+                    "Obsoleting",
+                    format!("{} {} by {} {}", name, evr, by, by_evr),
+                )?,
+            }
+        }
         Ok(())
     }
 
@@ -193,4 +666,707 @@ impl Lockfile {
     pub fn iter_packages(&self) -> impl Iterator<Item = &Package> {
         self.packages.iter()
     }
+
+    /// Summarize the download and installed sizes of the packages in the lockfile
+    pub fn size_summary(&self) -> SizeSummary {
+        let mut summary = SizeSummary {
+            download_size: 0,
+            install_size: 0,
+            packages: Vec::new(),
+            unknown: Vec::new(),
+        };
+        for pkg in &self.packages {
+            match (pkg.download_size, pkg.install_size) {
+                (Some(download_size), Some(install_size)) => {
+                    summary.download_size += download_size;
+                    summary.install_size += install_size;
+                    summary.packages.push(PackageSize {
+                        name: pkg.name.clone(),
+                        download_size,
+                        install_size,
+                    });
+                }
+                _ => summary.unknown.push(pkg.name.clone()),
+            }
+        }
+        summary.packages.sort_by(|a, b| {
+            b.install_size
+                .cmp(&a.install_size)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        summary
+    }
+
+    /// Explain why a package is in the lockfile.
+    ///
+    /// Returns the shortest chain from a package spec to the named package, starting with the spec
+    /// followed by the names of the packages in the chain, or `None` if the package isn't in the lockfile.
+    pub fn dependency_chain(&self, package: &str) -> Result<Option<Vec<String>>> {
+        if self.spec_packages.is_empty() {
+            bail!("the lock file does not contain dependency information. Run `rpmoci update` to regenerate it");
+        }
+        let dependencies = self.dependency_graph();
+
+        // Breadth first search from the package specs, recording the parent of each visited package
+        let mut parents: BTreeMap<&str, Option<&str>> = BTreeMap::new();
+        let mut queue = VecDeque::new();
+        for pkg in self.spec_packages.values().flatten() {
+            if parents.insert(pkg, None).is_none() {
+                queue.push_back(pkg.as_str());
+            }
+        }
+        while let Some(current) = queue.pop_front() {
+            if current == package {
+                let mut chain = vec![current.to_string()];
+                let mut parent = parents[current];
+                while let Some(p) = parent {
+                    chain.push(p.to_string());
+                    parent = parents[p];
+                }
+                let root = chain.last().expect("chain is non-empty");
+                let spec = self
+                    .spec_packages
+                    .iter()
+                    .find(|(_, pkgs)| pkgs.contains(root))
+                    .map(|(spec, _)| spec.clone())
+                    .expect("root of chain was selected by a spec");
+                chain.push(spec);
+                chain.reverse();
+                return Ok(Some(chain));
+            }
+            for dependency in dependencies.get(current).into_iter().flatten() {
+                if !parents.contains_key(dependency) {
+                    parents.insert(dependency, Some(current));
+                    queue.push_back(dependency);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The (name, evr, arch) of the packages that would be orphaned by removing the package specs
+    /// that aren't in `pkg_specs`: the dependencies of the removed specs' packages that no remaining
+    /// spec depends on.
+    ///
+    /// These are the packages dnf keeps installed when `clean_requirements_on_remove` is disabled.
+    pub(crate) fn orphaned_dependencies(
+        &self,
+        pkg_specs: &[String],
+    ) -> Vec<(String, String, String)> {
+        let dependencies = self.dependency_graph();
+        let (kept, removed): (Vec<_>, Vec<_>) = self
+            .spec_packages
+            .iter()
+            .partition(|(spec, _)| pkg_specs.contains(spec));
+        let kept = dependency_closure(&dependencies, kept.into_iter().flat_map(|(_, p)| p));
+        let removed_packages = removed
+            .iter()
+            .flat_map(|(_, pkgs)| pkgs.iter().map(String::as_str))
+            .collect::<BTreeSet<_>>();
+        let orphans = dependency_closure(&dependencies, removed.into_iter().flat_map(|(_, p)| p));
+        self.packages
+            .iter()
+            .filter(|pkg| {
+                let name = pkg.name.as_str();
+                orphans.contains(name) && !kept.contains(name) && !removed_packages.contains(name)
+            })
+            .map(|pkg| (pkg.name.clone(), pkg.evr.clone(), pkg.arch.clone()))
+            .collect()
+    }
+
+    /// Mark everything the packages assumed to be installed depend on as assumed installed,
+    /// as those packages are also installed in the image being built on
+    pub(crate) fn assume_dependencies_installed(&mut self) {
+        let closure = {
+            let dependencies = self.dependency_graph();
+            dependency_closure(
+                &dependencies,
+                self.packages
+                    .iter()
+                    .filter(|pkg| pkg.assumed_installed)
+                    .map(|pkg| &pkg.name),
+            )
+            .into_iter()
+            .map(str::to_string)
+            .collect::<BTreeSet<_>>()
+        };
+        self.packages = std::mem::take(&mut self.packages)
+            .into_iter()
+            .map(|mut pkg| {
+                pkg.assumed_installed |= closure.contains(&pkg.name);
+                pkg
+            })
+            .collect();
+    }
+
+    /// The names of the packages each package depends on
+    fn dependency_graph(&self) -> BTreeMap<&str, BTreeSet<&str>> {
+        let mut dependencies: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        for pkg in &self.packages {
+            dependencies
+                .entry(&pkg.name)
+                .or_default()
+                .extend(pkg.dependencies.iter().map(String::as_str));
+        }
+        for pkg in &self.local_packages {
+            dependencies
+                .entry(&pkg.name)
+                .or_default()
+                .extend(pkg.dependencies.iter().map(String::as_str));
+        }
+        dependencies
+    }
+}
+
+/// The names of the given packages and everything they depend on
+fn dependency_closure<'a>(
+    dependencies: &BTreeMap<&'a str, BTreeSet<&'a str>>,
+    packages: impl IntoIterator<Item = &'a String>,
+) -> BTreeSet<&'a str> {
+    let mut closure = BTreeSet::new();
+    let mut queue = packages.into_iter().map(String::as_str).collect::<Vec<_>>();
+    while let Some(current) = queue.pop() {
+        if closure.insert(current) {
+            queue.extend(dependencies.get(current).into_iter().flatten());
+        }
+    }
+    closure
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use super::{
+        merge_duplicate_packages, repository_ids, resolve, Algorithm, Checksum, Lockfile,
+        LockfileFormat, Package, PackageChange, RepoKeyInfo, Staleness,
+    };
+    use crate::config::{Config, Repository};
+
+    fn package(name: &str, evr: &str, arch: &str) -> Package {
+        package_with_dependencies(name, evr, arch, &[])
+    }
+
+    fn package_with_dependencies(
+        name: &str,
+        evr: &str,
+        arch: &str,
+        dependencies: &[&str],
+    ) -> Package {
+        Package {
+            name: name.to_string(),
+            evr: evr.to_string(),
+            arch: arch.to_string(),
+            checksum: Checksum {
+                algorithm: Algorithm::SHA256,
+                checksum: format!("{}-{}.{}", name, evr, arch),
+            },
+            repoid: "repo".to_string(),
+            location: None,
+            download_url: None,
+            alternate_repoids: BTreeSet::new(),
+            download_size: None,
+            install_size: None,
+            dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+            obsoletes: BTreeSet::new(),
+            assumed_installed: false,
+        }
+    }
+
+    #[test]
+    fn sizes_are_summarized() {
+        let sized = |name: &str, download_size: u64, install_size: u64| Package {
+            download_size: Some(download_size),
+            install_size: Some(install_size),
+            ..package(name, "1.0-1", "x86_64")
+        };
+        let lockfile = Lockfile {
+            pkg_specs: vec!["bash".to_string()],
+            packages: [
+                sized("bash", 1_800_000, 7_000_000),
+                sized("glibc", 2_000_000, 6_500_000),
+                sized("zlib", 100_000, 200_000),
+                package("ncurses", "6.4-1", "x86_64"),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let summary = lockfile.size_summary();
+        assert_eq!(summary.download_size, 3_900_000);
+        assert_eq!(summary.install_size, 13_700_000);
+        assert_eq!(
+            summary
+                .packages
+                .iter()
+                .map(|p| p.name.as_str())
+                .collect::<Vec<_>>(),
+            ["bash", "glibc", "zlib"]
+        );
+        assert_eq!(summary.unknown, ["ncurses"]);
+    }
+
+    #[test]
+    fn obsoleted_packages_are_reported() {
+        let lockfile = |packages: Vec<Package>| Lockfile {
+            pkg_specs: vec!["curl".to_string()],
+            packages: packages.into_iter().collect(),
+            ..Default::default()
+        };
+        let previous = lockfile(vec![
+            package("curl", "7.88-1", "x86_64"),
+            package("libcurl-minimal", "7.88-1", "x86_64"),
+            package("zlib", "1.2.13-1", "x86_64"),
+            package("openssl", "3.0-1", "x86_64"),
+        ]);
+        let libcurl = Package {
+            obsoletes: ["libcurl-minimal < 8.0".to_string()].into_iter().collect(),
+            ..package("libcurl", "8.0-1", "x86_64")
+        };
+        let current = lockfile(vec![
+            package("curl", "8.0-1", "x86_64"),
+            libcurl,
+            package("zlib", "1.2.13-1", "x86_64"),
+            package("ca-certificates", "2023-1", "noarch"),
+        ]);
+        let change = |kind: &str, name: &str, evr: &str| match kind {
+            "added" => PackageChange::Added {
+                name: name.to_string(),
+                evr: evr.to_string(),
+            },
+            _ => PackageChange::Removed {
+                name: name.to_string(),
+                evr: evr.to_string(),
+            },
+        };
+        assert_eq!(
+            current.package_changes(Some(&previous)),
+            vec![
+                PackageChange::Updated {
+                    name: "curl".to_string(),
+                    from: "7.88-1".to_string(),
+                    to: "8.0-1".to_string(),
+                },
+                PackageChange::Obsoleted {
+                    name: "libcurl-minimal".to_string(),
+                    evr: "7.88-1".to_string(),
+                    by: "libcurl".to_string(),
+                    by_evr: "8.0-1".to_string(),
+                },
+                change("removed", "openssl", "3.0-1"),
+                change("added", "ca-certificates", "2023-1"),
+            ]
+        );
+        // Without a previous lockfile every package is added
+        assert_eq!(current.package_changes(None).len(), 4);
+    }
+
+    #[test]
+    fn duplicate_packages_are_merged() {
+        let from_repo = |name: &str, repoid: &str| Package {
+            repoid: repoid.to_string(),
+            ..package(name, "1.2.13-1", "x86_64")
+        };
+        let mut rebuilt = from_repo("zlib", "c");
+        rebuilt.checksum.checksum = "rebuilt".to_string();
+        let merged = merge_duplicate_packages([
+            from_repo("zlib", "b"),
+            from_repo("bash", "a"),
+            from_repo("zlib", "a"),
+            from_repo("zlib", "b"),
+            rebuilt,
+        ])
+        .into_iter()
+        .collect::<Vec<_>>();
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[0].name, "bash");
+        assert!(merged[0].alternate_repoids.is_empty());
+        // Packages with the same NEVRA but different contents aren't merged
+        assert_eq!(merged[1].repoid, "c");
+        assert!(merged[1].alternate_repoids.is_empty());
+        // Identical packages are merged, keeping the first repository
+        assert_eq!(merged[2].repoid, "b");
+        assert_eq!(
+            merged[2].alternate_repoids,
+            BTreeSet::from(["a".to_string()])
+        );
+
+        // The alternate repositories are recorded in the lockfile
+        let serialized = toml::to_string(&merged[2]).unwrap();
+        assert!(serialized.contains("alternate_repoids = [\"a\"]"));
+        assert_eq!(toml::from_str::<Package>(&serialized).unwrap(), merged[2]);
+    }
+
+    #[test]
+    fn serialization_is_independent_of_resolution_order() {
+        let packages = vec![
+            package("zlib", "1.2.13-1", "x86_64"),
+            package("bash", "5.1.8-2", "x86_64"),
+            package("bash", "5.1.8-1", "x86_64"),
+            package("glibc", "2.35-1", "i686"),
+            package("glibc", "2.35-1", "x86_64"),
+        ];
+        let repos = ["b", "a", "c"];
+        let lockfile = |packages: Vec<Package>, specs: Vec<&str>, repos: Vec<&str>| Lockfile {
+            pkg_specs: specs.into_iter().map(String::from).collect(),
+            packages: packages.into_iter().collect(),
+            repo_gpg_config: repos
+                .into_iter()
+                .map(|repo| {
+                    (
+                        repo.to_string(),
+                        RepoKeyInfo {
+                            gpgcheck: true,
+                            keys: vec![repo.to_string()],
+                        },
+                    )
+                })
+                .collect::<BTreeMap<_, _>>(),
+            ..Default::default()
+        };
+
+        let first = lockfile(
+            packages.clone(),
+            vec!["zlib", "bash", "glibc"],
+            repos.to_vec(),
+        );
+        let second = lockfile(
+            packages.into_iter().rev().collect(),
+            vec!["glibc", "zlib", "bash"],
+            repos.into_iter().rev().collect(),
+        );
+        let first = toml::to_string_pretty(&first).unwrap();
+        assert_eq!(first, toml::to_string_pretty(&second).unwrap());
+
+        // Packages are sorted by name, then evr, then arch
+        let parsed: Lockfile = toml::from_str(&first).unwrap();
+        let nevras = parsed
+            .iter_packages()
+            .map(|p| format!("{}-{}.{}", p.name, p.evr, p.arch))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            nevras,
+            vec![
+                "bash-5.1.8-1.x86_64",
+                "bash-5.1.8-2.x86_64",
+                "glibc-2.35-1.i686",
+                "glibc-2.35-1.x86_64",
+                "zlib-1.2.13-1.x86_64"
+            ]
+        );
+        assert_eq!(parsed.pkg_specs, vec!["bash", "glibc", "zlib"]);
+    }
+
+    #[test]
+    fn json_lockfiles_round_trip() {
+        let packages = vec![
+            package_with_dependencies("bash", "5.1.8-2", "x86_64", &["glibc"]),
+            package("glibc", "2.35-1", "x86_64"),
+        ];
+        let lockfile = Lockfile {
+            pkg_specs: vec!["bash".to_string()],
+            packages: packages.into_iter().collect(),
+            repo_gpg_config: BTreeMap::from([(
+                "repo".to_string(),
+                RepoKeyInfo {
+                    gpgcheck: true,
+                    keys: vec!["key".to_string()],
+                },
+            )]),
+            spec_packages: BTreeMap::from([(
+                "bash".to_string(),
+                BTreeSet::from(["bash".to_string()]),
+            )]),
+            repositories: Some(BTreeSet::from(["repo".to_string()])),
+            os_release: Some(true),
+            ..Default::default()
+        };
+        let toml = lockfile.serialize_as(LockfileFormat::Toml).unwrap();
+        let json = lockfile.serialize_as(LockfileFormat::Json).unwrap();
+        assert_eq!(LockfileFormat::detect(&toml), LockfileFormat::Toml);
+        assert_eq!(LockfileFormat::detect(&json), LockfileFormat::Json);
+
+        // Both formats have the same content
+        let from_json = Lockfile::parse(&json).unwrap();
+        let from_toml = Lockfile::parse(&toml).unwrap();
+        assert_eq!(from_json.serialize_as(LockfileFormat::Toml).unwrap(), toml);
+        assert_eq!(from_toml.serialize_as(LockfileFormat::Json).unwrap(), json);
+    }
+
+    #[test]
+    fn shortest_dependency_chain() {
+        let packages = [
+            package_with_dependencies("skopeo", "1.9.1-1", "x86_64", &["gpgme", "glibc"]),
+            package_with_dependencies("gpgme", "1.17.0-1", "x86_64", &["libassuan", "glibc"]),
+            package_with_dependencies("libassuan", "2.5.5-1", "x86_64", &["libxml2"]),
+            package_with_dependencies("libxml2", "2.9.14-1", "x86_64", &["glibc", "zlib"]),
+            package_with_dependencies("glibc", "2.35-1", "x86_64", &["filesystem"]),
+            package("filesystem", "1.1-1", "x86_64"),
+            package_with_dependencies("curl", "7.86.0-1", "x86_64", &["libxml2"]),
+            package("zlib", "1.2.13-1", "x86_64"),
+        ];
+        let lockfile = Lockfile {
+            pkg_specs: vec!["skopeo-1.9.*".to_string(), "curl".to_string()],
+            packages: packages.into_iter().collect(),
+            spec_packages: [("skopeo-1.9.*", vec!["skopeo"]), ("curl", vec!["curl"])]
+                .into_iter()
+                .map(|(spec, pkgs)| {
+                    (
+                        spec.to_string(),
+                        pkgs.into_iter().map(String::from).collect(),
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            lockfile.dependency_chain("zlib").unwrap().unwrap(),
+            vec!["curl", "curl", "libxml2", "zlib"]
+        );
+        assert_eq!(
+            lockfile.dependency_chain("filesystem").unwrap().unwrap(),
+            vec!["skopeo-1.9.*", "skopeo", "glibc", "filesystem"]
+        );
+        assert_eq!(
+            lockfile.dependency_chain("skopeo").unwrap().unwrap(),
+            vec!["skopeo-1.9.*", "skopeo"]
+        );
+        assert!(lockfile.dependency_chain("bash").unwrap().is_none());
+    }
+
+    #[test]
+    fn orphaned_dependencies_of_removed_specs() {
+        let packages = [
+            package_with_dependencies("skopeo", "1.9.1-1", "x86_64", &["gpgme", "glibc"]),
+            package_with_dependencies("gpgme", "1.17.0-1", "x86_64", &["libassuan", "glibc"]),
+            package_with_dependencies("libassuan", "2.5.5-1", "x86_64", &["glibc"]),
+            package_with_dependencies("glibc", "2.35-1", "x86_64", &["filesystem"]),
+            package("filesystem", "1.1-1", "x86_64"),
+            package_with_dependencies("curl", "7.86.0-1", "x86_64", &["glibc"]),
+        ];
+        let lockfile = Lockfile {
+            pkg_specs: vec!["skopeo".to_string(), "curl".to_string()],
+            packages: packages.into_iter().collect(),
+            spec_packages: [("skopeo", vec!["skopeo"]), ("curl", vec!["curl"])]
+                .into_iter()
+                .map(|(spec, pkgs)| {
+                    (
+                        spec.to_string(),
+                        pkgs.into_iter().map(String::from).collect(),
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        // Removing skopeo orphans the dependencies curl doesn't share, but not skopeo itself
+        assert_eq!(
+            lockfile.orphaned_dependencies(&["curl".to_string()]),
+            vec![
+                (
+                    "gpgme".to_string(),
+                    "1.17.0-1".to_string(),
+                    "x86_64".to_string()
+                ),
+                (
+                    "libassuan".to_string(),
+                    "2.5.5-1".to_string(),
+                    "x86_64".to_string()
+                ),
+            ]
+        );
+        // Nothing is orphaned if no spec is removed
+        assert!(lockfile
+            .orphaned_dependencies(&["skopeo".to_string(), "curl".to_string()])
+            .is_empty());
+    }
+
+    #[test]
+    fn ordering_is_consistent_with_equality() {
+        let pkg = package("glibc", "2.35-1", "x86_64");
+        let mut assumed = pkg.clone();
+        assumed.assumed_installed = true;
+        assert_ne!(pkg, assumed);
+        assert_ne!(pkg.cmp(&assumed), std::cmp::Ordering::Equal);
+        assert_eq!(BTreeSet::from([pkg, assumed]).len(), 2);
+    }
+
+    #[test]
+    fn dependencies_of_assumed_installed_packages_are_assumed_installed() {
+        let mut glibc = package_with_dependencies("glibc", "2.35-1", "x86_64", &["filesystem"]);
+        glibc.assumed_installed = true;
+        let packages = [
+            package_with_dependencies("sed", "4.8-1", "x86_64", &["glibc", "pcre2"]),
+            package_with_dependencies("pcre2", "10.40-1", "x86_64", &["glibc"]),
+            glibc,
+            package_with_dependencies("filesystem", "1.1-1", "x86_64", &["setup"]),
+            package("setup", "2.14-1", "noarch"),
+        ];
+        let mut lockfile = Lockfile {
+            pkg_specs: vec!["sed".to_string()],
+            packages: packages.into_iter().collect(),
+            assume_installed: Some(vec!["glibc".to_string()]),
+            ..Default::default()
+        };
+
+        lockfile.assume_dependencies_installed();
+        assert_eq!(
+            lockfile.assumed_installed(),
+            BTreeSet::from([
+                &"filesystem".to_string(),
+                &"glibc".to_string(),
+                &"setup".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn staleness_is_detected() {
+        let cfg = |packages: &str, os_release: bool| -> Config {
+            toml::from_str(&format!(
+                "[contents]\nrepositories = [\"https://example.com/repo\"]\npackages = {}\nos_release = {}",
+                packages, os_release
+            ))
+            .unwrap()
+        };
+        let lockfile = Lockfile {
+            pkg_specs: vec!["bash".to_string(), "curl".to_string()],
+            repositories: Some(repository_ids(&cfg("[]", true).contents)),
+            os_release: Some(true),
+            ..Default::default()
+        };
+
+        assert!(lockfile
+            .staleness(&cfg(r#"["curl", "bash"]"#, true))
+            .is_empty());
+        assert_eq!(
+            lockfile.staleness(&cfg(r#"["bash", "curl-minimal"]"#, true)),
+            vec![Staleness::Packages]
+        );
+        assert_eq!(
+            lockfile.staleness(&cfg(r#"["bash", "curl"]"#, false)),
+            vec![Staleness::OsRelease]
+        );
+
+        let mut other_repo = cfg(r#"["bash", "curl"]"#, true);
+        other_repo.contents.repositories = vec![Repository::Id("fedora".to_string())];
+        assert_eq!(
+            lockfile.staleness(&other_repo),
+            vec![Staleness::Repositories]
+        );
+        assert!(!lockfile.is_compatible_excluding_local_rpms(&other_repo));
+
+        let mut arm64 = cfg(r#"["bash", "curl"]"#, true);
+        arm64
+            .apply_platform(Some(&"linux/arm64".parse().unwrap()))
+            .unwrap();
+        assert_eq!(lockfile.staleness(&arm64), vec![Staleness::Arch]);
+
+        let mut stacked = cfg(r#"["bash", "curl"]"#, true);
+        stacked.contents.assume_installed = vec!["glibc".to_string()];
+        assert_eq!(
+            lockfile.staleness(&stacked),
+            vec![Staleness::AssumedInstalled]
+        );
+        // The dependencies of the configured packages are also marked as assumed installed
+        let mut glibc = package_with_dependencies("glibc", "2.35-1", "x86_64", &["filesystem"]);
+        glibc.assumed_installed = true;
+        let mut filesystem = package("filesystem", "1.1-1", "x86_64");
+        filesystem.assumed_installed = true;
+        let stacked_lockfile = Lockfile {
+            packages: [glibc, filesystem].into_iter().collect(),
+            assume_installed: Some(vec!["glibc".to_string()]),
+            ..lockfile.clone()
+        };
+        assert!(stacked_lockfile.staleness(&stacked).is_empty());
+        assert_eq!(
+            stacked_lockfile.staleness(&cfg(r#"["bash", "curl"]"#, true)),
+            vec![Staleness::AssumedInstalled]
+        );
+
+        // Adding, editing or removing the lockfile of the image being built on is detected
+        let dir = tempfile::tempdir().unwrap();
+        let base_path = dir.path().join("base.lock");
+        let write_base = |evr: &str| {
+            let base = Lockfile {
+                packages: [package("glibc", evr, "x86_64")].into_iter().collect(),
+                ..lockfile.clone()
+            };
+            std::fs::write(&base_path, base.serialize_as(LockfileFormat::Toml).unwrap()).unwrap();
+        };
+        write_base("2.35-1");
+        let mut on_base = cfg(r#"["bash", "curl"]"#, true);
+        on_base.contents.assume_installed_lockfile = Some(base_path.clone());
+        assert_eq!(
+            lockfile.staleness(&on_base),
+            vec![Staleness::AssumeInstalledLockfile]
+        );
+        let on_base_lockfile = Lockfile {
+            assume_installed_lockfile: resolve::assumed_packages_digest(&on_base.contents).unwrap(),
+            ..lockfile.clone()
+        };
+        assert!(on_base_lockfile.staleness(&on_base).is_empty());
+        assert_eq!(
+            on_base_lockfile.staleness(&cfg(r#"["bash", "curl"]"#, true)),
+            vec![Staleness::AssumeInstalledLockfile]
+        );
+        write_base("2.35-2");
+        assert_eq!(
+            on_base_lockfile.staleness(&on_base),
+            vec![Staleness::AssumeInstalledLockfile]
+        );
+        std::fs::remove_file(&base_path).unwrap();
+        assert_eq!(
+            on_base_lockfile.staleness(&on_base),
+            vec![Staleness::AssumeInstalledLockfile]
+        );
+    }
+
+    #[test]
+    fn metadata_round_trips_without_affecting_comparisons() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("rpmoci.toml");
+        let config =
+            "[contents]\nrepositories = [\"https://example.com/repo\"]\npackages = [\"bash\"]\n";
+        std::fs::write(&manifest, config).unwrap();
+        let cfg: Config = toml::from_str(config).unwrap();
+        let lockfile = Lockfile {
+            pkg_specs: vec!["bash".to_string()],
+            packages: BTreeSet::from([package("bash", "5.1.8-2", "x86_64")]),
+            repositories: Some(repository_ids(&cfg.contents)),
+            os_release: Some(true),
+            ..Default::default()
+        };
+        let mut recorded = lockfile.clone();
+        recorded.record_metadata(&manifest).unwrap();
+        let metadata = recorded.metadata().unwrap().clone();
+        assert_eq!(metadata.rpmoci_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(metadata.config_path, manifest.display().to_string());
+        assert_eq!(
+            metadata.config_digest,
+            format!(
+                "sha256:{:x}",
+                <sha2::Sha256 as sha2::Digest>::digest(config.as_bytes())
+            )
+        );
+        chrono::DateTime::parse_from_rfc3339(&metadata.generated_at).unwrap();
+
+        for format in [LockfileFormat::Toml, LockfileFormat::Json] {
+            let serialized = recorded.serialize_as(format).unwrap();
+            assert!(serialized.contains("config_digest"));
+            let parsed = Lockfile::parse(&serialized).unwrap();
+            assert_eq!(parsed.metadata(), Some(&metadata));
+        }
+        assert!(!lockfile
+            .serialize_as(LockfileFormat::Toml)
+            .unwrap()
+            .contains("metadata"));
+
+        // The metadata doesn't make a lockfile stale, or show up as changes
+        assert!(recorded.staleness(&cfg).is_empty());
+        assert!(recorded.is_compatible_excluding_local_rpms(&cfg));
+        assert!(recorded.package_changes(Some(&lockfile)).is_empty());
+        assert!(lockfile.package_changes(Some(&recorded)).is_empty());
+    }
 }
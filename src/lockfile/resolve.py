@@ -17,39 +17,70 @@
 from dnf.i18n import _
 import dnf
 import hawkey
-import itertools
 import json
 import glob
 
 
-def resolve(base, packages):
+def resolve(
+    base,
+    packages,
+    preferred=(),
+    excludes=(),
+    weak_deps=(),
+    locked=(),
+    assumed=(),
+    assumed_pkgs=(),
+):
     """Resolves packages.
     base needs to be a dnf.Base() object that has had repos configured and fill_sack called.
-    packages is an array of requested package specifications"""
-    pkgs = itertools.chain.from_iterable(
-        [get_packages(base, pkg_spec) for pkg_spec in packages]
-    )
-    goal = hawkey.Goal(base.sack)
-    for pkg in pkgs:
-        goal.install(pkg)
-
-    if not goal.run(ignore_weak_deps=True):
-        msg = dnf.util._format_resolve_problems(goal.problem_rules())
-        raise dnf.exceptions.DepsolveError(msg)
+    packages is an array of requested package specifications. Specs that don't match a package name,
+    such as file paths, are capabilities of which one provider is installed
+    preferred is an array of (name, evr, arch) tuples of packages to install where possible
+    excludes is an array of package name globs or capabilities whose packages can't be installed
+    weak_deps is an array of the classes of weak dependencies to install:
+    recommends, supplements, suggests and enhances
+    locked is an array of (name, evr, arch) tuples of packages to keep at those versions,
+    unless the requested packages can't be resolved with them
+    assumed is an array of the names of packages that are already installed in the image being
+    built on, and assumed_pkgs is an array of the (name, evr, arch) tuples of such packages, e.g.
+    from the base image's lockfile. They are installed as they are in the image being built on,
+    so that they satisfy dependencies, and are marked as assumed installed in the output"""
+    exclude_packages(base, excludes)
+    unlocked = lock_packages(base, locked)
+    try:
+        spec_pkgs, goal = solve(base, packages, preferred, weak_deps, assumed, assumed_pkgs)
+    except dnf.exceptions.DepsolveError:
+        if unlocked is None:
+            raise
+        # Newly requested packages may need other versions of the locked packages
+        base.sack.remove_excludes(unlocked)
+        unlocked = None
+        spec_pkgs, goal = solve(base, packages, preferred, weak_deps, assumed, assumed_pkgs)
+    finally:
+        if unlocked is not None:
+            base.sack.remove_excludes(unlocked)
 
     resolved_pkgs = goal.list_installs()
     repo_gpg_info = {}
+    key_fingerprints = {}
     # Collect GPG keys for this repository
     for pkg in resolved_pkgs:
         if pkg.repoid != hawkey.CMDLINE_REPO_NAME and pkg.repoid not in repo_gpg_info:
             repo_gpg_info[pkg.repoid] = {
                 "gpgcheck": pkg.repo.gpgcheck,
-                "keys": retrieve_keys(pkg.repo),
+                "keys": retrieve_keys(pkg.repo, key_fingerprints),
             }
 
+    resolved_names = {pkg.name for pkg in resolved_pkgs}
+    assumed_nevras = {tuple(nevra) for nevra in assumed_pkgs}
     output = {
         "packages": [
-            pkg_to_dict(pkg)
+            dict(
+                pkg_to_dict(pkg),
+                dependencies=get_dependencies(base, pkg, resolved_pkgs),
+                assumed_installed=pkg.name in assumed
+                or (pkg.name, pkg.evr, pkg.arch) in assumed_nevras,
+            )
             for pkg in resolved_pkgs
             if pkg.repoid != hawkey.CMDLINE_REPO_NAME
         ],
@@ -57,15 +88,109 @@ def resolve(base, packages):
             {
                 "name": pkg.name,
                 "requires": [str(requires) for requires in pkg.requires],
+                "dependencies": get_dependencies(base, pkg, resolved_pkgs),
             }
             for pkg in resolved_pkgs
             if pkg.repoid == hawkey.CMDLINE_REPO_NAME
         ],
         "repo_gpg_config": repo_gpg_info,
+        "key_fingerprints": key_fingerprints,
+        "spec_packages": {
+            pkg_spec: sorted({pkg.name for pkg in pkgs} & resolved_names)
+            for pkg_spec, pkgs in spec_pkgs.items()
+        },
     }
     return json.dumps(output, indent=2)
 
 
+def solve(base, packages, preferred, weak_deps, assumed, assumed_pkgs):
+    """Find the packages matching each spec, and run a goal installing them
+    along with the packages assumed to be installed."""
+    preferred_pkgs = get_preferred_packages(base, preferred)
+    spec_pkgs = {
+        pkg_spec: get_preferred_spec_packages(base, pkg_spec, preferred_pkgs)
+        or get_packages(base, pkg_spec)
+        for pkg_spec in packages
+    }
+    goal = hawkey.Goal(base.sack)
+    for pkg_spec, pkgs in spec_pkgs.items():
+        if is_capability_spec(base, pkg_spec):
+            # Install one of the providers, as for a dependency
+            goal.install(select=hawkey.Selector(base.sack).set(pkg=pkgs))
+        else:
+            for pkg in pkgs:
+                goal.install(pkg)
+    for name in assumed:
+        for pkg in get_preferred_spec_packages(base, name, preferred_pkgs) or get_packages(
+            base, name
+        ):
+            goal.install(pkg)
+    for pkg in get_assumed_packages(base, assumed_pkgs):
+        goal.install(pkg)
+    # Preferred packages are dropped if they conflict with the requested packages
+    for pkg in preferred_pkgs:
+        goal.install(pkg, optional=True)
+
+    run_goal(goal)
+    install_weak_deps(base, goal, weak_deps)
+    return spec_pkgs, goal
+
+
+def run_goal(goal):
+    """Run the goal, ignoring weak dependencies as install_weak_deps handles them."""
+    if not goal.run(ignore_weak_deps=True):
+        msg = dnf.util._format_resolve_problems(goal.problem_rules())
+        raise dnf.exceptions.DepsolveError(msg)
+
+
+def install_weak_deps(base, goal, weak_deps):
+    """Add the weak dependencies of the given classes to the goal, until no more are found.
+    Recommends and suggests of resolved packages are satisfied by one of their available providers,
+    unless a resolved package already satisfies them.
+    Available packages that supplement or enhance a resolved package are installed.
+    Weak dependencies are installed where possible, so are dropped if they conflict."""
+    seen_deps = set()
+    seen_pkgs = set()
+    while True:
+        resolved_pkgs = goal.list_installs()
+        resolved = base.sack.query().filterm(pkg=resolved_pkgs)
+        added = False
+        for cls in ("recommends", "suggests"):
+            if cls not in weak_deps:
+                continue
+            for pkg in resolved_pkgs:
+                for reldep in getattr(pkg, cls):
+                    if str(reldep) in seen_deps:
+                        continue
+                    seen_deps.add(str(reldep))
+                    if resolved.filter(provides=reldep):
+                        continue
+                    providers = base.sack.query().available().filterm(provides=reldep)
+                    if providers:
+                        goal.install(
+                            select=hawkey.Selector(base.sack).set(pkg=providers.run()),
+                            optional=True,
+                        )
+                        added = True
+        for cls in ("supplements", "enhances"):
+            if cls not in weak_deps:
+                continue
+            query = (
+                base.sack.query()
+                .available()
+                .filterm(**{cls: resolved_pkgs})
+                .filterm(latest_per_arch_by_priority=True)
+            )
+            for pkg in query.run():
+                if pkg not in seen_pkgs:
+                    seen_pkgs.add(pkg)
+                    goal.install(pkg, optional=True)
+                    added = True
+        if not added:
+            return
+        run_goal(goal)
+
+
 def get_packages(base, pkg_spec):
     """Find packages matching given spec."""
     if pkg_spec.endswith(".rpm"):
@@ -85,12 +210,99 @@ def get_packages(base, pkg_spec):
     return pkgs
 
 
-def retrieve_keys(repo):
+def is_capability_spec(base, pkg_spec):
+    """Whether a spec doesn't match any package names, so matches the packages that provide it,
+    e.g a file path such as /usr/bin/python3 or a capability such as libz.so.1()(64bit)."""
+    if pkg_spec.endswith(".rpm"):
+        return False
+    subj = dnf.subject.Subject(pkg_spec)
+    return not subj.get_best_query(base.sack, with_provides=False, with_filenames=False)
+
+
+def get_preferred_packages(base, preferred):
+    """Find the available packages matching the given (name, evr, arch) tuples."""
+    preferred = {tuple(nevra) for nevra in preferred}
+    if not preferred:
+        return []
+    query = base.sack.query().available().filterm(name=[name for name, _, _ in preferred])
+    return [pkg for pkg in query.run() if (pkg.name, pkg.evr, pkg.arch) in preferred]
+
+
+def get_assumed_packages(base, assumed_pkgs):
+    """Find the available packages matching the given (name, evr, arch) tuples,
+    failing if any aren't available, as the packages that depend on them couldn't be resolved
+    against the versions that are installed."""
+    pkgs = get_preferred_packages(base, assumed_pkgs)
+    found = {(pkg.name, pkg.evr, pkg.arch) for pkg in pkgs}
+    missing = sorted(
+        "{}-{}.{}".format(*nevra) for nevra in {tuple(nevra) for nevra in assumed_pkgs} - found
+    )
+    if missing:
+        raise dnf.exceptions.DepsolveError(
+            "Packages assumed to be installed aren't available: {}".format(", ".join(missing))
+        )
+    return pkgs
+
+
+def lock_packages(base, locked):
+    """Exclude the available versions of locked packages other than the locked ones.
+    Packages whose locked version is no longer available are left alone.
+    Returns a query of the excluded packages, or None if nothing was excluded."""
+    locked = {tuple(nevra) for nevra in locked}
+    if not locked:
+        return None
+    pkgs = base.sack.query().available().filterm(name=[name for name, _, _ in locked]).run()
+    available = {pkg.name for pkg in pkgs if (pkg.name, pkg.evr, pkg.arch) in locked}
+    others = [
+        pkg
+        for pkg in pkgs
+        if pkg.name in available and (pkg.name, pkg.evr, pkg.arch) not in locked
+    ]
+    if not others:
+        return None
+    excluded = base.sack.query().filterm(pkg=others)
+    base.sack.add_excludes(excluded)
+    return excluded
+
+
+def exclude_packages(base, excludes):
+    """Exclude packages whose name matches, or that provide, any of the given specs."""
+    for spec in excludes:
+        query = base.sack.query().filterm(name__glob=spec)
+        try:
+            query = query.union(base.sack.query().filterm(provides=spec))
+        except hawkey.QueryException:
+            # Not a valid capability, so only match package names
+            pass
+        base.sack.add_excludes(query)
+
+
+def get_preferred_spec_packages(base, pkg_spec, preferred_pkgs):
+    """Find the preferred packages matching given spec."""
+    if pkg_spec.endswith(".rpm") or not preferred_pkgs:
+        return []
+    subj = dnf.subject.Subject(pkg_spec)
+    return subj.get_best_query(base.sack).filterm(pkg=preferred_pkgs).run()
+
+
+def get_dependencies(base, pkg, resolved_pkgs):
+    """Names of the resolved packages that satisfy the requirements of pkg."""
+    query = base.sack.query().filterm(pkg=resolved_pkgs)
+    providers = query.filter(provides=pkg.requires)
+    file_requires = [str(req) for req in pkg.requires if str(req).startswith("/")]
+    if file_requires:
+        providers = providers.union(query.filter(file=file_requires))
+    return sorted({provider.name for provider in providers if provider.name != pkg.name})
+
+
+def retrieve_keys(repo, key_fingerprints):
+    """Retrieve the repo's keys, recording the fingerprints of the keys served by each URL"""
     raw_keys = []
     if repo.gpgcheck:
         for keyurl in repo.gpgkey:
             for key in dnf.crypto.retrieve(keyurl, repo):
                 raw_keys.append(key.raw_key.decode())
+                key_fingerprints.setdefault(keyurl, []).append(key.fingerprint)
     return raw_keys
 
 
@@ -98,8 +310,14 @@ def pkg_to_dict(pkg):
     return {
         "name": pkg.name,
         "evr": pkg.evr,
+        "arch": pkg.arch,
         "checksum": chksum_to_dict(pkg.chksum),
         "repoid": pkg.repoid,
+        "location": pkg.location,
+        "download_url": pkg.remote_location(),
+        "download_size": pkg.downloadsize,
+        "install_size": pkg.installsize,
+        "obsoletes": [str(obsoletes) for obsoletes in pkg.obsoletes],
     }
 
 
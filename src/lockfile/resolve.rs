@@ -13,39 +13,119 @@
 //! You should have received a copy of the GNU General Public License
 //! along with this program.  If not, see <https://www.gnu.org/licenses/>.
 use glob::glob;
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
+use std::io::Write;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
-use log::debug;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, info};
+use pyo3::exceptions::PyModuleNotFoundError;
 use pyo3::prelude::*;
 use pyo3::types::{IntoPyDict, PyDict, PyString, PyTuple};
+use tempfile::NamedTempFile;
 use url::Url;
 
-use super::{DnfOutput, Lockfile};
-use crate::config::Config;
-use crate::config::Repository;
+use super::{merge_duplicate_packages, repository_ids, DnfOutput, Lockfile, Package};
+use crate::config::{
+    process_env, Config, Env, OsReleaseProvider, PackageConfig, Repository, RepositoryDefinition,
+};
+use crate::write;
 
 const ETC_OS_RELEASE: &str = "/etc/os-release";
+/// The number of lines of Python's output included in the errors of failed calls
+const CAPTURED_OUTPUT_LINES: usize = 20;
+/// Environment variable specifying a Python interpreter whose module search path
+/// is used by the embedded interpreter, to find dnf's Python bindings
+const RPMOCI_PYTHON: &str = "RPMOCI_PYTHON";
+/// Environment variable specifying a fixed time, in seconds since the unix epoch,
+/// to treat as the current time when resolving
+const RPMOCI_RESOLVE_TIME: &str = "RPMOCI_RESOLVE_TIME";
+
+/// The source of the current time used when resolving
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Clock {
+    /// The system clock
+    System,
+    /// A fixed point in time
+    Fixed(DateTime<Utc>),
+}
+
+impl Clock {
+    /// A fixed clock if `RPMOCI_RESOLVE_TIME` is set, otherwise the system clock
+    pub(crate) fn from_env() -> Result<Self> {
+        match env::var(RPMOCI_RESOLVE_TIME) {
+            Ok(time) => {
+                let timestamp = time.parse::<i64>().with_context(|| {
+                    format!("Failed to parse {} `{}`", RPMOCI_RESOLVE_TIME, time)
+                })?;
+                let time = DateTime::from_timestamp(timestamp, 0).ok_or_else(|| {
+                    anyhow::anyhow!("{} out of range: `{}`", RPMOCI_RESOLVE_TIME, time)
+                })?;
+                Ok(Clock::Fixed(time))
+            }
+            Err(_) => Ok(Clock::System),
+        }
+    }
+
+    /// The current time according to this clock
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        match self {
+            Clock::System => Utc::now(),
+            Clock::Fixed(time) => *time,
+        }
+    }
+
+    /// The `metadata_expire` value that makes dnf, which compares the age of cached metadata
+    /// against the system time `system_now`, expire metadata as if the current time were `self.now()`.
+    ///
+    /// A negative `metadata_expire` means metadata never expires, so is unchanged.
+    fn metadata_expire(&self, system_now: DateTime<Utc>, metadata_expire: i64) -> i64 {
+        if metadata_expire < 0 {
+            return metadata_expire;
+        }
+        let offset = system_now.signed_duration_since(self.now()).num_seconds();
+        metadata_expire.saturating_add(offset).max(0)
+    }
+}
 
 impl Lockfile {
     /// Perform dependency resolution on the given package specs
-    pub(crate) fn resolve(
+    pub(crate) fn resolve(pkg_specs: Vec<String>, contents: &PackageConfig) -> Result<Self> {
+        let clock = Clock::from_env()?;
+        Python::with_gil(|py| {
+            let mut base = setup_base(py, contents, &clock)?;
+            Self::resolve_with_base(py, &mut base, pkg_specs, contents, &[], &[])
+        })
+    }
+
+    /// Perform dependency resolution on the given package specs using an existing dnf Base.
+    ///
+    /// Packages in `locked` are kept at their (name, evr, arch), unless the package specs can't
+    /// be resolved with them. Packages in `kept` are installed where possible, as if they were
+    /// in the base lockfile.
+    fn resolve_with_base(
+        py: Python,
+        base: &mut Base,
         pkg_specs: Vec<String>,
-        repositories: &[Repository],
-        gpgkeys: Vec<Url>,
-        include_etc_os_release: bool,
+        contents: &PackageConfig,
+        locked: &[(String, String, String)],
+        kept: &[(String, String, String)],
     ) -> Result<Self> {
-        let output = Python::with_gil(|py| {
+        write::ok("Resolving", "package dependencies")?;
+        let assumed = assumed_packages(contents)?;
+        let output = (|| {
             // Resolve is a compiled in python module for resolving dependencies
             let resolve =
                 PyModule::from_code_bound(py, include_str!("resolve.py"), "resolve", "resolve")?;
-            let base = setup_base(py, repositories, &gpgkeys)?;
 
             let etc_os_release = ETC_OS_RELEASE.to_string();
-            let specs = if include_etc_os_release && !pkg_specs.contains(&etc_os_release) {
+            let specs = if contents.os_release && !pkg_specs.contains(&etc_os_release) {
                 let mut specs = pkg_specs.clone();
                 specs.push(etc_os_release.to_string());
                 specs
@@ -53,31 +133,137 @@ impl Lockfile {
                 pkg_specs.clone()
             };
 
-            let args = PyTuple::new_bound(py, &[base.to_object(py), specs.to_object(py)]);
+            let mut preferred = preferred_packages(contents)?;
+            preferred.extend_from_slice(kept);
+            let args = PyTuple::new_bound(
+                py,
+                &[
+                    base.to_object(py),
+                    specs.to_object(py),
+                    preferred.to_object(py),
+                    contents.exclude.to_object(py),
+                    contents.weak_deps.classes().to_object(py),
+                    locked.to_object(py),
+                    contents.assume_installed.to_object(py),
+                    assumed.to_object(py),
+                ],
+            );
             // Run the resolve function, returning a json string, which we shall deserialize.
-            let val: String = resolve.getattr("resolve")?.call1(args)?.extract()?;
+            let start = Instant::now();
+            let val: String =
+                call_capturing_output(&resolve.getattr("resolve")?, args)?.extract()?;
+            base.timings.depsolve += start.elapsed();
             Ok::<_, anyhow::Error>(val)
-        })
+        })()
         .context("Failed to resolve dependencies with dnf")?;
 
         let results: DnfOutput = serde_json::from_str(&output)?;
-        Ok(Lockfile {
+        verify_key_fingerprints(&results.key_fingerprints, &contents.gpgkey_fingerprints)?;
+        let mut lockfile = Lockfile {
             pkg_specs,
-            packages: results.packages.into_iter().collect(),
+            packages: merge_duplicate_packages(
+                results
+                    .packages
+                    .into_iter()
+                    .map(|pkg| unminted_download_url(pkg, contents)),
+            ),
             local_packages: results.local_packages.into_iter().collect(),
             repo_gpg_config: results.repo_gpg_config,
-            global_key_specs: gpgkeys,
-        })
+            global_key_specs: contents.gpgkeys.clone(),
+            spec_packages: results.spec_packages,
+            skipped_repos: base.skipped_repos.clone(),
+            repositories: Some(repository_ids(contents)),
+            os_release: Some(contents.os_release),
+            module_platform_id: base.module_platform_id.clone(),
+            arch: contents.arch.clone(),
+            assume_installed: Some(contents.assume_installed.clone()),
+            assume_installed_lockfile: contents
+                .assume_installed_lockfile
+                .as_ref()
+                .map(|_| packages_digest(&assumed)),
+            metadata: None,
+        };
+        lockfile.assume_dependencies_installed();
+        if let Some(expected) = &contents.os_release_provider {
+            verify_os_release_provider(&lockfile.spec_packages, &lockfile.packages, expected)?;
+        }
+        Ok(lockfile)
+    }
+
+    /// Create lockfiles for multiple configuration files.
+    ///
+    /// Configurations with identical repository configuration share a dnf Base, so that
+    /// repository metadata is only loaded once per distinct set of repositories.
+    /// Configurations with local RPMs are resolved separately, as local RPMs are added to the Base.
+    pub fn resolve_from_configs(cfgs: &[Config]) -> Result<Vec<Self>> {
+        Self::resolve_groups(cfgs, &Clock::from_env()?).map(|(lockfiles, _)| lockfiles)
+    }
+
+    /// Resolve configurations, sharing a dnf Base within each group of configurations that can share one.
+    /// Also returns, for each Base that was set up, the ids of the repositories loaded from the solv cache.
+    fn resolve_groups(
+        cfgs: &[Config],
+        clock: &Clock,
+    ) -> Result<(Vec<Self>, Vec<BTreeSet<String>>)> {
+        let mut lockfiles = cfgs.iter().map(|_| None).collect::<Vec<_>>();
+        let mut cached_repos = Vec::new();
+        for group in group_by_repositories(cfgs)? {
+            Python::with_gil(|py| {
+                let mut base = setup_base(py, &cfgs[group[0]].contents, clock)?;
+                cached_repos.push(base.cached_repos.clone());
+                for i in group {
+                    let contents = &cfgs[i].contents;
+                    lockfiles[i] = Some(Self::resolve_with_base(
+                        py,
+                        &mut base,
+                        contents.packages.clone(),
+                        contents,
+                        &[],
+                        &[],
+                    )?);
+                }
+                Ok::<_, anyhow::Error>(())
+            })?;
+        }
+        Ok((lockfiles.into_iter().flatten().collect(), cached_repos))
     }
 
     /// Create a lockfile from a configuration file
     pub fn resolve_from_config(cfg: &Config) -> Result<Self> {
-        Self::resolve(
-            cfg.contents.packages.clone(),
-            &cfg.contents.repositories,
-            cfg.contents.gpgkeys.clone(),
-            cfg.contents.os_release,
-        )
+        Self::resolve(cfg.contents.packages.clone(), &cfg.contents)
+    }
+
+    /// Create a lockfile from a configuration file, keeping the packages in this lockfile at their
+    /// locked versions.
+    ///
+    /// Only packages that aren't in this lockfile are resolved to the latest available version,
+    /// unless the configuration can't be resolved with the locked versions.
+    /// If `contents.clean_requirements_on_remove` is disabled, the dependencies of package specs
+    /// that were removed from the configuration are kept.
+    pub fn resolve_from_config_keeping_versions(&self, cfg: &Config) -> Result<Self> {
+        let clock = Clock::from_env()?;
+        let contents = self.reapply_module_platform_id(&cfg.contents);
+        let locked = self
+            .packages
+            .iter()
+            .map(|pkg| (pkg.name.clone(), pkg.evr.clone(), pkg.arch.clone()))
+            .collect::<Vec<_>>();
+        let kept = if contents.clean_requirements_on_remove {
+            Vec::new()
+        } else {
+            self.orphaned_dependencies(&cfg.contents.packages)
+        };
+        Python::with_gil(|py| {
+            let mut base = setup_base(py, &contents, &clock)?;
+            Self::resolve_with_base(
+                py,
+                &mut base,
+                cfg.contents.packages.clone(),
+                &contents,
+                &locked,
+                &kept,
+            )
+        })
     }
 
     /// Read the dependencies of local rpms
@@ -93,10 +279,7 @@ impl Lockfile {
                         Ok(paths) => Some(
                             paths
                                 .into_iter()
-                                .filter_map(|p| match p {
-                                    Ok(path) => Some(path),
-                                    Err(_) => None,
-                                })
+                                .filter_map(|p| p.ok())
                                 .collect::<Vec<PathBuf>>(),
                         ),
                         Err(_) => None,
@@ -109,6 +292,7 @@ impl Lockfile {
             .collect::<Vec<PathBuf>>();
 
         let output = Python::with_gil(|py| {
+            import_module(py, "rpm")?;
             // query_local is a compiled in python module for querying local dependencies
             let query = PyModule::from_code_bound(
                 py,
@@ -119,7 +303,8 @@ impl Lockfile {
 
             let args = PyTuple::new_bound(py, &[local.to_object(py)]);
             // Run the query function, returning a json string, which we shall deserialize.
-            let val: String = query.getattr("query_local")?.call1(args)?.extract()?;
+            let val: String =
+                call_capturing_output(&query.getattr("query_local")?, args)?.extract()?;
             Ok::<_, anyhow::Error>(val)
         })
         .context("Failed to resolve dependencies with dnf")?;
@@ -128,6 +313,20 @@ impl Lockfile {
         Ok(results)
     }
 
+    /// The configuration to update this lockfile with, using the module platform id it was
+    /// resolved with unless the configuration sets one
+    fn reapply_module_platform_id(&self, contents: &PackageConfig) -> PackageConfig {
+        let mut contents = contents.clone();
+        if contents.module_platform_id.is_none()
+            && !contents.dnf_options.contains_key("module_platform_id")
+        {
+            contents
+                .module_platform_id
+                .clone_from(&self.module_platform_id);
+        }
+        contents
+    }
+
     /// Create a lockfile by updating any dependencies in the current lockfile
     pub fn resolve_from_previous(&self, cfg: &Config) -> Result<Self> {
         let requires = cfg
@@ -146,21 +345,361 @@ impl Lockfile {
             .filter(|requires| !requires.starts_with("rpmlib("))
             .collect::<Vec<_>>();
 
-        let mut lockfile = Self::resolve(
-            requires,
-            &cfg.contents.repositories,
-            cfg.contents.gpgkeys.clone(),
-            cfg.contents.os_release,
-        )?;
+        let contents = self.reapply_module_platform_id(&cfg.contents);
+        let mut lockfile = Self::resolve(requires, &contents)?;
         lockfile.local_packages.clone_from(&self.local_packages);
         lockfile.pkg_specs.clone_from(&cfg.contents.packages);
+        // The resolve was seeded with the requires of local packages rather than their specs,
+        // so use the previous lockfile's record of the packages selected by local specs.
+        lockfile
+            .spec_packages
+            .retain(|spec, _| cfg.contents.packages.contains(spec) || spec == ETC_OS_RELEASE);
+        for (spec, pkgs) in &self.spec_packages {
+            if spec.ends_with(".rpm") {
+                lockfile.spec_packages.insert(spec.clone(), pkgs.clone());
+            }
+        }
         Ok(lockfile)
     }
 }
 
+/// Minted repository URLs may contain credentials, so record the download URLs of packages
+/// from those repositories relative to the configured URL instead
+fn unminted_download_url(mut pkg: Package, contents: &PackageConfig) -> Package {
+    let minted_url = contents.repositories.iter().find_map(|repo| match repo {
+        Repository::Definition(definition)
+            if definition.url_command.is_some() && repo.repo_id() == pkg.repoid =>
+        {
+            Some(&definition.url)
+        }
+        _ => None,
+    });
+    if let Some(url) = minted_url {
+        pkg.download_url = pkg
+            .location
+            .as_ref()
+            .and_then(|location| join_location(url, location).ok())
+            .map(String::from);
+    }
+    pkg
+}
+
+/// Resolve a package location relative to a repository's base URL
+pub(crate) fn join_location(base_url: &Url, location: &str) -> Result<Url> {
+    let mut base_url = base_url.clone();
+    // Ensure the location is resolved relative to the base URL, rather than its parent
+    if !base_url.path().ends_with('/') {
+        base_url.set_path(&format!("{}/", base_url.path()));
+    }
+    Ok(base_url.join(location)?)
+}
+
+/// The (name, evr, arch) of the packages in the base lockfile, if one is configured
+fn preferred_packages(contents: &PackageConfig) -> Result<Vec<(String, String, String)>> {
+    match &contents.base_lockfile {
+        Some(path) => locked_packages(path, "base lockfile"),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// The (name, evr, arch) of the packages in the lockfile of the image being built on, if one is configured
+fn assumed_packages(contents: &PackageConfig) -> Result<Vec<(String, String, String)>> {
+    match &contents.assume_installed_lockfile {
+        Some(path) => locked_packages(path, "assume_installed lockfile"),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// The digest of the packages in the lockfile of the image being built on, if one is configured.
+/// Lockfiles record it, so that they're out of date once that lockfile's packages change
+pub(super) fn assumed_packages_digest(contents: &PackageConfig) -> Result<Option<String>> {
+    Ok(match &contents.assume_installed_lockfile {
+        Some(_) => Some(packages_digest(&assumed_packages(contents)?)),
+        None => None,
+    })
+}
+
+/// A digest of a set of (name, evr, arch) packages, independent of their order
+fn packages_digest(packages: &[(String, String, String)]) -> String {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    for (name, evr, arch) in packages.iter().collect::<BTreeSet<_>>() {
+        hasher.update(format!("{}-{}.{}\n", name, evr, arch));
+    }
+    format!("sha256:{:x}", hasher.finalize())
+}
+
+/// The (name, evr, arch) of the packages in the lockfile at `path`
+fn locked_packages(path: &Path, description: &str) -> Result<Vec<(String, String, String)>> {
+    let lockfile = Lockfile::parse(
+        &std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {description} `{}`", path.display()))?,
+    )
+    .with_context(|| format!("Invalid {description} `{}`", path.display()))?;
+    Ok(lockfile
+        .packages
+        .into_iter()
+        .map(|pkg| (pkg.name, pkg.evr, pkg.arch))
+        .collect())
+}
+
+/// Check that gpgkey URLs with a fingerprint allowlist only served allowlisted keys
+fn verify_key_fingerprints(
+    served: &BTreeMap<String, Vec<String>>,
+    allowlists: &HashMap<Url, Vec<String>>,
+) -> Result<()> {
+    for (url, fingerprints) in served {
+        let Some(allowlist) = Url::parse(url).ok().and_then(|url| allowlists.get(&url)) else {
+            continue;
+        };
+        for fingerprint in fingerprints {
+            if !allowlist
+                .iter()
+                .any(|allowed| normalize_fingerprint(allowed) == normalize_fingerprint(fingerprint))
+            {
+                bail!(
+                    "GPG key with fingerprint `{}` served by `{}` is not in its gpgkey_fingerprints allowlist",
+                    fingerprint,
+                    url
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that /etc/os-release is provided by the expected package
+fn verify_os_release_provider(
+    spec_packages: &BTreeMap<String, BTreeSet<String>>,
+    packages: &BTreeSet<Package>,
+    expected: &OsReleaseProvider,
+) -> Result<()> {
+    let providers = spec_packages
+        .get(ETC_OS_RELEASE)
+        .filter(|providers| !providers.is_empty())
+        .with_context(|| {
+            format!(
+                "No package providing {} was resolved, but os_release_provider is set to `{}`",
+                ETC_OS_RELEASE, expected.name
+            )
+        })?;
+    for name in providers {
+        if *name != expected.name {
+            bail!(
+                "{} is provided by `{}` rather than the expected os_release_provider `{}`",
+                ETC_OS_RELEASE,
+                name,
+                expected.name
+            );
+        }
+    }
+    if let Some(version) = &expected.version {
+        for pkg in packages.iter().filter(|pkg| pkg.name == expected.name) {
+            let nevra = pkg.nevra()?;
+            if nevra.version != *version {
+                bail!(
+                    "{} is provided by `{}` version `{}` rather than the expected version `{}`",
+                    ETC_OS_RELEASE,
+                    pkg.name,
+                    nevra.version,
+                    version
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Normalize a key fingerprint, which may be written in groups separated by whitespace
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_ascii_uppercase()
+}
+
+/// Group the indices of configurations that can share a dnf Base.
+/// Configurations share a Base if they have no local RPMs and identical repository configuration, excludes
+/// and dnf options.
+fn group_by_repositories(cfgs: &[Config]) -> Result<Vec<Vec<usize>>> {
+    let mut groups: Vec<(Option<String>, Vec<usize>)> = Vec::new();
+    for (i, cfg) in cfgs.iter().enumerate() {
+        let key = if cfg
+            .contents
+            .packages
+            .iter()
+            .any(|spec| spec.ends_with(".rpm"))
+        {
+            None
+        } else {
+            Some(serde_json::to_string(&(
+                &cfg.contents.repositories,
+                &cfg.contents.gpgkeys,
+                cfg.contents.skip_if_unavailable,
+                // Excludes are applied to the Base's sack
+                &cfg.contents.exclude,
+                &cfg.contents.module_platform_id,
+                &cfg.contents.arch,
+                // As are the main configuration options set by `configure_main`
+                cfg.contents.keepcache,
+                cfg.contents.localpkg_gpgcheck,
+                cfg.contents.zchunk,
+                cfg.contents.clean_requirements_on_remove,
+                cfg.contents.dnf_options.iter().collect::<BTreeMap<_, _>>(),
+            ))?)
+        };
+        match groups
+            .iter_mut()
+            .find(|(group_key, _)| key.is_some() && *group_key == key)
+        {
+            Some((_, group)) => group.push(i),
+            None => groups.push((key, vec![i])),
+        }
+    }
+    Ok(groups.into_iter().map(|(_, group)| group).collect())
+}
+
+/// Error returned when a Python module that rpmoci depends on can't be imported
+#[derive(Debug)]
+pub struct MissingPythonModule {
+    /// The name of the missing module
+    pub module: String,
+}
+
+impl std::fmt::Display for MissingPythonModule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let package = match self.module.as_str() {
+            "dnf" | "libdnf" | "hawkey" | "rpm" => format!("python3-{}", self.module),
+            module => module.to_string(),
+        };
+        write!(
+            f,
+            "Python module `{}` not found: {} is not installed or not on PYTHONPATH. \
+            rpmoci requires the Python bindings for dnf, which can be installed with e.g `dnf install python3-dnf`",
+            self.module, package
+        )
+    }
+}
+
+impl std::error::Error for MissingPythonModule {}
+
+/// Import a Python module, returning a [`MissingPythonModule`] error if it (or one of the modules it imports) is not installed
+pub(crate) fn import_module<'py>(py: Python<'py>, name: &str) -> Result<Bound<'py, PyModule>> {
+    if let Some(python) = env::var_os(RPMOCI_PYTHON) {
+        extend_sys_path(py, &interpreter_sys_path(Path::new(&python))?)?;
+    }
+    PyModule::import_bound(py, name).map_err(|err| {
+        if err.is_instance_of::<PyModuleNotFoundError>(py) {
+            // The missing module may be a dependency of the one being imported, e.g libdnf for dnf
+            let module = err
+                .value_bound(py)
+                .getattr("name")
+                .and_then(|name| name.extract::<String>())
+                .unwrap_or_else(|_| name.to_string());
+            MissingPythonModule { module }.into()
+        } else {
+            err.into()
+        }
+    })
+}
+
+/// Return the module search path of a Python interpreter
+fn interpreter_sys_path(python: &Path) -> Result<Vec<String>> {
+    let output = Command::new(python)
+        .arg("-c")
+        .arg("import json, sys; print(json.dumps(sys.path))")
+        .output()
+        .with_context(|| format!("Failed to run Python interpreter `{}`", python.display()))?;
+    if !output.status.success() {
+        bail!(
+            "Failed to query sys.path of Python interpreter `{}`: {}",
+            python.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    serde_json::from_slice(&output.stdout).with_context(|| {
+        format!(
+            "Failed to parse sys.path of Python interpreter `{}`",
+            python.display()
+        )
+    })
+}
+
+/// Call a Python function with `sys.stdout` and `sys.stderr` redirected to a buffer.
+///
+/// dnf often prints diagnostics before raising an exception, so if the call fails the last
+/// [`CAPTURED_OUTPUT_LINES`] lines of its output are added to the error.
+/// Otherwise the output is written to stderr, if subprocess output is shown.
+pub(crate) fn call_capturing_output<'py>(
+    function: &Bound<'py, PyAny>,
+    args: impl IntoPy<Py<PyTuple>>,
+) -> Result<Bound<'py, PyAny>> {
+    let py = function.py();
+    let sys = py.import_bound("sys")?;
+    let buffer = py.import_bound("io")?.getattr("StringIO")?.call0()?;
+    let stdout = sys.getattr("stdout")?;
+    let stderr = sys.getattr("stderr")?;
+    sys.setattr("stdout", &buffer)?;
+    sys.setattr("stderr", &buffer)?;
+    let result = function.call1(args);
+    sys.setattr("stdout", stdout)?;
+    sys.setattr("stderr", stderr)?;
+    let output = buffer.call_method0("getvalue")?.extract::<String>()?;
+
+    match result {
+        Ok(value) => {
+            if write::show_subprocess_output() {
+                std::io::stderr().write_all(output.as_bytes())?;
+            }
+            Ok(value)
+        }
+        Err(err) if output.trim().is_empty() => Err(err.into()),
+        Err(err) => {
+            let lines = output.trim_end().lines().collect::<Vec<_>>();
+            let tail = &lines[lines.len().saturating_sub(CAPTURED_OUTPUT_LINES)..];
+            bail!("{}\noutput:\n{}", err, tail.join("\n"))
+        }
+    }
+}
+
+/// Append entries to the embedded interpreter's sys.path, skipping any that are already present
+fn extend_sys_path(py: Python, entries: &[String]) -> Result<()> {
+    let sys_path = py.import_bound("sys")?.getattr("path")?;
+    for entry in entries {
+        // An empty entry refers to the current directory, which we don't want to search
+        if !entry.is_empty() && !sys_path.contains(entry)? {
+            debug!("adding `{}` to sys.path", entry);
+            sys_path.call_method1("append", (entry,))?;
+        }
+    }
+    Ok(())
+}
+
 /// A wrapper around the dnf.Base object which ensures that plugins are unloaded
 pub(crate) struct Base<'a> {
     value: Bound<'a, PyAny>,
+    /// The ids of repositories that were skipped when filling the sack as they were unavailable
+    pub(crate) skipped_repos: BTreeSet<String>,
+    /// How long the phases of dnf operations using this Base took
+    pub(crate) timings: Timings,
+    /// The ids of repositories whose metadata was loaded from libdnf's solv cache rather than parsed
+    pub(crate) cached_repos: BTreeSet<String>,
+    /// The module platform id used to resolve modular repositories, if there is one
+    pub(crate) module_platform_id: Option<String>,
+    /// Client certificates written from the environment, removed when the Base is dropped
+    _client_certificates: Vec<ClientCertificate>,
+}
+
+/// Durations of the phases of dnf operations, to distinguish network bound and solver bound builds
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Timings {
+    /// Loading repository metadata
+    pub(crate) metadata_load: Duration,
+    /// Dependency resolution
+    pub(crate) depsolve: Duration,
+    /// Downloading packages
+    pub(crate) download: Duration,
 }
 
 impl<'a> Deref for Base<'a> {
@@ -173,6 +712,13 @@ impl<'a> Deref for Base<'a> {
 
 impl<'a> Drop for Base<'a> {
     fn drop(&mut self) {
+        debug!(
+            "dnf timings: metadata_load={:.3}s (cached repositories: {}) depsolve={:.3}s download={:.3}s",
+            self.timings.metadata_load.as_secs_f64(),
+            self.cached_repos.len(),
+            self.timings.depsolve.as_secs_f64(),
+            self.timings.download.as_secs_f64()
+        );
         // Unload plugins as otherwise dnf can raise an error when we call setup_base again
         // self.value.call_method0("unload_plugins").unwrap();
         // To support Azure Linx (Mariner), don't use unload_plugins
@@ -192,7 +738,7 @@ fn home_dir() -> Option<PathBuf> {
 }
 
 /// Return a directory to use for caching dnf data
-fn cache_dir() -> Option<PathBuf> {
+pub(crate) fn cache_dir() -> Option<PathBuf> {
     std::env::var_os("XDG_CACHE_HOME")
         .and_then(|s| {
             if s.is_empty() {
@@ -205,16 +751,174 @@ fn cache_dir() -> Option<PathBuf> {
         .map(|p| p.join("rpmoci"))
 }
 
-/// Initialize the dnf.Base object with the repositories configured in the rpmoci.toml
-/// The Base object also initializes and configures any system defined plugins
+/// Defaults applied to dnf's main configuration, to avoid telemetry and make network behaviour predictable.
+/// These can be overridden by `contents.dnf_options`.
+const DEFAULT_DNF_OPTIONS: &[(&str, &str)] = &[
+    // Don't report usage statistics to repositories
+    ("countme", "False"),
+    // Use mirrors in the order they're listed, rather than racing them
+    ("fastestmirror", "False"),
+    // Abort downloads that stall for this many seconds, rather than dnf's 30, so that dead mirrors are skipped quickly
+    ("timeout", "10"),
+    // Abort downloads slower than this many bytes per second for `timeout` seconds, rather than dnf's 1000
+    ("minrate", "10000"),
+];
+
+/// Apply the package configuration to dnf's main configuration
+fn configure_main(conf: &Bound<PyAny>, contents: &PackageConfig) -> Result<()> {
+    for (key, value) in DEFAULT_DNF_OPTIONS {
+        conf.call_method1("_set_value", (*key, *value))?;
+    }
+    // Repositories inherit this from the main configuration unless they set it themselves
+    conf.setattr("skip_if_unavailable", contents.skip_if_unavailable)?;
+    conf.setattr("keepcache", contents.keepcache)?;
+    conf.setattr("localpkg_gpgcheck", contents.localpkg_gpgcheck)?;
+    conf.setattr("zchunk", contents.zchunk)?;
+    conf.setattr(
+        "clean_requirements_on_remove",
+        contents.clean_requirements_on_remove,
+    )?;
+    // Options are parsed from strings, as with dnf's --setopt.
+    // Like --setopt, unknown options are skipped with a warning rather than failing resolution.
+    let mut options = contents.dnf_options.iter().collect::<Vec<_>>();
+    options.sort();
+    for (key, value) in options {
+        if !conf.hasattr(key.as_str())? {
+            write::warning(
+                "Warning",
+                format!("unknown dnf option `{}` in dnf_options is ignored", key),
+            )?;
+            continue;
+        }
+        conf.call_method1("_set_value", (key, value))
+            .with_context(|| format!("Failed to set dnf option `{}`", key))?;
+    }
+    if let Some(module_platform_id) = &contents.module_platform_id {
+        conf.setattr("module_platform_id", module_platform_id)?;
+    }
+    if let Some(arch) = &contents.arch {
+        // As dnf's --forcearch does. The sack is created for the `arch` substitution
+        let basearch = import_module(conf.py(), "dnf.rpm")?
+            .getattr("basearch")?
+            .call1((arch,))?;
+        let substitutions = conf.getattr("substitutions")?;
+        substitutions.set_item("arch", arch)?;
+        substitutions.set_item("basearch", basearch)?;
+        conf.setattr("ignorearch", true)?;
+    }
+    Ok(())
+}
+
+/// Detect the module platform id from the `PLATFORM_ID` in the os-release file under `root`, as dnf does
+fn detect_module_platform_id(root: &Path) -> Option<String> {
+    ["etc/os-release", "usr/lib/os-release"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(root.join(path)).ok())?
+        .lines()
+        .find_map(|line| line.strip_prefix("PLATFORM_ID="))
+        .map(|value| {
+            value
+                .trim()
+                .trim_matches(|c| c == '"' || c == '\'')
+                .to_string()
+        })
+        .filter(|value| !value.is_empty())
+}
+
+/// Initialize the dnf.Base object with the repositories configured in the rpmoci.toml, and load their metadata.
+/// The Base object also initializes and configures any system defined plugins.
+/// Metadata expiry is decided relative to the time given by `clock`.
 pub(crate) fn setup_base<'a>(
     py: Python<'a>,
-    repositories: &[Repository],
-    gpgkeys: &[Url],
+    contents: &PackageConfig,
+    clock: &Clock,
 ) -> Result<Base<'a>> {
-    let dnf = PyModule::import_bound(py, "dnf")?;
+    let mut base = configure_base(py, contents)?;
+    let conf = base.getattr("conf")?;
+
+    let mut enabled_repos = Vec::new();
+    let system_now = Utc::now();
+    for repo in base
+        .getattr("repos")?
+        .call_method0("iter_enabled")?
+        .iter()?
+    {
+        let repo = repo?;
+        if *clock != Clock::System {
+            let metadata_expire = repo.getattr("metadata_expire")?.extract::<i64>()?;
+            repo.setattr(
+                "metadata_expire",
+                clock.metadata_expire(system_now, metadata_expire),
+            )?;
+        }
+        let repo_id = repo.getattr("id")?.extract::<String>()?;
+        if !contents.host_keyring {
+            check_gpgkey_configured(&repo_id, &repo)?;
+        }
+        enabled_repos.push(repo_id);
+    }
+    // libdnf caches each repository's parsed metadata in a solv file, which it reuses while the
+    // checksum of the repository's repomd.xml is unchanged, so is only rewritten for new metadata
+    let cachedir = PathBuf::from(conf.getattr("cachedir")?.extract::<String>()?);
+    let solv_stamps = enabled_repos
+        .iter()
+        .map(|repo_id| solv_file_stamp(&cachedir, repo_id))
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    base.call_method(
+        "fill_sack",
+        (),
+        Some(&[("load_system_repo", false)].into_py_dict_bound(py)),
+    )
+    .map_err(|err| explain_zchunk_error(err.into(), contents.zchunk))?;
+    base.timings.metadata_load = start.elapsed();
+    for (repo_id, stamp) in enabled_repos.iter().zip(solv_stamps) {
+        if stamp.is_some() && stamp == solv_file_stamp(&cachedir, repo_id) {
+            info!(
+                "loaded repository `{}` from cached solv file, as its metadata{} is unchanged",
+                repo_id,
+                repo_revision(&base.getattr("repos")?.get_item(repo_id)?)
+                    .map(|revision| format!(" revision `{}`", revision))
+                    .unwrap_or_default()
+            );
+            base.cached_repos.insert(repo_id.clone());
+        }
+    }
+
+    // dnf disables repositories that it skips as they were unavailable
+    for repo_id in enabled_repos {
+        let repo = base.getattr("repos")?.get_item(&repo_id)?;
+        if !repo.getattr("enabled")?.extract::<bool>()? {
+            write::warning(
+                "Skipping",
+                format!("repository `{}` as it is unavailable", repo_id),
+            )?;
+            base.skipped_repos.insert(repo_id);
+        }
+    }
+    Ok(base)
+}
+
+/// Initialize the dnf.Base object with the repositories configured in the rpmoci.toml, without loading their metadata.
+/// The Base object also initializes and configures any system defined plugins.
+pub(crate) fn configure_base<'a>(py: Python<'a>, contents: &PackageConfig) -> Result<Base<'a>> {
+    check_unique_repo_ids(contents)?;
+    check_url_schemes(contents)?;
+    let repositories = &contents.repositories;
+    let gpgkeys = &contents.gpgkeys;
+    let dnf = import_module(py, "dnf")?;
     let base = dnf.getattr("Base")?.call0()?;
     let conf = base.getattr("conf")?;
+    configure_main(&conf, contents)?;
+    // Record the module platform id, detecting it as dnf would if it isn't configured
+    if conf.getattr("module_platform_id")?.is_none() {
+        if let Some(id) = detect_module_platform_id(Path::new("/")) {
+            conf.setattr("module_platform_id", id)?;
+        }
+    }
+    let module_platform_id = conf
+        .getattr("module_platform_id")?
+        .extract::<Option<String>>()?;
 
     // To support running in a user namespace override the cache and log directories
     // as dnf will choose directories only root can write to.
@@ -247,6 +951,19 @@ pub(crate) fn setup_base<'a>(
         repos.call_method0("all")?.call_method0("disable")?;
         // Enable the configured ones
         for repo_id in existing_repos {
+            if is_repo_id_glob(repo_id) {
+                let matching = repos.call_method1("get_matching", (repo_id,))?;
+                if matching.len()? == 0 {
+                    bail!(
+                        "No repositories in system config match the pattern '{}'",
+                        repo_id
+                    );
+                }
+                for repo in matching.iter()? {
+                    repo?.call_method0("enable")?;
+                }
+                continue;
+            }
             repos
                 .get_item(repo_id)
                 .map_err(|_| {
@@ -260,7 +977,14 @@ pub(crate) fn setup_base<'a>(
     }
 
     // Now configure any repositories defined by URL/definition
+    let mut client_certificates = Vec::new();
     for repo in repositories {
+        let Some(url) = base_url(repo)? else {
+            continue;
+        };
+        check_url_scheme(&url, contents.allow_insecure_http)
+            .with_context(|| format!("Invalid URL for repository `{}`", repo.repo_id()))?;
+        let client_certificate = repo_client_certificate(&repo.repo_id(), &process_env)?;
         let args = PyTuple::new_bound(
             py,
             &[
@@ -269,16 +993,17 @@ pub(crate) fn setup_base<'a>(
             ],
         );
         match &repo {
-            Repository::Url(url) => {
+            Repository::Url(_) => {
                 base.getattr("repos")?.call_method(
                     "add_new_repo",
                     args,
                     Some(&repo_kwargs(
-                        url,
-                        &HashMap::new(),
+                        &url,
+                        None,
                         gpgkeys,
-                        repo_username(&repo.repo_id()),
-                        repo_password(&repo.repo_id()),
+                        repo_username(&repo.repo_id(), &process_env),
+                        repo_password(&repo.repo_id(), &process_env),
+                        client_certificate.as_ref(),
                         py,
                     )),
                 )?;
@@ -289,26 +1014,130 @@ pub(crate) fn setup_base<'a>(
                     "add_new_repo",
                     args,
                     Some(&repo_kwargs(
-                        &definition.url,
-                        &definition.options,
+                        &url,
+                        Some(definition),
                         gpgkeys,
-                        repo_username(&repo.repo_id()),
-                        repo_password(&repo.repo_id()),
+                        repo_username(&repo.repo_id(), &process_env),
+                        repo_password(&repo.repo_id(), &process_env),
+                        client_certificate.as_ref(),
                         py,
                     )),
                 )?;
             }
         }
+        client_certificates.extend(client_certificate);
     }
 
     base.call_method0("configure_plugins")?;
 
-    base.call_method(
-        "fill_sack",
-        (),
-        Some(&[("load_system_repo", false)].into_py_dict_bound(py)),
-    )?;
-    Ok(Base { value: base })
+    Ok(Base {
+        value: base,
+        skipped_repos: BTreeSet::new(),
+        timings: Timings::default(),
+        cached_repos: BTreeSet::new(),
+        module_platform_id,
+        _client_certificates: client_certificates,
+    })
+}
+
+/// Add a hint to errors loading zchunk metadata, which can be caused by mirrors that serve incomplete or
+/// out of date `.zck` files alongside their gzip or xz compressed metadata
+fn explain_zchunk_error(err: anyhow::Error, zchunk: bool) -> anyhow::Error {
+    let message = err.to_string().to_lowercase();
+    if zchunk && (message.contains("zchunk") || message.contains(".zck")) {
+        err.context("Failed to load zchunk compressed repository metadata. If a mirror doesn't serve it correctly, set `contents.zchunk = false` to use the gzip or xz compressed metadata instead")
+    } else {
+        err
+    }
+}
+
+/// Whether a repository id from the configuration is a glob pattern matching system repositories, e.g `epel*`
+fn is_repo_id_glob(repo_id: &str) -> bool {
+    repo_id.contains(['*', '?', '['])
+}
+
+/// Check that a repository with gpgcheck enabled has a key to verify packages with,
+/// as otherwise builds only fail once the downloaded packages are verified.
+/// This isn't checked when packages are verified with the host's rpm keyring.
+fn check_gpgkey_configured(repo_id: &str, repo: &Bound<PyAny>) -> Result<()> {
+    let gpgcheck = repo.getattr("gpgcheck")?.extract::<bool>()?;
+    let gpgkeys = repo.getattr("gpgkey")?.extract::<Vec<String>>()?;
+    if gpgcheck && gpgkeys.iter().all(|key| key.trim().is_empty()) {
+        bail!(
+            "gpgcheck enabled for repo `{}` but no gpgkey configured. Add its key to `contents.gpgkeys` or the repository's `gpgkey` option, set `gpgcheck = \"False\"` in its options, or build with `--no-gpgkey-import` to verify its packages with the host's rpm keyring",
+            repo_id
+        );
+    }
+    Ok(())
+}
+
+/// The modification time and size of the solv file libdnf caches a repository's metadata in, if there is one
+fn solv_file_stamp(cachedir: &Path, repo_id: &str) -> Option<(std::time::SystemTime, u64)> {
+    let metadata = std::fs::metadata(cachedir.join(format!("{}.solv", repo_id))).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// The revision in a loaded repository's repomd.xml, if libdnf exposes it
+fn repo_revision(repo: &Bound<PyAny>) -> Option<String> {
+    repo.getattr("_repo")
+        .and_then(|repo| repo.call_method0("getRevision"))
+        .and_then(|revision| revision.extract::<String>())
+        .ok()
+        .filter(|revision| !revision.is_empty())
+}
+
+/// The base URL to use for a repository, running its `url_command` if it has one.
+/// Returns `None` for repositories specified by id.
+pub(crate) fn base_url(repo: &Repository) -> Result<Option<Url>> {
+    match repo {
+        Repository::Url(url) => Ok(Some(url.clone())),
+        Repository::Id(_) => Ok(None),
+        Repository::Definition(definition) => match &definition.url_command {
+            Some(command) => Ok(Some(run_url_command(
+                &repo.repo_id(),
+                &definition.url,
+                command,
+            )?)),
+            None => Ok(Some(definition.url.clone())),
+        },
+    }
+}
+
+/// Run a repository's `url_command` to get the base URL to use for the repository.
+///
+/// The command is passed the repository id and configured URL via the `RPMOCI_REPO_ID` and
+/// `RPMOCI_REPO_URL` environment variables, and must print the URL to stdout.
+fn run_url_command(repo_id: &str, url: &Url, command: &[String]) -> Result<Url> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("The url_command of repository `{}` is empty", repo_id);
+    };
+    let output = Command::new(program)
+        .args(args)
+        .env("RPMOCI_REPO_ID", repo_id)
+        .env("RPMOCI_REPO_URL", url.as_str())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to run url_command of repository `{}`", repo_id))?;
+    if !output.status.success() {
+        bail!(
+            "The url_command of repository `{}` failed: {}",
+            repo_id,
+            output.status
+        );
+    }
+    // The URL may contain credentials, so isn't included in errors
+    let url = String::from_utf8(output.stdout).with_context(|| {
+        format!(
+            "The url_command of repository `{}` printed invalid UTF-8",
+            repo_id
+        )
+    })?;
+    Url::parse(url.trim()).with_context(|| {
+        format!(
+            "The url_command of repository `{}` printed an invalid URL",
+            repo_id
+        )
+    })
 }
 
 fn default_repo_options() -> HashMap<String, String> {
@@ -318,16 +1147,14 @@ fn default_repo_options() -> HashMap<String, String> {
     options
 }
 
-pub(crate) fn repo_kwargs<'p>(
-    repo_url: &Url,
-    repo_options: &HashMap<String, String>,
+/// The options of a repository, merged with the defaults and global GPG keys
+fn repo_options(
+    definition: Option<&RepositoryDefinition>,
     gpgkeys: &[Url],
-    username: Option<String>,
-    password: Option<String>,
-    py: Python<'p>,
-) -> Bound<'p, PyDict> {
-    let mut kwargs = Vec::new();
-    let mut default_repo_options = default_repo_options();
+) -> BTreeMap<String, String> {
+    let mut options = default_repo_options()
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
 
     // Global keys need to be merged with the repo definition keys in case
     // they are needed for repo metadata verification
@@ -337,25 +1164,95 @@ pub(crate) fn repo_kwargs<'p>(
         .collect::<Vec<_>>()
         .join(" ");
     // If the repo definition specified gpgkey, this option won't be used
-    default_repo_options.insert("gpgkey".to_string(), global_gpgkeys.clone());
-
-    kwargs.push((
-        "baseurl".to_string(),
-        [PyString::new_bound(py, repo_url.as_ref())].to_object(py),
-    ));
+    options.insert("gpgkey".to_string(), global_gpgkeys.clone());
 
-    for (key, val) in repo_options {
+    for (key, val) in definition.iter().flat_map(|definition| &definition.options) {
         // If the repo definition specified gpgkey, add the global keys to it
         let val = if key == "gpgkey" {
-            (val.to_owned() + " " + &global_gpgkeys).to_object(py)
+            val.to_owned() + " " + &global_gpgkeys
         } else {
-            val.to_object(py)
+            val.to_owned()
         };
-        kwargs.push((key.to_string(), val));
-        default_repo_options.remove(key);
+        options.insert(key.to_string(), val);
+    }
+    options
+}
+
+/// The value of a dnf option set by one of a repository definition's fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DefinitionOption {
+    Bool(bool),
+    Number(u32),
+}
+
+impl ToPyObject for DefinitionOption {
+    fn to_object(&self, py: Python<'_>) -> PyObject {
+        match self {
+            DefinitionOption::Bool(val) => val.to_object(py),
+            DefinitionOption::Number(val) => val.to_object(py),
+        }
+    }
+}
+
+impl std::fmt::Display for DefinitionOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DefinitionOption::Bool(val) => val.fmt(f),
+            DefinitionOption::Number(val) => val.fmt(f),
+        }
     }
+}
+
+/// The dnf options set by a repository definition's fields, which override the same keys in its `options`
+fn definition_options(
+    definition: Option<&RepositoryDefinition>,
+) -> Vec<(&'static str, DefinitionOption)> {
+    let Some(definition) = definition else {
+        return Vec::new();
+    };
+    let bool_option = |key, val: Option<bool>| val.map(|val| (key, DefinitionOption::Bool(val)));
+    let number_option = |key, val: Option<u32>| val.map(|val| (key, DefinitionOption::Number(val)));
+    [
+        bool_option("skip_if_unavailable", definition.skip_if_unavailable),
+        // The repository metadata signature is verified using the same keys as packages
+        bool_option("repo_gpgcheck", definition.repo_gpgcheck),
+        // Download timeout, minimum rate and retries override dnf's global defaults
+        number_option("timeout", definition.timeout),
+        number_option("minrate", definition.minrate),
+        number_option("retries", definition.retries),
+        // dnf doesn't implement yum's failovermethod, but orders mirrors by latency when fastestmirror is set
+        bool_option(
+            "fastestmirror",
+            definition
+                .failovermethod
+                .map(|failovermethod| failovermethod.fastestmirror()),
+        ),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
 
-    for (key, val) in &default_repo_options {
+/// The keyword arguments used to add a repository to dnf.
+/// `definition` is the repository's definition, if it isn't just specified by URL.
+pub(crate) fn repo_kwargs<'p>(
+    repo_url: &Url,
+    definition: Option<&RepositoryDefinition>,
+    gpgkeys: &[Url],
+    username: Option<String>,
+    password: Option<String>,
+    client_certificate: Option<&ClientCertificate>,
+    py: Python<'p>,
+) -> Bound<'p, PyDict> {
+    let mut kwargs = Vec::new();
+    kwargs.push((
+        "baseurl".to_string(),
+        [PyString::new_bound(py, repo_url.as_ref())].to_object(py),
+    ));
+    for (key, val) in repo_options(definition, gpgkeys) {
+        kwargs.push((key, val.to_object(py)));
+    }
+    for (key, val) in definition_options(definition) {
         kwargs.push((key.to_string(), val.to_object(py)));
     }
 
@@ -368,38 +1265,1525 @@ pub(crate) fn repo_kwargs<'p>(
         debug!("using password from environment");
         kwargs.push(("password".to_string(), password.to_object(py)));
     }
+    if let Some(client_certificate) = client_certificate {
+        debug!("using client certificate from environment");
+        kwargs.push((
+            "sslclientcert".to_string(),
+            client_certificate.cert.path().to_object(py),
+        ));
+        if let Some(key) = &client_certificate.key {
+            kwargs.push(("sslclientkey".to_string(), key.path().to_object(py)));
+        }
+    }
 
     kwargs.into_py_dict_bound(py)
 }
 
-fn repo_username(repo_id: &str) -> Option<String> {
-    env::var(format!(
-        "RPMOCI_{}_HTTP_USERNAME",
-        repo_id.to_ascii_uppercase()
-    ))
-    .ok()
+/// Render the repositories defined by URL as a dnf .repo file, so that dnf can be run against them directly.
+///
+/// Repositories specified by id are already defined in the system's configuration, so are only noted in comments.
+/// Credentials from the environment aren't included.
+pub(crate) fn repo_file(contents: &PackageConfig, env: Env) -> String {
+    let mut file = String::new();
+    for repo in &contents.repositories {
+        let repo_id = repo.repo_id();
+        let (url, definition) = match repo {
+            Repository::Id(_) => {
+                file.push_str(&format!(
+                    "# Repository `{}` is defined by the system configuration\n\n",
+                    repo_id
+                ));
+                continue;
+            }
+            Repository::Url(url) => (url, None),
+            Repository::Definition(definition) => (&definition.url, Some(definition)),
+        };
+        file.push_str(&format!("[{}]\nname={}\n", repo_id, repo_id));
+        if definition.is_some_and(|definition| definition.url_command.is_some()) {
+            file.push_str("# rpmoci runs the repository's url_command to get the URL to use\n");
+        }
+        if repo_env_var(&repo_id, "HTTP_USERNAME", env).is_some()
+            || repo_env_var(&repo_id, "SSL_CLIENT_CERT_PEM", env).is_some()
+        {
+            file.push_str("# Credentials from the environment are omitted\n");
+        }
+        file.push_str(&format!("baseurl={}\n", url));
+        let mut options = repo_options(definition, &contents.gpgkeys);
+        for (key, val) in definition_options(definition) {
+            options.insert(key.to_string(), val.to_string());
+        }
+        for (key, val) in options {
+            file.push_str(&format!("{}={}\n", key, val.trim()));
+        }
+        file.push('\n');
+    }
+    file
 }
 
-fn repo_password(repo_id: &str) -> Option<String> {
-    env::var(format!(
-        "RPMOCI_{}_HTTP_PASSWORD",
-        repo_id.to_ascii_uppercase()
-    ))
-    .ok()
+/// Fail if multiple repositories have the same id, as dnf would silently use only one of them
+pub(crate) fn check_unique_repo_ids(contents: &PackageConfig) -> Result<()> {
+    let mut ids = BTreeSet::new();
+    for repo in &contents.repositories {
+        let id = repo.repo_id();
+        if !ids.insert(id.clone()) {
+            bail!("Multiple repositories have the id `{}`", id);
+        }
+    }
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{collections::HashMap, str::FromStr};
+/// URL schemes that repositories and GPG keys can always be fetched with
+const SECURE_URL_SCHEMES: [&str; 2] = ["https", "file"];
 
-    use url::Url;
+/// Fail if a URL uses a scheme other than `https` or `file`, or `http` if `allow_insecure_http` is set.
+/// The URL isn't included in errors as it may contain credentials.
+pub(crate) fn check_url_scheme(url: &Url, allow_insecure_http: bool) -> Result<()> {
+    match url.scheme() {
+        scheme if SECURE_URL_SCHEMES.contains(&scheme) => Ok(()),
+        "http" if allow_insecure_http => Ok(()),
+        "http" => bail!(
+            "`http` URLs are insecure, use `https` or set `contents.allow_insecure_http = true`"
+        ),
+        scheme => bail!(
+            "`{}` URLs aren't supported, use `https` or `file` URLs",
+            scheme
+        ),
+    }
+}
 
-    use crate::{
-        config::{Repository, RepositoryDefinition},
-        lockfile::Lockfile,
+/// Fail if any of the configured repository, mirror list, metalink or GPG key URLs use a disallowed scheme.
+/// URLs printed by a repository's `url_command` are checked when the command is run.
+pub(crate) fn check_url_schemes(contents: &PackageConfig) -> Result<()> {
+    let check = |url: &Url, what: &str| {
+        check_url_scheme(url, contents.allow_insecure_http).with_context(|| what.to_string())
     };
+    for key in &contents.gpgkeys {
+        check(key, "Invalid GPG key URL in `contents.gpgkeys`")?;
+    }
+    for repo in &contents.repositories {
+        let repo_id = repo.repo_id();
+        let (url, options) = match repo {
+            Repository::Id(_) => continue,
+            Repository::Url(url) => (url, None),
+            Repository::Definition(definition) => (&definition.url, Some(&definition.options)),
+        };
+        check(url, &format!("Invalid URL for repository `{}`", repo_id))?;
+        for (key, value) in options.into_iter().flatten() {
+            let urls = match key.as_str() {
+                "mirrorlist" | "metalink" => vec![value.as_str()],
+                // dnf accepts whitespace or comma separated lists of keys
+                "gpgkey" => value
+                    .split(|c: char| c.is_whitespace() || c == ',')
+                    .filter(|url| !url.is_empty())
+                    .collect(),
+                _ => continue,
+            };
+            for url in urls {
+                let what = format!("Invalid `{}` URL for repository `{}`", key, repo_id);
+                let url = Url::parse(url).with_context(|| what.clone())?;
+                check(&url, &what)?;
+            }
+        }
+    }
+    Ok(())
+}
 
-    #[test]
+/// Read a per-repository environment variable, `RPMOCI_<ID>_<suffix>`.
+///
+/// `<ID>` is the uppercased repo id with any characters that aren't ASCII alphanumerics
+/// replaced by underscores, so that ids like `my-repo.x86_64` map to variables that can be set in a shell.
+/// The uppercased repo id is also checked, for compatibility.
+fn repo_env_var(repo_id: &str, suffix: &str, env: Env) -> Option<String> {
+    let normalized = repo_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    env(&format!("RPMOCI_{}_{}", normalized, suffix))
+        .or_else(|_| {
+            env(&format!(
+                "RPMOCI_{}_{}",
+                repo_id.to_ascii_uppercase(),
+                suffix
+            ))
+        })
+        .ok()
+}
+
+pub(crate) fn repo_username(repo_id: &str, env: Env) -> Option<String> {
+    repo_env_var(repo_id, "HTTP_USERNAME", env)
+}
+
+pub(crate) fn repo_password(repo_id: &str, env: Env) -> Option<String> {
+    repo_env_var(repo_id, "HTTP_PASSWORD", env)
+}
+
+/// A TLS client certificate, and optionally its key, written to temporary files.
+/// The files are removed when this is dropped.
+#[derive(Debug)]
+pub(crate) struct ClientCertificate {
+    cert: NamedTempFile,
+    key: Option<NamedTempFile>,
+}
+
+/// Write a PEM encoded value to a temporary file that only the current user can read
+fn write_pem(pem: &str) -> Result<NamedTempFile> {
+    let mut file = tempfile::Builder::new()
+        .suffix(".pem")
+        .permissions(std::fs::Permissions::from_mode(0o600))
+        .tempfile()?;
+    file.write_all(pem.as_bytes())?;
+    file.flush()?;
+    Ok(file)
+}
+
+/// The TLS client certificate of a repository, if its PEM encoded contents are given by
+/// the `RPMOCI_<ID>_SSL_CLIENT_CERT_PEM` and `RPMOCI_<ID>_SSL_CLIENT_KEY_PEM` environment variables.
+pub(crate) fn repo_client_certificate(
+    repo_id: &str,
+    env: Env,
+) -> Result<Option<ClientCertificate>> {
+    let key = repo_env_var(repo_id, "SSL_CLIENT_KEY_PEM", env);
+    let Some(cert) = repo_env_var(repo_id, "SSL_CLIENT_CERT_PEM", env) else {
+        if key.is_some() {
+            bail!(
+                "A client key is set in the environment for repository `{}`, but no client certificate",
+                repo_id
+            );
+        }
+        return Ok(None);
+    };
+    let context = || {
+        format!(
+            "Failed to write client certificate of repository `{}`",
+            repo_id
+        )
+    };
+    Ok(Some(ClientCertificate {
+        cert: write_pem(&cert).with_context(context)?,
+        key: key
+            .map(|key| write_pem(&key))
+            .transpose()
+            .with_context(context)?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, BTreeSet, HashMap},
+        os::unix::fs::PermissionsExt,
+        path::PathBuf,
+        str::FromStr,
+        time::Duration,
+    };
+
+    use chrono::{DateTime, Utc};
+    use pyo3::prelude::*;
+    use url::Url;
+
+    use super::{
+        call_capturing_output, check_unique_repo_ids, check_url_scheme, check_url_schemes,
+        configure_main, detect_module_platform_id, explain_zchunk_error, extend_sys_path,
+        group_by_repositories, import_module, interpreter_sys_path, repo_client_certificate,
+        repo_file, repo_kwargs, repo_password, repo_username, run_url_command, setup_base,
+        unminted_download_url, verify_key_fingerprints, verify_os_release_provider, Clock,
+        MissingPythonModule,
+    };
+    use crate::{
+        config::{
+            Config, FailoverMethod, OsReleaseProvider, PackageConfig, Repository,
+            RepositoryDefinition, WeakDependencies,
+        },
+        lockfile::{Lockfile, Package},
+    };
+
+    const MARINER_GPGKEY: &str = "https://raw.githubusercontent.com/microsoft/CBL-Mariner/2.0/SPECS/mariner-repos/MICROSOFT-RPM-GPG-KEY";
+
+    /// A repository definition with just the given URL, to be completed with struct update syntax
+    fn repository_definition(url: Url) -> RepositoryDefinition {
+        RepositoryDefinition {
+            id: None,
+            url,
+            options: HashMap::new(),
+            skip_if_unavailable: None,
+            repo_gpgcheck: None,
+            url_command: None,
+            timeout: None,
+            minrate: None,
+            retries: None,
+            failovermethod: None,
+        }
+    }
+
+    #[test]
+    fn test_interpreter_sys_path() {
+        // Use a stub interpreter that reports a directory containing a stub module
+        let dir = tempfile::tempdir().unwrap();
+        let modules = dir.path().join("modules");
+        std::fs::create_dir(&modules).unwrap();
+        std::fs::write(modules.join("rpmoci_test_stub.py"), "VALUE = 42\n").unwrap();
+        let python = dir.path().join("python");
+        std::fs::write(
+            &python,
+            format!("#!/bin/sh\necho '[\"\", \"{}\"]'\n", modules.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&python, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+
+        let entries = interpreter_sys_path(&python).unwrap();
+        assert_eq!(entries, vec![String::new(), modules.display().to_string()]);
+        Python::with_gil(|py| {
+            extend_sys_path(py, &entries).unwrap();
+            // Extending is idempotent
+            extend_sys_path(py, &entries).unwrap();
+            let sys_path = py.import_bound("sys").unwrap().getattr("path").unwrap();
+            let sys_path: Vec<String> = sys_path.extract().unwrap();
+            assert_eq!(
+                sys_path
+                    .iter()
+                    .filter(|p| **p == modules.display().to_string())
+                    .count(),
+                1
+            );
+
+            let value: i32 = import_module(py, "rpmoci_test_stub")
+                .unwrap()
+                .getattr("VALUE")
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(value, 42);
+
+            py.import_bound("sys")
+                .unwrap()
+                .getattr("path")
+                .unwrap()
+                .call_method1("remove", (modules.display().to_string(),))
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_missing_python_module() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("rpmoci_test_wrapper.py"),
+            "import rpmoci_test_missing\n",
+        )
+        .unwrap();
+        Python::with_gil(|py| {
+            let sys_path = py.import_bound("sys").unwrap().getattr("path").unwrap();
+            sys_path.call_method1("append", (dir.path(),)).unwrap();
+
+            let err = import_module(py, "rpmoci_test_missing").unwrap_err();
+            let missing = err.downcast_ref::<MissingPythonModule>().unwrap();
+            assert_eq!(missing.module, "rpmoci_test_missing");
+            assert!(err
+                .to_string()
+                .contains("not installed or not on PYTHONPATH"));
+
+            // Modules missing dependencies are reported by the name of the dependency
+            let err = import_module(py, "rpmoci_test_wrapper").unwrap_err();
+            let missing = err.downcast_ref::<MissingPythonModule>().unwrap();
+            assert_eq!(missing.module, "rpmoci_test_missing");
+
+            sys_path.call_method1("remove", (dir.path(),)).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_repo_kwargs_skip_if_unavailable() {
+        let url = Url::from_str("https://example.com/repo").unwrap();
+        let definition = RepositoryDefinition {
+            skip_if_unavailable: Some(true),
+            ..repository_definition(url.clone())
+        };
+        Python::with_gil(|py| {
+            let kwargs = repo_kwargs(&url, Some(&definition), &[], None, None, None, py);
+            let skip: bool = kwargs
+                .get_item("skip_if_unavailable")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert!(skip);
+
+            // When unset, the repository inherits the global default
+            let kwargs = repo_kwargs(&url, None, &[], None, None, None, py);
+            assert!(kwargs.get_item("skip_if_unavailable").unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_repo_kwargs_network_options() {
+        let url = Url::from_str("https://example.com/repo").unwrap();
+        let definition = RepositoryDefinition {
+            timeout: Some(120),
+            minrate: Some(100),
+            retries: Some(20),
+            ..repository_definition(url.clone())
+        };
+        Python::with_gil(|py| {
+            let kwargs = repo_kwargs(&url, Some(&definition), &[], None, None, None, py);
+            let option =
+                |name: &str| -> u32 { kwargs.get_item(name).unwrap().unwrap().extract().unwrap() };
+            assert_eq!(option("timeout"), 120);
+            assert_eq!(option("minrate"), 100);
+            assert_eq!(option("retries"), 20);
+
+            // When unset, the repository inherits the global defaults
+            let kwargs = repo_kwargs(&url, None, &[], None, None, None, py);
+            for name in ["timeout", "minrate", "retries"] {
+                assert!(kwargs.get_item(name).unwrap().is_none());
+            }
+        });
+    }
+
+    #[test]
+    fn test_repo_kwargs_failovermethod() {
+        let url = Url::from_str("https://example.com/repo").unwrap();
+        let definition = |failovermethod| RepositoryDefinition {
+            failovermethod,
+            ..repository_definition(url.clone())
+        };
+        Python::with_gil(|py| {
+            for (failovermethod, fastestmirror) in [
+                (FailoverMethod::Priority, false),
+                (FailoverMethod::Fastest, true),
+            ] {
+                let definition = definition(Some(failovermethod));
+                let kwargs = repo_kwargs(&url, Some(&definition), &[], None, None, None, py);
+                let value: bool = kwargs
+                    .get_item("fastestmirror")
+                    .unwrap()
+                    .unwrap()
+                    .extract()
+                    .unwrap();
+                assert_eq!(value, fastestmirror);
+            }
+
+            // When unset, the repository inherits the global default
+            let kwargs = repo_kwargs(&url, Some(&definition(None)), &[], None, None, None, py);
+            assert!(kwargs.get_item("fastestmirror").unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_mirror_failover() {
+        // Both the base URL and the first mirror are unreachable, so metadata is loaded from the second mirror
+        let dir = tempfile::tempdir().unwrap();
+        let mirrorlist = dir.path().join("mirrors");
+        std::fs::write(
+            &mirrorlist,
+            "https://127.0.0.1:1/dead\nhttps://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64\n",
+        )
+        .unwrap();
+        let contents = PackageConfig {
+            repositories: vec![Repository::Definition(RepositoryDefinition {
+                id: Some("mirrored".to_string()),
+                options: HashMap::from([(
+                    "mirrorlist".to_string(),
+                    Url::from_file_path(&mirrorlist).unwrap().to_string(),
+                )]),
+                timeout: Some(5),
+                retries: Some(1),
+                failovermethod: Some(FailoverMethod::Priority),
+                ..repository_definition(Url::from_str("https://127.0.0.1:1/dead").unwrap())
+            })],
+            gpgkeys: vec![Url::from_str(MARINER_GPGKEY).unwrap()],
+            ..Default::default()
+        };
+        Python::with_gil(|py| {
+            let mut base = setup_base(py, &contents, &Clock::System).unwrap();
+            let lockfile = Lockfile::resolve_with_base(
+                py,
+                &mut base,
+                vec!["zlib".to_string()],
+                &contents,
+                &[],
+                &[],
+            )
+            .unwrap();
+            assert!(lockfile.packages.iter().any(|p| p.name == "zlib"));
+        });
+    }
+
+    #[test]
+    fn test_repo_kwargs_repo_gpgcheck() {
+        let url = Url::from_str("https://example.com/repo").unwrap();
+        let key = Url::from_str("https://example.com/key.asc").unwrap();
+        let definition = RepositoryDefinition {
+            repo_gpgcheck: Some(true),
+            ..repository_definition(url.clone())
+        };
+        Python::with_gil(|py| {
+            let kwargs = repo_kwargs(&url, Some(&definition), &[key], None, None, None, py);
+            let repo_gpgcheck: bool = kwargs
+                .get_item("repo_gpgcheck")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert!(repo_gpgcheck);
+            // The metadata is verified with the configured keys
+            let gpgkey: String = kwargs
+                .get_item("gpgkey")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(gpgkey, "https://example.com/key.asc");
+
+            // Off by default
+            let kwargs = repo_kwargs(&url, None, &[], None, None, None, py);
+            assert!(kwargs.get_item("repo_gpgcheck").unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_group_by_repositories() {
+        let cfg = |repositories: &str, packages: &str| -> Config {
+            toml::from_str(&format!(
+                "[contents]\nrepositories = {}\npackages = {}",
+                repositories, packages
+            ))
+            .unwrap()
+        };
+        let cfgs = [
+            cfg(r#"["https://example.com/a"]"#, r#"["foo"]"#),
+            cfg(r#"["https://example.com/b"]"#, r#"["foo"]"#),
+            cfg(r#"["https://example.com/a"]"#, r#"["bar"]"#),
+            cfg(r#"["https://example.com/a"]"#, r#"["bar", "local.rpm"]"#),
+            cfg(r#"["https://example.com/a"]"#, r#"["baz", "local.rpm"]"#),
+            cfg(r#"["https://example.com/b"]"#, r#"["baz"]"#),
+            cfg(
+                r#"["https://example.com/a"]"#,
+                "[\"foo\"]\nexclude = [\"qux\"]",
+            ),
+            cfg(
+                r#"["https://example.com/a"]"#,
+                "[\"foo\"]\ndnf_options = { installonly_limit = \"2\" }",
+            ),
+            cfg(
+                r#"["https://example.com/a"]"#,
+                "[\"bar\"]\ndnf_options = { installonly_limit = \"2\" }",
+            ),
+        ];
+        assert_eq!(
+            group_by_repositories(&cfgs).unwrap(),
+            vec![
+                vec![0, 2],
+                vec![1, 5],
+                vec![3],
+                vec![4],
+                vec![6],
+                vec![7, 8]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timings() {
+        let contents = PackageConfig {
+            repositories: vec![Repository::Url(
+                Url::from_str("https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64")
+                    .unwrap(),
+            )],
+            gpgkeys: vec![Url::from_str(MARINER_GPGKEY).unwrap()],
+            ..Default::default()
+        };
+        Python::with_gil(|py| {
+            let mut base = setup_base(py, &contents, &Clock::System).unwrap();
+            assert!(base.timings.metadata_load > Duration::ZERO);
+            Lockfile::resolve_with_base(
+                py,
+                &mut base,
+                vec!["zlib".to_string()],
+                &contents,
+                &[],
+                &[],
+            )
+            .unwrap();
+            assert!(base.timings.depsolve > Duration::ZERO);
+        });
+    }
+
+    #[test]
+    fn test_resolve_from_configs_shares_base() {
+        let cfg = |packages: &str| -> Config {
+            toml::from_str(&format!(
+                "[contents]\nrepositories = [\"https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64\"]\ngpgkeys = [\"{}\"]\npackages = {}",
+                MARINER_GPGKEY, packages
+            ))
+            .unwrap()
+        };
+        let cfgs = [cfg(r#"["zlib"]"#), cfg(r#"["bzip2-libs"]"#)];
+        let repo_id = cfgs[0].contents.repositories[0].repo_id();
+
+        // Both configurations are resolved with a single Base
+        let (lockfiles, cached_repos) = Lockfile::resolve_groups(&cfgs, &Clock::System).unwrap();
+        assert_eq!(cached_repos.len(), 1);
+        assert!(lockfiles[0].packages.iter().any(|pkg| pkg.name == "zlib"));
+        assert!(!lockfiles[0]
+            .packages
+            .iter()
+            .any(|pkg| pkg.name == "bzip2-libs"));
+        assert!(lockfiles[1]
+            .packages
+            .iter()
+            .any(|pkg| pkg.name == "bzip2-libs"));
+
+        // A second run loads the repository's metadata from the cache populated by the first
+        let (second, cached_repos) = Lockfile::resolve_groups(&cfgs, &Clock::System).unwrap();
+        assert_eq!(cached_repos, vec![BTreeSet::from([repo_id])]);
+        assert_eq!(second[0].packages, lockfiles[0].packages);
+        assert_eq!(second[1].packages, lockfiles[1].packages);
+    }
+
+    #[test]
+    fn test_solv_cache_is_reused() {
+        let contents = PackageConfig {
+            repositories: vec![Repository::Url(
+                Url::from_str("https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64")
+                    .unwrap(),
+            )],
+            gpgkeys: vec![Url::from_str(MARINER_GPGKEY).unwrap()],
+            ..Default::default()
+        };
+        let repo_id = contents.repositories[0].repo_id();
+        Python::with_gil(|py| {
+            // The first load populates the cache if needed, the second reuses it
+            drop(setup_base(py, &contents, &Clock::System).unwrap());
+            let mut base = setup_base(py, &contents, &Clock::System).unwrap();
+            assert!(base.cached_repos.contains(&repo_id));
+            Lockfile::resolve_with_base(
+                py,
+                &mut base,
+                vec!["zlib".to_string()],
+                &contents,
+                &[],
+                &[],
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_repo_id_globs() {
+        let reposdir = tempfile::tempdir().unwrap();
+        for id in ["foo-baseos", "bar-baseos", "epel", "epel-testing", "other"] {
+            std::fs::write(
+                reposdir.path().join(format!("{}.repo", id)),
+                format!(
+                    "[{id}]\nname={id}\nbaseurl=file:///nonexistent/{id}\ngpgcheck=0\nskip_if_unavailable=1\n"
+                ),
+            )
+            .unwrap();
+        }
+        let contents = |repositories: &[&str]| PackageConfig {
+            repositories: repositories
+                .iter()
+                .map(|id| Repository::Id(id.to_string()))
+                .collect(),
+            dnf_options: HashMap::from([(
+                "reposdir".to_string(),
+                reposdir.path().display().to_string(),
+            )]),
+            ..Default::default()
+        };
+        Python::with_gil(|py| {
+            let base = setup_base(py, &contents(&["*-baseos", "epel"]), &Clock::System).unwrap();
+            let mut enabled = base
+                .getattr("repos")
+                .unwrap()
+                .call_method0("iter_enabled")
+                .unwrap()
+                .iter()
+                .unwrap()
+                .map(|repo| {
+                    repo.unwrap()
+                        .getattr("id")
+                        .unwrap()
+                        .extract::<String>()
+                        .unwrap()
+                })
+                .collect::<Vec<_>>();
+            enabled.sort();
+            assert_eq!(enabled, vec!["bar-baseos", "epel", "foo-baseos"]);
+            drop(base);
+
+            let err = setup_base(py, &contents(&["*-appstream"]), &Clock::System)
+                .err()
+                .unwrap();
+            assert!(err
+                .to_string()
+                .contains("No repositories in system config match the pattern '*-appstream'"));
+        });
+    }
+
+    #[test]
+    fn test_gpgcheck_requires_gpgkey() {
+        let repo = |options: &[(&str, &str)]| {
+            Repository::Definition(RepositoryDefinition {
+                id: Some("unsigned".to_string()),
+                options: options
+                    .iter()
+                    .map(|(key, val)| (key.to_string(), val.to_string()))
+                    .collect(),
+                ..repository_definition(Url::from_str("https://example.com/repo").unwrap())
+            })
+        };
+        let contents = PackageConfig {
+            repositories: vec![repo(&[])],
+            ..Default::default()
+        };
+        Python::with_gil(|py| {
+            let err = setup_base(py, &contents, &Clock::System)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(
+                err.contains("gpgcheck enabled for repo `unsigned` but no gpgkey configured"),
+                "{}",
+                err
+            );
+            // The check happens before any metadata is downloaded, so other configurations fail later
+            for contents in [
+                PackageConfig {
+                    repositories: vec![repo(&[("gpgcheck", "False")])],
+                    ..Default::default()
+                },
+                PackageConfig {
+                    repositories: vec![repo(&[("gpgkey", "https://example.com/key")])],
+                    ..Default::default()
+                },
+                // Packages verified with the host's keyring don't need a key configured
+                PackageConfig {
+                    host_keyring: true,
+                    ..contents.clone()
+                },
+            ] {
+                let err = format!("{:?}", setup_base(py, &contents, &Clock::System).err());
+                assert!(!err.contains("no gpgkey configured"), "{}", err);
+            }
+        });
+    }
+
+    #[test]
+    fn test_verify_key_fingerprints() {
+        let key_url = "https://packages.microsoft.com/keys/microsoft.asc";
+        let served = [(
+            key_url.to_string(),
+            vec!["BC528686B50D79E339D3721CEB3E94ADBE1229CF".to_string()],
+        )]
+        .into_iter()
+        .collect();
+
+        // Keys served by URLs without an allowlist aren't checked
+        verify_key_fingerprints(&served, &HashMap::new()).unwrap();
+
+        let allowlist = |fingerprints: &[&str]| {
+            [(
+                Url::from_str(key_url).unwrap(),
+                fingerprints.iter().map(|f| f.to_string()).collect(),
+            )]
+            .into_iter()
+            .collect()
+        };
+        verify_key_fingerprints(
+            &served,
+            &allowlist(&["bc52 8686 b50d 79e3 39d3  721c eb3e 94ad be12 29cf"]),
+        )
+        .unwrap();
+        let err = verify_key_fingerprints(
+            &served,
+            &allowlist(&["0000000000000000000000000000000000000000"]),
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("BC528686B50D79E339D3721CEB3E94ADBE1229CF"));
+    }
+
+    #[test]
+    fn test_verify_os_release_provider() {
+        let spec_packages = [(
+            "/etc/os-release".to_string(),
+            ["mariner-release".to_string()].into_iter().collect(),
+        )]
+        .into_iter()
+        .collect();
+        let packages = [toml::from_str::<Package>(
+            r#"
+            name = "mariner-release"
+            evr = "2.0-56.cm2"
+            arch = "noarch"
+            checksum = { algorithm = "sha256", checksum = "00" }
+            repoid = "mariner"
+            "#,
+        )
+        .unwrap()]
+        .into_iter()
+        .collect();
+        let provider = |name: &str, version: Option<&str>| OsReleaseProvider {
+            name: name.to_string(),
+            version: version.map(ToString::to_string),
+        };
+
+        verify_os_release_provider(
+            &spec_packages,
+            &packages,
+            &provider("mariner-release", None),
+        )
+        .unwrap();
+        verify_os_release_provider(
+            &spec_packages,
+            &packages,
+            &provider("mariner-release", Some("2.0")),
+        )
+        .unwrap();
+        let err = verify_os_release_provider(
+            &spec_packages,
+            &packages,
+            &provider("mariner-release", Some("3.0")),
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("rather than the expected version `3.0`"));
+        let err = verify_os_release_provider(
+            &spec_packages,
+            &packages,
+            &provider("fedora-release", None),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("`mariner-release` rather than"));
+        let err = verify_os_release_provider(
+            &BTreeMap::new(),
+            &packages,
+            &provider("mariner-release", None),
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("No package providing /etc/os-release"));
+    }
+
+    #[test]
+    fn test_run_url_command() {
+        let url = Url::from_str("https://bucket.s3.amazonaws.com/repo/").unwrap();
+        let command = |script: &str| vec!["sh".to_string(), "-c".to_string(), script.to_string()];
+        let minted = run_url_command(
+            "s3repo",
+            &url,
+            &command(r#"echo "${RPMOCI_REPO_URL}?X-Amz-Signature=${RPMOCI_REPO_ID}""#),
+        )
+        .unwrap();
+        assert_eq!(
+            minted.as_str(),
+            "https://bucket.s3.amazonaws.com/repo/?X-Amz-Signature=s3repo"
+        );
+
+        assert!(run_url_command("s3repo", &url, &command("exit 1")).is_err());
+        assert!(run_url_command("s3repo", &url, &command("echo not-a-url")).is_err());
+        assert!(run_url_command("s3repo", &url, &[]).is_err());
+    }
+
+    /// An environment containing the given variables
+    fn test_env(
+        vars: &'static [(&'static str, &'static str)],
+    ) -> impl Fn(&str) -> Result<String, std::env::VarError> {
+        |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+                .ok_or(std::env::VarError::NotPresent)
+        }
+    }
+
+    #[test]
+    fn test_repo_id_env_vars() {
+        let env = test_env(&[
+            ("RPMOCI_MY_REPO_X86_64_HTTP_USERNAME", "user"),
+            ("RPMOCI_OTHER-REPO_HTTP_PASSWORD", "legacy"),
+        ]);
+        assert_eq!(
+            repo_username("my-repo.x86_64", &env),
+            Some("user".to_string())
+        );
+        assert_eq!(repo_password("my-repo.x86_64", &env), None);
+        assert_eq!(
+            repo_password("other-repo", &env),
+            Some("legacy".to_string())
+        );
+    }
+
+    #[test]
+    fn test_repo_client_certificate_from_env() {
+        let env = test_env(&[
+            ("RPMOCI_CERT_REPO_SSL_CLIENT_CERT_PEM", "certificate"),
+            ("RPMOCI_CERT_REPO_SSL_CLIENT_KEY_PEM", "key"),
+            ("RPMOCI_KEY_ONLY_REPO_SSL_CLIENT_KEY_PEM", "key"),
+        ]);
+        assert!(repo_client_certificate("no-cert-repo", &env)
+            .unwrap()
+            .is_none());
+        assert!(repo_client_certificate("key-only-repo", &env).is_err());
+
+        let certificate = repo_client_certificate("cert-repo", &env).unwrap().unwrap();
+        let url = Url::from_str("https://example.com/repo").unwrap();
+        let (cert, key) = Python::with_gil(|py| {
+            let kwargs = repo_kwargs(&url, None, &[], None, None, Some(&certificate), py);
+            let path = |name: &str| -> PathBuf {
+                kwargs.get_item(name).unwrap().unwrap().extract().unwrap()
+            };
+            (path("sslclientcert"), path("sslclientkey"))
+        });
+        assert_eq!(std::fs::read_to_string(&cert).unwrap(), "certificate");
+        assert_eq!(std::fs::read_to_string(&key).unwrap(), "key");
+        for path in [&cert, &key] {
+            let mode = std::fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        drop(certificate);
+        assert!(!cert.exists());
+        assert!(!key.exists());
+    }
+
+    #[test]
+    fn test_repo_file() {
+        let env = test_env(&[
+            ("RPMOCI_REPO_FILE_SECRET_HTTP_USERNAME", "user"),
+            ("RPMOCI_REPO_FILE_SECRET_HTTP_PASSWORD", "hunter2"),
+        ]);
+        let cfg: Config = toml::from_str(
+            r#"
+            [contents]
+            repositories = [
+              "system-repo",
+              { url = "https://example.com/base", id = "repo-file-secret", skip_if_unavailable = true, options = { gpgkey = "https://example.com/repo.asc", priority = "10" } },
+              "https://example.com/extra",
+            ]
+            gpgkeys = ["https://example.com/global.asc"]
+            packages = ["foo"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            repo_file(&cfg.contents, &env),
+            "\
+# Repository `system-repo` is defined by the system configuration
+
+[repo-file-secret]
+name=repo-file-secret
+# Credentials from the environment are omitted
+baseurl=https://example.com/base
+gpgcheck=True
+gpgkey=https://example.com/repo.asc https://example.com/global.asc
+priority=10
+skip_if_unavailable=true
+sslverify=True
+
+[example.com_extra]
+name=example.com_extra
+baseurl=https://example.com/extra
+gpgcheck=True
+gpgkey=https://example.com/global.asc
+sslverify=True
+
+"
+        );
+        assert!(!repo_file(&cfg.contents, &env).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_repo_file_matches_repo_kwargs() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [contents]
+            repositories = [
+              { url = "https://example.com/base", skip_if_unavailable = false, repo_gpgcheck = true, timeout = 60, minrate = 10, retries = 5, failovermethod = "fastest", options = { timeout = "1" } },
+            ]
+            packages = ["foo"]
+            "#,
+        )
+        .unwrap();
+        let Repository::Definition(definition) = &cfg.contents.repositories[0] else {
+            panic!("expected a repository definition");
+        };
+        let file = repo_file(&cfg.contents, &test_env(&[]));
+        Python::with_gil(|py| {
+            let kwargs = repo_kwargs(&definition.url, Some(definition), &[], None, None, None, py);
+            for key in [
+                "skip_if_unavailable",
+                "repo_gpgcheck",
+                "timeout",
+                "minrate",
+                "retries",
+                "fastestmirror",
+            ] {
+                let value = kwargs.get_item(key).unwrap().unwrap();
+                let value = match value.extract::<bool>() {
+                    Ok(value) => value.to_string(),
+                    Err(_) => value.extract::<u32>().unwrap().to_string(),
+                };
+                assert!(
+                    file.lines()
+                        .any(|line| line == format!("{}={}", key, value)),
+                    "{}={} isn't in\n{}",
+                    key,
+                    value,
+                    file
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_check_url_schemes() {
+        let contents = |repositories: &str, allow_insecure_http: bool| {
+            let cfg: Config = toml::from_str(&format!(
+                "[contents]\nrepositories = [{}]\npackages = []\nallow_insecure_http = {}",
+                repositories, allow_insecure_http
+            ))
+            .unwrap();
+            cfg.contents
+        };
+
+        for repositories in [
+            r#""system-repo", "https://example.com/repo", "file:///srv/repo""#,
+            r#"{ url = "https://example.com/repo", options = { gpgkey = "https://example.com/a.asc,file:///etc/pki/b.asc", metalink = "https://example.com/metalink" } }"#,
+        ] {
+            check_url_schemes(&contents(repositories, false)).unwrap();
+        }
+
+        for repositories in [
+            r#""http://example.com/repo""#,
+            r#"{ url = "https://example.com/repo", options = { mirrorlist = "http://example.com/mirrors" } }"#,
+            r#"{ url = "https://example.com/repo", options = { gpgkey = "https://example.com/a.asc http://example.com/b.asc" } }"#,
+        ] {
+            let err = check_url_schemes(&contents(repositories, false)).unwrap_err();
+            assert!(format!("{:#}", err).contains("allow_insecure_http"));
+            // Plain http is accepted when explicitly allowed
+            check_url_schemes(&contents(repositories, true)).unwrap();
+        }
+
+        // Other schemes are never allowed
+        let err = check_url_schemes(&contents(r#""ftp://example.com/repo""#, true)).unwrap_err();
+        assert!(format!("{:#}", err).contains("`ftp` URLs aren't supported"));
+
+        let mut insecure_key = contents("", false);
+        insecure_key.gpgkeys = vec![Url::from_str("http://example.com/key.asc").unwrap()];
+        assert!(check_url_schemes(&insecure_key).is_err());
+
+        // URLs from url_command are checked separately
+        let minted = Url::from_str("http://bucket.example.com/repo?sig=secret").unwrap();
+        let err = check_url_scheme(&minted, false).unwrap_err();
+        assert!(!err.to_string().contains("secret"));
+        check_url_scheme(&minted, true).unwrap();
+    }
+
+    #[test]
+    fn test_check_unique_repo_ids() {
+        let repo = |id: &str| {
+            Repository::Definition(RepositoryDefinition {
+                id: Some(id.to_string()),
+                ..repository_definition(Url::from_str("https://example.com/repo").unwrap())
+            })
+        };
+        let mut contents = PackageConfig {
+            repositories: vec![repo("foo"), repo("bar")],
+            ..Default::default()
+        };
+        check_unique_repo_ids(&contents).unwrap();
+        contents
+            .repositories
+            .push(Repository::Id("foo".to_string()));
+        let err = check_unique_repo_ids(&contents).unwrap_err();
+        assert!(err.to_string().contains("`foo`"));
+    }
+
+    #[test]
+    fn test_fixed_clock_metadata_expire() {
+        let system_now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let hour_ago = system_now - chrono::Duration::hours(1);
+        let clock = Clock::Fixed(hour_ago);
+        assert_eq!(clock.now(), hour_ago);
+        // Metadata that is 2 hours old at the system time is 1 hour old at the fixed time
+        assert_eq!(clock.metadata_expire(system_now, 3600), 7200);
+        assert_eq!(clock.metadata_expire(system_now, -1), -1);
+        // Metadata expires immediately if the fixed time is far in the future
+        let clock = Clock::Fixed(system_now + chrono::Duration::days(7));
+        assert_eq!(clock.metadata_expire(system_now, 3600), 0);
+        assert_eq!(Clock::System.metadata_expire(Utc::now(), 3600), 3600);
+    }
+
+    /// A stand in for dnf's main configuration, which records the options set on it
+    fn fake_conf(py: Python) -> Bound<PyAny> {
+        let locals = pyo3::types::PyDict::new_bound(py);
+        py.run_bound(
+            r#"
+class Conf:
+    countme = True
+    fastestmirror = True
+    module_platform_id = None
+    timeout = 30
+    minrate = 1000
+    tsflags = []
+
+    def _set_value(self, name, value):
+        setattr(self, name, value)
+"#,
+            None,
+            Some(&locals),
+        )
+        .unwrap();
+        locals.get_item("Conf").unwrap().unwrap().call0().unwrap()
+    }
+
+    #[test]
+    fn test_configure_main() {
+        let contents = PackageConfig {
+            keepcache: true,
+            localpkg_gpgcheck: true,
+            zchunk: false,
+            ..Default::default()
+        };
+        Python::with_gil(|py| {
+            let conf = fake_conf(py);
+            configure_main(&conf, &contents).unwrap();
+            assert!(conf
+                .getattr("keepcache")
+                .unwrap()
+                .extract::<bool>()
+                .unwrap());
+            assert!(conf
+                .getattr("localpkg_gpgcheck")
+                .unwrap()
+                .extract::<bool>()
+                .unwrap());
+            assert!(!conf
+                .getattr("skip_if_unavailable")
+                .unwrap()
+                .extract::<bool>()
+                .unwrap());
+            assert!(!conf.getattr("zchunk").unwrap().extract::<bool>().unwrap());
+            assert!(conf
+                .getattr("clean_requirements_on_remove")
+                .unwrap()
+                .extract::<bool>()
+                .unwrap());
+        });
+        let contents = PackageConfig {
+            clean_requirements_on_remove: false,
+            ..Default::default()
+        };
+        Python::with_gil(|py| {
+            let conf = fake_conf(py);
+            configure_main(&conf, &contents).unwrap();
+            assert!(!conf
+                .getattr("clean_requirements_on_remove")
+                .unwrap()
+                .extract::<bool>()
+                .unwrap());
+        });
+    }
+
+    #[test]
+    fn test_explain_zchunk_error() {
+        let err =
+            || anyhow::anyhow!("Failed to download metadata: Zchunk header checksum didn't match");
+        let message = format!("{:#}", explain_zchunk_error(err(), true));
+        assert!(
+            message.contains("set `contents.zchunk = false`"),
+            "{}",
+            message
+        );
+        // Other errors, or errors when zchunk is disabled, are unchanged
+        let message = format!("{:#}", explain_zchunk_error(err(), false));
+        assert!(!message.contains("contents.zchunk"));
+        let other = anyhow::anyhow!("Cannot download repomd.xml");
+        assert_eq!(
+            explain_zchunk_error(other, true).to_string(),
+            "Cannot download repomd.xml"
+        );
+    }
+
+    #[test]
+    fn test_gzip_repodata() {
+        // A repository with only gzip compressed metadata, as served by mirrors without zchunk support
+        let repo = tempfile::tempdir().unwrap();
+        std::fs::create_dir(repo.path().join("repodata")).unwrap();
+        let primary = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metadata xmlns="http://linux.duke.edu/metadata/common" xmlns:rpm="http://linux.duke.edu/metadata/rpm" packages="1">
+<package type="rpm">
+  <name>foo</name>
+  <arch>noarch</arch>
+  <version epoch="0" ver="1.0" rel="1"/>
+  <checksum type="sha256" pkgid="YES">2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae</checksum>
+  <summary>foo</summary>
+  <description>foo</description>
+  <packager/>
+  <url/>
+  <time file="1" build="1"/>
+  <size package="1" installed="1" archive="1"/>
+  <location href="foo-1.0-1.noarch.rpm"/>
+  <format>
+    <rpm:license>MIT</rpm:license>
+    <rpm:provides>
+      <rpm:entry name="foo" flags="EQ" epoch="0" ver="1.0" rel="1"/>
+    </rpm:provides>
+  </format>
+</package>
+</metadata>
+"#;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, primary.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        std::fs::write(repo.path().join("repodata/primary.xml.gz"), &compressed).unwrap();
+        let sha256 = |data: &[u8]| format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(data));
+        std::fs::write(
+            repo.path().join("repodata/repomd.xml"),
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<repomd xmlns="http://linux.duke.edu/metadata/repo" xmlns:rpm="http://linux.duke.edu/metadata/rpm">
+  <revision>1</revision>
+  <data type="primary">
+    <checksum type="sha256">{}</checksum>
+    <open-checksum type="sha256">{}</open-checksum>
+    <location href="repodata/primary.xml.gz"/>
+    <timestamp>1</timestamp>
+    <size>{}</size>
+    <open-size>{}</open-size>
+  </data>
+</repomd>
+"#,
+                sha256(&compressed),
+                sha256(primary.as_bytes()),
+                compressed.len(),
+                primary.len()
+            ),
+        )
+        .unwrap();
+
+        for zchunk in [true, false] {
+            let contents = PackageConfig {
+                repositories: vec![Repository::Definition(RepositoryDefinition {
+                    id: Some("gzip".to_string()),
+                    options: HashMap::from([("gpgcheck".to_string(), "False".to_string())]),
+                    ..repository_definition(Url::from_directory_path(repo.path()).unwrap())
+                })],
+                os_release: false,
+                zchunk,
+                ..Default::default()
+            };
+            let lock = Lockfile::resolve(vec!["foo".to_string()], &contents).unwrap();
+            assert!(lock.packages.iter().any(|p| p.name == "foo"));
+        }
+    }
+
+    #[test]
+    fn python_output_is_captured_in_errors() {
+        Python::with_gil(|py| {
+            let module = PyModule::from_code_bound(
+                py,
+                r#"
+import sys
+
+def fail(lines):
+    for i in range(lines):
+        print("line %d" % i)
+    print("No match for argument: foo", file=sys.stderr)
+    raise RuntimeError("resolution failed")
+
+def succeed():
+    print("progress")
+    return "done"
+"#,
+                "capture",
+                "capture",
+            )
+            .unwrap();
+            let sys = py.import_bound("sys").unwrap();
+            let stdout = sys.getattr("stdout").unwrap();
+
+            let err = call_capturing_output(&module.getattr("fail").unwrap(), (30,))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("RuntimeError: resolution failed"));
+            assert!(err.contains("No match for argument: foo"));
+            // Only the last lines are kept
+            assert!(err.contains("line 29"));
+            assert!(err.contains("line 11"));
+            assert!(!err.contains("line 10"));
+            assert!(sys.getattr("stdout").unwrap().is(&stdout));
+
+            let value = call_capturing_output(&module.getattr("succeed").unwrap(), ()).unwrap();
+            assert_eq!(value.extract::<String>().unwrap(), "done");
+            assert!(sys.getattr("stdout").unwrap().is(&stdout));
+        });
+    }
+
+    #[test]
+    fn test_module_platform_id() {
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(detect_module_platform_id(root.path()), None);
+        std::fs::create_dir_all(root.path().join("usr/lib")).unwrap();
+        std::fs::write(
+            root.path().join("usr/lib/os-release"),
+            "NAME=\"Example\"\nPLATFORM_ID=\"platform:el9\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            detect_module_platform_id(root.path()),
+            Some("platform:el9".to_string())
+        );
+
+        // The module platform id is recorded in the lockfile, and reapplied when updating it
+        let lockfile = Lockfile::parse(
+            "pkg_specs = []\npackages = []\nmodule_platform_id = \"platform:el8\"\n",
+        )
+        .unwrap();
+        assert!(toml::to_string(&lockfile)
+            .unwrap()
+            .contains("module_platform_id = \"platform:el8\""));
+        let contents = lockfile.reapply_module_platform_id(&PackageConfig::default());
+        assert_eq!(contents.module_platform_id.as_deref(), Some("platform:el8"));
+        Python::with_gil(|py| {
+            let conf = fake_conf(py);
+            configure_main(&conf, &contents).unwrap();
+            let id: String = conf
+                .getattr("module_platform_id")
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(id, "platform:el8");
+        });
+
+        // Configuration overrides the recorded value
+        let configured = PackageConfig {
+            module_platform_id: Some("platform:el9".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            lockfile
+                .reapply_module_platform_id(&configured)
+                .module_platform_id
+                .as_deref(),
+            Some("platform:el9")
+        );
+        let dnf_options = PackageConfig {
+            dnf_options: HashMap::from([(
+                "module_platform_id".to_string(),
+                "platform:el9".to_string(),
+            )]),
+            ..Default::default()
+        };
+        assert_eq!(
+            lockfile
+                .reapply_module_platform_id(&dnf_options)
+                .module_platform_id,
+            None
+        );
+    }
+
+    #[test]
+    fn test_default_dnf_options() {
+        let option = |conf: &Bound<PyAny>, key: &str| -> String {
+            conf.getattr(key).unwrap().extract().unwrap()
+        };
+        Python::with_gil(|py| {
+            let conf = fake_conf(py);
+            configure_main(&conf, &PackageConfig::default()).unwrap();
+            assert_eq!(option(&conf, "countme"), "False");
+            assert_eq!(option(&conf, "fastestmirror"), "False");
+            assert_eq!(option(&conf, "timeout"), "10");
+            assert_eq!(option(&conf, "minrate"), "10000");
+
+            let contents = PackageConfig {
+                dnf_options: HashMap::from([
+                    ("timeout".to_string(), "120".to_string()),
+                    ("countme".to_string(), "True".to_string()),
+                ]),
+                ..Default::default()
+            };
+            let conf = fake_conf(py);
+            configure_main(&conf, &contents).unwrap();
+            assert_eq!(option(&conf, "countme"), "True");
+            assert_eq!(option(&conf, "fastestmirror"), "False");
+            assert_eq!(option(&conf, "timeout"), "120");
+            assert_eq!(option(&conf, "minrate"), "10000");
+        });
+    }
+
+    #[test]
+    fn test_arbitrary_dnf_options() {
+        let contents = PackageConfig {
+            dnf_options: HashMap::from([
+                ("tsflags".to_string(), "nodocs".to_string()),
+                ("not_a_dnf_option".to_string(), "1".to_string()),
+            ]),
+            ..Default::default()
+        };
+        Python::with_gil(|py| {
+            let conf = fake_conf(py);
+            configure_main(&conf, &contents).unwrap();
+            assert_eq!(
+                conf.getattr("tsflags")
+                    .unwrap()
+                    .extract::<String>()
+                    .unwrap(),
+                "nodocs"
+            );
+            // Unknown options are skipped
+            assert!(!conf.hasattr("not_a_dnf_option").unwrap());
+        });
+    }
+
+    #[test]
+    fn test_unminted_download_url() {
+        let contents = PackageConfig {
+            repositories: vec![Repository::Definition(RepositoryDefinition {
+                id: Some("minted".to_string()),
+                url_command: Some(vec!["mint".to_string()]),
+                ..repository_definition(Url::from_str("https://example.com/repo").unwrap())
+            })],
+            ..Default::default()
+        };
+        let pkg = |repoid: &str| {
+            let mut pkg: Package = toml::from_str(&format!(
+                r#"
+                name = "foo"
+                evr = "1.0-1"
+                repoid = "{repoid}"
+                location = "Packages/f/foo-1.0-1.x86_64.rpm"
+                download_url = "https://example.com/repo?token=secret"
+                checksum = {{ algorithm = "sha256", checksum = "abc" }}
+                "#
+            ))
+            .unwrap();
+            pkg = unminted_download_url(pkg, &contents);
+            pkg.download_url.unwrap()
+        };
+        assert_eq!(
+            pkg("minted"),
+            "https://example.com/repo/Packages/f/foo-1.0-1.x86_64.rpm"
+        );
+        assert_eq!(pkg("other"), "https://example.com/repo?token=secret");
+    }
+
+    #[test]
+    fn test_minimal_resolve() {
+        let mariner_repository = Repository::Url(
+            Url::from_str("https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64")
+                .unwrap(),
+        );
+        let contents = PackageConfig {
+            repositories: vec![mariner_repository],
+            gpgkeys: vec![Url::from_str(MARINER_GPGKEY).unwrap()],
+            os_release: false,
+            ..Default::default()
+        };
+        let lock = Lockfile::resolve(vec!["zlib".to_string()], &contents).unwrap();
+        // Every package is the spec or one of its hard dependencies
+        for pkg in &lock.packages {
+            assert!(
+                lock.dependency_chain(&pkg.name).unwrap().is_some(),
+                "unexpected package {}",
+                pkg.name
+            );
+        }
+        assert!(!lock.packages.iter().any(|p| p.name == "mariner-release"));
+        for pkg in &lock.packages {
+            let download_url = pkg.download_url.as_ref().unwrap();
+            assert!(download_url
+                .starts_with("https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64/"));
+            assert!(download_url.ends_with(pkg.location.as_ref().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_repo_gpgcheck_resolve() {
+        // The mariner repositories publish a detached signature of their repomd.xml
+        let mariner_repository = Repository::Definition(RepositoryDefinition {
+            id: Some("marinersigned".to_string()),
+            repo_gpgcheck: Some(true),
+            ..repository_definition(
+                Url::from_str("https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64")
+                    .unwrap(),
+            )
+        });
+        let contents = PackageConfig {
+            repositories: vec![mariner_repository],
+            gpgkeys: vec![Url::from_str("https://raw.githubusercontent.com/microsoft/CBL-Mariner/2.0/SPECS/mariner-repos/MICROSOFT-RPM-GPG-KEY").unwrap()],
+            ..Default::default()
+        };
+        let lock = Lockfile::resolve(vec!["zlib".to_string()], &contents).unwrap();
+        assert!(lock.packages.iter().any(|p| p.name == "zlib"));
+    }
+
+    #[test]
+    fn test_exclude_provides() {
+        let contents = PackageConfig {
+            repositories: vec![Repository::Url(
+                Url::from_str("https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64")
+                    .unwrap(),
+            )],
+            gpgkeys: vec![Url::from_str(MARINER_GPGKEY).unwrap()],
+            ..Default::default()
+        };
+        let lock = Lockfile::resolve(vec!["zlib".to_string()], &contents).unwrap();
+        assert!(lock.packages.iter().any(|p| p.name == "zlib"));
+
+        // zlib is the only provider of its soname, so excluding the soname excludes zlib
+        let contents = PackageConfig {
+            exclude: vec!["libz.so.1()(64bit)".to_string()],
+            ..contents
+        };
+        let err = Lockfile::resolve(vec!["zlib".to_string()], &contents).unwrap_err();
+        assert!(format!("{:?}", err).contains("No packages available for spec 'zlib'"));
+    }
+
+    #[test]
+    fn test_capability_specs() {
+        let contents = PackageConfig {
+            repositories: vec![Repository::Url(
+                Url::from_str("https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64")
+                    .unwrap(),
+            )],
+            gpgkeys: vec![Url::from_str(MARINER_GPGKEY).unwrap()],
+            ..Default::default()
+        };
+        // A file path and a soname are resolved to the packages that provide them
+        let lock = Lockfile::resolve(
+            vec!["/usr/bin/sed".to_string(), "libz.so.1()(64bit)".to_string()],
+            &contents,
+        )
+        .unwrap();
+        assert_eq!(
+            lock.spec_packages["/usr/bin/sed"],
+            BTreeSet::from(["sed".to_string()])
+        );
+        assert_eq!(
+            lock.spec_packages["libz.so.1()(64bit)"],
+            BTreeSet::from(["zlib".to_string()])
+        );
+        assert!(lock.packages.iter().any(|p| p.name == "sed"));
+
+        let err =
+            Lockfile::resolve(vec!["/usr/bin/no-such-file".to_string()], &contents).unwrap_err();
+        assert!(format!("{:?}", err).contains("No packages available for spec"));
+    }
+
+    #[test]
     fn test_weak_deps() {
         // prce2-tools in mariner recommends pcre2-docs. use this to test weak dep behaviour
         let mut options = HashMap::new();
@@ -408,19 +2792,164 @@ mod tests {
 
         let mariner_repository = Repository::Definition(RepositoryDefinition {
             id: Some("marinertest".to_string()),
-            url: Url::from_str("https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64")
-                .unwrap(),
             options,
+            ..repository_definition(
+                Url::from_str("https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64")
+                    .unwrap(),
+            )
         });
-        let repositories = vec![mariner_repository];
+        let contents = PackageConfig {
+            repositories: vec![mariner_repository],
+            os_release: true,
+            ..Default::default()
+        };
 
-        let lock = Lockfile::resolve(
-            vec!["pcre2-tools".to_string()],
-            &repositories,
-            Vec::new(),
-            true,
+        let lock = Lockfile::resolve(vec!["pcre2-tools".to_string()], &contents).unwrap();
+        assert!(!lock.packages.iter().any(|p| p.name == "pcre2-doc"));
+
+        // Other classes of weak dependencies don't install recommended packages
+        let contents = PackageConfig {
+            weak_deps: WeakDependencies {
+                suggests: true,
+                ..Default::default()
+            },
+            ..contents
+        };
+        let lock = Lockfile::resolve(vec!["pcre2-tools".to_string()], &contents).unwrap();
+        assert!(!lock.packages.iter().any(|p| p.name == "pcre2-doc"));
+
+        let contents = PackageConfig {
+            weak_deps: WeakDependencies {
+                recommends: true,
+                ..Default::default()
+            },
+            ..contents
+        };
+        let lock = Lockfile::resolve(vec!["pcre2-tools".to_string()], &contents).unwrap();
+        assert!(lock.packages.iter().any(|p| p.name == "pcre2-doc"));
+    }
+
+    #[test]
+    fn test_base_lockfile() {
+        let mut options = HashMap::new();
+        options.insert("gpgcheck".to_string(), "True".to_string());
+        options.insert("gpgkey".to_string(), "https://raw.githubusercontent.com/microsoft/CBL-Mariner/2.0/SPECS/mariner-repos/MICROSOFT-RPM-GPG-KEY,https://packages.microsoft.com/keys/microsoft.asc".to_string());
+        let mariner_repository = Repository::Definition(RepositoryDefinition {
+            id: Some("marinertest".to_string()),
+            options,
+            ..repository_definition(
+                Url::from_str("https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64")
+                    .unwrap(),
+            )
+        });
+        let contents = PackageConfig {
+            repositories: vec![mariner_repository],
+            ..Default::default()
+        };
+        let base = Lockfile::resolve(vec!["bash".to_string()], &contents).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let base_path = dir.path().join("base.lock");
+        base.write_to_file(&base_path).unwrap();
+
+        let contents = PackageConfig {
+            base_lockfile: Some(base_path),
+            ..contents
+        };
+        let variant = Lockfile::resolve(vec!["pcre2-tools".to_string()], &contents).unwrap();
+        // The variant contains the base packages, at the base's versions
+        for pkg in &base.packages {
+            assert!(variant
+                .packages
+                .iter()
+                .any(|p| p.name == pkg.name && p.evr == pkg.evr));
+        }
+        assert!(variant.packages.iter().any(|p| p.name == "pcre2-tools"));
+    }
+
+    #[test]
+    fn test_locked_versions_are_kept() {
+        let cfg: Config = toml::from_str(
+            r#"
+[contents]
+repositories = ["https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64"]
+gpgkeys = ["https://raw.githubusercontent.com/microsoft/CBL-Mariner/2.0/SPECS/mariner-repos/MICROSOFT-RPM-GPG-KEY"]
+packages = ["zlib", "tini"]
+"#,
         )
         .unwrap();
-        assert!(!lock.packages.iter().any(|p| p.name == "pcre2-doc"));
+        // The repository has newer versions of zlib
+        let previous: Lockfile = toml::from_str(
+            r#"
+pkg_specs = ["zlib"]
+
+[[packages]]
+name = "zlib"
+evr = "1.2.12-2.cm2"
+arch = "x86_64"
+repoid = "packages.microsoft.com_cbl-mariner_2.0_prod_base_x86_64"
+checksum = { algorithm = "sha256", checksum = "" }
+"#,
+        )
+        .unwrap();
+        let lock = previous.resolve_from_config_keeping_versions(&cfg).unwrap();
+        assert!(lock
+            .packages
+            .iter()
+            .any(|p| p.name == "zlib" && p.evr == "1.2.12-2.cm2"));
+        assert!(lock.packages.iter().any(|p| p.name == "tini"));
+    }
+
+    #[test]
+    fn test_clean_requirements_on_remove() {
+        // bash, whose dependency zlib isn't required by tini, has been removed from the manifest
+        let previous: Lockfile = toml::from_str(
+            r#"
+pkg_specs = ["bash", "tini"]
+
+[spec_packages]
+bash = ["bash"]
+tini = ["tini"]
+
+[[packages]]
+name = "bash"
+evr = "5.1.8-1.cm2"
+arch = "x86_64"
+repoid = "packages.microsoft.com_cbl-mariner_2.0_prod_base_x86_64"
+checksum = { algorithm = "sha256", checksum = "" }
+dependencies = ["zlib"]
+
+[[packages]]
+name = "zlib"
+evr = "1.2.12-2.cm2"
+arch = "x86_64"
+repoid = "packages.microsoft.com_cbl-mariner_2.0_prod_base_x86_64"
+checksum = { algorithm = "sha256", checksum = "" }
+"#,
+        )
+        .unwrap();
+        for clean in [true, false] {
+            let cfg: Config = toml::from_str(&format!(
+                r#"
+[contents]
+repositories = ["https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64"]
+gpgkeys = ["https://raw.githubusercontent.com/microsoft/CBL-Mariner/2.0/SPECS/mariner-repos/MICROSOFT-RPM-GPG-KEY"]
+packages = ["tini"]
+os_release = false
+clean_requirements_on_remove = {}
+"#,
+                clean
+            ))
+            .unwrap();
+            let lock = previous.resolve_from_config_keeping_versions(&cfg).unwrap();
+            assert!(lock.packages.iter().any(|p| p.name == "tini"));
+            assert!(!lock.packages.iter().any(|p| p.name == "bash"));
+            // The orphaned dependency is only kept, at its locked version, if requirements aren't cleaned
+            assert_eq!(
+                lock.packages
+                    .iter()
+                    .any(|p| p.name == "zlib" && p.evr == "1.2.12-2.cm2"),
+                !clean
+            );
+        }
     }
 }
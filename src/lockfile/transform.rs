@@ -0,0 +1,361 @@
+//! Copyright (C) Microsoft Corporation.
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use flate2::{Compression, GzBuilder};
+use walkdir::WalkDir;
+
+use crate::config::Transformation;
+use crate::rootfs::resolve_in_root;
+use crate::write;
+
+/// The directory containing man pages, relative to the root filesystem
+const MAN_DIR: &str = "usr/share/man";
+/// Extensions of man pages that are already compressed
+const COMPRESSED_EXTENSIONS: [&str; 6] = ["gz", "bz2", "xz", "lzma", "zst", "Z"];
+
+/// Apply transformations to files in the root filesystem.
+///
+/// `files` are absolute paths in the image, e.g. the files owned by a package, and are resolved
+/// inside the root filesystem, so symlinked directories can't lead outside it.
+/// If it's `None`, the transformations are applied to every file in the root filesystem.
+/// Files that don't exist, or that a transformation doesn't apply to, are skipped.
+/// Intermediate files are written to `tmp_dir`.
+pub(super) fn apply_transformations(
+    installroot: &Path,
+    transformations: &[Transformation],
+    files: Option<&[PathBuf]>,
+    tmp_dir: &Path,
+) -> Result<()> {
+    if transformations.is_empty() {
+        return Ok(());
+    }
+    let mut paths = match files {
+        Some(files) => files
+            .iter()
+            .filter_map(|file| resolve_in_root(installroot, file, false).transpose())
+            .collect::<Result<Vec<_>>>()?,
+        None => WalkDir::new(installroot)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.file_type().is_dir())
+            .map(|entry| entry.into_path())
+            .collect(),
+    };
+    paths.sort();
+    for transformation in transformations {
+        match transformation {
+            Transformation::StripDebug => strip_debug(&paths, tmp_dir)?,
+            Transformation::CompressManPages => compress_man_pages(installroot, &paths)?,
+        }
+    }
+    Ok(())
+}
+
+/// Remove debugging symbols from ELF files.
+///
+/// Each file is stripped into a temporary file, whose contents replace the original's only if it's smaller,
+/// so that already stripped files are left untouched and hard links are preserved.
+/// Files that `strip` fails on are skipped with a warning.
+fn strip_debug(paths: &[PathBuf], tmp_dir: &Path) -> Result<()> {
+    let mut seen = HashSet::new();
+    for path in paths {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            continue;
+        };
+        if !metadata.is_file() || !seen.insert((metadata.dev(), metadata.ino())) || !is_elf(path)? {
+            continue;
+        }
+        let stripped = tempfile::NamedTempFile::new_in(tmp_dir).with_context(|| {
+            format!("Failed to create temporary file in `{}`", tmp_dir.display())
+        })?;
+        let output = Command::new("strip")
+            .arg("--strip-debug")
+            .arg("-o")
+            .arg(stripped.path())
+            .arg(path)
+            .output()
+            .context("Failed to run `strip`")?;
+        if !output.status.success() {
+            write::warning(
+                "Warning",
+                format!(
+                    "failed to strip `{}`, leaving it unchanged: {}",
+                    path.display(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            )?;
+            continue;
+        }
+        if stripped.as_file().metadata()?.len() < metadata.len() {
+            replace_contents(path, &fs::read(stripped.path())?, metadata.permissions())
+                .with_context(|| format!("Failed to strip `{}`", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a file starts with the ELF magic number
+fn is_elf(path: &Path) -> Result<bool> {
+    let mut magic = [0; 4];
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open `{}`", path.display()))?;
+    Ok(file.read_exact(&mut magic).is_ok() && magic == *b"\x7fELF")
+}
+
+/// Overwrite a file's contents in place, temporarily making it writable if it's read-only
+fn replace_contents(path: &Path, contents: &[u8], permissions: fs::Permissions) -> Result<()> {
+    let mode = permissions.mode();
+    if mode & 0o200 == 0 {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o200))?;
+    }
+    fs::write(path, contents)?;
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+/// Compress uncompressed man pages with gzip, adding a `.gz` extension.
+///
+/// Symlinks to compressed man pages are replaced by symlinks with a `.gz` extension to the compressed pages.
+/// Link targets are resolved inside the root filesystem.
+fn compress_man_pages(installroot: &Path, paths: &[PathBuf]) -> Result<()> {
+    let man_pages = paths
+        .iter()
+        .filter(|path| is_uncompressed_man_page(installroot, path))
+        .collect::<Vec<_>>();
+    let mut links = Vec::new();
+    for path in man_pages {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            continue;
+        };
+        if metadata.is_symlink() {
+            links.push(path);
+        } else if metadata.is_file() {
+            let compressed = with_gz_extension(path);
+            let mut encoder = GzBuilder::new().write(
+                File::create(&compressed)
+                    .with_context(|| format!("Failed to create `{}`", compressed.display()))?,
+                Compression::best(),
+            );
+            std::io::copy(&mut File::open(path)?, &mut encoder)?;
+            encoder.finish()?;
+            fs::set_permissions(&compressed, metadata.permissions())?;
+            std::os::unix::fs::chown(&compressed, Some(metadata.uid()), Some(metadata.gid()))?;
+            fs::remove_file(path)?;
+        }
+    }
+
+    for link in links {
+        let target = fs::read_link(link)?;
+        // The link's path relative to the root, so that relative targets are resolved from its directory
+        let parent = link
+            .parent()
+            .and_then(|parent| parent.strip_prefix(installroot).ok())
+            .unwrap_or(Path::new(""));
+        let exists = |target: &Path| {
+            resolve_in_root(installroot, &parent.join(target), true)
+                .ok()
+                .flatten()
+                .is_some_and(|path| fs::symlink_metadata(path).is_ok())
+        };
+        let compressed_target = with_gz_extension(&target);
+        if !exists(&target) && exists(&compressed_target) {
+            fs::remove_file(link)?;
+            std::os::unix::fs::symlink(&compressed_target, with_gz_extension(link))?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a path is in the man page directory and doesn't have a compressed extension
+fn is_uncompressed_man_page(installroot: &Path, path: &Path) -> bool {
+    path.strip_prefix(installroot)
+        .is_ok_and(|relative| relative.starts_with(MAN_DIR))
+        && !path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension))
+}
+
+/// The path with `.gz` appended
+fn with_gz_extension(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".gz");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use flate2::read::GzDecoder;
+
+    use super::apply_transformations;
+    use crate::config::Transformation;
+
+    #[test]
+    fn debug_symbols_are_stripped() {
+        let root = tempfile::tempdir().unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let bin = root.path().join("usr/bin");
+        fs::create_dir_all(&bin).unwrap();
+        let source = root.path().join("hello.c");
+        fs::write(&source, "int main(void) { return 0; }\n").unwrap();
+        let status = Command::new("cc")
+            .arg("-g")
+            .arg("-o")
+            .arg(bin.join("hello"))
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        fs::hard_link(bin.join("hello"), bin.join("hello-link")).unwrap();
+        fs::write(bin.join("script"), "#!/bin/sh\n").unwrap();
+        // An ELF magic number without a valid ELF file
+        fs::write(bin.join("broken"), b"\x7fELF not really").unwrap();
+        let unstripped = fs::metadata(bin.join("hello")).unwrap();
+
+        apply_transformations(root.path(), &[Transformation::StripDebug], None, tmp.path())
+            .unwrap();
+        let stripped = fs::metadata(bin.join("hello")).unwrap();
+        assert!(stripped.len() < unstripped.len());
+        assert_eq!(stripped.ino(), unstripped.ino());
+        assert_eq!(stripped.mode(), unstripped.mode());
+        assert!(Command::new(bin.join("hello")).status().unwrap().success());
+        assert_eq!(
+            fs::read_to_string(bin.join("script")).unwrap(),
+            "#!/bin/sh\n"
+        );
+        assert_eq!(fs::read(bin.join("broken")).unwrap(), b"\x7fELF not really");
+
+        // Already stripped files are left untouched
+        let mtime = stripped.mtime_nsec();
+        apply_transformations(root.path(), &[Transformation::StripDebug], None, tmp.path())
+            .unwrap();
+        assert_eq!(fs::metadata(bin.join("hello")).unwrap().mtime_nsec(), mtime);
+
+        // Only the listed files are transformed
+        let status = Command::new("cc")
+            .arg("-g")
+            .arg("-o")
+            .arg(bin.join("other"))
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let unstripped = fs::metadata(bin.join("other")).unwrap().len();
+        apply_transformations(
+            root.path(),
+            &[Transformation::StripDebug],
+            Some(&[PathBuf::from("/usr/bin/hello")]),
+            tmp.path(),
+        )
+        .unwrap();
+        assert_eq!(fs::metadata(bin.join("other")).unwrap().len(), unstripped);
+
+        // Stripped files are written to the temporary directory
+        let missing = tmp.path().join("missing");
+        let err = apply_transformations(
+            root.path(),
+            &[Transformation::StripDebug],
+            Some(&[PathBuf::from("/usr/bin/other")]),
+            &missing,
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains(&missing.display().to_string()));
+    }
+
+    #[test]
+    fn listed_files_are_resolved_inside_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let man1 = outside.path().join("usr/share/man/man1");
+        fs::create_dir_all(&man1).unwrap();
+        fs::write(man1.join("foo.1"), ".TH FOO 1\n").unwrap();
+        fs::create_dir_all(root.path().join("usr/share")).unwrap();
+        // An absolute symlink, which would lead outside the root if it were followed on the host
+        std::os::unix::fs::symlink(
+            outside.path().join("usr/share/man"),
+            root.path().join("usr/share/man"),
+        )
+        .unwrap();
+
+        apply_transformations(
+            root.path(),
+            &[Transformation::CompressManPages],
+            Some(&[PathBuf::from("/usr/share/man/man1/foo.1")]),
+            tmp.path(),
+        )
+        .unwrap();
+        assert!(man1.join("foo.1").exists());
+        assert!(!man1.join("foo.1.gz").exists());
+    }
+
+    #[test]
+    fn man_pages_are_compressed() {
+        let root = tempfile::tempdir().unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let man1 = root.path().join("usr/share/man/man1");
+        fs::create_dir_all(&man1).unwrap();
+        fs::write(man1.join("foo.1"), ".TH FOO 1\n").unwrap();
+        fs::write(man1.join("bar.1.gz"), "already compressed").unwrap();
+        std::os::unix::fs::symlink("foo.1", man1.join("alias.1")).unwrap();
+        // Absolute targets are resolved inside the root
+        std::os::unix::fs::symlink("/usr/share/man/man1/foo.1", man1.join("absolute.1")).unwrap();
+        fs::write(root.path().join("README"), "not a man page").unwrap();
+
+        apply_transformations(
+            root.path(),
+            &[Transformation::CompressManPages],
+            None,
+            tmp.path(),
+        )
+        .unwrap();
+        assert!(!man1.join("foo.1").exists());
+        let mut page = String::new();
+        GzDecoder::new(fs::File::open(man1.join("foo.1.gz")).unwrap())
+            .read_to_string(&mut page)
+            .unwrap();
+        assert_eq!(page, ".TH FOO 1\n");
+        assert_eq!(
+            fs::read_to_string(man1.join("bar.1.gz")).unwrap(),
+            "already compressed"
+        );
+        assert!(!man1.join("alias.1").exists());
+        assert_eq!(
+            fs::read_link(man1.join("alias.1.gz")).unwrap(),
+            PathBuf::from("foo.1.gz")
+        );
+        assert_eq!(
+            fs::read_link(man1.join("absolute.1.gz")).unwrap(),
+            PathBuf::from("/usr/share/man/man1/foo.1.gz")
+        );
+        assert_eq!(
+            fs::read_to_string(root.path().join("README")).unwrap(),
+            "not a man page"
+        );
+    }
+}
@@ -15,7 +15,8 @@
 
 use anyhow::Result;
 use clap::Parser;
-use rpmoci::write;
+use rpmoci::cli::ProgressFormat;
+use rpmoci::write::{self, HumanReporter, JsonReporter, QuietReporter};
 
 fn main() {
     if let Err(err) = try_main() {
@@ -32,5 +33,13 @@ fn try_main() -> Result<()> {
     env_logger::Builder::new()
         .filter_level(args.verbose.log_level_filter())
         .init();
+    match (args.progress, args.verbose.is_silent()) {
+        (ProgressFormat::Human, false) => write::set_reporter(HumanReporter),
+        (ProgressFormat::Human, true) => write::set_reporter(QuietReporter(HumanReporter)),
+        (ProgressFormat::Json, false) => write::set_reporter(JsonReporter::new(std::io::stderr())),
+        (ProgressFormat::Json, true) => {
+            write::set_reporter(QuietReporter(JsonReporter::new(std::io::stderr())))
+        }
+    }
     rpmoci::main(args.command)
 }
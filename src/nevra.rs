@@ -0,0 +1,217 @@
+//! Copyright (C) Microsoft Corporation.
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Parsing of package identifiers
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+/// A package's name, epoch, version, release and architecture.
+///
+/// NEVRAs have the form `name-[epoch:]version-release.arch`.
+/// Names may contain hyphens, so the version and release are taken from the last two hyphen separated
+/// components, and the architecture from the final dot separated component.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Nevra {
+    /// The package name
+    pub name: String,
+    /// The package epoch, if one is specified
+    pub epoch: Option<u32>,
+    /// The package version
+    pub version: String,
+    /// The package release
+    pub release: String,
+    /// The package architecture
+    pub arch: String,
+}
+
+impl Nevra {
+    /// Parse a NEVRA of the form `name-[epoch:]version-release.arch`
+    pub fn parse(nevra: &str) -> Result<Self> {
+        (|| {
+            let Some((nevr, arch)) = nevra.rsplit_once('.') else {
+                bail!("missing architecture");
+            };
+            let Some((nev, release)) = nevr.rsplit_once('-') else {
+                bail!("missing release");
+            };
+            let Some((name, ev)) = nev.rsplit_once('-') else {
+                bail!("missing version");
+            };
+            let (epoch, version) = match ev.split_once(':') {
+                Some((epoch, version)) => (
+                    Some(
+                        epoch
+                            .parse()
+                            .with_context(|| format!("invalid epoch `{}`", epoch))?,
+                    ),
+                    version,
+                ),
+                None => (None, ev),
+            };
+            for (component, value) in [
+                ("name", name),
+                ("version", version),
+                ("release", release),
+                ("architecture", arch),
+            ] {
+                if value.is_empty() {
+                    bail!("empty {}", component);
+                }
+            }
+            if version.contains(':') {
+                bail!("invalid version `{}`", version);
+            }
+            Ok(Nevra {
+                name: name.to_string(),
+                epoch,
+                version: version.to_string(),
+                release: release.to_string(),
+                arch: arch.to_string(),
+            })
+        })()
+        .with_context(|| format!("Invalid NEVRA `{}`", nevra))
+    }
+
+    /// The package's `[epoch:]version-release`, as recorded in lockfiles
+    pub fn evr(&self) -> String {
+        match self.epoch {
+            Some(epoch) => format!("{}:{}-{}", epoch, self.version, self.release),
+            None => format!("{}-{}", self.version, self.release),
+        }
+    }
+}
+
+impl FromStr for Nevra {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Nevra::parse(s)
+    }
+}
+
+impl fmt::Display for Nevra {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}.{}", self.name, self.evr(), self.arch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Nevra;
+
+    #[test]
+    fn nevras_are_parsed() {
+        for (nevra, name, epoch, version, release, arch) in [
+            (
+                "bash-5.1.8-4.cm2.x86_64",
+                "bash",
+                None,
+                "5.1.8",
+                "4.cm2",
+                "x86_64",
+            ),
+            (
+                "389-ds-base-1.4.3-1.el8.x86_64",
+                "389-ds-base",
+                None,
+                "1.4.3",
+                "1.el8",
+                "x86_64",
+            ),
+            (
+                "perl-Time-HiRes-1:1.9764-2.cm2.x86_64",
+                "perl-Time-HiRes",
+                Some(1),
+                "1.9764",
+                "2.cm2",
+                "x86_64",
+            ),
+            (
+                "python3-dnf-0:4.8.0-1.cm2.noarch",
+                "python3-dnf",
+                Some(0),
+                "4.8.0",
+                "1.cm2",
+                "noarch",
+            ),
+            (
+                "kernel-6.1.0-rc1-1.x86_64",
+                "kernel-6.1.0",
+                None,
+                "rc1",
+                "1",
+                "x86_64",
+            ),
+            (
+                "libstdc++-11.2.0-7.cm2.aarch64",
+                "libstdc++",
+                None,
+                "11.2.0",
+                "7.cm2",
+                "aarch64",
+            ),
+            ("a-1-1.src", "a", None, "1", "1", "src"),
+            (
+                "openssl-1.1.1k-28.cm2.x86_64",
+                "openssl",
+                None,
+                "1.1.1k",
+                "28.cm2",
+                "x86_64",
+            ),
+            (
+                "tzdata-2024a-1.cm2.noarch",
+                "tzdata",
+                None,
+                "2024a",
+                "1.cm2",
+                "noarch",
+            ),
+        ] {
+            let parsed = Nevra::parse(nevra).unwrap();
+            assert_eq!(
+                parsed,
+                Nevra {
+                    name: name.to_string(),
+                    epoch,
+                    version: version.to_string(),
+                    release: release.to_string(),
+                    arch: arch.to_string(),
+                },
+                "{}",
+                nevra
+            );
+            assert_eq!(parsed.to_string(), nevra);
+        }
+    }
+
+    #[test]
+    fn invalid_nevras_are_rejected() {
+        for nevra in [
+            "",
+            "bash",
+            "bash.x86_64",
+            "bash-5.1.8.x86_64",
+            "bash-5.1.8-4.cm2.",
+            "-5.1.8-4.cm2.x86_64",
+            "bash--4.cm2.x86_64",
+            "bash-x:5.1.8-4.cm2.x86_64",
+            "bash-1:2:5.1.8-4.cm2.x86_64",
+        ] {
+            assert!(Nevra::parse(nevra).is_err(), "{}", nevra);
+        }
+    }
+}
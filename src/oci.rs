@@ -0,0 +1,3218 @@
+//! Copyright (C) Microsoft Corporation.
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::{File, TryLockError};
+use std::io::{BufReader, Read, Seek, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::{GzDecoder, GzEncoder};
+use flate2::Compression;
+use ocidir::cap_std::fs::OpenOptions;
+use ocidir::oci_spec::image::{
+    Descriptor, DescriptorBuilder, HistoryBuilder, ImageConfiguration, ImageIndex,
+    ImageIndexBuilder, ImageManifest, ImageManifestBuilder, MediaType, Platform,
+    ANNOTATION_REF_NAME, SCHEMA_VERSION,
+};
+use ocidir::OciDir;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::archive::{append_dir_all_with_xattrs, LayerPart, Mtimes};
+use crate::config::LayerConfig;
+use crate::rootfs::resolve_in_root;
+use crate::sign::SIMPLE_SIGNING_MEDIA_TYPE;
+
+/// Prefix of the temporary files that blobs are written to, before being renamed to their digest
+const TMP_BLOB_PREFIX: &str = ".tmp-";
+/// Prefix of the temporary file that the index is written to, before replacing `index.json`
+const TMP_INDEX_PREFIX: &str = "index.json.tmp-";
+/// The empty JSON object, used as the config of artifact manifests
+const EMPTY_JSON: &[u8] = b"{}";
+/// Layer annotation recording the composefs digest of the layer's root filesystem
+pub(crate) const COMPOSEFS_DIGEST_ANNOTATION: &str = "containers.composefs.digest";
+/// Manifest annotation marking images built with the best effort install policy, which may be missing packages
+pub(crate) const POTENTIALLY_INCOMPLETE_ANNOTATION: &str =
+    "com.microsoft.rpmoci.potentially-incomplete";
+/// Manifest annotation listing the packages that failed to install during a best effort build
+pub(crate) const FAILED_PACKAGES_ANNOTATION: &str = "com.microsoft.rpmoci.failed-packages";
+/// Media type of Docker image manifests (schema 2)
+pub(crate) const DOCKER_MANIFEST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.v2+json";
+/// Media type of Docker image configurations
+pub(crate) const DOCKER_CONFIG_MEDIA_TYPE: &str = "application/vnd.docker.container.image.v1+json";
+/// Media type of uncompressed Docker image layers
+pub(crate) const DOCKER_LAYER_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar";
+/// Media type of gzip compressed Docker image layers
+pub(crate) const DOCKER_LAYER_GZIP_MEDIA_TYPE: &str =
+    "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+/// Compute the composefs digest of a layer's contents, from its tar stream
+pub(crate) fn composefs_digest(oci_dir: &OciDir, layer: &ImageLayer) -> Result<String> {
+    crate::composefs::composefs_digest(decompressed_layer(oci_dir, &layer.descriptor)?)
+        .context("Failed to compute composefs digest of layer")
+}
+
+/// Annotations for the root filesystem layer's descriptor
+pub(crate) fn layer_annotations(
+    composefs_digest: Option<String>,
+) -> Option<HashMap<String, String>> {
+    composefs_digest
+        .map(|digest| HashMap::from([(COMPOSEFS_DIGEST_ANNOTATION.to_string(), digest)]))
+}
+
+/// An image layer written to a blob store
+#[derive(Debug, Clone)]
+pub(crate) struct ImageLayer {
+    /// The descriptor of the layer's compressed blob
+    pub(crate) descriptor: Descriptor,
+    /// The digest of the layer's uncompressed tar stream
+    pub(crate) diff_id: String,
+}
+
+/// Create a gzip compressed image layer from the given part of the root filesystem at `rootfs`,
+/// writing it to `store` with digests computed with `algorithm`.
+///
+/// Any mtimes later than `clamp_mtime` are clamped to it.
+/// Fails if the uncompressed layer is larger than `cfg.max_size`.
+pub(crate) fn create_image_layer(
+    store: &dyn BlobStore,
+    algorithm: DigestAlgorithm,
+    rootfs: &Path,
+    mtimes: &Mtimes,
+    cfg: &LayerConfig,
+    part: LayerPart,
+    progress: Option<&mut dyn FnMut(u64)>,
+) -> Result<ImageLayer> {
+    write_image_layer(store, algorithm, cfg, progress, &mut |tar| {
+        let mut builder = tar::Builder::new(tar);
+        builder.follow_symlinks(false);
+        append_dir_all_with_xattrs(&mut builder, rootfs, mtimes, cfg, part)
+            .context("failed to archive root filesystem")?;
+        builder.finish()?;
+        Ok(())
+    })
+}
+
+/// Create a gzip compressed image layer from a tar stream that has already been built,
+/// e.g. by a package manager, so that the stream is only compressed and hashed rather than
+/// being extracted to disk and archived again.
+///
+/// Fails if the tar stream is larger than `cfg.max_size`.
+// Not yet used outside tests: nothing in the build produces a tar stream, as dnf extracts packages to the installroot
+#[allow(dead_code)]
+pub(crate) fn create_image_layer_from_tar(
+    store: &dyn BlobStore,
+    algorithm: DigestAlgorithm,
+    mut tar: impl Read,
+    cfg: &LayerConfig,
+    progress: Option<&mut dyn FnMut(u64)>,
+) -> Result<ImageLayer> {
+    write_image_layer(store, algorithm, cfg, progress, &mut |writer| {
+        std::io::copy(&mut tar, writer).context("failed to read layer tar stream")?;
+        Ok(())
+    })
+}
+
+/// Write a gzip compressed image layer whose tar stream is written by `write_tar` to `store`,
+/// computing the digests of the compressed blob and of the tar stream, its diff_id, with `algorithm`.
+///
+/// Fails if the tar stream is larger than `cfg.max_size`.
+/// The gzip header has a zero mtime and an unknown (255) OS byte, so the compressed
+/// blob is as reproducible as the tar stream.
+fn write_image_layer(
+    store: &dyn BlobStore,
+    algorithm: DigestAlgorithm,
+    cfg: &LayerConfig,
+    mut progress: Option<&mut dyn FnMut(u64)>,
+    write_tar: &mut dyn FnMut(&mut dyn Write) -> Result<()>,
+) -> Result<ImageLayer> {
+    let mut diff_id = String::new();
+    let (digest, size) = store
+        .write_with(algorithm, &mut |blob| {
+            let progress = progress.as_mut().map(|p| &mut **p as &mut dyn FnMut(u64));
+            let blob = ProgressWriter::new(blob, progress);
+            let compressor = flate2::write::GzEncoder::new(blob, Compression::fast());
+            let mut tar = DigestWriter::new(compressor, algorithm);
+            write_tar(&mut tar)?;
+            let (compressor, uncompressed_digest, size) = tar.finish();
+            check_max_size(size, cfg)?;
+            compressor.finish()?;
+            diff_id = format!("{}:{}", algorithm, uncompressed_digest);
+            Ok(())
+        })
+        .map_err(explain_storage_full)?;
+    Ok(ImageLayer {
+        descriptor: DescriptorBuilder::default()
+            .media_type(MediaType::ImageLayerGzip)
+            .digest(digest)
+            .size(size as i64)
+            .build()?,
+        diff_id,
+    })
+}
+
+/// Add a layer to a manifest, recording its diff_id and a history entry in the image configuration
+pub(crate) fn push_layer(
+    manifest: &mut ImageManifest,
+    config: &mut ImageConfiguration,
+    layer: ImageLayer,
+    annotations: Option<HashMap<String, String>>,
+    created_by: &str,
+    created: chrono::DateTime<chrono::Utc>,
+) -> Result<()> {
+    let mut descriptor = layer.descriptor;
+    descriptor.set_annotations(annotations);
+    manifest.layers_mut().push(descriptor);
+    let mut rootfs = config.rootfs().clone();
+    rootfs.diff_ids_mut().push(layer.diff_id);
+    config.set_rootfs(rootfs);
+    let history = HistoryBuilder::default()
+        .created(created.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+        .created_by(created_by)
+        .build()?;
+    config.history_mut().push(history);
+    Ok(())
+}
+
+/// Check the uncompressed size of the given part of the root filesystem at `rootfs` as an image layer
+/// against `cfg.max_size`, without writing the layer, e.g. for dry runs
+pub(crate) fn check_layer_size(
+    rootfs: &Path,
+    mtimes: &Mtimes,
+    cfg: &LayerConfig,
+    part: LayerPart,
+) -> Result<()> {
+    let mut size = 0;
+    {
+        let mut record_size = |written| size = written;
+        let mut builder =
+            tar::Builder::new(ProgressWriter::new(std::io::sink(), Some(&mut record_size)));
+        builder.follow_symlinks(false);
+        append_dir_all_with_xattrs(&mut builder, rootfs, mtimes, cfg, part)
+            .context("failed to archive root filesystem")?;
+        builder.finish()?;
+    }
+    check_max_size(size, cfg)
+}
+
+/// Fail if an image layer's uncompressed size exceeds `cfg.max_size`
+fn check_max_size(size: u64, cfg: &LayerConfig) -> Result<()> {
+    if let Some(max_size) = cfg.max_size.filter(|max_size| size > *max_size) {
+        bail!(
+            "The root filesystem layer is {} bytes uncompressed, exceeding layer.max_size of {} bytes",
+            size,
+            max_size
+        );
+    }
+    Ok(())
+}
+
+/// A writer that passes the total number of bytes written through it to a callback after each write,
+/// e.g. to report progress writing a layer blob
+pub(crate) struct ProgressWriter<'a, W> {
+    inner: W,
+    written: u64,
+    progress: Option<&'a mut dyn FnMut(u64)>,
+}
+
+impl<'a, W: Write> ProgressWriter<'a, W> {
+    pub(crate) fn new(inner: W, progress: Option<&'a mut dyn FnMut(u64)>) -> Self {
+        Self {
+            inner,
+            written: 0,
+            progress,
+        }
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        if let Some(progress) = &mut self.progress {
+            progress(self.written);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Add an actionable explanation to errors caused by a filesystem running out of space
+fn explain_storage_full(err: anyhow::Error) -> anyhow::Error {
+    let storage_full = err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::StorageFull)
+    });
+    if storage_full {
+        err.context(
+            "Ran out of disk space while writing the root filesystem layer to the image layout. \
+            Free up space on the filesystem containing the image layout, \
+            or if it shares a filesystem with the temporary directory use `--tmp-dir` to move the installroot elsewhere",
+        )
+    } else {
+        err
+    }
+}
+
+/// The compression of an image layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LayerCompression {
+    /// Uncompressed
+    None,
+    /// gzip
+    Gzip,
+    /// zstd, which Docker images don't support
+    Zstd,
+}
+
+impl LayerCompression {
+    fn media_type(self) -> MediaType {
+        match self {
+            LayerCompression::None => MediaType::ImageLayer,
+            LayerCompression::Gzip => MediaType::ImageLayerGzip,
+            LayerCompression::Zstd => MediaType::ImageLayerZstd,
+        }
+    }
+}
+
+/// The media types used for an image's manifest, config and layers
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MediaTypes {
+    /// The OCI image spec's media types
+    #[default]
+    Oci,
+    /// Docker's image manifest v2 schema 2 media types, for registries that don't support OCI images
+    Docker,
+}
+
+impl MediaTypes {
+    fn manifest(self) -> MediaType {
+        match self {
+            MediaTypes::Oci => MediaType::ImageManifest,
+            MediaTypes::Docker => MediaType::Other(DOCKER_MANIFEST_MEDIA_TYPE.to_string()),
+        }
+    }
+
+    fn config(self) -> MediaType {
+        match self {
+            MediaTypes::Oci => MediaType::ImageConfig,
+            MediaTypes::Docker => MediaType::Other(DOCKER_CONFIG_MEDIA_TYPE.to_string()),
+        }
+    }
+
+    /// The equivalent of an OCI layer media type
+    fn layer(self, media_type: &MediaType) -> Result<MediaType> {
+        Ok(match (self, media_type) {
+            (MediaTypes::Oci, _) => media_type.clone(),
+            (MediaTypes::Docker, MediaType::ImageLayer) => {
+                MediaType::Other(DOCKER_LAYER_MEDIA_TYPE.to_string())
+            }
+            (MediaTypes::Docker, MediaType::ImageLayerGzip) => {
+                MediaType::Other(DOCKER_LAYER_GZIP_MEDIA_TYPE.to_string())
+            }
+            (MediaTypes::Docker, media_type) => bail!(
+                "Layers with media type `{}` can't be used in Docker images",
+                media_type
+            ),
+        })
+    }
+}
+
+/// Whether a manifest descriptor's media type is that of an OCI or Docker image manifest
+fn is_image_manifest(media_type: &MediaType) -> bool {
+    match media_type {
+        MediaType::ImageManifest => true,
+        MediaType::Other(media_type) => media_type == DOCKER_MANIFEST_MEDIA_TYPE,
+        _ => false,
+    }
+}
+
+/// Open a layer's blob, decompressing it according to the descriptor's media type
+fn decompressed_layer(oci_dir: &OciDir, descriptor: &Descriptor) -> Result<Box<dyn Read>> {
+    let blob = BufReader::new(
+        open_blob(oci_dir, descriptor)
+            .with_context(|| format!("Failed to read layer `{}`", descriptor.digest()))?,
+    );
+    Ok(match descriptor.media_type() {
+        MediaType::ImageLayer => Box::new(blob),
+        MediaType::ImageLayerGzip => Box::new(GzDecoder::new(blob)),
+        MediaType::ImageLayerZstd => Box::new(zstd::stream::read::Decoder::with_buffer(blob)?),
+        MediaType::Other(media_type) if media_type == DOCKER_LAYER_MEDIA_TYPE => Box::new(blob),
+        MediaType::Other(media_type) if media_type == DOCKER_LAYER_GZIP_MEDIA_TYPE => {
+            Box::new(GzDecoder::new(blob))
+        }
+        // Signature payloads are stored uncompressed
+        MediaType::Other(media_type) if media_type == SIMPLE_SIGNING_MEDIA_TYPE => Box::new(blob),
+        media_type => bail!(
+            "Unsupported media type `{}` for layer `{}`",
+            media_type,
+            descriptor.digest()
+        ),
+    })
+}
+
+/// Compute the diff_id of a layer, i.e. the digest of its uncompressed contents,
+/// by decompressing its blob according to the descriptor's media type.
+pub(crate) fn layer_diff_id(
+    oci_dir: &OciDir,
+    descriptor: &Descriptor,
+    algorithm: DigestAlgorithm,
+) -> Result<String> {
+    let mut reader = decompressed_layer(oci_dir, descriptor)?;
+    let mut writer = DigestWriter::new(std::io::sink(), algorithm);
+    std::io::copy(&mut reader, &mut writer)
+        .with_context(|| format!("Failed to decompress layer `{}`", descriptor.digest()))?;
+    let (_, digest, _) = writer.finish();
+    Ok(format!("{}:{}", algorithm, digest))
+}
+
+/// Recompress a layer, writing a new blob and returning its descriptor.
+///
+/// The layer's uncompressed contents, and so its diff_id, are unchanged, as are its annotations.
+/// The layer is given the media type of `compression` in `media_types`.
+/// The existing blob is left in place, as other manifests may reference it.
+fn recompress_layer(
+    oci_dir: &OciDir,
+    descriptor: &Descriptor,
+    compression: LayerCompression,
+    media_types: MediaTypes,
+) -> Result<Descriptor> {
+    let media_type = media_types.layer(&compression.media_type())?;
+    if descriptor.media_type() == &media_type {
+        return Ok(descriptor.clone());
+    }
+    let Some((algorithm, _)) = DigestAlgorithm::parse_digest(descriptor.digest()) else {
+        bail!("Unsupported digest `{}`", descriptor.digest());
+    };
+    let layer = decompressed_layer(oci_dir, descriptor)?;
+    let data: Box<dyn Read> = match compression {
+        LayerCompression::None => layer,
+        LayerCompression::Gzip => Box::new(GzEncoder::new(layer, Compression::fast())),
+        LayerCompression::Zstd => Box::new(zstd::stream::read::Encoder::new(layer, 0)?),
+    };
+    let mut builder = write_blob(oci_dir, algorithm, data, media_type)
+        .with_context(|| format!("Failed to recompress layer `{}`", descriptor.digest()))?;
+    if let Some(annotations) = descriptor.annotations() {
+        builder = builder.annotations(annotations.clone());
+    }
+    Ok(builder.build()?)
+}
+
+/// Recompress the layers of the manifest with the given tag or digest, e.g. so that the layers of a base image
+/// match the compression of the layers stacked on it. Returns how many layers were recompressed.
+///
+/// Diff_ids are digests of the uncompressed layers, so the image configuration is unchanged.
+/// The manifest is replaced in the index by the recompressed manifest, which keeps its annotations and platform.
+/// The original manifest and layer blobs are left in place, to be removed by garbage collection.
+pub(crate) fn recompress_image(
+    oci_dir: &OciDir,
+    reference: &str,
+    compression: LayerCompression,
+) -> Result<usize> {
+    let descriptor = referenced_manifest(oci_dir, reference)?;
+    if !is_image_manifest(descriptor.media_type()) {
+        bail!("`{}` isn't an image manifest", reference);
+    }
+    let Some((algorithm, _)) = DigestAlgorithm::parse_digest(descriptor.digest()) else {
+        bail!("Unsupported digest `{}`", descriptor.digest());
+    };
+    let media_types = if descriptor.media_type() == &MediaType::ImageManifest {
+        MediaTypes::Oci
+    } else {
+        MediaTypes::Docker
+    };
+    let mut manifest = ImageManifest::from_reader(open_blob(oci_dir, &descriptor)?)
+        .with_context(|| format!("Failed to parse manifest `{}`", descriptor.digest()))?;
+    if manifest.artifact_type().is_some() {
+        bail!("`{}` is an artifact manifest, not an image", reference);
+    }
+    let layers = manifest
+        .layers()
+        .iter()
+        .map(|layer| recompress_layer(oci_dir, layer, compression, media_types))
+        .collect::<Result<Vec<_>>>()?;
+    let recompressed = layers
+        .iter()
+        .zip(manifest.layers())
+        .filter(|(new, old)| new.digest() != old.digest())
+        .count();
+    if recompressed == 0 {
+        return Ok(0);
+    }
+    manifest.set_layers(layers);
+    let written = write_json_blob(
+        oci_dir,
+        algorithm,
+        &manifest,
+        descriptor.media_type().clone(),
+    )?
+    .build()?;
+    let mut replacement = descriptor.clone();
+    replacement.set_digest(written.digest().clone());
+    replacement.set_size(written.size());
+
+    let mut index = read_or_create_index(oci_dir)?;
+    let manifests = index
+        .manifests()
+        .iter()
+        .map(|d| {
+            if *d == descriptor {
+                replacement.clone()
+            } else {
+                d.clone()
+            }
+        })
+        .collect();
+    index.set_manifests(manifests);
+    write_index(oci_dir, &index)?;
+    Ok(recompressed)
+}
+
+/// What a build does when the image directory already contains files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExistingLayout {
+    /// Add the image to the existing layout, alongside the images already in it
+    #[default]
+    Reuse,
+    /// Fail rather than modify the existing layout
+    Fail,
+    /// Remove the existing layout and create a new one. Directories that aren't image layouts aren't removed
+    Replace,
+}
+
+/// The algorithm used to compute the digests of blobs in an image layout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DigestAlgorithm {
+    /// SHA-256, the OCI default
+    #[default]
+    Sha256,
+    /// SHA-512
+    Sha512,
+}
+
+impl Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DigestAlgorithm::Sha256 => write!(f, "sha256"),
+            DigestAlgorithm::Sha512 => write!(f, "sha512"),
+        }
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+/// A writer that computes the digest of the data written through it
+pub(crate) struct DigestWriter<W> {
+    inner: W,
+    hasher: Hasher,
+    size: u64,
+}
+
+impl<W: Write> DigestWriter<W> {
+    pub(crate) fn new(inner: W, algorithm: DigestAlgorithm) -> Self {
+        let hasher = match algorithm {
+            DigestAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            DigestAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        };
+        Self {
+            inner,
+            hasher,
+            size: 0,
+        }
+    }
+
+    /// Return the inner writer, the hex encoded digest and the number of bytes written
+    pub(crate) fn finish(self) -> (W, String, u64) {
+        let digest = match self.hasher {
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Sha512(hasher) => format!("{:x}", hasher.finalize()),
+        };
+        (self.inner, digest, self.size)
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        match &mut self.hasher {
+            Hasher::Sha256(hasher) => hasher.update(&buf[..written]),
+            Hasher::Sha512(hasher) => hasher.update(&buf[..written]),
+        }
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl DigestAlgorithm {
+    /// Split a digest of the form `<algorithm>:<hex>` into its algorithm and hex encoded value
+    fn parse_digest(digest: &str) -> Option<(Self, &str)> {
+        match digest.split_once(':')? {
+            ("sha256", hex) => Some((DigestAlgorithm::Sha256, hex)),
+            ("sha512", hex) => Some((DigestAlgorithm::Sha512, hex)),
+            _ => None,
+        }
+    }
+}
+
+/// Open the blob referenced by a descriptor, which may use any supported digest algorithm
+pub(crate) fn open_blob(
+    oci_dir: &OciDir,
+    descriptor: &Descriptor,
+) -> Result<ocidir::cap_std::fs::File> {
+    let Some((algorithm, hex)) = DigestAlgorithm::parse_digest(descriptor.digest()) else {
+        bail!("Unsupported digest `{}`", descriptor.digest());
+    };
+    Ok(oci_dir.dir.open(format!("blobs/{}/{}", algorithm, hex))?)
+}
+
+/// Storage for content addressed blobs, keyed by digests of the form `<algorithm>:<hex>`.
+///
+/// The image layout's `blobs` directory is the default implementation,
+/// but blobs can be written through to any store, e.g. a registry or CAS.
+/// Every blob of an image, including its layers, is written through this trait.
+pub trait BlobStore {
+    /// Store a blob with a known digest
+    fn put(&self, digest: &str, blob: &mut dyn Read) -> Result<()>;
+
+    /// Whether a blob with the given digest is stored
+    fn exists(&self, digest: &str) -> Result<bool>;
+
+    /// Store a blob whose digest isn't known in advance, written by `produce`, returning its digest and size.
+    ///
+    /// The blob is spooled to a temporary file to compute its digest before it's stored.
+    fn write_with(
+        &self,
+        algorithm: DigestAlgorithm,
+        produce: &mut dyn FnMut(&mut dyn Write) -> Result<()>,
+    ) -> Result<(String, u64)> {
+        let mut writer = DigestWriter::new(tempfile::tempfile()?, algorithm);
+        produce(&mut writer)?;
+        let (mut file, hex, size) = writer.finish();
+        let digest = format!("{}:{}", algorithm, hex);
+        if !self.exists(&digest)? {
+            file.rewind()?;
+            self.put(&digest, &mut file)?;
+        }
+        Ok((digest, size))
+    }
+
+    /// Store a blob read from `data`, returning its digest and size
+    fn write(&self, algorithm: DigestAlgorithm, data: &mut dyn Read) -> Result<(String, u64)> {
+        self.write_with(algorithm, &mut |blob| {
+            std::io::copy(data, blob).context("Failed to write blob")?;
+            Ok(())
+        })
+    }
+}
+
+impl BlobStore for OciDir {
+    fn put(&self, digest: &str, blob: &mut dyn Read) -> Result<()> {
+        let Some((algorithm, expected)) = DigestAlgorithm::parse_digest(digest) else {
+            bail!("Unsupported digest `{}`", digest);
+        };
+        write_layout_blob(
+            self,
+            algorithm,
+            &mut |file| {
+                std::io::copy(blob, file).context("Failed to write blob")?;
+                Ok(())
+            },
+            Some(expected),
+        )?;
+        Ok(())
+    }
+
+    fn exists(&self, digest: &str) -> Result<bool> {
+        let Some((algorithm, hex)) = DigestAlgorithm::parse_digest(digest) else {
+            bail!("Unsupported digest `{}`", digest);
+        };
+        Ok(self
+            .dir
+            .try_exists(format!("blobs/{}/{}", algorithm, hex))?)
+    }
+
+    // Blobs are written to a temporary file in the layout and renamed into place, avoiding a copy
+    fn write_with(
+        &self,
+        algorithm: DigestAlgorithm,
+        produce: &mut dyn FnMut(&mut dyn Write) -> Result<()>,
+    ) -> Result<(String, u64)> {
+        let (hex, size) = write_layout_blob(self, algorithm, produce, None)?;
+        Ok((format!("{}:{}", algorithm, hex), size))
+    }
+}
+
+/// Write a blob to a temporary file in `blobs/<algorithm>/`, then sync it and rename it to its digest,
+/// returning the blob's hex encoded digest and size.
+///
+/// The temporary file is removed if `produce` fails, or if the digest isn't `expected`.
+fn write_layout_blob(
+    oci_dir: &OciDir,
+    algorithm: DigestAlgorithm,
+    produce: &mut dyn FnMut(&mut dyn Write) -> Result<()>,
+    expected: Option<&str>,
+) -> Result<(String, u64)> {
+    let blob_dir = format!("blobs/{}", algorithm);
+    oci_dir.dir.create_dir_all(&blob_dir)?;
+    let (tmp_path, file) = create_tmp_file(oci_dir, &format!("{}/{}", blob_dir, TMP_BLOB_PREFIX))?;
+    let mut writer = DigestWriter::new(file, algorithm);
+    if let Err(e) = produce(&mut writer) {
+        let _ = oci_dir.dir.remove_file(&tmp_path);
+        return Err(e);
+    }
+    // The file stays locked until it has been renamed
+    let (file, hex, size) = writer.finish();
+    if let Some(expected) = expected.filter(|expected| *expected != hex) {
+        oci_dir.dir.remove_file(&tmp_path)?;
+        bail!(
+            "Blob has digest `{}:{}` rather than `{}:{}`",
+            algorithm,
+            hex,
+            algorithm,
+            expected
+        );
+    }
+    file.sync_all()?;
+    oci_dir
+        .dir
+        .rename(&tmp_path, &oci_dir.dir, format!("{}/{}", blob_dir, hex))?;
+    drop(file);
+    Ok((hex, size))
+}
+
+/// Create a temporary file in the image layout, named with the given prefix,
+/// and hold an exclusive lock on it until the returned file is closed.
+///
+/// The lock tells `remove_stale_temp_files` that the file is in use. Unlike a process id,
+/// it can be checked by processes in other PID namespaces, e.g. containers sharing the layout.
+fn create_tmp_file(oci_dir: &OciDir, prefix: &str) -> Result<(String, File)> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    let mut n = 0;
+    loop {
+        let path = format!("{}{}-{}", prefix, std::process::id(), n);
+        n += 1;
+        let file = match oci_dir.dir.open_with(&path, &options) {
+            Ok(file) => file.into_std(),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create `{}`", path));
+            }
+        };
+        file.lock()
+            .with_context(|| format!("Failed to lock `{}`", path))?;
+        // Another process may have found the file unlocked and removed it as stale before it was locked
+        let linked = match oci_dir.dir.open(&path) {
+            Ok(linked) => {
+                let (linked, file) = (linked.into_std().metadata()?, file.metadata()?);
+                linked.dev() == file.dev() && linked.ino() == file.ino()
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        if linked {
+            return Ok((path, file));
+        }
+    }
+}
+
+/// Write a blob to a blob store, returning a descriptor for it
+pub(crate) fn write_blob(
+    store: &dyn BlobStore,
+    algorithm: DigestAlgorithm,
+    mut data: impl Read,
+    media_type: MediaType,
+) -> Result<DescriptorBuilder> {
+    let (digest, size) = store.write(algorithm, &mut data)?;
+    Ok(DescriptorBuilder::default()
+        .media_type(media_type)
+        .digest(digest)
+        .size(size as i64))
+}
+
+/// Write a value as a JSON blob to a blob store, returning a descriptor for it.
+///
+/// The value is serialized as canonical JSON, with sorted object keys and no insignificant whitespace,
+/// as ocidir does. The digest then doesn't depend on field order, e.g. of annotations in a `HashMap`.
+fn write_json_blob(
+    store: &dyn BlobStore,
+    algorithm: DigestAlgorithm,
+    value: &impl serde::Serialize,
+    media_type: MediaType,
+) -> Result<DescriptorBuilder> {
+    write_blob(
+        store,
+        algorithm,
+        canonical_json(value)?.as_slice(),
+        media_type,
+    )
+}
+
+/// Serialize a value as canonical JSON
+fn canonical_json(value: &impl serde::Serialize) -> Result<Vec<u8>> {
+    let mut json = Vec::new();
+    let mut serializer =
+        serde_json::Serializer::with_formatter(&mut json, olpc_cjson::CanonicalFormatter::new());
+    value
+        .serialize(&mut serializer)
+        .context("Failed to serialize JSON blob")?;
+    Ok(json)
+}
+
+/// Insert a manifest and its config into the image layout, tagging the manifest with `tag`.
+///
+/// The layers referenced by `manifest` must have been written with the same digest algorithm.
+pub(crate) fn insert_manifest_and_config(
+    oci_dir: &OciDir,
+    manifest: ImageManifest,
+    config: ImageConfiguration,
+    tag: &str,
+    algorithm: DigestAlgorithm,
+) -> Result<()> {
+    insert_manifest_and_config_blob(oci_dir, manifest, &config, tag, algorithm, MediaTypes::Oci)
+}
+
+/// Like `insert_manifest_and_config`, but adds the given fields to the `config` object of the image configuration.
+/// This supports extensions to the image configuration that the OCI image spec doesn't define, such as Docker's `Healthcheck`.
+///
+/// The manifest, config and layers are given Docker media types if `media_types` is `Docker`.
+pub(crate) fn insert_manifest_and_extended_config(
+    oci_dir: &OciDir,
+    manifest: ImageManifest,
+    config: ImageConfiguration,
+    extensions: serde_json::Map<String, serde_json::Value>,
+    tag: &str,
+    algorithm: DigestAlgorithm,
+    media_types: MediaTypes,
+) -> Result<()> {
+    if media_types == MediaTypes::Docker && algorithm != DigestAlgorithm::Sha256 {
+        bail!("Docker images only support sha256 digests");
+    }
+    if extensions.is_empty() && media_types == MediaTypes::Oci {
+        return insert_manifest_and_config(oci_dir, manifest, config, tag, algorithm);
+    }
+    let mut value = serde_json::to_value(&config)?;
+    let Some(object) = value.as_object_mut() else {
+        bail!("The image configuration isn't a JSON object");
+    };
+    let Some(container_config) = object
+        .entry("config")
+        .or_insert_with(|| serde_json::Value::Object(Default::default()))
+        .as_object_mut()
+    else {
+        bail!("The image configuration's `config` isn't a JSON object");
+    };
+    container_config.extend(extensions);
+    insert_manifest_and_config_blob(oci_dir, manifest, &value, tag, algorithm, media_types)
+}
+
+/// Write the image configuration blob and the manifest, and tag the manifest in the index
+fn insert_manifest_and_config_blob(
+    oci_dir: &OciDir,
+    mut manifest: ImageManifest,
+    config: &impl serde::Serialize,
+    tag: &str,
+    algorithm: DigestAlgorithm,
+    media_types: MediaTypes,
+) -> Result<()> {
+    for layer in manifest.layers() {
+        if DigestAlgorithm::parse_digest(layer.digest()).map(|(a, _)| a) != Some(algorithm) {
+            bail!(
+                "Layer `{}` doesn't have a {} digest",
+                layer.digest(),
+                algorithm
+            );
+        }
+    }
+    let mut layers = manifest.layers().clone();
+    for layer in &mut layers {
+        layer.set_media_type(media_types.layer(layer.media_type())?);
+    }
+    manifest.set_layers(layers);
+    manifest.set_media_type(Some(media_types.manifest()));
+    let config = write_json_blob(oci_dir, algorithm, &config, media_types.config())?.build()?;
+    manifest.set_config(config);
+
+    let descriptor = write_json_blob(oci_dir, algorithm, &manifest, media_types.manifest())?
+        .platform(Platform::default())
+        .annotations(HashMap::from([(
+            ANNOTATION_REF_NAME.to_string(),
+            tag.to_string(),
+        )]))
+        .build()?;
+
+    let mut index = read_or_create_index(oci_dir)?;
+    let mut manifests = index.manifests().clone();
+    manifests.retain(|d| IndexManifest::from(d).tag.as_deref() != Some(tag));
+    manifests.push(descriptor);
+    index.set_manifests(manifests);
+    write_index(oci_dir, &index)
+}
+
+/// The image index, or an empty index if the layout doesn't have one yet
+fn read_or_create_index(oci_dir: &OciDir) -> Result<ImageIndex> {
+    match oci_dir.read_index()? {
+        Some(index) => Ok(index),
+        None => Ok(ImageIndexBuilder::default()
+            .schema_version(SCHEMA_VERSION)
+            .manifests(Vec::new())
+            .build()?),
+    }
+}
+
+/// Add annotations to the image index, replacing existing annotations with the same keys
+pub(crate) fn set_index_annotations(
+    oci_dir: &OciDir,
+    annotations: &HashMap<String, String>,
+) -> Result<()> {
+    let mut index = read_or_create_index(oci_dir)?;
+    let mut merged = index.annotations().clone().unwrap_or_default();
+    merged.extend(annotations.clone());
+    index.set_annotations(Some(merged));
+    write_index(oci_dir, &index)
+}
+
+/// Atomically replace the image index, setting its media type.
+///
+/// The blob directories are synced first, so that the index never references blobs that
+/// could be lost if the system crashes.
+fn write_index(oci_dir: &OciDir, index: &ImageIndex) -> Result<()> {
+    let mut index = index.clone();
+    index.set_media_type(Some(MediaType::ImageIndex));
+    sync_blob_dirs(oci_dir)?;
+    let (tmp_name, mut file) =
+        create_tmp_file(oci_dir, TMP_INDEX_PREFIX).context("Failed to write image index")?;
+    file.write_all(&serde_json::to_vec(&index)?)
+        .and_then(|_| file.sync_all())
+        .context("Failed to write image index")?;
+    oci_dir
+        .dir
+        .rename(&tmp_name, &oci_dir.dir, "index.json")
+        .context("Failed to replace image index")?;
+    Ok(())
+}
+
+/// Sync the blob directories, so that blobs renamed into them persist
+fn sync_blob_dirs(oci_dir: &OciDir) -> Result<()> {
+    for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+        let blob_dir = format!("blobs/{}", algorithm);
+        if oci_dir.dir.try_exists(&blob_dir)? {
+            oci_dir
+                .dir
+                .open(&blob_dir)
+                .and_then(|dir| dir.sync_all())
+                .with_context(|| format!("Failed to sync `{}`", blob_dir))?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove temporary files left in the image layout by interrupted rpmoci processes, returning how many were removed.
+///
+/// Processes hold a lock on the temporary files they're writing, so locked files are kept.
+pub(crate) fn remove_stale_temp_files(oci_dir: &OciDir) -> Result<usize> {
+    let mut removed = 0;
+    let mut remove_stale = |dir: &str, prefix: &str| -> Result<()> {
+        if !oci_dir.dir.try_exists(dir)? {
+            return Ok(());
+        }
+        for entry in oci_dir.dir.read_dir(dir)? {
+            let name = entry?.file_name();
+            if !name.to_str().is_some_and(|name| name.starts_with(prefix)) {
+                continue;
+            }
+            let path = Path::new(dir).join(name);
+            let file = match oci_dir.dir.open(&path) {
+                Ok(file) => file.into_std(),
+                // Renamed or removed by the process that wrote it
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to open `{}`", path.display()))
+                }
+            };
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => continue,
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).with_context(|| format!("Failed to lock `{}`", path.display()))
+                }
+            }
+            // The file is removed while locked, so its writer can tell it was removed
+            match oci_dir.dir.remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to remove `{}`", path.display()))
+                }
+            }
+        }
+        Ok(())
+    };
+    remove_stale(".", TMP_INDEX_PREFIX)?;
+    for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+        remove_stale(&format!("blobs/{}", algorithm), TMP_BLOB_PREFIX)?;
+    }
+    Ok(removed)
+}
+
+/// Check that an image layout conforms to the OCI image spec, returning a description of each violation.
+///
+/// This checks the layout's version, the index and the manifests it references,
+/// that each descriptor's size and digest match its blob, that configs can be parsed,
+/// and that the diff_ids in each config match its layers.
+pub(crate) fn validate_layout(oci_dir: &OciDir) -> Result<Vec<String>> {
+    let mut violations = Vec::new();
+    match oci_dir.dir.read_to_string("oci-layout") {
+        Ok(layout) => {
+            let version = serde_json::from_str::<serde_json::Value>(&layout)
+                .ok()
+                .and_then(|layout| layout["imageLayoutVersion"].as_str().map(str::to_string));
+            if version.as_deref() != Some("1.0.0") {
+                violations.push("oci-layout doesn't have imageLayoutVersion 1.0.0".to_string());
+            }
+        }
+        Err(e) => violations.push(format!("Failed to read oci-layout: {}", e)),
+    }
+
+    let index = match oci_dir.read_index() {
+        Ok(Some(index)) => index,
+        Ok(None) => {
+            violations.push("index.json is missing".to_string());
+            return Ok(violations);
+        }
+        Err(e) => {
+            violations.push(format!("Failed to parse index.json: {:#}", e));
+            return Ok(violations);
+        }
+    };
+    if index.schema_version() != SCHEMA_VERSION {
+        violations.push(format!(
+            "index.json has schemaVersion {}",
+            index.schema_version()
+        ));
+    }
+
+    for descriptor in index.manifests() {
+        if !is_image_manifest(descriptor.media_type()) {
+            violations.push(format!(
+                "Manifest `{}` has unsupported media type `{}`",
+                descriptor.digest(),
+                descriptor.media_type()
+            ));
+            continue;
+        }
+        if !verify_blob(oci_dir, descriptor, &mut violations)? {
+            continue;
+        }
+        let manifest = match ImageManifest::from_reader(open_blob(oci_dir, descriptor)?) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                violations.push(format!(
+                    "Failed to parse manifest `{}`: {}",
+                    descriptor.digest(),
+                    e
+                ));
+                continue;
+            }
+        };
+        validate_manifest(oci_dir, &manifest, &mut violations)?;
+    }
+    Ok(violations)
+}
+
+fn validate_manifest(
+    oci_dir: &OciDir,
+    manifest: &ImageManifest,
+    violations: &mut Vec<String>,
+) -> Result<()> {
+    if manifest.artifact_type().is_some() {
+        // Artifacts' blobs may have any media type, and their config is typically the empty descriptor
+        for blob in manifest.layers().iter().chain([manifest.config()]) {
+            verify_blob(oci_dir, blob, violations)?;
+        }
+        return Ok(());
+    }
+
+    let mut layers_valid = true;
+    for layer in manifest.layers() {
+        let supported = match layer.media_type() {
+            MediaType::ImageLayer | MediaType::ImageLayerGzip | MediaType::ImageLayerZstd => true,
+            MediaType::Other(media_type) => [
+                SIMPLE_SIGNING_MEDIA_TYPE,
+                DOCKER_LAYER_MEDIA_TYPE,
+                DOCKER_LAYER_GZIP_MEDIA_TYPE,
+            ]
+            .contains(&media_type.as_str()),
+            _ => false,
+        };
+        if !supported {
+            violations.push(format!(
+                "Layer `{}` has unsupported media type `{}`",
+                layer.digest(),
+                layer.media_type()
+            ));
+        }
+        layers_valid &= verify_blob(oci_dir, layer, violations)?;
+    }
+
+    let descriptor = manifest.config();
+    let config_media_type = descriptor.media_type();
+    if config_media_type != &MediaType::ImageConfig
+        && config_media_type != &MediaType::Other(DOCKER_CONFIG_MEDIA_TYPE.to_string())
+    {
+        violations.push(format!(
+            "Config `{}` has unsupported media type `{}`",
+            descriptor.digest(),
+            descriptor.media_type()
+        ));
+    }
+    if !verify_blob(oci_dir, descriptor, violations)? {
+        return Ok(());
+    }
+    let config = match ImageConfiguration::from_reader(open_blob(oci_dir, descriptor)?) {
+        Ok(config) => config,
+        Err(e) => {
+            violations.push(format!(
+                "Failed to parse config `{}`: {}",
+                descriptor.digest(),
+                e
+            ));
+            return Ok(());
+        }
+    };
+
+    let diff_ids = config.rootfs().diff_ids();
+    if diff_ids.len() != manifest.layers().len() {
+        violations.push(format!(
+            "Config `{}` has {} diff_ids but its manifest has {} layers",
+            descriptor.digest(),
+            diff_ids.len(),
+            manifest.layers().len()
+        ));
+    } else if layers_valid {
+        for (diff_id, layer) in diff_ids.iter().zip(manifest.layers()) {
+            let Some((algorithm, _)) = DigestAlgorithm::parse_digest(diff_id) else {
+                violations.push(format!(
+                    "Config `{}` has diff_id `{}` with an unsupported digest algorithm",
+                    descriptor.digest(),
+                    diff_id
+                ));
+                continue;
+            };
+            if DigestAlgorithm::parse_digest(layer.digest())
+                .map(|(layer_algorithm, _)| layer_algorithm)
+                != Some(algorithm)
+            {
+                violations.push(format!(
+                    "Config `{}` has diff_id `{}` for layer `{}`, which uses a different digest algorithm",
+                    descriptor.digest(),
+                    diff_id,
+                    layer.digest()
+                ));
+                continue;
+            }
+            match layer_diff_id(oci_dir, layer, algorithm) {
+                Ok(actual) if &actual == diff_id => {}
+                Ok(actual) => violations.push(format!(
+                    "Layer `{}` has diff_id `{}`, but the config records `{}`",
+                    layer.digest(),
+                    actual,
+                    diff_id
+                )),
+                Err(e) => violations.push(format!("{:#}", e)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that a descriptor's blob exists and matches its size and digest, recording any violation.
+/// Returns whether the blob is valid.
+fn verify_blob(
+    oci_dir: &OciDir,
+    descriptor: &Descriptor,
+    violations: &mut Vec<String>,
+) -> Result<bool> {
+    let digest = descriptor.digest();
+    let Some((algorithm, expected)) = DigestAlgorithm::parse_digest(digest) else {
+        violations.push(format!("Descriptor has unsupported digest `{}`", digest));
+        return Ok(false);
+    };
+    let mut blob = match open_blob(oci_dir, descriptor) {
+        Ok(blob) => blob,
+        Err(e) => {
+            violations.push(format!("Failed to open blob `{}`: {:#}", digest, e));
+            return Ok(false);
+        }
+    };
+    let mut writer = DigestWriter::new(std::io::sink(), algorithm);
+    std::io::copy(&mut blob, &mut writer)
+        .with_context(|| format!("Failed to read blob `{}`", digest))?;
+    let (_, actual, size) = writer.finish();
+    let mut valid = true;
+    if actual != expected {
+        violations.push(format!(
+            "Blob `{}` has digest `{}:{}`",
+            digest, algorithm, actual
+        ));
+        valid = false;
+    }
+    if size as i64 != descriptor.size() {
+        violations.push(format!(
+            "Blob `{}` has size {}, but its descriptor has size {}",
+            digest,
+            size,
+            descriptor.size()
+        ));
+        valid = false;
+    }
+    Ok(valid)
+}
+
+/// A manifest referenced by the image index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IndexManifest {
+    /// The manifest's tag, from its `org.opencontainers.image.ref.name` annotation
+    pub(crate) tag: Option<String>,
+    /// The manifest's digest
+    pub(crate) digest: String,
+}
+
+impl From<&Descriptor> for IndexManifest {
+    fn from(descriptor: &Descriptor) -> Self {
+        IndexManifest {
+            tag: descriptor
+                .annotations()
+                .as_ref()
+                .and_then(|annotations| annotations.get(ANNOTATION_REF_NAME))
+                .cloned(),
+            digest: descriptor.digest().to_string(),
+        }
+    }
+}
+
+/// List the manifests in the image index
+pub(crate) fn list_manifests(oci_dir: &OciDir) -> Result<Vec<IndexManifest>> {
+    let Some(index) = oci_dir.read_index()? else {
+        return Ok(Vec::new());
+    };
+    Ok(index.manifests().iter().map(IndexManifest::from).collect())
+}
+
+/// Remove the manifests with the given tag or digest from the image index, returning how many were removed.
+///
+/// The manifests' blobs are left in place, to be removed by garbage collection.
+pub(crate) fn remove_manifests(oci_dir: &OciDir, reference: &str) -> Result<usize> {
+    let Some(mut index) = oci_dir.read_index()? else {
+        bail!("The image layout has no index");
+    };
+    let mut manifests = index.manifests().clone();
+    let count = manifests.len();
+    manifests.retain(|descriptor| {
+        let manifest = IndexManifest::from(descriptor);
+        manifest.tag.as_deref() != Some(reference) && manifest.digest != reference
+    });
+    let removed = count - manifests.len();
+    if removed == 0 {
+        bail!("No manifest has the tag or digest `{}`", reference);
+    }
+    index.set_manifests(manifests);
+    write_index(oci_dir, &index)?;
+    Ok(removed)
+}
+
+/// The descriptor of the manifest with the given tag
+fn tagged_manifest(oci_dir: &OciDir, tag: &str) -> Result<Descriptor> {
+    oci_dir
+        .read_index()?
+        .and_then(|index| {
+            index
+                .manifests()
+                .iter()
+                .find(|descriptor| IndexManifest::from(*descriptor).tag.as_deref() == Some(tag))
+                .cloned()
+        })
+        .with_context(|| format!("No manifest has the tag `{}`", tag))
+}
+
+/// Compare the manifests tagged `tag` in two image layouts.
+///
+/// Returns a description of the first difference between them, or `None` if the manifests are identical.
+pub(crate) fn image_difference(a: &OciDir, b: &OciDir, tag: &str) -> Result<Option<String>> {
+    let (a_descriptor, b_descriptor) = (tagged_manifest(a, tag)?, tagged_manifest(b, tag)?);
+    if a_descriptor.digest() == b_descriptor.digest() {
+        return Ok(None);
+    }
+    let a_manifest = ImageManifest::from_reader(open_blob(a, &a_descriptor)?)?;
+    let b_manifest = ImageManifest::from_reader(open_blob(b, &b_descriptor)?)?;
+
+    // Layers are compared first, as differing layers also change the config's diff_ids
+    if a_manifest.layers().len() != b_manifest.layers().len() {
+        return Ok(Some(format!(
+            "the images have {} and {} layers",
+            a_manifest.layers().len(),
+            b_manifest.layers().len()
+        )));
+    }
+    for (i, (a_layer, b_layer)) in a_manifest
+        .layers()
+        .iter()
+        .zip(b_manifest.layers())
+        .enumerate()
+    {
+        if a_layer.digest() != b_layer.digest() {
+            let entry = layer_difference(a, a_layer, b, b_layer)?
+                .map(|entry| format!(": {}", entry))
+                .unwrap_or_default();
+            return Ok(Some(format!(
+                "layer {} `{}` differs from `{}`{}",
+                i,
+                a_layer.digest(),
+                b_layer.digest(),
+                entry
+            )));
+        }
+    }
+    if a_manifest.config().digest() != b_manifest.config().digest() {
+        let a_config: serde_json::Value =
+            serde_json::from_reader(open_blob(a, a_manifest.config())?)?;
+        let b_config: serde_json::Value =
+            serde_json::from_reader(open_blob(b, b_manifest.config())?)?;
+        let fields = match (a_config.as_object(), b_config.as_object()) {
+            (Some(a_fields), Some(b_fields)) => a_fields
+                .keys()
+                .chain(b_fields.keys().filter(|key| !a_fields.contains_key(*key)))
+                .filter(|key| a_fields.get(*key) != b_fields.get(*key))
+                .cloned()
+                .collect::<Vec<_>>(),
+            _ => Vec::new(),
+        };
+        return Ok(Some(format!(
+            "config `{}` differs from `{}` in fields: {}",
+            a_manifest.config().digest(),
+            b_manifest.config().digest(),
+            fields.join(", ")
+        )));
+    }
+
+    Ok(Some(format!(
+        "manifest `{}` differs from `{}`, but their config and layers are identical",
+        a_descriptor.digest(),
+        b_descriptor.digest()
+    )))
+}
+
+/// The descriptor of the manifest with the given tag or digest
+fn referenced_manifest(oci_dir: &OciDir, reference: &str) -> Result<Descriptor> {
+    oci_dir
+        .read_index()?
+        .and_then(|index| {
+            index
+                .manifests()
+                .iter()
+                .find(|descriptor| {
+                    let manifest = IndexManifest::from(*descriptor);
+                    manifest.tag.as_deref() == Some(reference) || manifest.digest == reference
+                })
+                .cloned()
+        })
+        .with_context(|| format!("No manifest has the tag or digest `{}`", reference))
+}
+
+/// Read the image configuration of the manifest with the given tag or digest.
+///
+/// The digests of the manifest and config blobs are verified. The configuration is returned as JSON,
+/// so that extensions the OCI image spec doesn't define are kept.
+pub(crate) fn image_config(oci_dir: &OciDir, reference: &str) -> Result<serde_json::Value> {
+    let descriptor = referenced_manifest(oci_dir, reference)?;
+    let read_verified = |descriptor: &Descriptor| -> Result<Vec<u8>> {
+        let mut violations = Vec::new();
+        if !verify_blob(oci_dir, descriptor, &mut violations)? {
+            bail!(violations.join(", "));
+        }
+        let mut blob = Vec::new();
+        open_blob(oci_dir, descriptor)?.read_to_end(&mut blob)?;
+        Ok(blob)
+    };
+    let manifest = ImageManifest::from_reader(read_verified(&descriptor)?.as_slice())
+        .with_context(|| format!("Failed to parse manifest `{}`", descriptor.digest()))?;
+    if manifest.artifact_type().is_some() {
+        bail!("`{}` is an artifact manifest, not an image", reference);
+    }
+    let config = manifest.config();
+    serde_json::from_slice(&read_verified(config)?)
+        .with_context(|| format!("Failed to parse config `{}`", config.digest()))
+}
+
+/// Attach an artifact to the manifest with the given tag or digest, following the OCI referrers model.
+///
+/// The artifact is written as the single layer of a manifest with the given `artifact_type`, the empty config
+/// and a `subject` referencing the manifest. The artifact manifest is added to the index untagged,
+/// where `referrers` finds it. Returns the artifact manifest's descriptor.
+pub(crate) fn attach_artifact(
+    oci_dir: &OciDir,
+    reference: &str,
+    artifact_type: &str,
+    artifact: impl Read,
+    media_type: &str,
+    algorithm: DigestAlgorithm,
+) -> Result<Descriptor> {
+    let subject = referenced_manifest(oci_dir, reference)?;
+    let subject = DescriptorBuilder::default()
+        .media_type(subject.media_type().clone())
+        .digest(subject.digest().clone())
+        .size(subject.size())
+        .build()?;
+    let layer = write_blob(oci_dir, algorithm, artifact, MediaType::from(media_type))?.build()?;
+    let config = write_blob(oci_dir, algorithm, EMPTY_JSON, MediaType::EmptyJSON)?
+        .data(openssl::base64::encode_block(EMPTY_JSON))
+        .build()?;
+    let manifest = ImageManifestBuilder::default()
+        .schema_version(SCHEMA_VERSION)
+        .media_type(MediaType::ImageManifest)
+        .artifact_type(MediaType::from(artifact_type))
+        .config(config)
+        .layers(vec![layer])
+        .subject(subject)
+        .build()?;
+    let descriptor = write_json_blob(oci_dir, algorithm, &manifest, MediaType::ImageManifest)?
+        .artifact_type(MediaType::from(artifact_type))
+        .build()?;
+
+    let mut index = read_or_create_index(oci_dir)?;
+    let mut manifests = index.manifests().clone();
+    manifests.push(descriptor.clone());
+    index.set_manifests(manifests);
+    write_index(oci_dir, &index)?;
+    Ok(descriptor)
+}
+
+/// The referrers of the manifest with the given tag or digest, as returned by the OCI distribution spec's
+/// referrers API: an index of the descriptors of the manifests in the layout whose `subject` is that manifest,
+/// optionally filtered by artifact type.
+pub(crate) fn referrers(
+    oci_dir: &OciDir,
+    reference: &str,
+    artifact_type: Option<&str>,
+) -> Result<ImageIndex> {
+    let subject = referenced_manifest(oci_dir, reference)?;
+    let mut referrers = Vec::new();
+    for descriptor in oci_dir
+        .read_index()?
+        .map(|index| index.manifests().clone())
+        .unwrap_or_default()
+    {
+        if descriptor.media_type() != &MediaType::ImageManifest {
+            continue;
+        }
+        let manifest = ImageManifest::from_reader(open_blob(oci_dir, &descriptor)?)?;
+        if manifest.subject().as_ref().map(|subject| subject.digest()) != Some(subject.digest()) {
+            continue;
+        }
+        let manifest_artifact_type = manifest
+            .artifact_type()
+            .clone()
+            .unwrap_or_else(|| manifest.config().media_type().clone());
+        if artifact_type
+            .is_some_and(|artifact_type| manifest_artifact_type.to_string() != artifact_type)
+        {
+            continue;
+        }
+        let mut referrer = DescriptorBuilder::default()
+            .media_type(MediaType::ImageManifest)
+            .digest(descriptor.digest().clone())
+            .size(descriptor.size())
+            .artifact_type(manifest_artifact_type)
+            .build()?;
+        referrer.set_annotations(manifest.annotations().clone());
+        referrers.push(referrer);
+    }
+    Ok(ImageIndexBuilder::default()
+        .schema_version(SCHEMA_VERSION)
+        .media_type(MediaType::ImageIndex)
+        .manifests(referrers)
+        .build()?)
+}
+
+/// Describe the first entry that differs between two layers, if any
+fn layer_difference(
+    a: &OciDir,
+    a_layer: &Descriptor,
+    b: &OciDir,
+    b_layer: &Descriptor,
+) -> Result<Option<String>> {
+    let mut a_archive = tar::Archive::new(decompressed_layer(a, a_layer)?);
+    let mut b_archive = tar::Archive::new(decompressed_layer(b, b_layer)?);
+    let mut a_entries = a_archive.entries()?;
+    let mut b_entries = b_archive.entries()?;
+    loop {
+        let (mut a_entry, mut b_entry) = match (a_entries.next(), b_entries.next()) {
+            (None, None) => return Ok(None),
+            (Some(entry), None) => {
+                return Ok(Some(format!(
+                    "`{}` is only in the first image",
+                    entry?.path()?.display()
+                )))
+            }
+            (None, Some(entry)) => {
+                return Ok(Some(format!(
+                    "`{}` is only in the second image",
+                    entry?.path()?.display()
+                )))
+            }
+            (Some(a_entry), Some(b_entry)) => (a_entry?, b_entry?),
+        };
+        let path = a_entry.path()?.into_owned();
+        if path != b_entry.path()?.as_ref() {
+            return Ok(Some(format!(
+                "`{}` and `{}` are in the same position",
+                path.display(),
+                b_entry.path()?.display()
+            )));
+        }
+        if a_entry.header().as_bytes() != b_entry.header().as_bytes() {
+            return Ok(Some(format!("`{}` has different metadata", path.display())));
+        }
+        let (mut a_contents, mut b_contents) = (Vec::new(), Vec::new());
+        a_entry.read_to_end(&mut a_contents)?;
+        b_entry.read_to_end(&mut b_contents)?;
+        if a_contents != b_contents {
+            return Ok(Some(format!("`{}` has different contents", path.display())));
+        }
+    }
+}
+
+/// Prefix of whiteout files, which mark that the path without the prefix is removed
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Opaque whiteout file, which marks that its directory's contents from lower layers are removed
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Extract the root filesystem of the manifest with the given tag or digest to `dest`,
+/// applying its layers in order and handling whiteouts as the OCI image spec describes.
+///
+/// Symlinks are resolved inside `dest`, so that entries are never written outside it.
+/// File ownership isn't restored, so that images can be unpacked without privileges.
+pub(crate) fn unpack(oci_dir: &OciDir, reference: &str, dest: &Path) -> Result<()> {
+    let descriptor = referenced_manifest(oci_dir, reference)?;
+    let manifest = ImageManifest::from_reader(open_blob(oci_dir, &descriptor)?)?;
+
+    std::fs::create_dir_all(dest)
+        .with_context(|| format!("Failed to create `{}`", dest.display()))?;
+    let dest = dest.canonicalize()?;
+    for layer in manifest.layers() {
+        // Whiteouts only apply to lower layers, so are applied before the layer's other entries are extracted
+        let mut archive = tar::Archive::new(decompressed_layer(oci_dir, layer)?);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let path = entry.path()?;
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let parent = path.parent().unwrap_or(Path::new(""));
+            if name == OPAQUE_WHITEOUT {
+                let Some(dir) = unpacked_path(&dest, parent)? else {
+                    continue;
+                };
+                if dir.symlink_metadata().is_ok_and(|meta| meta.is_dir()) {
+                    for child in std::fs::read_dir(&dir)? {
+                        remove_path(&child?.path())?;
+                    }
+                }
+            } else if let Some(name) = name.strip_prefix(WHITEOUT_PREFIX) {
+                if let Some(path) = unpacked_path(&dest, &parent.join(name))? {
+                    remove_path(&path)?;
+                }
+            }
+        }
+
+        let mut archive = tar::Archive::new(decompressed_layer(oci_dir, layer)?);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive.set_unpack_xattrs(true);
+        archive.set_overwrite(true);
+        // Directories' modes and mtimes are set once their contents have been extracted,
+        // so that read-only directories can be populated and their mtimes aren't changed
+        let mut directories = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            if path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(WHITEOUT_PREFIX))
+            {
+                continue;
+            }
+            // The root directory's entry, e.g `./`, describes `dest` itself
+            if path
+                .components()
+                .all(|component| matches!(component, Component::CurDir))
+            {
+                continue;
+            }
+            let Some(target) = unpacked_path(&dest, &path)? else {
+                bail!(
+                    "Failed to unpack `{}` from layer `{}`: a parent directory is a file",
+                    path.display(),
+                    layer.digest()
+                );
+            };
+            // An entry replaces any existing path, unless both are directories
+            let is_dir = entry.header().entry_type().is_dir();
+            let existing_dir = target.symlink_metadata().is_ok_and(|meta| meta.is_dir());
+            if !(existing_dir && is_dir) {
+                remove_path(&target)?;
+            }
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            if is_dir {
+                entry.set_preserve_permissions(false);
+                entry.set_preserve_mtime(false);
+            }
+            // Hard link targets are resolved like the entry's path, rather than relative to the working directory
+            let link_target = match entry.header().entry_type() {
+                tar::EntryType::Link => match entry.link_name()? {
+                    Some(link_name) => unpacked_path(&dest, &link_name)?,
+                    None => None,
+                },
+                _ => None,
+            };
+            match link_target {
+                Some(link_target) => std::fs::hard_link(&link_target, &target).map(|_| ()),
+                None => entry.unpack(&target).map(|_| ()),
+            }
+            .with_context(|| {
+                format!(
+                    "Failed to unpack `{}` from layer `{}`",
+                    path.display(),
+                    layer.digest()
+                )
+            })?;
+            if is_dir {
+                directories.push((
+                    target,
+                    entry.header().mode()? & 0o7777,
+                    entry.header().mtime()?,
+                ));
+            }
+        }
+        for (dir, mode, mtime) in directories.into_iter().rev() {
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(mode))?;
+            filetime::set_file_mtime(&dir, filetime::FileTime::from_unix_time(mtime as i64, 0))?;
+        }
+    }
+    Ok(())
+}
+
+/// The location of a layer entry's path under `dest`, resolving symlinks in its parent directories inside `dest`.
+/// Returns `None` if a parent directory is a file.
+fn unpacked_path(dest: &Path, path: &Path) -> Result<Option<PathBuf>> {
+    let relative = path
+        .components()
+        .filter(|component| !matches!(component, Component::CurDir))
+        .collect::<PathBuf>();
+    if relative.file_name().is_none() {
+        return Ok(None);
+    }
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        bail!(
+            "Layer entry `{}` is outside the root filesystem",
+            path.display()
+        );
+    }
+    resolve_in_root(dest, &relative, false)
+}
+
+/// Remove a file, symlink or directory, if it exists
+fn remove_path(path: &Path) -> Result<()> {
+    match path.symlink_metadata() {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path)?,
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{Read, Write};
+
+    use ocidir::cap_std::{ambient_authority, fs::Dir};
+    use ocidir::oci_spec::image::{
+        ImageConfiguration, ImageConfigurationBuilder, ImageManifest, MediaType,
+    };
+    use ocidir::{new_empty_manifest, OciDir};
+
+    use super::{
+        attach_artifact, canonical_json, check_layer_size, create_image_layer,
+        create_image_layer_from_tar, create_tmp_file, decompressed_layer, explain_storage_full,
+        image_config, image_difference, insert_manifest_and_config,
+        insert_manifest_and_extended_config, layer_annotations, layer_diff_id, list_manifests,
+        open_blob, push_layer, recompress_image, referenced_manifest, referrers, remove_manifests,
+        remove_stale_temp_files, set_index_annotations, unpack, validate_layout, write_blob,
+        write_json_blob, BlobStore, DigestAlgorithm, LayerCompression, MediaTypes,
+        COMPOSEFS_DIGEST_ANNOTATION, DOCKER_CONFIG_MEDIA_TYPE, DOCKER_LAYER_GZIP_MEDIA_TYPE,
+        DOCKER_MANIFEST_MEDIA_TYPE,
+    };
+    use crate::archive::{append_dir_all_with_xattrs, IdMapping, LayerPart, Mtimes};
+    use crate::config::LayerConfig;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+
+    fn oci_dir() -> (tempfile::TempDir, OciDir) {
+        let image = tempfile::tempdir().unwrap();
+        let dir = Dir::open_ambient_dir(image.path(), ambient_authority()).unwrap();
+        let oci_dir = OciDir::ensure(&dir).unwrap();
+        (image, oci_dir)
+    }
+
+    #[test]
+    fn layer_progress_reports_the_blob_size() {
+        let (image, oci_dir) = oci_dir();
+        let rootfs = tempfile::tempdir().unwrap();
+        // Incompressible contents, so that the blob is written in several chunks
+        let mut contents = vec![0; 1 << 20];
+        openssl::rand::rand_bytes(&mut contents).unwrap();
+        std::fs::write(rootfs.path().join("file"), &contents).unwrap();
+
+        let mut reports = Vec::new();
+        let mut progress = |written: u64| reports.push(written);
+        let layer = create_image_layer(
+            &oci_dir,
+            DigestAlgorithm::Sha256,
+            rootfs.path(),
+            &Mtimes::clamp(0),
+            &LayerConfig::default(),
+            LayerPart::All,
+            Some(&mut progress),
+        )
+        .unwrap();
+        assert!(reports.len() > 1);
+        assert!(reports.windows(2).all(|pair| pair[0] <= pair[1]));
+        let size = layer.descriptor.size() as u64;
+        assert_eq!(reports.last(), Some(&size));
+        let hex = layer.descriptor.digest().trim_start_matches("sha256:");
+        assert_eq!(
+            std::fs::metadata(image.path().join("blobs/sha256").join(hex))
+                .unwrap()
+                .len(),
+            size
+        );
+
+        // The blob is the same as ocidir's gzip layer writer produces
+        let mut builder = oci_dir
+            .create_layer(flate2::Compression::fast().into())
+            .unwrap();
+        builder.follow_symlinks(false);
+        append_dir_all_with_xattrs(
+            &mut builder,
+            rootfs.path(),
+            &Mtimes::clamp(0),
+            &LayerConfig::default(),
+            LayerPart::All,
+        )
+        .unwrap();
+        let expected = builder.into_inner().unwrap().complete().unwrap();
+        assert_eq!(
+            layer.descriptor.digest().to_string(),
+            expected.blob.digest_id()
+        );
+        assert_eq!(
+            layer.diff_id,
+            format!("sha256:{}", expected.uncompressed_sha256)
+        );
+    }
+
+    #[test]
+    fn layer_size_budget_is_enforced() {
+        let (_image, oci_dir) = oci_dir();
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::write(rootfs.path().join("file"), vec![0; 64 << 10]).unwrap();
+        let create = |max_size| {
+            create_image_layer(
+                &oci_dir,
+                DigestAlgorithm::Sha256,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig {
+                    max_size,
+                    ..Default::default()
+                },
+                LayerPart::All,
+                None,
+            )
+        };
+        let err = create(Some(1024)).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(
+            message.contains("bytes uncompressed, exceeding layer.max_size of 1024 bytes"),
+            "{}",
+            message
+        );
+        assert!(create(Some(1 << 20)).is_ok());
+
+        // Checking the size without writing the layer measures the same size
+        let check = |max_size| {
+            check_layer_size(
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig {
+                    max_size,
+                    ..Default::default()
+                },
+                LayerPart::All,
+            )
+        };
+        let checked = check(Some(1024)).unwrap_err().to_string();
+        assert!(message.contains(&checked), "{}", checked);
+        check(Some(1 << 20)).unwrap();
+        check(None).unwrap();
+    }
+
+    #[test]
+    fn diff_ids_are_computed_per_media_type() {
+        let (_image, oci_dir) = oci_dir();
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+
+        let gzip_layer = create_image_layer(
+            &oci_dir,
+            DigestAlgorithm::Sha256,
+            rootfs.path(),
+            &Mtimes::clamp(0),
+            &LayerConfig::default(),
+            LayerPart::All,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            layer_diff_id(&oci_dir, &gzip_layer.descriptor, DigestAlgorithm::Sha256).unwrap(),
+            gzip_layer.diff_id
+        );
+
+        let mut descriptor = write_blob(
+            &oci_dir,
+            DigestAlgorithm::Sha256,
+            b"uncompressed".as_slice(),
+            MediaType::ImageLayer,
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        assert_eq!(
+            &layer_diff_id(&oci_dir, &descriptor, DigestAlgorithm::Sha256).unwrap(),
+            descriptor.digest()
+        );
+
+        descriptor.set_media_type(MediaType::ImageConfig);
+        assert!(layer_diff_id(&oci_dir, &descriptor, DigestAlgorithm::Sha256).is_err());
+    }
+
+    #[test]
+    fn layers_are_created_from_tar_streams() {
+        for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+            let (_image, oci_dir) = oci_dir();
+            let rootfs = tempfile::tempdir().unwrap();
+            std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+            let cfg = LayerConfig::default();
+
+            let mut builder = tar::Builder::new(Vec::new());
+            builder.follow_symlinks(false);
+            append_dir_all_with_xattrs(
+                &mut builder,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &cfg,
+                LayerPart::All,
+            )
+            .unwrap();
+            let tar = builder.into_inner().unwrap();
+
+            let layer =
+                create_image_layer_from_tar(&oci_dir, algorithm, tar.as_slice(), &cfg, None)
+                    .unwrap();
+            assert_eq!(layer.descriptor.media_type(), &MediaType::ImageLayerGzip);
+            assert!(layer
+                .descriptor
+                .digest()
+                .starts_with(&format!("{}:", algorithm)));
+            assert_eq!(
+                layer_diff_id(&oci_dir, &layer.descriptor, algorithm).unwrap(),
+                layer.diff_id
+            );
+            if algorithm == DigestAlgorithm::Sha256 {
+                assert_eq!(layer.diff_id, format!("sha256:{:x}", Sha256::digest(&tar)));
+            }
+
+            // The layer is identical to one archived from the root filesystem
+            let archived = create_image_layer(
+                &oci_dir,
+                algorithm,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &cfg,
+                LayerPart::All,
+                None,
+            )
+            .unwrap();
+            assert_eq!(archived.descriptor, layer.descriptor);
+            assert_eq!(archived.diff_id, layer.diff_id);
+
+            // The size budget applies to the tar stream
+            let cfg = LayerConfig {
+                max_size: Some(tar.len() as u64 - 1),
+                ..LayerConfig::default()
+            };
+            assert!(
+                create_image_layer_from_tar(&oci_dir, algorithm, tar.as_slice(), &cfg, None)
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn recompressed_images_keep_their_diff_ids() {
+        for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+            let (_image, oci_dir) = oci_dir();
+            let rootfs = tempfile::tempdir().unwrap();
+            std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+            let layer = create_image_layer(
+                &oci_dir,
+                algorithm,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                LayerPart::All,
+                None,
+            )
+            .unwrap();
+            let mut manifest = new_empty_manifest()
+                .media_type(MediaType::ImageManifest)
+                .build()
+                .unwrap();
+            let mut config = ImageConfigurationBuilder::default().build().unwrap();
+            push_layer(
+                &mut manifest,
+                &mut config,
+                layer,
+                Some(HashMap::from([("key".to_string(), "value".to_string())])),
+                "test",
+                chrono::Utc::now(),
+            )
+            .unwrap();
+            insert_manifest_and_config(&oci_dir, manifest, config, "base", algorithm).unwrap();
+            let manifest = |oci_dir: &OciDir| {
+                let descriptor = referenced_manifest(oci_dir, "base").unwrap();
+                ImageManifest::from_reader(open_blob(oci_dir, &descriptor).unwrap()).unwrap()
+            };
+            let gzip = manifest(&oci_dir);
+            let diff_ids =
+                ImageConfiguration::from_reader(open_blob(&oci_dir, gzip.config()).unwrap())
+                    .unwrap()
+                    .rootfs()
+                    .diff_ids()
+                    .clone();
+
+            assert_eq!(
+                recompress_image(&oci_dir, "base", LayerCompression::Zstd).unwrap(),
+                1
+            );
+            let zstd = manifest(&oci_dir);
+            let layer = &zstd.layers()[0];
+            assert_eq!(layer.media_type(), &MediaType::ImageLayerZstd);
+            assert!(layer.digest().starts_with(&format!("{}:", algorithm)));
+            assert_eq!(layer.annotations(), gzip.layers()[0].annotations());
+            assert_eq!(zstd.config(), gzip.config());
+            assert_eq!(
+                vec![layer_diff_id(&oci_dir, layer, algorithm).unwrap()],
+                diff_ids
+            );
+            assert_eq!(list_manifests(&oci_dir).unwrap().len(), 1);
+            assert_eq!(validate_layout(&oci_dir).unwrap(), Vec::<String>::new());
+
+            // Layers that already have the requested compression are unchanged
+            assert_eq!(
+                recompress_image(&oci_dir, "base", LayerCompression::Zstd).unwrap(),
+                0
+            );
+            for compression in [LayerCompression::None, LayerCompression::Gzip] {
+                recompress_image(&oci_dir, "base", compression).unwrap();
+                let recompressed = manifest(&oci_dir);
+                let layer = &recompressed.layers()[0];
+                assert_eq!(layer.media_type(), &compression.media_type());
+                assert_eq!(
+                    vec![layer_diff_id(&oci_dir, layer, algorithm).unwrap()],
+                    diff_ids
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn docker_images_are_not_recompressed_with_zstd() {
+        let (_image, oci_dir) = oci_dir();
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+        let layer = create_image_layer(
+            &oci_dir,
+            DigestAlgorithm::Sha256,
+            rootfs.path(),
+            &Mtimes::clamp(0),
+            &LayerConfig::default(),
+            LayerPart::All,
+            None,
+        )
+        .unwrap();
+        let mut manifest = new_empty_manifest()
+            .media_type(MediaType::ImageManifest)
+            .build()
+            .unwrap();
+        let mut config = ImageConfigurationBuilder::default().build().unwrap();
+        push_layer(
+            &mut manifest,
+            &mut config,
+            layer,
+            None,
+            "test",
+            chrono::Utc::now(),
+        )
+        .unwrap();
+        insert_manifest_and_extended_config(
+            &oci_dir,
+            manifest,
+            config,
+            Default::default(),
+            "foo",
+            DigestAlgorithm::Sha256,
+            MediaTypes::Docker,
+        )
+        .unwrap();
+
+        assert!(recompress_image(&oci_dir, "foo", LayerCompression::Zstd).is_err());
+        assert_eq!(
+            recompress_image(&oci_dir, "foo", LayerCompression::Gzip).unwrap(),
+            0
+        );
+        recompress_image(&oci_dir, "foo", LayerCompression::None).unwrap();
+        assert_eq!(validate_layout(&oci_dir).unwrap(), Vec::<String>::new());
+    }
+
+    /// A blob store that keeps blobs in memory
+    #[derive(Default)]
+    struct MemoryStore(RefCell<HashMap<String, Vec<u8>>>);
+
+    impl BlobStore for MemoryStore {
+        fn put(&self, digest: &str, blob: &mut dyn Read) -> anyhow::Result<()> {
+            let mut data = Vec::new();
+            blob.read_to_end(&mut data)?;
+            self.0.borrow_mut().insert(digest.to_string(), data);
+            Ok(())
+        }
+
+        fn exists(&self, digest: &str) -> anyhow::Result<bool> {
+            Ok(self.0.borrow().contains_key(digest))
+        }
+    }
+
+    #[test]
+    fn json_blobs_are_canonical() {
+        let store = MemoryStore::default();
+        // The same labels inserted in different orders
+        let config = |order: &mut dyn Iterator<Item = usize>| {
+            let labels = order
+                .map(|i| (format!("label{}", i), i.to_string()))
+                .collect::<HashMap<_, _>>();
+            let mut config = ocidir::oci_spec::image::ConfigBuilder::default()
+                .build()
+                .unwrap();
+            config.set_labels(Some(labels));
+            ImageConfigurationBuilder::default()
+                .config(config)
+                .build()
+                .unwrap()
+        };
+        let first = config(&mut (0..32));
+        let second = config(&mut (0..32).rev());
+        let digest = |config: &ImageConfiguration| {
+            write_json_blob(
+                &store,
+                DigestAlgorithm::Sha256,
+                config,
+                MediaType::ImageConfig,
+            )
+            .unwrap()
+            .build()
+            .unwrap()
+            .digest()
+            .clone()
+        };
+        assert_eq!(digest(&first), digest(&second));
+        assert_eq!(store.0.borrow().len(), 1);
+
+        let json = String::from_utf8(canonical_json(&first).unwrap()).unwrap();
+        assert!(json.contains(r#"{"Labels":{"label0":"0","label1":"1","label10":"10","#));
+    }
+
+    #[test]
+    fn blobs_are_written_through_blob_stores() {
+        let store = MemoryStore::default();
+        let config = ImageConfigurationBuilder::default().build().unwrap();
+        for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+            let blob = write_blob(
+                &store,
+                algorithm,
+                b"hello".as_slice(),
+                MediaType::ImageLayer,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+            assert!(blob.digest().starts_with(&format!("{}:", algorithm)));
+            assert_eq!(blob.size(), 5);
+            assert_eq!(store.0.borrow()[blob.digest()], b"hello");
+
+            let json = write_json_blob(&store, algorithm, &config, MediaType::ImageConfig)
+                .unwrap()
+                .build()
+                .unwrap();
+            assert_eq!(
+                store.0.borrow()[json.digest()],
+                canonical_json(&config).unwrap()
+            );
+        }
+        assert_eq!(store.0.borrow().len(), 4);
+
+        // Layers are written through the store too
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+        for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+            let layer = create_image_layer(
+                &store,
+                algorithm,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                LayerPart::All,
+                None,
+            )
+            .unwrap();
+            assert_eq!(layer.descriptor.media_type(), &MediaType::ImageLayerGzip);
+            let blob = store.0.borrow()[layer.descriptor.digest()].clone();
+            assert_eq!(blob.len() as i64, layer.descriptor.size());
+            let mut tar = Vec::new();
+            flate2::read::GzDecoder::new(blob.as_slice())
+                .read_to_end(&mut tar)
+                .unwrap();
+            let mut writer = super::DigestWriter::new(std::io::sink(), algorithm);
+            writer.write_all(&tar).unwrap();
+            let (_, hex, _) = writer.finish();
+            assert_eq!(layer.diff_id, format!("{}:{}", algorithm, hex));
+        }
+        assert_eq!(store.0.borrow().len(), 6);
+
+        // The image layout verifies the digests of blobs put into it
+        let (_image, oci_dir) = oci_dir();
+        let digest = format!("sha256:{:x}", Sha256::digest(b"hello"));
+        oci_dir.put(&digest, &mut b"hello".as_slice()).unwrap();
+        assert!(BlobStore::exists(&oci_dir, &digest).unwrap());
+        let other = format!("sha256:{:x}", Sha256::digest(b"other"));
+        assert!(oci_dir.put(&other, &mut b"hello".as_slice()).is_err());
+        assert!(!BlobStore::exists(&oci_dir, &other).unwrap());
+        assert!(BlobStore::exists(&oci_dir, &digest).unwrap());
+    }
+
+    #[test]
+    fn composefs_dump_reflects_layer_contents() {
+        use std::os::unix::fs::MetadataExt;
+
+        let (_image, oci_dir) = oci_dir();
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(rootfs.path().join("run/lock")).unwrap();
+        std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+        let cfg = LayerConfig {
+            uid_map: vec![IdMapping {
+                from: std::fs::metadata(rootfs.path()).unwrap().uid(),
+                to: 1234,
+                count: 1,
+            }],
+            ..Default::default()
+        };
+        let dump = || {
+            let layer = create_image_layer(
+                &oci_dir,
+                DigestAlgorithm::Sha256,
+                rootfs.path(),
+                &Mtimes::clamp(100),
+                &cfg,
+                LayerPart::All,
+                None,
+            )
+            .unwrap();
+            crate::composefs::dump_file(decompressed_layer(&oci_dir, &layer.descriptor).unwrap())
+                .unwrap()
+        };
+
+        let original = dump();
+        let lines = String::from_utf8(original.clone()).unwrap();
+        // Ownership is mapped and mtimes are clamped, as in the layer
+        assert!(lines.contains("/file 5 100644 1 1234 "));
+        assert!(lines.contains(" 100.0 "));
+        // Excluded paths aren't included
+        assert!(lines.contains("/run 0 "));
+        assert!(!lines.contains("/run/lock"));
+
+        // Changes to excluded paths don't change the digest
+        std::fs::write(rootfs.path().join("run/lock/pid"), "1").unwrap();
+        assert_eq!(dump(), original);
+        // Changes to included paths do
+        std::fs::write(rootfs.path().join("file"), "world").unwrap();
+        assert_ne!(dump(), original);
+    }
+
+    #[test]
+    fn composefs_digest_is_annotated() {
+        let (_image, oci_dir) = oci_dir();
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+
+        let mut manifest = new_empty_manifest()
+            .media_type(MediaType::ImageManifest)
+            .build()
+            .unwrap();
+        let mut config = ImageConfigurationBuilder::default().build().unwrap();
+        for digest in [None, Some("a".repeat(64))] {
+            let layer = create_image_layer(
+                &oci_dir,
+                DigestAlgorithm::Sha256,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                LayerPart::All,
+                None,
+            )
+            .unwrap();
+            push_layer(
+                &mut manifest,
+                &mut config,
+                layer,
+                layer_annotations(digest),
+                "test",
+                chrono::Utc::now(),
+            )
+            .unwrap();
+        }
+
+        let layers = manifest.layers();
+        assert!(layers[0].annotations().is_none());
+        assert_eq!(
+            layers[1]
+                .annotations()
+                .as_ref()
+                .unwrap()
+                .get(COMPOSEFS_DIGEST_ANNOTATION)
+                .unwrap(),
+            &"a".repeat(64)
+        );
+    }
+
+    #[test]
+    fn manifests_can_be_removed_from_the_index() {
+        let (_image, oci_dir) = oci_dir();
+        for (tag, author) in [("foo", "a"), ("bar", "b")] {
+            let manifest = new_empty_manifest()
+                .media_type(MediaType::ImageManifest)
+                .build()
+                .unwrap();
+            // Use distinct configs so that the manifests have distinct digests
+            let config = ImageConfigurationBuilder::default()
+                .author(author)
+                .build()
+                .unwrap();
+            oci_dir
+                .insert_manifest_and_config(manifest, config, Some(tag), Default::default())
+                .unwrap();
+        }
+        let manifests = list_manifests(&oci_dir).unwrap();
+        let tags = manifests
+            .iter()
+            .map(|m| m.tag.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(tags, ["foo", "bar"]);
+
+        assert_eq!(remove_manifests(&oci_dir, "foo").unwrap(), 1);
+        assert_eq!(list_manifests(&oci_dir).unwrap(), manifests[1..]);
+        assert!(remove_manifests(&oci_dir, "foo").is_err());
+
+        // The last manifest can be removed by digest
+        assert_eq!(remove_manifests(&oci_dir, &manifests[1].digest).unwrap(), 1);
+        assert!(list_manifests(&oci_dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sha512_layouts() {
+        use sha2::{Digest, Sha512};
+
+        let (image, oci_dir) = oci_dir();
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+        let create = |algorithm| {
+            create_image_layer(
+                &oci_dir,
+                algorithm,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                LayerPart::All,
+                None,
+            )
+            .unwrap()
+        };
+        // Layers must use the manifest's digest algorithm
+        let mut manifest = new_empty_manifest()
+            .media_type(MediaType::ImageManifest)
+            .build()
+            .unwrap();
+        let mut config = ImageConfigurationBuilder::default().build().unwrap();
+        push_layer(
+            &mut manifest,
+            &mut config,
+            create(DigestAlgorithm::Sha256),
+            None,
+            "test",
+            chrono::Utc::now(),
+        )
+        .unwrap();
+        let err =
+            insert_manifest_and_config(&oci_dir, manifest, config, "foo", DigestAlgorithm::Sha512)
+                .unwrap_err();
+        assert!(err.to_string().contains("doesn't have a sha512 digest"));
+
+        std::fs::remove_dir_all(image.path().join("blobs/sha256")).unwrap();
+        let layer = create(DigestAlgorithm::Sha512);
+
+        let mut manifest = new_empty_manifest()
+            .media_type(MediaType::ImageManifest)
+            .build()
+            .unwrap();
+        let mut config = ImageConfigurationBuilder::default().build().unwrap();
+        push_layer(
+            &mut manifest,
+            &mut config,
+            layer,
+            layer_annotations(Some("digest".to_string())),
+            "test",
+            chrono::Utc::now(),
+        )
+        .unwrap();
+        insert_manifest_and_config(&oci_dir, manifest, config, "foo", DigestAlgorithm::Sha512)
+            .unwrap();
+
+        // Check a descriptor's digest and size match its blob in blobs/sha512
+        let read_blob = |digest: &str, size: i64| {
+            let hex = digest.strip_prefix("sha512:").unwrap();
+            let blob = std::fs::read(image.path().join("blobs/sha512").join(hex)).unwrap();
+            assert_eq!(format!("{:x}", Sha512::digest(&blob)), hex);
+            assert_eq!(blob.len() as i64, size);
+            blob
+        };
+        let index = oci_dir.read_index().unwrap().unwrap();
+        let [descriptor] = index.manifests().as_slice() else {
+            panic!("expected a single manifest");
+        };
+        let manifest: ocidir::oci_spec::image::ImageManifest =
+            serde_json::from_slice(&read_blob(descriptor.digest(), descriptor.size())).unwrap();
+        read_blob(manifest.config().digest(), manifest.config().size());
+        let [layer] = manifest.layers().as_slice() else {
+            panic!("expected a single layer");
+        };
+        read_blob(layer.digest(), layer.size());
+        assert_eq!(
+            layer.annotations().as_ref().unwrap()[COMPOSEFS_DIGEST_ANNOTATION],
+            "digest"
+        );
+        // Nothing is written with sha256 digests
+        assert!(!image.path().join("blobs/sha256").exists());
+        assert_eq!(
+            list_manifests(&oci_dir).unwrap()[0].tag.as_deref(),
+            Some("foo")
+        );
+    }
+
+    #[test]
+    fn storage_full_errors_are_explained() {
+        let err = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::StorageFull))
+            .context("failed to archive root filesystem");
+        let err = explain_storage_full(err);
+        assert!(err.to_string().contains("Ran out of disk space"));
+        assert!(err.to_string().contains("--tmp-dir"));
+
+        let err = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(!explain_storage_full(err)
+            .to_string()
+            .contains("Ran out of disk space"));
+    }
+
+    #[test]
+    fn compressed_layers_are_reproducible() {
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+        let mut digests = Vec::new();
+        for _ in 0..2 {
+            let (image, oci_dir) = oci_dir();
+            let layer = create_image_layer(
+                &oci_dir,
+                DigestAlgorithm::Sha256,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                LayerPart::All,
+                None,
+            )
+            .unwrap();
+            let blob = std::fs::read(
+                image
+                    .path()
+                    .join("blobs/sha256")
+                    .join(layer.descriptor.digest().trim_start_matches("sha256:")),
+            )
+            .unwrap();
+            // gzip header: magic, compression method, flags, 4 byte mtime, extra flags, OS
+            assert_eq!(&blob[4..8], &[0, 0, 0, 0]);
+            assert_eq!(blob[9], 255);
+            digests.push(layer.descriptor.digest().to_string());
+        }
+        assert_eq!(digests[0], digests[1]);
+    }
+
+    #[test]
+    fn layouts_are_validated() {
+        for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+            let (image, oci_dir) = oci_dir();
+            let rootfs = tempfile::tempdir().unwrap();
+            std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+            let layer = create_image_layer(
+                &oci_dir,
+                algorithm,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                LayerPart::All,
+                None,
+            )
+            .unwrap();
+            let mut manifest = new_empty_manifest()
+                .media_type(MediaType::ImageManifest)
+                .build()
+                .unwrap();
+            let mut config = ImageConfigurationBuilder::default().build().unwrap();
+            push_layer(
+                &mut manifest,
+                &mut config,
+                layer,
+                layer_annotations(None),
+                "test",
+                chrono::Utc::now(),
+            )
+            .unwrap();
+            insert_manifest_and_config(&oci_dir, manifest, config, "foo", algorithm).unwrap();
+            assert_eq!(validate_layout(&oci_dir).unwrap(), Vec::<String>::new());
+
+            // Corrupt every blob
+            let blob_dir = image.path().join("blobs").join(algorithm.to_string());
+            for entry in std::fs::read_dir(blob_dir).unwrap() {
+                let path = entry.unwrap().path();
+                let mut contents = std::fs::read(&path).unwrap();
+                contents.push(b'\n');
+                std::fs::write(path, contents).unwrap();
+            }
+            let violations = validate_layout(&oci_dir).unwrap();
+            assert_eq!(violations.len(), 2, "{:?}", violations);
+            assert!(violations[0].contains("has digest"));
+            assert!(violations[1].contains("has size"));
+        }
+    }
+
+    #[test]
+    fn layouts_can_mix_digest_algorithms() {
+        let (_image, oci_dir) = oci_dir();
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+        for (algorithm, tag) in [
+            (DigestAlgorithm::Sha256, "sha256"),
+            (DigestAlgorithm::Sha512, "sha512"),
+        ] {
+            let layer = create_image_layer(
+                &oci_dir,
+                algorithm,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                LayerPart::All,
+                None,
+            )
+            .unwrap();
+            let mut manifest = new_empty_manifest()
+                .media_type(MediaType::ImageManifest)
+                .build()
+                .unwrap();
+            let mut config = ImageConfigurationBuilder::default().build().unwrap();
+            push_layer(
+                &mut manifest,
+                &mut config,
+                layer,
+                layer_annotations(None),
+                "test",
+                chrono::Utc::now(),
+            )
+            .unwrap();
+            insert_manifest_and_config(&oci_dir, manifest, config, tag, algorithm).unwrap();
+        }
+        assert_eq!(list_manifests(&oci_dir).unwrap().len(), 2);
+        assert_eq!(validate_layout(&oci_dir).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn config_extensions_are_written() {
+        let cfg: crate::config::Config = toml::from_str(
+            r#"
+[contents]
+repositories = []
+packages = []
+
+[image]
+docker_compat = true
+healthcheck = { test = ["CMD", "curl", "-f", "http://localhost"], interval = "1m30s", retries = 3 }
+"#,
+        )
+        .unwrap();
+        let extensions = cfg.image.docker_config_extensions().unwrap();
+
+        for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+            let (_image, oci_dir) = oci_dir();
+            let rootfs = tempfile::tempdir().unwrap();
+            std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+            let layer = create_image_layer(
+                &oci_dir,
+                algorithm,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                LayerPart::All,
+                None,
+            )
+            .unwrap();
+            let mut manifest = new_empty_manifest()
+                .media_type(MediaType::ImageManifest)
+                .build()
+                .unwrap();
+            let mut config = cfg
+                .image
+                .to_oci_image_configuration(HashMap::new(), chrono::Utc::now())
+                .unwrap();
+            push_layer(
+                &mut manifest,
+                &mut config,
+                layer,
+                layer_annotations(None),
+                "test",
+                chrono::Utc::now(),
+            )
+            .unwrap();
+            insert_manifest_and_extended_config(
+                &oci_dir,
+                manifest,
+                config,
+                extensions.clone(),
+                "foo",
+                algorithm,
+                MediaTypes::Oci,
+            )
+            .unwrap();
+            assert_eq!(validate_layout(&oci_dir).unwrap(), Vec::<String>::new());
+
+            let descriptor = oci_dir.read_index().unwrap().unwrap().manifests()[0].clone();
+            let manifest =
+                ImageManifest::from_reader(open_blob(&oci_dir, &descriptor).unwrap()).unwrap();
+            assert!(manifest.layers()[0]
+                .digest()
+                .starts_with(&format!("{}:", algorithm)));
+            let config: serde_json::Value =
+                serde_json::from_reader(open_blob(&oci_dir, manifest.config()).unwrap()).unwrap();
+            assert_eq!(
+                config["config"]["Healthcheck"],
+                serde_json::json!({
+                    "Test": ["CMD", "curl", "-f", "http://localhost"],
+                    "Interval": 90_000_000_000u64,
+                    "Retries": 3,
+                })
+            );
+            // The rest of the configuration is kept
+            assert_eq!(
+                config["config"]["Env"][0],
+                "PATH=/usr/local/bin:/usr/local/sbin:/usr/bin:/usr/sbin:/bin:/sbin"
+            );
+            assert_eq!(config["rootfs"]["diff_ids"].as_array().unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn docker_media_types_are_written() {
+        let (_image, oci_dir) = oci_dir();
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::write(rootfs.path().join("file"), "hello").unwrap();
+        let layer = create_image_layer(
+            &oci_dir,
+            DigestAlgorithm::Sha256,
+            rootfs.path(),
+            &Mtimes::clamp(0),
+            &LayerConfig::default(),
+            LayerPart::All,
+            None,
+        )
+        .unwrap();
+        let mut manifest = new_empty_manifest()
+            .media_type(MediaType::ImageManifest)
+            .build()
+            .unwrap();
+        let mut config = crate::config::ImageConfig::default()
+            .to_oci_image_configuration(HashMap::new(), chrono::Utc::now())
+            .unwrap();
+        push_layer(
+            &mut manifest,
+            &mut config,
+            layer,
+            layer_annotations(None),
+            "test",
+            chrono::Utc::now(),
+        )
+        .unwrap();
+        insert_manifest_and_extended_config(
+            &oci_dir,
+            manifest.clone(),
+            config.clone(),
+            Default::default(),
+            "foo",
+            DigestAlgorithm::Sha256,
+            MediaTypes::Docker,
+        )
+        .unwrap();
+        assert_eq!(validate_layout(&oci_dir).unwrap(), Vec::<String>::new());
+
+        let descriptor = oci_dir.read_index().unwrap().unwrap().manifests()[0].clone();
+        let docker_manifest = MediaType::Other(DOCKER_MANIFEST_MEDIA_TYPE.to_string());
+        assert_eq!(descriptor.media_type(), &docker_manifest);
+        let written =
+            ImageManifest::from_reader(open_blob(&oci_dir, &descriptor).unwrap()).unwrap();
+        assert_eq!(written.media_type().as_ref(), Some(&docker_manifest));
+        assert_eq!(
+            written.config().media_type(),
+            &MediaType::Other(DOCKER_CONFIG_MEDIA_TYPE.to_string())
+        );
+        assert_eq!(
+            written.layers()[0].media_type(),
+            &MediaType::Other(DOCKER_LAYER_GZIP_MEDIA_TYPE.to_string())
+        );
+        // The layer blob is shared with the OCI image
+        assert_eq!(written.layers()[0].digest(), manifest.layers()[0].digest());
+        assert_eq!(
+            layer_diff_id(&oci_dir, &written.layers()[0], DigestAlgorithm::Sha256).unwrap(),
+            config.rootfs().diff_ids()[0]
+        );
+
+        // Docker images don't support other digest algorithms
+        assert!(insert_manifest_and_extended_config(
+            &oci_dir,
+            manifest,
+            config,
+            Default::default(),
+            "bar",
+            DigestAlgorithm::Sha512,
+            MediaTypes::Docker,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sha512_images_use_sha512_diff_ids() {
+        let (_image, oci_dir) = oci_dir();
+        let mut manifest = new_empty_manifest()
+            .media_type(MediaType::ImageManifest)
+            .build()
+            .unwrap();
+        let mut config = ImageConfigurationBuilder::default().build().unwrap();
+        for contents in ["lower", "upper"] {
+            let rootfs = tempfile::tempdir().unwrap();
+            std::fs::write(rootfs.path().join("file"), contents).unwrap();
+            let layer = create_image_layer(
+                &oci_dir,
+                DigestAlgorithm::Sha512,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                LayerPart::All,
+                None,
+            )
+            .unwrap();
+            push_layer(
+                &mut manifest,
+                &mut config,
+                layer,
+                None::<HashMap<String, String>>,
+                "test",
+                chrono::Utc::now(),
+            )
+            .unwrap();
+        }
+        insert_manifest_and_config(&oci_dir, manifest, config, "foo", DigestAlgorithm::Sha512)
+            .unwrap();
+        assert_eq!(validate_layout(&oci_dir).unwrap(), Vec::<String>::new());
+
+        let descriptor = oci_dir.read_index().unwrap().unwrap().manifests()[0].clone();
+        let manifest =
+            ImageManifest::from_reader(open_blob(&oci_dir, &descriptor).unwrap()).unwrap();
+        let config =
+            ImageConfiguration::from_reader(open_blob(&oci_dir, manifest.config()).unwrap())
+                .unwrap();
+        assert_eq!(config.rootfs().diff_ids().len(), 2);
+        for (diff_id, layer) in config.rootfs().diff_ids().iter().zip(manifest.layers()) {
+            assert!(diff_id.starts_with("sha512:"), "{}", diff_id);
+            assert_eq!(
+                *diff_id,
+                layer_diff_id(&oci_dir, layer, DigestAlgorithm::Sha512).unwrap()
+            );
+        }
+
+        // A config whose diff_ids use a different algorithm to the layers is invalid
+        let mut config = config.clone();
+        let mut rootfs = config.rootfs().clone();
+        rootfs.set_diff_ids(
+            manifest
+                .layers()
+                .iter()
+                .map(|layer| layer_diff_id(&oci_dir, layer, DigestAlgorithm::Sha256).unwrap())
+                .collect(),
+        );
+        config.set_rootfs(rootfs);
+        let config = write_json_blob(
+            &oci_dir,
+            DigestAlgorithm::Sha512,
+            &config,
+            MediaType::ImageConfig,
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        let mut manifest = manifest.clone();
+        manifest.set_config(config);
+        let descriptor = write_json_blob(
+            &oci_dir,
+            DigestAlgorithm::Sha512,
+            &manifest,
+            MediaType::ImageManifest,
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        let mut index = oci_dir.read_index().unwrap().unwrap();
+        index.set_manifests(vec![descriptor]);
+        super::write_index(&oci_dir, &index).unwrap();
+        let violations = validate_layout(&oci_dir).unwrap();
+        assert_eq!(violations.len(), 2, "{:?}", violations);
+        assert!(violations[0].contains("which uses a different digest algorithm"));
+    }
+
+    #[test]
+    fn image_configs_are_extracted() {
+        let (image, oci_dir) = oci_dir();
+        let manifest = new_empty_manifest()
+            .media_type(MediaType::ImageManifest)
+            .build()
+            .unwrap();
+        let config = crate::config::ImageConfig {
+            platform: Some("linux/arm64/v8".to_string()),
+            ..Default::default()
+        }
+        .to_oci_image_configuration(HashMap::new(), chrono::Utc::now())
+        .unwrap();
+        insert_manifest_and_config(
+            &oci_dir,
+            manifest,
+            config,
+            "latest",
+            DigestAlgorithm::Sha256,
+        )
+        .unwrap();
+        let image_digest = list_manifests(&oci_dir).unwrap()[0].digest.clone();
+
+        for reference in ["latest", image_digest.as_str()] {
+            let config = image_config(&oci_dir, reference).unwrap();
+            assert_eq!(config["architecture"], "arm64");
+            assert_eq!(config["os"], "linux");
+            assert_eq!(config["variant"], "v8");
+        }
+        assert!(image_config(&oci_dir, "missing").is_err());
+
+        // The config's digest is verified
+        let descriptor = referenced_manifest(&oci_dir, "latest").unwrap();
+        let manifest =
+            ImageManifest::from_reader(open_blob(&oci_dir, &descriptor).unwrap()).unwrap();
+        let config_path = image
+            .path()
+            .join("blobs/sha256")
+            .join(manifest.config().digest().trim_start_matches("sha256:"));
+        let mut tampered = std::fs::read(&config_path).unwrap();
+        tampered[0] = b' ';
+        std::fs::write(&config_path, tampered).unwrap();
+        let err = image_config(&oci_dir, "latest").unwrap_err();
+        assert!(err.to_string().contains("has digest"), "{}", err);
+    }
+
+    #[test]
+    fn index_has_media_type_and_annotations() {
+        for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+            let (image, oci_dir) = oci_dir();
+            let manifest = new_empty_manifest()
+                .media_type(MediaType::ImageManifest)
+                .build()
+                .unwrap();
+            let config = ImageConfigurationBuilder::default().build().unwrap();
+            set_index_annotations(
+                &oci_dir,
+                &HashMap::from([("org.example.layout".to_string(), "test".to_string())]),
+            )
+            .unwrap();
+            insert_manifest_and_config(&oci_dir, manifest, config, "latest", algorithm).unwrap();
+
+            let index: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(image.path().join("index.json")).unwrap())
+                    .unwrap();
+            assert_eq!(
+                index["mediaType"],
+                "application/vnd.oci.image.index.v1+json"
+            );
+            assert_eq!(index["annotations"]["org.example.layout"], "test");
+            assert_eq!(index["manifests"].as_array().unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn artifacts_are_attached_as_referrers() {
+        let (_image, oci_dir) = oci_dir();
+        let manifest = new_empty_manifest()
+            .media_type(MediaType::ImageManifest)
+            .build()
+            .unwrap();
+        let config = ImageConfigurationBuilder::default().build().unwrap();
+        insert_manifest_and_config(
+            &oci_dir,
+            manifest,
+            config,
+            "latest",
+            DigestAlgorithm::Sha256,
+        )
+        .unwrap();
+        let image_digest = list_manifests(&oci_dir).unwrap()[0].digest.clone();
+
+        let sbom = br#"{"spdxVersion": "SPDX-2.3"}"#;
+        let descriptor = attach_artifact(
+            &oci_dir,
+            "latest",
+            "application/spdx+json",
+            sbom.as_slice(),
+            "application/spdx+json",
+            DigestAlgorithm::Sha256,
+        )
+        .unwrap();
+        let artifact =
+            ImageManifest::from_reader(open_blob(&oci_dir, &descriptor).unwrap()).unwrap();
+        assert_eq!(artifact.subject().as_ref().unwrap().digest(), &image_digest);
+        assert_eq!(
+            artifact.artifact_type().as_ref().unwrap().to_string(),
+            "application/spdx+json"
+        );
+        assert_eq!(artifact.config().media_type(), &MediaType::EmptyJSON);
+        let mut blob = Vec::new();
+        open_blob(&oci_dir, &artifact.layers()[0])
+            .unwrap()
+            .read_to_end(&mut blob)
+            .unwrap();
+        assert_eq!(blob, sbom);
+        assert_eq!(validate_layout(&oci_dir).unwrap(), Vec::<String>::new());
+
+        // The artifact is untagged, and is a referrer of the image
+        let manifests = list_manifests(&oci_dir).unwrap();
+        assert_eq!(manifests.len(), 2);
+        assert_eq!(manifests[1].tag, None);
+        let index = referrers(&oci_dir, &image_digest, None).unwrap();
+        assert_eq!(index.manifests().len(), 1);
+        assert_eq!(index.manifests()[0].digest(), descriptor.digest());
+        assert_eq!(
+            index.manifests()[0]
+                .artifact_type()
+                .as_ref()
+                .unwrap()
+                .to_string(),
+            "application/spdx+json"
+        );
+        assert!(
+            referrers(&oci_dir, "latest", Some("application/vnd.cyclonedx+json"))
+                .unwrap()
+                .manifests()
+                .is_empty()
+        );
+        // The artifact has no referrers of its own
+        assert!(referrers(&oci_dir, descriptor.digest(), None)
+            .unwrap()
+            .manifests()
+            .is_empty());
+        assert!(attach_artifact(
+            &oci_dir,
+            "missing",
+            "application/spdx+json",
+            sbom.as_slice(),
+            "application/spdx+json",
+            DigestAlgorithm::Sha256,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn stale_temp_files_are_removed() {
+        let (_image, oci_dir) = oci_dir();
+        // Simulate a process that was killed while writing a blob and the index, so the files aren't locked
+        let dead = "1-0";
+        oci_dir.dir.create_dir_all("blobs/sha512").unwrap();
+        oci_dir
+            .dir
+            .write(format!("blobs/sha256/.tmp-{}", dead), "partial layer")
+            .unwrap();
+        oci_dir
+            .dir
+            .write(format!("blobs/sha512/.tmp-{}", dead), "partial layer")
+            .unwrap();
+        oci_dir
+            .dir
+            .write(format!("index.json.tmp-{}", dead), "{")
+            .unwrap();
+        // Files that are being written are locked, so are kept, whichever process is writing them
+        let (running, file) = create_tmp_file(&oci_dir, "blobs/sha256/.tmp-").unwrap();
+
+        assert_eq!(remove_stale_temp_files(&oci_dir).unwrap(), 3);
+        assert!(!oci_dir
+            .dir
+            .try_exists(format!("blobs/sha256/.tmp-{}", dead))
+            .unwrap());
+        assert!(!oci_dir
+            .dir
+            .try_exists(format!("index.json.tmp-{}", dead))
+            .unwrap());
+        assert!(oci_dir.dir.try_exists(&running).unwrap());
+        // Once it's closed without being renamed, it's stale
+        drop(file);
+        assert_eq!(remove_stale_temp_files(&oci_dir).unwrap(), 1);
+        assert_eq!(remove_stale_temp_files(&oci_dir).unwrap(), 0);
+
+        // The next build succeeds, leaving no temporary files behind
+        for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+            let manifest = new_empty_manifest()
+                .media_type(MediaType::ImageManifest)
+                .build()
+                .unwrap();
+            let config = ImageConfigurationBuilder::default().build().unwrap();
+            insert_manifest_and_config(&oci_dir, manifest, config, "latest", algorithm).unwrap();
+        }
+        assert_eq!(validate_layout(&oci_dir).unwrap(), Vec::<String>::new());
+        for dir in [".", "blobs/sha256", "blobs/sha512"] {
+            for entry in oci_dir.dir.read_dir(dir).unwrap() {
+                let name = entry.unwrap().file_name();
+                assert!(
+                    !name.to_string_lossy().contains(".tmp"),
+                    "{}/{:?}",
+                    dir,
+                    name
+                );
+            }
+        }
+    }
+
+    /// The type, mode, mtime and contents or link target of each path under `root`
+    fn tree(root: &std::path::Path) -> Vec<(String, String)> {
+        use std::os::unix::fs::MetadataExt;
+        walkdir::WalkDir::new(root)
+            .sort_by_file_name()
+            .into_iter()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let meta = entry.path().symlink_metadata().unwrap();
+                let path = entry
+                    .path()
+                    .strip_prefix(root)
+                    .unwrap()
+                    .display()
+                    .to_string();
+                let description = if meta.is_symlink() {
+                    format!("-> {}", std::fs::read_link(entry.path()).unwrap().display())
+                } else if meta.is_dir() {
+                    format!("dir {:o} {}", meta.mode() & 0o7777, meta.mtime())
+                } else {
+                    format!(
+                        "file {:o} {} {}",
+                        meta.mode() & 0o7777,
+                        meta.mtime(),
+                        std::fs::read_to_string(entry.path()).unwrap()
+                    )
+                };
+                (path, description)
+            })
+            .filter(|(path, _)| !path.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn images_are_unpacked() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (_image, oci_dir) = oci_dir();
+        let rootfs = tempfile::tempdir().unwrap();
+        let root = rootfs.path();
+        for dir in ["etc", "usr/bin", "readonly", "opaque/sub"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        std::fs::write(root.join("etc/removed"), "removed").unwrap();
+        std::fs::write(root.join("usr/bin/tool"), "#!/bin/sh").unwrap();
+        std::fs::set_permissions(
+            root.join("usr/bin/tool"),
+            std::fs::Permissions::from_mode(0o750),
+        )
+        .unwrap();
+        std::fs::hard_link(root.join("usr/bin/tool"), root.join("usr/bin/alias")).unwrap();
+        std::os::unix::fs::symlink("usr/bin", root.join("bin")).unwrap();
+        std::os::unix::fs::symlink("/usr/lib", root.join("lib")).unwrap();
+        std::fs::write(root.join("readonly/file"), "read only").unwrap();
+        std::fs::write(root.join("opaque/sub/lower"), "lower").unwrap();
+        std::fs::set_permissions(
+            root.join("readonly"),
+            std::fs::Permissions::from_mode(0o555),
+        )
+        .unwrap();
+
+        let mut manifest = new_empty_manifest()
+            .media_type(MediaType::ImageManifest)
+            .build()
+            .unwrap();
+        let mut config = ImageConfigurationBuilder::default().build().unwrap();
+        let layer = create_image_layer(
+            &oci_dir,
+            DigestAlgorithm::Sha256,
+            root,
+            &Mtimes::clamp(i64::MAX),
+            &LayerConfig::default(),
+            LayerPart::All,
+            None,
+        )
+        .unwrap();
+        push_layer(
+            &mut manifest,
+            &mut config,
+            layer,
+            None::<HashMap<String, String>>,
+            "test",
+            chrono::Utc::now(),
+        )
+        .unwrap();
+        insert_manifest_and_config(
+            &oci_dir,
+            manifest.clone(),
+            config.clone(),
+            "base",
+            DigestAlgorithm::Sha256,
+        )
+        .unwrap();
+
+        // Unpacking a single layer image reproduces its root filesystem
+        let unpacked = tempfile::tempdir().unwrap();
+        unpack(&oci_dir, "base", unpacked.path()).unwrap();
+        assert_eq!(tree(unpacked.path()), tree(root));
+
+        // An upper layer with an entry for the root directory and whiteouts, replacing a symlink with a file
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_mtime(1);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "./", std::io::empty())
+            .unwrap();
+        let mut append = |path: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(1);
+            header.set_cksum();
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        append("etc/.wh.removed", b"");
+        append("opaque/.wh..wh..opq", b"");
+        append("opaque/upper", b"upper");
+        append("bin", b"not a link");
+        // Absolute symlinks are resolved inside the unpacked root filesystem, not on the host
+        append("lib/added", b"added");
+        let layer = create_image_layer_from_tar(
+            &oci_dir,
+            DigestAlgorithm::Sha256,
+            builder.into_inner().unwrap().as_slice(),
+            &LayerConfig::default(),
+            None,
+        )
+        .unwrap();
+        push_layer(
+            &mut manifest,
+            &mut config,
+            layer,
+            None,
+            "test",
+            chrono::Utc::now(),
+        )
+        .unwrap();
+        insert_manifest_and_config(&oci_dir, manifest, config, "app", DigestAlgorithm::Sha256)
+            .unwrap();
+
+        let unpacked = tempfile::tempdir().unwrap();
+        unpack(&oci_dir, "app", unpacked.path()).unwrap();
+        let paths = tree(unpacked.path())
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "bin",
+                "etc",
+                "lib",
+                "opaque",
+                "opaque/upper",
+                "readonly",
+                "readonly/file",
+                "usr",
+                "usr/bin",
+                "usr/bin/alias",
+                "usr/bin/tool",
+                "usr/lib",
+                "usr/lib/added"
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(unpacked.path().join("usr/lib/added")).unwrap(),
+            "added"
+        );
+        assert_eq!(
+            std::fs::read_to_string(unpacked.path().join("bin")).unwrap(),
+            "not a link"
+        );
+
+        assert!(unpack(&oci_dir, "missing", unpacked.path()).is_err());
+    }
+
+    #[test]
+    fn image_differences_are_reported() {
+        let build = |contents: &str, created: i64| {
+            let (image, oci_dir) = oci_dir();
+            let rootfs = tempfile::tempdir().unwrap();
+            std::fs::write(rootfs.path().join("a"), "same").unwrap();
+            std::fs::write(rootfs.path().join("b"), contents).unwrap();
+            let mut manifest = new_empty_manifest()
+                .media_type(MediaType::ImageManifest)
+                .build()
+                .unwrap();
+            let mut config = ImageConfigurationBuilder::default().build().unwrap();
+            let layer = create_image_layer(
+                &oci_dir,
+                DigestAlgorithm::Sha256,
+                rootfs.path(),
+                &Mtimes::clamp(0),
+                &LayerConfig::default(),
+                LayerPart::All,
+                None,
+            )
+            .unwrap();
+            let created = chrono::DateTime::from_timestamp(created, 0).unwrap();
+            push_layer(
+                &mut manifest,
+                &mut config,
+                layer,
+                None::<HashMap<String, String>>,
+                "test",
+                created,
+            )
+            .unwrap();
+            config.set_created(Some(created.to_rfc3339()));
+            insert_manifest_and_config(
+                &oci_dir,
+                manifest,
+                config,
+                "latest",
+                DigestAlgorithm::Sha256,
+            )
+            .unwrap();
+            (image, oci_dir)
+        };
+
+        // Identical inputs produce identical images
+        let (_a, a) = build("contents", 0);
+        let (_b, b) = build("contents", 0);
+        assert_eq!(image_difference(&a, &b, "latest").unwrap(), None);
+
+        // A different creation time is reported as a config difference
+        let (_c, c) = build("contents", 1);
+        let difference = image_difference(&a, &c, "latest").unwrap().unwrap();
+        assert!(difference.starts_with("config `sha256:"), "{}", difference);
+        assert!(difference.contains("created"), "{}", difference);
+
+        // Different file contents are reported as a layer difference, naming the file
+        let (_d, d) = build("other contents", 0);
+        let difference = image_difference(&a, &d, "latest").unwrap().unwrap();
+        assert!(difference.starts_with("layer 0 `sha256:"), "{}", difference);
+        assert!(
+            difference.ends_with(": `b` has different metadata"),
+            "{}",
+            difference
+        );
+
+        assert!(image_difference(&a, &b, "missing").is_err());
+    }
+}
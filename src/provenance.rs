@@ -0,0 +1,224 @@
+//! Copyright (C) Microsoft Corporation.
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use ocidir::OciDir;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::config::Repository;
+use crate::oci::list_manifests;
+
+/// The in-toto statement type
+pub(crate) const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v1";
+/// The SLSA provenance predicate type
+pub(crate) const PROVENANCE_PREDICATE_TYPE: &str = "https://slsa.dev/provenance/v1";
+/// The SLSA build type of rpmoci builds
+const BUILD_TYPE: &str = "https://github.com/microsoft/rpmoci/build/v1";
+/// The SLSA builder id of rpmoci
+const BUILDER_ID: &str = "https://github.com/microsoft/rpmoci";
+
+/// Create an in-toto statement with a SLSA provenance predicate describing the build of the
+/// manifest tagged `tag`.
+///
+/// The inputs recorded are the rpmoci manifest, the lock file and the package repositories.
+/// Repositories defined by id are recorded by name only, and minted repository URLs aren't recorded.
+pub(crate) fn provenance_statement(
+    oci_dir: &OciDir,
+    image: &str,
+    tag: &str,
+    manifest_path: &Path,
+    lockfile_path: &Path,
+    repositories: &[Repository],
+) -> Result<Value> {
+    let Some(manifest) = list_manifests(oci_dir)?
+        .into_iter()
+        .find(|manifest| manifest.tag.as_deref() == Some(tag))
+    else {
+        bail!("No manifest has the tag `{}`", tag);
+    };
+    let Some((algorithm, digest)) = manifest.digest.split_once(':') else {
+        bail!("Invalid manifest digest `{}`", manifest.digest);
+    };
+
+    let mut dependencies = vec![
+        file_descriptor(manifest_path)?,
+        file_descriptor(lockfile_path)?,
+    ];
+    for repo in repositories {
+        dependencies.push(match repo {
+            Repository::Id(id) => json!({ "name": id }),
+            Repository::Url(url) => json!({ "name": repo.repo_id(), "uri": url }),
+            Repository::Definition(definition) => {
+                json!({ "name": repo.repo_id(), "uri": definition.url })
+            }
+        });
+    }
+
+    Ok(json!({
+        "_type": STATEMENT_TYPE,
+        "subject": [{
+            "name": format!("{}:{}", image, tag),
+            "digest": { (algorithm): digest },
+        }],
+        "predicateType": PROVENANCE_PREDICATE_TYPE,
+        "predicate": {
+            "buildDefinition": {
+                "buildType": BUILD_TYPE,
+                "externalParameters": {
+                    "manifest": manifest_path,
+                    "image": image,
+                    "tag": tag,
+                },
+                "resolvedDependencies": dependencies,
+            },
+            "runDetails": {
+                "builder": {
+                    "id": BUILDER_ID,
+                    "version": { (crate::NAME): env!("CARGO_PKG_VERSION") },
+                },
+            },
+        },
+    }))
+}
+
+/// Write the provenance statement of the manifest tagged `tag` to `path` as JSON
+pub(crate) fn write_provenance(
+    path: &Path,
+    oci_dir: &OciDir,
+    image: &str,
+    tag: &str,
+    manifest_path: &Path,
+    lockfile_path: &Path,
+    repositories: &[Repository],
+) -> Result<()> {
+    let statement = provenance_statement(
+        oci_dir,
+        image,
+        tag,
+        manifest_path,
+        lockfile_path,
+        repositories,
+    )?;
+    std::fs::write(path, serde_json::to_vec_pretty(&statement)?)
+        .with_context(|| format!("Failed to write provenance to `{}`", path.display()))
+}
+
+/// An in-toto resource descriptor of a file, recording its sha256 digest
+fn file_descriptor(path: &Path) -> Result<Value> {
+    let contents =
+        std::fs::read(path).with_context(|| format!("Failed to read `{}`", path.display()))?;
+    Ok(json!({
+        "uri": path,
+        "digest": { "sha256": format!("{:x}", Sha256::digest(&contents)) },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use ocidir::cap_std::{ambient_authority, fs::Dir};
+    use ocidir::oci_spec::image::{ImageConfigurationBuilder, MediaType};
+    use ocidir::{new_empty_manifest, OciDir};
+    use sha2::{Digest, Sha256};
+
+    use super::{provenance_statement, PROVENANCE_PREDICATE_TYPE, STATEMENT_TYPE};
+    use crate::config::Config;
+    use crate::oci::{insert_manifest_and_config, list_manifests, DigestAlgorithm};
+
+    #[test]
+    fn provenance_records_inputs_and_image_digest() {
+        let image = tempfile::tempdir().unwrap();
+        let dir = Dir::open_ambient_dir(image.path(), ambient_authority()).unwrap();
+        let oci_dir = OciDir::ensure(&dir).unwrap();
+        let manifest = new_empty_manifest()
+            .media_type(MediaType::ImageManifest)
+            .build()
+            .unwrap();
+        let config = ImageConfigurationBuilder::default().build().unwrap();
+        insert_manifest_and_config(
+            &oci_dir,
+            manifest,
+            config,
+            "latest",
+            DigestAlgorithm::Sha256,
+        )
+        .unwrap();
+        let image_digest = list_manifests(&oci_dir).unwrap()[0].digest.clone();
+
+        let inputs = tempfile::tempdir().unwrap();
+        let manifest_path = inputs.path().join("rpmoci.toml");
+        let config = r#"
+[contents]
+repositories = ["mariner-official-base", "https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64"]
+packages = ["tini"]
+"#;
+        std::fs::write(&manifest_path, config).unwrap();
+        let lockfile_path = inputs.path().join("rpmoci.lock");
+        std::fs::write(&lockfile_path, "pkg_specs = [\"tini\"]\n").unwrap();
+        let cfg: Config = toml::from_str(config).unwrap();
+
+        let statement = provenance_statement(
+            &oci_dir,
+            "foo",
+            "latest",
+            &manifest_path,
+            &lockfile_path,
+            &cfg.contents.repositories,
+        )
+        .unwrap();
+        assert_eq!(statement["_type"], STATEMENT_TYPE);
+        assert_eq!(statement["predicateType"], PROVENANCE_PREDICATE_TYPE);
+        assert_eq!(statement["subject"][0]["name"], "foo:latest");
+        assert_eq!(
+            format!(
+                "sha256:{}",
+                statement["subject"][0]["digest"]["sha256"]
+                    .as_str()
+                    .unwrap()
+            ),
+            image_digest
+        );
+
+        let dependencies = statement["predicate"]["buildDefinition"]["resolvedDependencies"]
+            .as_array()
+            .unwrap();
+        assert_eq!(dependencies.len(), 4);
+        assert_eq!(
+            dependencies[0]["digest"]["sha256"],
+            format!("{:x}", Sha256::digest(config))
+        );
+        assert_eq!(
+            dependencies[1]["digest"]["sha256"],
+            format!("{:x}", Sha256::digest("pkg_specs = [\"tini\"]\n"))
+        );
+        assert_eq!(dependencies[2]["name"], "mariner-official-base");
+        assert!(dependencies[2].get("uri").is_none());
+        assert_eq!(
+            dependencies[3]["uri"],
+            "https://packages.microsoft.com/cbl-mariner/2.0/prod/base/x86_64"
+        );
+
+        assert!(provenance_statement(
+            &oci_dir,
+            "foo",
+            "missing",
+            &manifest_path,
+            &lockfile_path,
+            &cfg.contents.repositories,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Copyright (C) Microsoft Corporation.
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+/// The maximum number of symlinks followed when resolving a path, as Linux's `MAXSYMLINKS`
+const MAX_SYMLINKS: usize = 40;
+
+/// Resolve a path in the root filesystem at `root`, following symlinks as if `root` were `/`.
+///
+/// Absolute symlink targets are resolved relative to `root`, and `..` components can't leave it,
+/// so the result is always inside `root`. The final component is only followed if `follow` is true.
+/// Components that don't exist are taken to be directories.
+/// Returns `None` if a component other than the last is an existing file that isn't a directory.
+pub(crate) fn resolve_in_root(root: &Path, path: &Path, follow: bool) -> Result<Option<PathBuf>> {
+    let mut resolved = PathBuf::new();
+    let mut pending = Vec::new();
+    push_components(&mut pending, path);
+    let mut symlinks = 0;
+    while let Some(component) = pending.pop() {
+        if component == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&component);
+        let last = pending.is_empty();
+        match std::fs::symlink_metadata(root.join(&candidate)) {
+            Ok(metadata) if metadata.is_symlink() && (follow || !last) => {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    bail!(
+                        "Too many levels of symbolic links resolving `{}`",
+                        path.display()
+                    );
+                }
+                let target = std::fs::read_link(root.join(&candidate))?;
+                if target.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                push_components(&mut pending, &target);
+            }
+            Ok(metadata) if !last && !metadata.is_dir() => return Ok(None),
+            Ok(_) => resolved = candidate,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => resolved = candidate,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some(root.join(resolved)))
+}
+
+/// Push a path's components onto a stack of components to resolve, so that the first component is popped first
+fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(name) => pending.push(name.to_owned()),
+            Component::ParentDir => pending.push(OsString::from("..")),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    use super::resolve_in_root;
+
+    #[test]
+    fn paths_are_resolved_inside_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        std::fs::create_dir_all(root.join("usr/lib")).unwrap();
+        std::fs::write(root.join("usr/lib/file"), "").unwrap();
+        symlink("/usr/lib", root.join("lib")).unwrap();
+        symlink("../../../../usr/lib", root.join("usr/escape")).unwrap();
+        symlink("/etc", root.join("usr/etc")).unwrap();
+        symlink("loop", root.join("loop")).unwrap();
+        let resolve = |path: &str, follow| resolve_in_root(root, Path::new(path), follow);
+
+        // Absolute symlinks are resolved relative to the root
+        assert_eq!(
+            resolve("/lib/file", false).unwrap(),
+            Some(root.join("usr/lib/file"))
+        );
+        // `..` can't leave the root
+        assert_eq!(
+            resolve("usr/escape/file", false).unwrap(),
+            Some(root.join("usr/lib/file"))
+        );
+        assert_eq!(
+            resolve("../../lib", true).unwrap(),
+            Some(root.join("usr/lib"))
+        );
+        // The final component is only followed if requested
+        assert_eq!(resolve("lib", false).unwrap(), Some(root.join("lib")));
+        assert_eq!(resolve("lib", true).unwrap(), Some(root.join("usr/lib")));
+        // Missing components, including missing symlink targets, are taken to be directories
+        assert_eq!(
+            resolve("usr/etc/missing/file", false).unwrap(),
+            Some(root.join("etc/missing/file"))
+        );
+        // Files aren't directories
+        assert_eq!(resolve("usr/lib/file/child", false).unwrap(), None);
+        assert!(resolve("loop/file", false).is_err());
+    }
+}
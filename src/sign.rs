@@ -0,0 +1,231 @@
+//! Copyright (C) Microsoft Corporation.
+//!
+//! This program is free software: you can redistribute it and/or modify
+//! it under the terms of the GNU General Public License as published by
+//! the Free Software Foundation, either version 3 of the License, or
+//! (at your option) any later version.
+//!
+//! This program is distributed in the hope that it will be useful,
+//! but WITHOUT ANY WARRANTY; without even the implied warranty of
+//! MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//! GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License
+//! along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::oci::{insert_manifest_and_config, list_manifests, write_blob, DigestAlgorithm};
+use anyhow::{bail, Context, Result};
+use ocidir::oci_spec::image::{ImageConfigurationBuilder, MediaType, RootFsBuilder};
+use ocidir::{new_empty_manifest, OciDir};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::Signer;
+
+/// Environment variable holding the password of an encrypted signing key
+pub(crate) const SIGNING_PASSWORD_ENV: &str = "COSIGN_PASSWORD";
+/// Media type of the sigstore simple signing payload layer
+pub(crate) const SIMPLE_SIGNING_MEDIA_TYPE: &str =
+    "application/vnd.dev.cosign.simplesigning.v1+json";
+/// Layer annotation holding the base64 encoded signature of the payload
+pub(crate) const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// Load an ECDSA private key from a PEM file, decrypting it with `password` if it's encrypted.
+///
+/// Keys in cosign's own encrypted format aren't supported, as they use a cipher OpenSSL doesn't provide.
+pub(crate) fn load_signing_key(path: &Path, password: Option<&str>) -> Result<PKey<Private>> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("Failed to read signing key `{}`", path.display()))?;
+    if String::from_utf8_lossy(&pem).contains("ENCRYPTED SIGSTORE PRIVATE KEY")
+        || String::from_utf8_lossy(&pem).contains("ENCRYPTED COSIGN PRIVATE KEY")
+    {
+        bail!(
+            "Signing key `{}` is in cosign's encrypted format, which isn't supported. Use a PEM encoded (optionally encrypted) PKCS#8 ECDSA key",
+            path.display()
+        );
+    }
+    let key = match password {
+        Some(password) => PKey::private_key_from_pem_passphrase(&pem, password.as_bytes()),
+        None => PKey::private_key_from_pem(&pem),
+    }
+    .with_context(|| format!("Failed to parse signing key `{}`", path.display()))?;
+    if key.id() != Id::EC {
+        bail!("Signing key `{}` isn't an ECDSA key", path.display());
+    }
+    Ok(key)
+}
+
+/// Sign the manifest tagged `tag`, storing the signature in the image layout following the
+/// sigstore convention: a manifest tagged `<algorithm>-<digest>.sig` whose single layer is a
+/// simple signing payload, annotated with the payload's signature.
+///
+/// Returns the tag of the signature manifest.
+pub(crate) fn sign_image(
+    oci_dir: &OciDir,
+    key: &PKey<Private>,
+    reference: &str,
+    tag: &str,
+    algorithm: DigestAlgorithm,
+) -> Result<String> {
+    let Some(manifest) = list_manifests(oci_dir)?
+        .into_iter()
+        .find(|manifest| manifest.tag.as_deref() == Some(tag))
+    else {
+        bail!("No manifest has the tag `{}`", tag);
+    };
+
+    let payload = serde_json::to_vec(&serde_json::json!({
+        "critical": {
+            "identity": { "docker-reference": reference },
+            "image": { "docker-manifest-digest": manifest.digest },
+            "type": "cosign container image signature",
+        },
+        "optional": null,
+    }))?;
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    let signature = signer
+        .sign_oneshot_to_vec(&payload)
+        .context("Failed to sign image")?;
+
+    // The payload is stored uncompressed, so its diff_id is its digest
+    let layer = write_blob(
+        oci_dir,
+        algorithm,
+        payload.as_slice(),
+        MediaType::Other(SIMPLE_SIGNING_MEDIA_TYPE.to_string()),
+    )?
+    .annotations(HashMap::from([(
+        SIGNATURE_ANNOTATION.to_string(),
+        openssl::base64::encode_block(&signature),
+    )]))
+    .build()?;
+    let config = ImageConfigurationBuilder::default()
+        .rootfs(
+            RootFsBuilder::default()
+                .typ("layers")
+                .diff_ids(vec![layer.digest().to_string()])
+                .build()?,
+        )
+        .build()?;
+    let signature_manifest = new_empty_manifest()
+        .media_type(MediaType::ImageManifest)
+        .layers(vec![layer])
+        .build()?;
+
+    let signature_tag = format!("{}.sig", manifest.digest.replace(':', "-"));
+    insert_manifest_and_config(
+        oci_dir,
+        signature_manifest,
+        config,
+        &signature_tag,
+        algorithm,
+    )?;
+    Ok(signature_tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use ocidir::cap_std::{ambient_authority, fs::Dir};
+    use ocidir::oci_spec::image::{ImageConfigurationBuilder, ImageManifest, MediaType};
+    use ocidir::{new_empty_manifest, OciDir};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::sign::Verifier;
+    use openssl::symm::Cipher;
+
+    use super::{load_signing_key, sign_image, SIGNATURE_ANNOTATION, SIMPLE_SIGNING_MEDIA_TYPE};
+    use crate::oci::{
+        insert_manifest_and_config, list_manifests, open_blob, validate_layout, DigestAlgorithm,
+    };
+
+    #[test]
+    fn images_are_signed() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let keys = tempfile::tempdir().unwrap();
+        let plain = keys.path().join("plain.pem");
+        std::fs::write(&plain, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let encrypted = keys.path().join("encrypted.pem");
+        std::fs::write(
+            &encrypted,
+            key.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), b"hunter2")
+                .unwrap(),
+        )
+        .unwrap();
+        let public_key = key.public_key_to_pem().unwrap();
+
+        load_signing_key(&plain, None).unwrap();
+        assert!(load_signing_key(&encrypted, Some("wrong")).is_err());
+        let key = load_signing_key(&encrypted, Some("hunter2")).unwrap();
+
+        for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+            let image = tempfile::tempdir().unwrap();
+            let dir = Dir::open_ambient_dir(image.path(), ambient_authority()).unwrap();
+            let oci_dir = OciDir::ensure(&dir).unwrap();
+            let manifest = new_empty_manifest()
+                .media_type(MediaType::ImageManifest)
+                .build()
+                .unwrap();
+            let config = ImageConfigurationBuilder::default().build().unwrap();
+            insert_manifest_and_config(&oci_dir, manifest, config, "latest", algorithm).unwrap();
+            let image_digest = list_manifests(&oci_dir).unwrap()[0].digest.clone();
+
+            let signature_tag =
+                sign_image(&oci_dir, &key, "example.com/app", "latest", algorithm).unwrap();
+            assert_eq!(
+                signature_tag,
+                format!("{}.sig", image_digest.replace(':', "-"))
+            );
+
+            let signature_manifest = list_manifests(&oci_dir)
+                .unwrap()
+                .into_iter()
+                .find(|manifest| manifest.tag.as_deref() == Some(signature_tag.as_str()))
+                .unwrap();
+            let descriptor = oci_dir
+                .read_index()
+                .unwrap()
+                .unwrap()
+                .manifests()
+                .iter()
+                .find(|d| d.digest() == &signature_manifest.digest)
+                .unwrap()
+                .clone();
+            let manifest =
+                ImageManifest::from_reader(open_blob(&oci_dir, &descriptor).unwrap()).unwrap();
+            assert_eq!(manifest.layers().len(), 1);
+            let layer = &manifest.layers()[0];
+            assert_eq!(
+                layer.media_type(),
+                &MediaType::Other(SIMPLE_SIGNING_MEDIA_TYPE.to_string())
+            );
+            assert!(layer.digest().starts_with(&format!("{}:", algorithm)));
+
+            let payload = std::io::read_to_string(open_blob(&oci_dir, layer).unwrap()).unwrap();
+            let payload_json: serde_json::Value = serde_json::from_str(&payload).unwrap();
+            assert_eq!(
+                payload_json["critical"]["image"]["docker-manifest-digest"],
+                image_digest.as_str()
+            );
+            assert_eq!(
+                payload_json["critical"]["identity"]["docker-reference"],
+                "example.com/app"
+            );
+
+            let signature = openssl::base64::decode_block(
+                &layer.annotations().as_ref().unwrap()[SIGNATURE_ANNOTATION],
+            )
+            .unwrap();
+            let public_key = PKey::public_key_from_pem(&public_key).unwrap();
+            let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key).unwrap();
+            assert!(verifier
+                .verify_oneshot(&signature, payload.as_bytes())
+                .unwrap());
+
+            assert_eq!(validate_layout(&oci_dir).unwrap(), Vec::<String>::new());
+        }
+    }
+}
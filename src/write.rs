@@ -14,17 +14,158 @@
 //!
 //! You should have received a copy of the GNU General Public License
 //! along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::cell::RefCell;
 use std::fmt::Display;
 use std::io::{self, Write};
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::Serialize;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
-fn msg(label: &str, message: impl Display, color: &ColorSpec) -> io::Result<()> {
-    let mut stderr = StandardStream::stderr(ColorChoice::Auto);
-    stderr.set_color(color)?;
-    write!(&mut stderr, "{:>20} ", label)?;
-    stderr.set_color(ColorSpec::new().set_fg(None))?;
-    writeln!(&mut stderr, "{}", message)?;
-    Ok(())
+/// The severity of a progress message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    /// Progress through the build pipeline
+    Ok,
+    /// Something the user may need to act on
+    Warning,
+    /// A failure
+    Error,
+}
+
+/// Receives the progress messages rpmoci writes as it resolves, downloads and builds images
+pub trait Reporter: Send + Sync {
+    /// Report a message, e.g. label `Installing` with message `packages`
+    fn report(&self, level: Level, label: &str, message: &str) -> io::Result<()>;
+
+    /// Whether the output of subprocesses such as dnf should be shown.
+    /// Reporters producing machine-readable or quiet output return false.
+    fn show_subprocess_output(&self) -> bool {
+        true
+    }
+}
+
+/// Writes colored, human readable messages to stderr. This is the default reporter.
+#[derive(Debug, Default)]
+pub struct HumanReporter;
+
+impl Reporter for HumanReporter {
+    fn report(&self, level: Level, label: &str, message: &str) -> io::Result<()> {
+        let mut color = ColorSpec::new();
+        match level {
+            Level::Ok => color.set_fg(Some(Color::Green)),
+            Level::Warning => color.set_fg(Some(Color::Yellow)).set_bold(true),
+            Level::Error => color.set_fg(Some(Color::Red)).set_bold(true),
+        };
+        let mut stderr = StandardStream::stderr(ColorChoice::Auto);
+        stderr.set_color(&color)?;
+        write!(&mut stderr, "{:>20} ", label)?;
+        stderr.set_color(ColorSpec::new().set_fg(None))?;
+        writeln!(&mut stderr, "{}", message)?;
+        Ok(())
+    }
+}
+
+/// Writes each message as a line of JSON, e.g.
+/// `{"level":"ok","label":"Installing","message":"packages"}`
+#[derive(Debug)]
+pub struct JsonReporter<W> {
+    writer: Mutex<W>,
+}
+
+impl<W> JsonReporter<W> {
+    /// Create a reporter writing JSON lines to `writer`
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Return the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    level: Level,
+    label: &'a str,
+    message: &'a str,
+}
+
+impl<W: Write + Send> Reporter for JsonReporter<W> {
+    fn report(&self, level: Level, label: &str, message: &str) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        serde_json::to_writer(
+            &mut *writer,
+            &JsonMessage {
+                level,
+                label,
+                message,
+            },
+        )?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+
+    fn show_subprocess_output(&self) -> bool {
+        false
+    }
+}
+
+/// Only passes errors to the wrapped reporter
+#[derive(Debug)]
+pub struct QuietReporter<R>(pub R);
+
+impl<R: Reporter> Reporter for QuietReporter<R> {
+    fn report(&self, level: Level, label: &str, message: &str) -> io::Result<()> {
+        if level == Level::Error {
+            self.0.report(level, label, message)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn show_subprocess_output(&self) -> bool {
+        false
+    }
+}
+
+static REPORTER: RwLock<Option<Arc<dyn Reporter>>> = RwLock::new(None);
+
+thread_local! {
+    static SCOPED_REPORTER: RefCell<Option<Arc<dyn Reporter>>> = const { RefCell::new(None) };
+}
+
+/// Set the reporter that messages are written to. Defaults to [`HumanReporter`].
+pub fn set_reporter(reporter: impl Reporter + 'static) {
+    *REPORTER.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(reporter));
+}
+
+/// Run `f`, writing the messages it writes on this thread to `reporter`
+pub fn with_reporter<T>(reporter: Arc<dyn Reporter>, f: impl FnOnce() -> T) -> T {
+    let previous = SCOPED_REPORTER.with(|scoped| scoped.replace(Some(reporter)));
+    let result = f();
+    SCOPED_REPORTER.with(|scoped| *scoped.borrow_mut() = previous);
+    result
+}
+
+fn reporter() -> Arc<dyn Reporter> {
+    SCOPED_REPORTER
+        .with(|scoped| scoped.borrow().clone())
+        .or_else(|| REPORTER.read().unwrap_or_else(|e| e.into_inner()).clone())
+        .unwrap_or_else(|| Arc::new(HumanReporter))
+}
+
+/// Whether the output of subprocesses such as dnf should be shown
+pub fn show_subprocess_output() -> bool {
+    reporter().show_subprocess_output()
+}
+
+fn msg(level: Level, label: &str, message: impl Display) -> io::Result<()> {
+    reporter().report(level, label, &message.to_string())
 }
 
 /// Write an ok message to stderr
@@ -33,7 +174,16 @@ fn msg(label: &str, message: impl Display, color: &ColorSpec) -> io::Result<()>
 ///
 /// Will return `Err` if a problem is encountered writing to stderr
 pub fn ok(label: &str, message: impl Display) -> io::Result<()> {
-    msg(label, message, ColorSpec::new().set_fg(Some(Color::Green)))
+    msg(Level::Ok, label, message)
+}
+
+/// Write a warning message to stderr
+///
+/// # Errors
+///
+/// Will return `Err` if a problem is encountered writing to stderr
+pub fn warning(label: &str, message: impl Display) -> io::Result<()> {
+    msg(Level::Warning, label, message)
 }
 
 /// Write an error message to stderr
@@ -42,9 +192,78 @@ pub fn ok(label: &str, message: impl Display) -> io::Result<()> {
 ///
 /// Will return `Err` if a problem is encountered writing to stderr
 pub fn error(label: &str, message: impl Display) -> io::Result<()> {
-    msg(
-        label,
-        message,
-        ColorSpec::new().set_fg(Some(Color::Red)).set_bold(true),
-    )
+    msg(Level::Error, label, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{
+        error, ok, show_subprocess_output, warning, with_reporter, JsonReporter, Level,
+        QuietReporter, Reporter,
+    };
+
+    /// A reporter that records the messages it receives
+    #[derive(Default)]
+    struct Capture(Mutex<Vec<(Level, String, String)>>);
+
+    impl Reporter for Capture {
+        fn report(&self, level: Level, label: &str, message: &str) -> std::io::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((level, label.to_string(), message.to_string()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn messages_are_reported() {
+        let capture = Arc::new(Capture::default());
+        with_reporter(capture.clone(), || {
+            ok("Installing", "packages").unwrap();
+            warning("Warning", "careful").unwrap();
+            assert!(show_subprocess_output());
+        });
+        // Messages written outside the scope aren't captured
+        assert_eq!(
+            *capture.0.lock().unwrap(),
+            vec![
+                (Level::Ok, "Installing".to_string(), "packages".to_string()),
+                (Level::Warning, "Warning".to_string(), "careful".to_string()),
+            ]
+        );
+
+        let quiet = Arc::new(QuietReporter(Capture::default()));
+        with_reporter(quiet.clone(), || {
+            ok("Installing", "packages").unwrap();
+            error("Error", "failed").unwrap();
+            assert!(!show_subprocess_output());
+        });
+        assert_eq!(
+            *quiet.0 .0.lock().unwrap(),
+            vec![(Level::Error, "Error".to_string(), "failed".to_string())]
+        );
+    }
+
+    #[test]
+    fn json_messages_are_written_as_lines() {
+        let reporter = JsonReporter::new(Vec::new());
+        reporter
+            .report(Level::Ok, "Creating", "root filesystem layer")
+            .unwrap();
+        reporter
+            .report(Level::Warning, "Warning", "a \"quoted\" message")
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(reporter.into_inner()).unwrap(),
+            concat!(
+                r#"{"level":"ok","label":"Creating","message":"root filesystem layer"}"#,
+                "\n",
+                r#"{"level":"warning","label":"Warning","message":"a \"quoted\" message"}"#,
+                "\n",
+            )
+        );
+    }
 }
@@ -5,9 +5,9 @@ use std::{
     process::Command,
 };
 
-use rpmoci::lockfile::Lockfile;
+use rpmoci::lockfile::{Keyring, Lockfile};
 
-use ocidir::oci_spec::image::ImageIndex;
+use ocidir::oci_spec::image::{ImageIndex, ImageManifest};
 use test_temp_dir::TestTempDir;
 
 // Path to rpmoci binary under test
@@ -317,3 +317,654 @@ fn copy_to_docker(image: &str, root: impl AsRef<Path>) {
         .expect("failed to run skopeo");
     assert!(status.success());
 }
+
+#[test]
+fn test_no_gpgkey_import() {
+    // Test that signatures can be verified using a pre-imported keyring rather than the lockfile's keys
+    let (_tmp_dir, root) = setup_test("no_gpgkey_import");
+    let output = rpmoci().arg("update").current_dir(&root).output().unwrap();
+    assert!(output.status.success());
+    let output = rpmoci()
+        .arg("vendor")
+        .arg("--out-dir=vendor")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let lockfile: Lockfile =
+        toml::from_str(&fs::read_to_string(root.join("rpmoci.lock")).unwrap()).unwrap();
+    let vendor_dir = root.join("vendor");
+
+    // No keys have been imported to this keyring, so verification fails
+    let empty_keyring = root.join("empty-keyring");
+    fs::create_dir(&empty_keyring).unwrap();
+    assert!(lockfile
+        .check_gpg_keys(&vendor_dir, Keyring::Existing(&empty_keyring))
+        .is_err());
+
+    let keyring = root.join("keyring");
+    fs::create_dir(&keyring).unwrap();
+    let status = Command::new("rpm")
+        .arg("--root")
+        .arg(&keyring)
+        .arg("--import")
+        .arg("https://raw.githubusercontent.com/microsoft/CBL-Mariner/2.0/SPECS/mariner-repos/MICROSOFT-RPM-GPG-KEY")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    lockfile
+        .check_gpg_keys(&vendor_dir, Keyring::Existing(&keyring))
+        .unwrap();
+}
+
+#[test]
+fn test_no_gpgkey_configured() {
+    // Repositories with gpgcheck enabled but no key configured are an error, unless packages are verified
+    // with keys already imported into the host's keyring
+    let (_tmp_dir, root) = setup_test("no_gpgkey_configured");
+    let output = rpmoci().arg("update").current_dir(&root).output().unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("but no gpgkey configured"));
+
+    // The lock file is written before the packages are verified, which needs the key in the host's keyring
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=foo")
+        .arg("--tag=bar")
+        .arg("--no-gpgkey-import")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(!stderr.contains("no gpgkey configured"));
+    assert!(root.join("rpmoci.lock").exists());
+
+    let output = rpmoci()
+        .arg("vendor")
+        .arg("--out-dir=vendor")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let output = rpmoci()
+        .arg("vendor")
+        .arg("--out-dir=vendor")
+        .arg("--no-gpgkey-import")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(!stderr.contains("no gpgkey configured"));
+
+    // Verify the vendored packages against a keyring the key has been imported into,
+    // as --no-gpgkey-import does with the host's keyring
+    let lockfile: Lockfile =
+        toml::from_str(&fs::read_to_string(root.join("rpmoci.lock")).unwrap()).unwrap();
+    let keyring = root.join("keyring");
+    fs::create_dir(&keyring).unwrap();
+    let status = Command::new("rpm")
+        .arg("--root")
+        .arg(&keyring)
+        .arg("--import")
+        .arg("https://raw.githubusercontent.com/microsoft/CBL-Mariner/2.0/SPECS/mariner-repos/MICROSOFT-RPM-GPG-KEY")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    lockfile
+        .check_gpg_keys(&root.join("vendor"), Keyring::Existing(&keyring))
+        .unwrap();
+}
+
+#[test]
+fn test_install_policy() {
+    // Test that a package whose scriptlet fails fails the build, unless the best effort policy is used
+    let (_tmp_dir, root) = setup_test("install_policy");
+    rpm::PackageBuilder::new("good", "1.0", "MIT", "noarch", "installs")
+        .release("1")
+        .build()
+        .unwrap()
+        .write_file(root.join("good-1.0-1.noarch.rpm"))
+        .unwrap();
+    rpm::PackageBuilder::new("bad", "1.0", "MIT", "noarch", "fails to install")
+        .release("1")
+        .pre_install_script("exit 1")
+        .build()
+        .unwrap()
+        .write_file(root.join("bad-1.0-1.noarch.rpm"))
+        .unwrap();
+
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=policy")
+        .arg("--tag=stop")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(!output.status.success());
+
+    let mut config = fs::read_to_string(root.join("rpmoci.toml")).unwrap();
+    config.push_str("install_policy = \"best_effort\"\n");
+    fs::write(root.join("rpmoci.toml"), config).unwrap();
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=policy")
+        .arg("--tag=best-effort")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(output.status.success());
+
+    let index = ImageIndex::from_file(root.join("policy").join("index.json")).unwrap();
+    let digest = index.manifests()[0].digest();
+    let manifest = ImageManifest::from_file(
+        root.join("policy/blobs/sha256")
+            .join(digest.trim_start_matches("sha256:")),
+    )
+    .unwrap();
+    let annotations = manifest.annotations().as_ref().unwrap();
+    assert_eq!(
+        annotations["com.microsoft.rpmoci.potentially-incomplete"],
+        "true"
+    );
+    assert_eq!(annotations["com.microsoft.rpmoci.failed-packages"], "bad");
+}
+
+#[test]
+fn test_file_conflicts() {
+    // Test that packages claiming a file with different contents fail the build,
+    // unless a policy choosing which package's file to keep is used
+    let (_tmp_dir, root) = setup_test("file_conflicts");
+    for name in ["alpha", "zeta"] {
+        let source = root.join(name);
+        fs::write(&source, name).unwrap();
+        rpm::PackageBuilder::new(name, "1.0", "MIT", "noarch", "conflicts")
+            .release("1")
+            .with_file(&source, rpm::FileOptions::new("/etc/conflict"))
+            .unwrap()
+            .build()
+            .unwrap()
+            .write_file(root.join(format!("{}-1.0-1.noarch.rpm", name)))
+            .unwrap();
+    }
+
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=conflicts")
+        .arg("--tag=fail")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(!output.status.success());
+
+    let config = fs::read_to_string(root.join("rpmoci.toml")).unwrap();
+    for (policy, expected) in [("first_wins", "alpha"), ("last_wins", "zeta")] {
+        fs::write(
+            root.join("rpmoci.toml"),
+            format!("{}file_conflicts = \"{}\"\n", config, policy),
+        )
+        .unwrap();
+        let output = rpmoci()
+            .arg("build")
+            .arg("--image=conflicts")
+            .arg(format!("--tag={}", policy))
+            .current_dir(&root)
+            .output()
+            .unwrap();
+        let stderr = std::str::from_utf8(&output.stderr).unwrap();
+        eprintln!("stderr: {}", stderr);
+        assert!(output.status.success());
+        assert!(stderr.contains(&format!("keeping the file from {}", expected)));
+
+        let index = ImageIndex::from_file(root.join("conflicts").join("index.json")).unwrap();
+        let digest = index
+            .manifests()
+            .iter()
+            .find(|manifest| {
+                manifest.annotations().as_ref().unwrap()["org.opencontainers.image.ref.name"]
+                    == policy
+            })
+            .unwrap()
+            .digest();
+        let blob = |digest: &str| {
+            root.join("conflicts/blobs/sha256")
+                .join(digest.trim_start_matches("sha256:"))
+        };
+        let manifest = ImageManifest::from_file(blob(digest)).unwrap();
+        let layer = fs::File::open(blob(manifest.layers()[0].digest())).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(layer));
+        let mut contents = String::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap() == Path::new("etc/conflict") {
+                std::io::Read::read_to_string(&mut entry, &mut contents).unwrap();
+            }
+        }
+        assert_eq!(contents, expected);
+    }
+}
+
+#[test]
+fn test_localpkg_gpgcheck() {
+    // Test that an unsigned local RPM can be installed alongside signed repository packages,
+    // unless local packages' signatures are verified
+    let (_tmp_dir, root) = setup_test("localpkg_gpgcheck");
+    rpm::PackageBuilder::new("local", "1.0", "MIT", "noarch", "unsigned")
+        .release("1")
+        .build()
+        .unwrap()
+        .write_file(root.join("local-1.0-1.noarch.rpm"))
+        .unwrap();
+
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=localpkg")
+        .arg("--tag=unverified")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(output.status.success());
+
+    let config = fs::read_to_string(root.join("rpmoci.toml")).unwrap();
+    fs::write(
+        root.join("rpmoci.toml"),
+        config.replace("[contents]\n", "[contents]\nlocalpkg_gpgcheck = true\n"),
+    )
+    .unwrap();
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=localpkg")
+        .arg("--tag=verified")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("Failed to verify local RPM"));
+}
+
+#[test]
+fn test_build_all() {
+    let out = test_temp_dir::TestTempDir::from_complete_item_path(&format!(
+        "it::{}",
+        std::thread::current().name().unwrap()
+    ));
+    let root = out.as_path_untracked().to_path_buf();
+    let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/build_all");
+    let configs = root.join("configs");
+    fs::create_dir_all(&configs).unwrap();
+    for name in ["db.toml", "web.toml"] {
+        fs::copy(fixture.join(name), configs.join(name)).unwrap();
+    }
+
+    // Neither config has a lock file, so both builds fail with --locked and both are reported
+    let output = rpmoci()
+        .args(["build-all", "configs", "--locked", "--image=foo"])
+        .current_dir(&root)
+        .env("NO_COLOR", "YES")
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("'foo:db'"));
+    assert!(stderr.contains("'foo:web'"));
+    assert!(stderr.contains("2 of 2 images failed to build"));
+
+    // --fail-fast stops after the first failure
+    let output = rpmoci()
+        .args(["build-all", "configs/*.toml", "--locked", "--fail-fast"])
+        .arg("--image=foo")
+        .current_dir(&root)
+        .env("NO_COLOR", "YES")
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(!output.status.success());
+    assert!(!stderr.contains("'foo:web'"));
+    assert!(stderr.contains("1 of 2 images failed to build (1 skipped by --fail-fast)"));
+
+    // Without --locked both configs are resolved and built.
+    // The repository metadata cached while building the first is reused by the second.
+    let output = rpmoci()
+        .args(["build-all", "configs", "--image=foo", "-vv"])
+        .current_dir(&root)
+        .env("NO_COLOR", "YES")
+        .env("XDG_CACHE_HOME", root.join("cache"))
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(output.status.success());
+    let (_, second_build) = stderr.split_once("'foo:web'").unwrap();
+    assert!(second_build.contains("from cached solv file"));
+    assert!(root.join("configs/db.lock").exists());
+    assert!(root.join("configs/web.lock").exists());
+    let index = ImageIndex::from_file(root.join("foo").join("index.json")).unwrap();
+    let mut tags = index
+        .manifests()
+        .iter()
+        .filter_map(|manifest| {
+            manifest
+                .annotations()
+                .as_ref()?
+                .get("org.opencontainers.image.ref.name")
+                .cloned()
+        })
+        .collect::<Vec<_>>();
+    tags.sort();
+    assert_eq!(tags, vec!["db", "web"]);
+}
+
+#[test]
+fn test_check_reproducible() {
+    let (_tmp_dir, root) = setup_test("simple_build");
+    let output = rpmoci()
+        .args(["build", "--image=foo", "--tag=bar", "--check-reproducible"])
+        .current_dir(&root)
+        .env("NO_COLOR", "YES")
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(output.status.success());
+    assert!(stderr.contains("image 'foo:bar' is reproducible"));
+}
+
+#[test]
+fn test_rpm_macros() {
+    // Test that %_install_langs limits the locales installed by packages' %lang files
+    let (_tmp_dir, root) = setup_test("rpm_macros");
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=macros")
+        .arg("--tag=latest")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(output.status.success());
+
+    let index = ImageIndex::from_file(root.join("macros").join("index.json")).unwrap();
+    let blob = |digest: &str| {
+        root.join("macros/blobs/sha256")
+            .join(digest.trim_start_matches("sha256:"))
+    };
+    let manifest = ImageManifest::from_file(blob(index.manifests()[0].digest())).unwrap();
+    let layer = fs::File::open(blob(manifest.layers()[0].digest())).unwrap();
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(layer));
+    let mut locales = std::collections::BTreeSet::new();
+    for entry in archive.entries().unwrap() {
+        let path = entry.unwrap().path().unwrap().into_owned();
+        // Message catalogs are usr/share/locale/<locale>/LC_MESSAGES/<domain>.mo
+        let components = path.iter().collect::<Vec<_>>();
+        if components.len() == 6 && path.starts_with("usr/share/locale") {
+            locales.insert(components[3].to_owned());
+        }
+    }
+    assert!(locales.contains(std::ffi::OsStr::new("de")));
+    assert_eq!(locales.len(), 1, "{:?}", locales);
+}
+
+#[test]
+fn test_assume_installed() {
+    // Test that packages assumed to be installed satisfy dependencies without being in the layer
+    let (_tmp_dir, root) = setup_test("assume_installed");
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=stacked")
+        .arg("--tag=latest")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(output.status.success());
+    let lockfile = fs::read_to_string(root.join("rpmoci.lock")).unwrap();
+    assert!(lockfile.contains("assumed_installed = true"));
+
+    let index = ImageIndex::from_file(root.join("stacked").join("index.json")).unwrap();
+    let blob = |digest: &str| {
+        root.join("stacked/blobs/sha256")
+            .join(digest.trim_start_matches("sha256:"))
+    };
+    let manifest = ImageManifest::from_file(blob(index.manifests()[0].digest())).unwrap();
+    let layer = fs::File::open(blob(manifest.layers()[0].digest())).unwrap();
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(layer));
+    let paths = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().into_owned())
+        .collect::<Vec<_>>();
+    assert!(paths.iter().any(|path| path.ends_with("usr/bin/sed")));
+    assert!(!paths
+        .iter()
+        .any(|path| path.starts_with("usr/lib") && path.ends_with("libc.so.6")));
+
+    // The packages glibc depends on are also in the image being built on
+    let lockfile: toml::Value = toml::from_str(&lockfile).unwrap();
+    let packages = lockfile["packages"].as_array().unwrap();
+    let package = |name: &str| {
+        packages
+            .iter()
+            .find(|pkg| pkg["name"].as_str() == Some(name))
+    };
+    let assumed_installed = |name: &str| {
+        package(name)
+            .and_then(|pkg| pkg.get("assumed_installed"))
+            .and_then(toml::Value::as_bool)
+            == Some(true)
+    };
+    let dependencies = package("glibc").unwrap()["dependencies"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|dependency| dependency.as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert!(!dependencies.is_empty());
+    for dependency in &dependencies {
+        assert!(assumed_installed(dependency), "{}", dependency);
+    }
+
+    // so their files are only in the layer if a package that isn't assumed installed owns them too
+    let layer_dir = root.join("layer");
+    let layer = fs::File::open(blob(manifest.layers()[0].digest())).unwrap();
+    tar::Archive::new(flate2::read::GzDecoder::new(layer))
+        .unpack(&layer_dir)
+        .unwrap();
+    let rpm = |args: &[&str]| {
+        let output = Command::new("rpm")
+            .arg("--root")
+            .arg(&layer_dir)
+            .args(args)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+    for dependency in &dependencies {
+        for file in rpm(&["-ql", dependency]).lines() {
+            let path = layer_dir.join(file.trim_start_matches('/'));
+            if !path
+                .symlink_metadata()
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                continue;
+            }
+            assert!(
+                rpm(&["-qf", "--qf", "%{NAME}\n", file])
+                    .lines()
+                    .any(|owner| !assumed_installed(owner)),
+                "{} of {} is in the layer",
+                file,
+                dependency
+            );
+        }
+    }
+}
+
+#[test]
+fn test_assume_installed_lockfile() {
+    // Test that the packages in the lockfile of the image being built on are assumed installed at their locked versions
+    let (_tmp_dir, root) = setup_test("assume_installed");
+    let config = fs::read_to_string(root.join("rpmoci.toml")).unwrap();
+    fs::create_dir(root.join("base")).unwrap();
+    fs::write(
+        root.join("base/rpmoci.toml"),
+        config.replace(
+            "packages = [\"sed\"]\nassume_installed = [\"glibc\"]",
+            "packages = [\"glibc\"]",
+        ),
+    )
+    .unwrap();
+    let output = rpmoci()
+        .arg("update")
+        .current_dir(root.join("base"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    fs::write(
+        root.join("rpmoci.toml"),
+        config.replace(
+            "assume_installed = [\"glibc\"]",
+            "assume_installed_lockfile = \"base/rpmoci.lock\"",
+        ),
+    )
+    .unwrap();
+    let output = rpmoci().arg("update").current_dir(&root).output().unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(output.status.success());
+
+    let packages = |path: PathBuf| {
+        let lockfile: toml::Value = toml::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        lockfile["packages"].as_array().unwrap().clone()
+    };
+    let stacked = packages(root.join("rpmoci.lock"));
+    for base_pkg in packages(root.join("base/rpmoci.lock")) {
+        let pkg = stacked
+            .iter()
+            .find(|pkg| pkg["name"] == base_pkg["name"])
+            .unwrap();
+        assert_eq!(pkg["evr"], base_pkg["evr"]);
+        assert_eq!(
+            pkg.get("assumed_installed"),
+            Some(&toml::Value::Boolean(true))
+        );
+    }
+    let sed = stacked
+        .iter()
+        .find(|pkg| pkg["name"].as_str() == Some("sed"))
+        .unwrap();
+    assert!(sed.get("assumed_installed").is_none());
+}
+
+#[test]
+fn test_package_overrides() {
+    // Test that packages added for a single build are installed without modifying the lock file
+    let (_tmp_dir, root) = setup_test("simple_build");
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=foo")
+        .arg("--tag=bar")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let lockfile = fs::read_to_string(root.join("rpmoci.lock")).unwrap();
+
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=foo")
+        .arg("--tag=overridden")
+        .arg("--add-package=sed")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(root.join("rpmoci.lock")).unwrap(),
+        lockfile
+    );
+
+    let index = ImageIndex::from_file(root.join("foo").join("index.json")).unwrap();
+    let digest = index
+        .manifests()
+        .iter()
+        .find(|manifest| {
+            manifest.annotations().as_ref().unwrap()["org.opencontainers.image.ref.name"]
+                == "overridden"
+        })
+        .unwrap()
+        .digest();
+    let blob = |digest: &str| {
+        root.join("foo/blobs/sha256")
+            .join(digest.trim_start_matches("sha256:"))
+    };
+    let manifest = ImageManifest::from_file(blob(digest)).unwrap();
+    let layer = fs::File::open(blob(manifest.layers()[0].digest())).unwrap();
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(layer));
+    assert!(archive
+        .entries()
+        .unwrap()
+        .any(|entry| entry.unwrap().path().unwrap() == Path::new("usr/bin/sed")));
+}
+
+#[test]
+fn test_dry_run() {
+    // Test that a dry run installs the packages and reports the root filesystem without writing an image
+    let (_tmp_dir, root) = setup_test("simple_build");
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=foo")
+        .arg("--tag=bar")
+        .arg("--dry-run")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(output.status.success());
+    assert!(stderr.contains("root filesystem of"));
+    assert!(!stderr.contains("root filesystem of 0 files"));
+    // The packages were resolved, but no image layout or blobs were written
+    assert!(root.join("rpmoci.lock").exists());
+    assert!(!root.join("foo").exists());
+
+    // The layer size budget is checked without writing the layer
+    let config = fs::read_to_string(root.join("rpmoci.toml")).unwrap();
+    fs::write(
+        root.join("rpmoci.toml"),
+        format!("{}\n[layer]\nmax_size = 1024\n", config),
+    )
+    .unwrap();
+    let output = rpmoci()
+        .arg("build")
+        .arg("--image=foo")
+        .arg("--tag=bar")
+        .arg("--dry-run")
+        .current_dir(&root)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    eprintln!("stderr: {}", stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("exceeding layer.max_size of 1024 bytes"));
+    assert!(!root.join("foo").exists());
+}